@@ -3,10 +3,9 @@ use std::io::Write;
 use anyhow::Result;
 use clap::Parser;
 use tokio::io::AsyncReadExt;
-use tokio_serial::SerialPortBuilderExt;
 
-use rax25::r#async::{ConnectionBuilder, PortType};
-use rax25::{parse_duration, Addr};
+use rax25::r#async::{ConnectionBuilder, LineEnding, PortType};
+use rax25::{parse_duration, Addr, KissConfig};
 
 #[derive(Parser, Debug)]
 struct Opt {
@@ -14,6 +13,10 @@ struct Opt {
     #[clap(short = 'p', default_value = "/dev/null")]
     port: String,
 
+    /// Serial port speed, between host and TNC.
+    #[clap(long, default_value = "9600")]
+    baud: u32,
+
     /// Source callsign and SSID.
     #[clap(short = 's')]
     src: String,
@@ -34,6 +37,14 @@ struct Opt {
     #[clap(long)]
     capture: Option<std::path::PathBuf>,
 
+    /// Include the KISS command byte in captured packets.
+    #[clap(long)]
+    capture_kiss: bool,
+
+    /// Capture packets in/out to pcapng, marked with their direction.
+    #[clap(long)]
+    capture_pcapng: Option<std::path::PathBuf>,
+
     /// Initial SRT value.
     #[clap(long, value_parser = parse_duration)]
     srt: Option<std::time::Duration>,
@@ -60,7 +71,7 @@ async fn main() -> Result<()> {
         .init()
         .unwrap();
     let port = if opt.port.contains('/') {
-        PortType::Serial(tokio_serial::new(&opt.port, 9600).open_native_async()?)
+        PortType::serial(&opt.port, &KissConfig::new(opt.baud))?
     } else {
         PortType::Tcp(tokio::net::TcpStream::connect(&opt.port).await?)
     };
@@ -73,6 +84,12 @@ async fn main() -> Result<()> {
         if let Some(capture) = opt.capture {
             builder = builder.capture(capture);
         }
+        if let Some(capture) = opt.capture_pcapng {
+            builder = builder.capture_pcapng(capture);
+        }
+        if opt.capture_kiss {
+            builder = builder.capture_link_type(rax25::pcap::LinkType::Ax25Kiss);
+        }
         if let Some(v) = opt.srt {
             builder = builder.srt_default(v);
         }
@@ -82,6 +99,9 @@ async fn main() -> Result<()> {
         if let Some(v) = opt.mtu {
             builder = builder.mtu(v);
         }
+        if opt.cr {
+            builder = builder.line_ending(LineEnding::Cr);
+        }
         builder
     };
 
@@ -110,13 +130,8 @@ async fn main() -> Result<()> {
                     eprintln!("Got 'exit' from user");
                     break;
                 }
-                let buf: Vec<_> = if opt.cr {
-                    buf.iter().map(|&b| if b == b'\n' { b'\r' } else {b}).collect()
-                } else {
-                    buf.to_vec()
-                };
                 //eprintln!("Got {buf:?} from stdin");
-                client.write(&buf).await?;
+                client.write(buf).await?;
             },
             data = client.read() => {
                 let data = data?;
@@ -128,7 +143,6 @@ async fn main() -> Result<()> {
                     Ok(s) => s,
                     Err(_) => String::from_utf8(data.iter().map(|&b| b & 0x7F).collect())?,
                 };
-                let s = if opt.cr { s.replace("\r", "\n") } else {s};
                 print!("{s}");
                 std::io::stdout().flush()?;
             },
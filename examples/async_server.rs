@@ -1,9 +1,10 @@
 use anyhow::Result;
 use clap::Parser;
-use tokio_serial::SerialPortBuilderExt;
 
-use rax25::r#async::{ConnectionBuilder, PortType};
-use rax25::{parse_duration, Addr};
+use rax25::r#async::{
+    CancelToken, Cancelled, ConnectionBuilder, KissTcpServer, LineEnding, PortType,
+};
+use rax25::{parse_duration, Addr, KissConfig};
 
 #[derive(Parser, Debug)]
 struct Opt {
@@ -11,6 +12,15 @@ struct Opt {
     #[clap(short = 'p', default_value = "/dev/null")]
     port: String,
 
+    /// Listen for a TNC program to connect to `port`, instead of connecting
+    /// to it.
+    #[clap(long)]
+    listen: bool,
+
+    /// Serial port speed, between host and TNC.
+    #[clap(long, default_value = "9600")]
+    baud: u32,
+
     /// Source callsign and SSID.
     #[clap(short = 's')]
     src: String,
@@ -27,6 +37,14 @@ struct Opt {
     #[clap(long)]
     capture: Option<std::path::PathBuf>,
 
+    /// Include the KISS command byte in captured packets.
+    #[clap(long)]
+    capture_kiss: bool,
+
+    /// Capture packets in/out to pcapng, marked with their direction.
+    #[clap(long)]
+    capture_pcapng: Option<std::path::PathBuf>,
+
     /// Initial SRT value.
     #[clap(long, value_parser = parse_duration)]
     srt: Option<std::time::Duration>,
@@ -49,16 +67,38 @@ async fn main() -> Result<()> {
         .init()
         .unwrap();
     let port = if opt.port.contains('/') {
-        PortType::Serial(tokio_serial::new(&opt.port, 9600).open_native_async()?)
+        PortType::serial(&opt.port, &KissConfig::new(opt.baud))?
+    } else if opt.listen {
+        let server = KissTcpServer::bind(&opt.port).await?;
+        println!("Awaiting KISS connection on {}", server.local_addr()?);
+        let (port, addr) = server.accept().await?;
+        println!("KISS connection from {addr}");
+        port
     } else {
         PortType::Tcp(tokio::net::TcpStream::connect(&opt.port).await?)
     };
+    let cancel = CancelToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("Got Ctrl-C, shutting down");
+                cancel.cancel();
+            }
+        }
+    });
     println!("Awaiting connection");
     let mut client = {
-        let mut builder = ConnectionBuilder::new(Addr::new(&opt.src)?, port)?;
+        let mut builder = ConnectionBuilder::new(Addr::new(&opt.src)?, port)?.cancel_token(cancel);
         if let Some(capture) = opt.capture {
             builder = builder.capture(capture);
         }
+        if let Some(capture) = opt.capture_pcapng {
+            builder = builder.capture_pcapng(capture);
+        }
+        if opt.capture_kiss {
+            builder = builder.capture_link_type(rax25::pcap::LinkType::Ax25Kiss);
+        }
         if let Some(v) = opt.srt {
             builder = builder.srt_default(v);
         }
@@ -68,14 +108,25 @@ async fn main() -> Result<()> {
         if let Some(v) = opt.mtu {
             builder = builder.mtu(v);
         }
-        builder.accept().await?
+        if opt.cr {
+            builder = builder.line_ending(LineEnding::Cr);
+        }
+        match builder.accept().await {
+            Ok(c) => c,
+            Err(e) if e.is::<Cancelled>() => return Ok(()),
+            Err(e) => return Err(e),
+        }
     };
     println!("Connected");
     client.write(b"Welcome to the server!\n").await?;
     loop {
         tokio::select! {
             data = client.read() => {
-                let data = data?;
+                let data = match data {
+                    Ok(data) => data,
+                    Err(e) if e.is::<Cancelled>() => break,
+                    Err(e) => return Err(e),
+                };
                 if data.is_empty() {
                     eprintln!("Got EOF");
                     break;
@@ -6,7 +6,7 @@ use anyhow::Result;
 use clap::Parser;
 
 use rax25::sync::Client;
-use rax25::{Addr, Kiss};
+use rax25::{Addr, Kiss, KissConfig};
 
 #[derive(Parser, Debug)]
 struct Opt {
@@ -14,6 +14,10 @@ struct Opt {
     #[clap(short = 'p', default_value = "/dev/null")]
     port: String,
 
+    /// Serial port speed, between host and TNC.
+    #[clap(long, default_value = "9600")]
+    baud: u32,
+
     /// Source callsign and SSID.
     #[clap(short = 's')]
     src: String,
@@ -43,7 +47,7 @@ fn main() -> Result<()> {
         .verbosity(opt.v)
         .init()
         .unwrap();
-    let k = Kiss::new(&opt.port)?;
+    let k = Kiss::with_config(&opt.port, &KissConfig::new(opt.baud))?;
     let mut c = Client::new(Addr::new(&opt.src)?, Box::new(k));
 
     let d = done.clone();
@@ -8,7 +8,7 @@ use clap::Parser;
 use log::debug;
 
 use rax25::sync::Client;
-use rax25::{Addr, BusHub, BusKiss};
+use rax25::{Addr, BusHub, BusKiss, KissConfig};
 
 #[derive(Parser, Debug)]
 struct Opt {
@@ -16,6 +16,10 @@ struct Opt {
     #[clap(short = 'p', default_value = "/dev/null")]
     port: String,
 
+    /// Serial port speed, between host and TNC.
+    #[clap(long, default_value = "9600")]
+    baud: u32,
+
     /// Source callsign and SSID.
     #[clap(short = 's')]
     src: String,
@@ -38,7 +42,7 @@ fn main() -> Result<()> {
         .init()
         .unwrap();
     let bus = Arc::new(Mutex::new(bus::Bus::<rax25::BusMessage>::new(10)));
-    let mut bk = BusKiss::new(&opt.port, bus.clone())?;
+    let mut bk = BusKiss::with_config(&opt.port, &KissConfig::new(opt.baud), bus.clone())?;
     std::thread::spawn(move || {
         bk.run();
     });
@@ -0,0 +1,98 @@
+//! APRS helpers.
+//!
+//! APRS frames are plain UI frames, but by convention sent to a "tocall"
+//! destination identifying the software (or just `APRS`), via a digipeater
+//! path like `WIDE1-1,WIDE2-1`, with the APRS data in the info field.
+//!
+//! http://www.aprs.org/doc/APRS101.PDF
+//!
+//! # Example
+//!
+#![cfg_attr(feature = "kiss", doc = "```no_run")]
+#![cfg_attr(not(feature = "kiss"), doc = "```ignore")]
+//! use rax25::{aprs, Addr, Kiss};
+//!
+//! let mut kiss = Kiss::new("/dev/rfcomm0")?;
+//! aprs::beacon(
+//!     &mut kiss,
+//!     &Addr::new("M0THC-1")?,
+//!     &[Addr::new("WIDE1-1")?],
+//!     b">rax25 test station",
+//! )?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use anyhow::Result;
+use log::debug;
+
+use crate::{Addr, Hub, Packet};
+
+/// Default APRS destination, for software that has no tocall of its own.
+pub const DEFAULT_TOCALL: &str = "APRS";
+
+/// Build an APRS UI frame.
+///
+/// PID is always 0xF0 (no layer 3), as required by APRS.
+#[must_use]
+pub fn packet(src: &Addr, tocall: &Addr, path: &[Addr], info: &[u8]) -> Packet {
+    Packet::ui(src, tocall, path, 0xF0, info)
+}
+
+/// Send an APRS frame to the default tocall `APRS`.
+pub fn beacon(hub: &mut dyn Hub, src: &Addr, path: &[Addr], info: &[u8]) -> Result<()> {
+    beacon_to(hub, src, &Addr::new(DEFAULT_TOCALL)?, path, info)
+}
+
+/// Send an APRS frame to the given tocall.
+pub fn beacon_to(
+    hub: &mut dyn Hub,
+    src: &Addr,
+    tocall: &Addr,
+    path: &[Addr],
+    info: &[u8],
+) -> Result<()> {
+    debug!(
+        "Sending APRS frame {} -> {} via {} digipeaters",
+        src.call(),
+        tocall.call(),
+        path.len()
+    );
+    hub.send(&packet(src, tocall, path, info).serialize(false))
+}
+
+#[cfg(all(test, feature = "bus"))]
+mod tests {
+    use super::*;
+    use crate::{BusHub, PacketType, Ui};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn beacon() -> Result<()> {
+        let bus = Arc::new(Mutex::new(bus::Bus::<crate::BusMessage>::new(10)));
+        let mut rx = BusHub::new(bus.clone());
+        let mut tx = BusHub::new(bus);
+        let src = Addr::new("M0THC-1")?;
+        let path = vec![Addr::new("WIDE1-1")?];
+        super::beacon(&mut tx, &src, &path, b">hello")?;
+
+        let frame = rx
+            .recv_timeout(std::time::Duration::from_secs(1))?
+            .expect("no frame sent");
+        let p = Packet::parse(&frame, Some(false))?;
+        assert_eq!(p.src.call(), "M0THC-1");
+        assert_eq!(p.dst.call(), "APRS");
+        assert_eq!(p.digipeater, path);
+        assert_eq!(
+            p.packet_type,
+            PacketType::Ui(Ui {
+                push: false,
+                pid: 0xF0,
+                payload: b">hello".to_vec(),
+            })
+        );
+        // Address extension bit not set on the source, since the path follows.
+        assert_eq!(frame[13] & 1, 0);
+        Ok(())
+    }
+}
+/* vim: textwidth=80
+ */
@@ -6,6 +6,8 @@
 //! outstanding most of the time. Otherwise events like timers and received
 //! packets don't happen.
 //!
+//! To serve more than one connection on a port, use a `Listener`.
+//!
 //! If the caller is not interested in the received data, then it's probably
 //! best to spawn a task that reads in a loop and discards.
 //!
@@ -53,20 +55,244 @@
 //! }
 //! ```
 use std::collections::VecDeque;
+use std::future::Future;
 use std::pin::Pin;
 
-use crate::pcap::PcapWriter;
+use crate::pcap::{LinkType, PcapWriter, PcapngWriter, RotatingPcapWriter};
 use crate::state::{self, Event, ReturnEvent};
-use crate::{Addr, Packet, PacketType};
+use crate::{Addr, Direction, Packet, PacketType};
 
 use anyhow::{Error, Result};
-use log::debug;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
+use log::{debug, error, warn};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 pub enum PortType {
     Serial(tokio_serial::SerialStream),
     Tcp(tokio::net::TcpStream),
+    /// Kernel AX.25 interface, bypassing KISS.
+    #[cfg(target_os = "linux")]
+    Kernel(crate::linux::AsyncRawSocket),
+    /// Connection demultiplexed from a shared port by a `Listener`.
+    Mux(MuxPort),
+    /// Custom transport.
+    Hub(HubPort),
+}
+
+impl PortType {
+    /// Open a KISS serial port with the given settings.
+    pub fn serial(port: &str, config: &crate::KissConfig) -> Result<Self> {
+        use tokio_serial::SerialPortBuilderExt;
+        Ok(PortType::Serial(config.builder(port).open_native_async()?))
+    }
+
+    /// Use a custom transport.
+    #[must_use]
+    pub fn hub(hub: Box<dyn AsyncHub>) -> Self {
+        PortType::Hub(HubPort::new(hub))
+    }
+}
+
+/// Async version of `Hub`, for plugging custom transports into the async
+/// client with `PortType::hub()`.
+///
+/// Like `Hub`, frames are complete AX.25 frames, without KISS escaping. The
+/// methods are poll style, like `AsyncRead` and `AsyncWrite`, so that the
+/// trait can be used as `dyn AsyncHub`.
+pub trait AsyncHub: Send + Unpin {
+    /// Send a frame.
+    fn poll_send(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        frame: &[u8],
+    ) -> std::task::Poll<std::io::Result<()>>;
+
+    /// Receive a frame. Ok(None) means the transport is closed.
+    fn poll_recv(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<Option<Vec<u8>>>>;
+}
+
+/// Create two ports connected to each other in memory.
+///
+/// Useful for tests, running both ends of a connection in the same program.
+#[must_use]
+pub fn duplex() -> (PortType, PortType) {
+    let (atx, brx) = tokio::sync::mpsc::unbounded_channel();
+    let (btx, arx) = tokio::sync::mpsc::unbounded_channel();
+    (
+        PortType::hub(Box::new(ChannelHub { tx: atx, rx: arx })),
+        PortType::hub(Box::new(ChannelHub { tx: btx, rx: brx })),
+    )
+}
+
+/// In memory `AsyncHub`, one end of a `duplex()`.
+struct ChannelHub {
+    tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+    rx: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl AsyncHub for ChannelHub {
+    fn poll_send(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+        frame: &[u8],
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(
+            self.tx
+                .send(frame.to_vec())
+                .map_err(|_| std::io::ErrorKind::BrokenPipe.into()),
+        )
+    }
+
+    fn poll_recv(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<Option<Vec<u8>>>> {
+        self.rx.poll_recv(cx).map(Ok)
+    }
+}
+
+/// Port using an `AsyncHub`, presenting its frames as a KISS byte stream.
+pub struct HubPort {
+    hub: Box<dyn AsyncHub>,
+
+    /// KISS encoded incoming frames, not yet read.
+    rbuf: VecDeque<u8>,
+
+    /// KISS encoded outgoing data, not yet a complete frame.
+    wbuf: VecDeque<u8>,
+
+    /// Outgoing frames, not yet sent.
+    outq: VecDeque<Vec<u8>>,
+}
+
+impl HubPort {
+    #[must_use]
+    pub(crate) fn new(hub: Box<dyn AsyncHub>) -> Self {
+        Self {
+            hub,
+            rbuf: VecDeque::new(),
+            wbuf: VecDeque::new(),
+            outq: VecDeque::new(),
+        }
+    }
+
+    /// Send all queued frames.
+    fn poll_send_queued(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        while let Some(frame) = self.outq.front() {
+            std::task::ready!(self.hub.poll_send(cx, frame))?;
+            self.outq.pop_front();
+        }
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+impl tokio::io::AsyncRead for HubPort {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        if self.rbuf.is_empty() {
+            match std::task::ready!(self.hub.poll_recv(cx))? {
+                Some(frame) => self.rbuf.extend(crate::escape(&frame, 0)),
+                // EOF.
+                None => return std::task::Poll::Ready(Ok(())),
+            }
+        }
+        let n = std::cmp::min(buf.remaining(), self.rbuf.len());
+        let data: Vec<u8> = self.rbuf.drain(..n).collect();
+        buf.put_slice(&data);
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+impl tokio::io::AsyncWrite for HubPort {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        // Don't queue up unbounded frames if the hub is not keeping up.
+        std::task::ready!(self.poll_send_queued(cx))?;
+        self.wbuf.extend(buf);
+        while let Some((a, b)) = crate::find_frame(&self.wbuf) {
+            if b - a < 2 {
+                // Back to back FENDs.
+                self.wbuf.drain(..(a + 1));
+                continue;
+            }
+            // Skip FEND and the KISS command byte.
+            let frame: Vec<_> = self
+                .wbuf
+                .iter()
+                .skip(a + 2)
+                .take(b - a - 2)
+                .cloned()
+                .collect();
+            self.wbuf.drain(..b);
+            match crate::unescape(&frame) {
+                Ok(frame) => self.outq.push_back(frame),
+                Err(e) => debug!("Dropping frame: {e}"),
+            }
+        }
+        // Sending is best effort here. Anything left is sent on the next
+        // write or flush.
+        let _ = self.poll_send_queued(cx)?;
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.poll_send_queued(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// KISS over TCP server, for TNC programs that connect in.
+///
+/// This is the reverse of `PortType::Tcp` from a connected `TcpStream`, like
+/// Direwolf's KISS TCP port, but with rax25 on the listening side. The framing
+/// is the same KISS either way.
+pub struct KissTcpServer {
+    listener: tokio::net::TcpListener,
+}
+
+impl KissTcpServer {
+    /// Listen on the given address, e.g. `[::]:8001`.
+    pub async fn bind<A: tokio::net::ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(Self {
+            listener: tokio::net::TcpListener::bind(addr).await?,
+        })
+    }
+
+    /// The address actually listened on. Useful when binding to port 0.
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Wait for a TNC program to connect.
+    ///
+    /// Returns a port for `ConnectionBuilder` or `Listener`, and the address
+    /// of the TNC program.
+    pub async fn accept(&self) -> Result<(PortType, std::net::SocketAddr)> {
+        let (stream, addr) = self.listener.accept().await?;
+        stream.set_nodelay(true)?;
+        Ok((PortType::Tcp(stream), addr))
+    }
 }
 
 impl tokio::io::AsyncRead for PortType {
@@ -78,6 +304,10 @@ impl tokio::io::AsyncRead for PortType {
         match *self {
             PortType::Serial(ref mut x) => Pin::new(x).poll_read(cx, buf),
             PortType::Tcp(ref mut x) => Pin::new(x).poll_read(cx, buf),
+            #[cfg(target_os = "linux")]
+            PortType::Kernel(ref mut x) => Pin::new(x).poll_read(cx, buf),
+            PortType::Mux(ref mut x) => Pin::new(x).poll_read(cx, buf),
+            PortType::Hub(ref mut x) => Pin::new(x).poll_read(cx, buf),
         }
     }
 }
@@ -91,6 +321,10 @@ impl tokio::io::AsyncWrite for PortType {
         match *self {
             PortType::Serial(ref mut x) => Pin::new(x).poll_write(cx, buf),
             PortType::Tcp(ref mut x) => Pin::new(x).poll_write(cx, buf),
+            #[cfg(target_os = "linux")]
+            PortType::Kernel(ref mut x) => Pin::new(x).poll_write(cx, buf),
+            PortType::Mux(ref mut x) => Pin::new(x).poll_write(cx, buf),
+            PortType::Hub(ref mut x) => Pin::new(x).poll_write(cx, buf),
         }
     }
 
@@ -101,6 +335,10 @@ impl tokio::io::AsyncWrite for PortType {
         match *self {
             PortType::Serial(ref mut x) => Pin::new(x).poll_flush(cx),
             PortType::Tcp(ref mut x) => Pin::new(x).poll_flush(cx),
+            #[cfg(target_os = "linux")]
+            PortType::Kernel(ref mut x) => Pin::new(x).poll_flush(cx),
+            PortType::Mux(ref mut x) => Pin::new(x).poll_flush(cx),
+            PortType::Hub(ref mut x) => Pin::new(x).poll_flush(cx),
         }
     }
 
@@ -111,8 +349,281 @@ impl tokio::io::AsyncWrite for PortType {
         match *self {
             PortType::Serial(ref mut x) => Pin::new(x).poll_shutdown(cx),
             PortType::Tcp(ref mut x) => Pin::new(x).poll_shutdown(cx),
+            #[cfg(target_os = "linux")]
+            PortType::Kernel(ref mut x) => Pin::new(x).poll_shutdown(cx),
+            PortType::Mux(ref mut x) => Pin::new(x).poll_shutdown(cx),
+            PortType::Hub(ref mut x) => Pin::new(x).poll_shutdown(cx),
+        }
+    }
+}
+
+/// One connection's view of a port shared through a `Listener`.
+///
+/// Reads yield only the KISS frames for this connection. Writes are passed on
+/// to the shared port, one whole frame at a time.
+pub struct MuxPort {
+    rx: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl tokio::io::AsyncRead for MuxPort {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        while self.pending.is_empty() {
+            match self.rx.poll_recv(cx) {
+                std::task::Poll::Ready(Some(frame)) => self.pending = frame,
+                // Shared port closed. Signal EOF.
+                std::task::Poll::Ready(None) => return std::task::Poll::Ready(Ok(())),
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            }
+        }
+        let n = buf.remaining().min(self.pending.len());
+        buf.put_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+impl tokio::io::AsyncWrite for MuxPort {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        // Always taking the whole buffer means a `write_all()` of a frame is
+        // never interleaved with another connection's frame.
+        std::task::Poll::Ready(match self.tx.send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "shared port closed",
+            )),
+        })
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+/// Token for cancelling blocked operations on a `Client`.
+///
+/// Once cancelled, `connect()`, `accept()`, `read()` and other waiting calls
+/// return a `Cancelled` error promptly, leaving the `Client` (if any) in a
+/// state where a graceful `disconnect()` can still be made.
+///
+/// Clones share the same cancellation state.
+///
+/// ```no_run
+/// use rax25::r#async::{CancelToken, Cancelled, Client};
+///
+/// async fn serve(mut client: Client, cancel: CancelToken) -> anyhow::Result<()> {
+///     tokio::spawn({
+///         let cancel = cancel.clone();
+///         async move {
+///             tokio::signal::ctrl_c().await.unwrap();
+///             cancel.cancel();
+///         }
+///     });
+///     loop {
+///         match client.read().await {
+///             Ok(data) if data.is_empty() => return Ok(()),
+///             Ok(data) => println!("Got {data:?}"),
+///             Err(e) if e.is::<Cancelled>() => break,
+///             Err(e) => return Err(e),
+///         }
+///     }
+///     client.disconnect().await
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct CancelToken {
+    tx: std::sync::Arc<tokio::sync::watch::Sender<bool>>,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancelToken {
+    /// Create a new, not yet cancelled, token.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            tx: std::sync::Arc::new(tokio::sync::watch::Sender::new(false)),
+        }
+    }
+
+    /// Cancel all operations using this token.
+    pub fn cancel(&self) {
+        self.tx.send_replace(true);
+    }
+
+    /// Return true if the token has been cancelled.
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Wait until the token is cancelled.
+    pub async fn cancelled(&self) {
+        let mut rx = self.tx.subscribe();
+        // Can't fail, since we're holding the sender.
+        let _ = rx.wait_for(|&c| c).await;
+    }
+}
+
+/// Error returned when an operation was aborted via a `CancelToken`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Error returned when the peer refused a connection, answering DM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionRefused;
+
+impl std::fmt::Display for ConnectionRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "connection refused")
+    }
+}
+
+impl std::error::Error for ConnectionRefused {}
+
+/// Default max number of received frames queued for the state machine.
+pub const DEFAULT_MAX_INCOMING_FRAMES: usize = 1000;
+
+/// N2 to use when probing if the peer supports extended mode.
+///
+/// Like N2, this counts the first SABME too.
+pub const SABME_PROBE_RETRIES: u8 = 3;
+
+/// Default max number of received KISS bytes not yet forming a full frame.
+///
+/// This is enough for a max size frame, even if every byte is escaped.
+pub const DEFAULT_MAX_INCOMING_KISS: usize = 2 * state::DEFAULT_MTU_IN + 100;
+
+/// What to drop when an incoming queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Drop the oldest queued data, to make room for the new.
+    #[default]
+    Oldest,
+
+    /// Drop the new data.
+    Newest,
+}
+
+/// Line ending translation, for text connections.
+///
+/// BBS style hosts use CR as line ending, while local programs want NL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    /// No translation. Binary safe.
+    #[default]
+    None,
+
+    /// NL is sent as CR, and received CR is returned as NL.
+    Cr,
+
+    /// Like `Cr`, but also clear the high bit of received bytes, for hosts
+    /// sending 7 bit text with parity.
+    Cr7Bit,
+}
+
+impl LineEnding {
+    /// Translate data about to be written.
+    fn outgoing(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            LineEnding::None => data.to_vec(),
+            LineEnding::Cr | LineEnding::Cr7Bit => data
+                .iter()
+                .map(|&b| if b == b'\n' { b'\r' } else { b })
+                .collect(),
+        }
+    }
+
+    /// Translate received data, in place.
+    fn incoming(self, data: &mut [u8]) {
+        if self == LineEnding::None {
+            return;
+        }
+        for b in data {
+            if self == LineEnding::Cr7Bit {
+                *b &= 0x7F;
+            }
+            if *b == b'\r' {
+                *b = b'\n';
+            }
+        }
+    }
+}
+
+/// Client statistics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Received frames dropped because the incoming frame queue was full.
+    pub dropped_frames: u64,
+
+    /// Received bytes dropped because the KISS buffer was full.
+    pub dropped_kiss_bytes: u64,
+
+    /// Received frames not teed to `read_frame()`, because its queue was full.
+    pub dropped_tee_frames: u64,
+
+    /// Frames not sent to a `tap()`, because its queue was full.
+    pub dropped_tap_frames: u64,
+
+    /// UI frames not sent to a `ui_frames()`, because its queue was full.
+    pub dropped_ui_frames: u64,
+}
+
+/// Add items to a queue, dropping according to policy if it grows over `max`.
+///
+/// Returns the number of items dropped.
+fn push_bounded<T>(
+    q: &mut VecDeque<T>,
+    items: impl IntoIterator<Item = T>,
+    max: usize,
+    policy: DropPolicy,
+) -> u64 {
+    let mut dropped = 0;
+    for item in items {
+        if q.len() >= max {
+            dropped += 1;
+            match policy {
+                DropPolicy::Oldest => {
+                    q.pop_front();
+                }
+                DropPolicy::Newest => continue,
+            }
         }
+        q.push_back(item);
     }
+    dropped
 }
 
 /// Connection Builder.
@@ -122,10 +633,40 @@ pub struct ConnectionBuilder {
     me: Addr,
     extended: Option<bool>,
     capture: Option<std::path::PathBuf>,
+    capture_rotating: Option<(std::path::PathBuf, u64)>,
+    capture_pcapng: Option<std::path::PathBuf>,
+    capture_link_type: Option<LinkType>,
     port: PortType,
     t3v: Option<std::time::Duration>,
+    idle_timeout: Option<std::time::Duration>,
     srt: Option<std::time::Duration>,
     mtu: Option<usize>,
+    mtu_in: Option<usize>,
+    max_obuf: Option<usize>,
+    requeue_on_reset: Option<bool>,
+    srej: Option<bool>,
+    segmentation: Option<bool>,
+    lenient_initial_seq: Option<bool>,
+    strict_window: Option<bool>,
+    strict_source: Option<bool>,
+    adaptive_window: Option<bool>,
+    max_modulus: Option<u8>,
+    accept_filter: Option<state::AcceptFilterFn>,
+    accept_any_ssid: Option<bool>,
+    listen: Option<bool>,
+    n2: Option<u8>,
+    window: Option<u8>,
+    on_parse_error: Option<ParseErrorFn>,
+    on_dl_error: Option<state::DlErrorFn>,
+    max_incoming_frames: Option<usize>,
+    max_incoming_kiss: Option<usize>,
+    drop_policy: Option<DropPolicy>,
+    line_ending: Option<LineEnding>,
+    fcs: Option<bool>,
+    txdelay: Option<std::time::Duration>,
+    frame_gap: Option<std::time::Duration>,
+    tee_frames: Option<bool>,
+    cancel: Option<CancelToken>,
 }
 
 impl ConnectionBuilder {
@@ -135,9 +676,39 @@ impl ConnectionBuilder {
             me,
             extended: None,
             capture: None,
+            capture_rotating: None,
+            capture_pcapng: None,
+            capture_link_type: None,
             t3v: None,
+            idle_timeout: None,
             srt: None,
             mtu: None,
+            mtu_in: None,
+            max_obuf: None,
+            requeue_on_reset: None,
+            srej: None,
+            segmentation: None,
+            lenient_initial_seq: None,
+            strict_window: None,
+            strict_source: None,
+            adaptive_window: None,
+            max_modulus: None,
+            accept_filter: None,
+            accept_any_ssid: None,
+            listen: None,
+            n2: None,
+            window: None,
+            on_parse_error: None,
+            on_dl_error: None,
+            max_incoming_frames: None,
+            max_incoming_kiss: None,
+            tee_frames: None,
+            drop_policy: None,
+            line_ending: None,
+            fcs: None,
+            txdelay: None,
+            frame_gap: None,
+            cancel: None,
             port,
         })
     }
@@ -148,10 +719,11 @@ impl ConnectionBuilder {
     /// for ACK (RR) roundtrips, but is not supported by all implementations.
     ///
     /// Enable or disable extended mode with `Some(bool)`, or use `None` to have
-    /// clients first try one, then the other.
-    ///
-    /// TODO: Heuristics is not actually implemented, so passing None currently
-    /// forces extended mode to be off, since that's more supported.
+    /// clients first try extended, then fall back to standard if the peer
+    /// refuses it with a DM or FRMR, or doesn't answer SABME within
+    /// `SABME_PROBE_RETRIES` retries. Use
+    /// `Client::extended()` or `Client::connect_result()` to find out what
+    /// happened.
     #[must_use]
     pub fn extended(mut self, ext: Option<bool>) -> ConnectionBuilder {
         self.extended = ext;
@@ -167,7 +739,43 @@ impl ConnectionBuilder {
         self
     }
 
-    /// Set default SRT value, used for T1 (retransmit) timer.
+    /// Capture incoming and outgoing frames to pcap files in a directory.
+    ///
+    /// A new file is started when the current one would grow beyond
+    /// `max_bytes`. Use `Client::capture_path()` to find the current file.
+    ///
+    /// Overrides `capture()`.
+    #[must_use]
+    pub fn capture_rotating(
+        mut self,
+        dir: std::path::PathBuf,
+        max_bytes: u64,
+    ) -> ConnectionBuilder {
+        self.capture_rotating = Some((dir, max_bytes));
+        self
+    }
+
+    /// Capture incoming and outgoing frames to a pcapng file.
+    ///
+    /// Unlike pcap, pcapng timestamps don't wrap in 2038 or 2106, and frames
+    /// are marked as inbound or outbound.
+    ///
+    /// Overrides `capture()` and `capture_rotating()`.
+    #[must_use]
+    pub fn capture_pcapng(mut self, path: std::path::PathBuf) -> ConnectionBuilder {
+        self.capture_pcapng = Some(path);
+        self
+    }
+
+    /// Set the link type of capture files. Default `LinkType::Ax25`.
+    #[must_use]
+    pub fn capture_link_type(mut self, v: LinkType) -> ConnectionBuilder {
+        self.capture_link_type = Some(v);
+        self
+    }
+
+    /// Set default SRT value. The T1 (retransmit) timer starts out at twice
+    /// this.
     #[must_use]
     pub fn srt_default(mut self, v: std::time::Duration) -> ConnectionBuilder {
         self.srt = Some(v);
@@ -181,6 +789,16 @@ impl ConnectionBuilder {
         self
     }
 
+    /// Disconnect after this long without any data sent or received.
+    ///
+    /// T3 polls don't count as activity. Idleness is checked when T3 expires,
+    /// so set T3 shorter than this for a timely disconnect.
+    #[must_use]
+    pub fn idle_timeout(mut self, v: std::time::Duration) -> ConnectionBuilder {
+        self.idle_timeout = Some(v);
+        self
+    }
+
     /// Set MTU. Only used for outgoing packets.
     #[must_use]
     pub fn mtu(mut self, v: usize) -> ConnectionBuilder {
@@ -188,62 +806,659 @@ impl ConnectionBuilder {
         self
     }
 
+    /// Set max size of the output buffer. Default `state::MAX_OBUF_SIZE`.
+    ///
+    /// Writes wait for room when it's full.
     #[must_use]
-    fn create_data(&self) -> state::Data {
-        let mut data = state::Data::new(self.me.clone());
-        if let Some(v) = self.srt {
-            data.srt_default(v);
-        }
-        if let Some(v) = self.t3v {
-            data.t3v(v);
-        }
-        if let Some(v) = self.mtu {
-            data.mtu(v);
-        }
-        data
+    pub fn max_obuf(mut self, v: usize) -> ConnectionBuilder {
+        self.max_obuf = Some(v);
+        self
     }
 
-    /// Initiate a connection.
-    pub async fn connect(self, peer: Addr) -> Result<Client> {
-        let mut cli = Client::internal_new(self.create_data(), self.port);
-        if let Some(capture) = self.capture {
-            cli.capture(capture)?;
-        }
-        // TODO: rather than default to false, we should support trying extended
-        // first, then standard.
-        cli.connect(peer, self.extended.unwrap_or(false)).await
+    /// Set max incoming payload size. Default `state::DEFAULT_MTU_IN`.
+    ///
+    /// Bigger I frames reset the link, with DlError::O.
+    #[must_use]
+    pub fn mtu_in(mut self, v: usize) -> ConnectionBuilder {
+        self.mtu_in = Some(v);
+        self
     }
 
-    /// Accept a single connection.
+    /// Retransmit unacked data after the peer resets the link, instead of
+    /// dropping it as the spec says.
+    #[must_use]
+    pub fn requeue_on_reset(mut self, v: bool) -> ConnectionBuilder {
+        self.requeue_on_reset = Some(v);
+        self
+    }
+
+    /// Use SREJ to request only lost frames, instead of REJ.
     ///
-    /// For production services this is probably not what you want, since a
-    /// server tends to want to serve more than one connection both sequentially
-    /// and concurrently.
+    /// Only enable this if the peer is known to support SREJ.
+    #[must_use]
+    pub fn srej(mut self, v: bool) -> ConnectionBuilder {
+        self.srej = Some(v);
+        self
+    }
+
+    /// Split writes bigger than the MTU into segments, delivered to the peer
+    /// application as one read.
     ///
-    /// But this crate doesn't yet have a multi-connection API. Maybe it
-    /// shouldn't, though, but instead rely on a TCP-based multiplexer?
-    pub async fn accept(self) -> Result<Client> {
-        let mut data = self.create_data();
-        data.able_to_establish = true;
-        let mut cli = Client::internal_new(data, self.port);
-        // Extended attribute ignored. Should it be?
-        if let Some(capture) = self.capture {
-            cli.capture(capture)?;
-        }
-        loop {
-            cli.wait_event().await?;
-            if cli.state.is_state_connected() {
-                return Ok(cli);
-            }
-        }
+    /// Only enable this if the peer is known to support segment reassembly.
+    /// Received segments are always reassembled.
+    #[must_use]
+    pub fn segmentation(mut self, v: bool) -> ConnectionBuilder {
+        self.segmentation = Some(v);
+        self
     }
-}
 
-/// An async AX.25 client.
-///
-/// Despite its name, it's used both for the initiating and listening side of a
-/// connection. Probably should be renamed.
-pub struct Client {
+    /// Accept a peer whose first iframe doesn't have N(S)=0, adopting its
+    /// sequence numbers instead of rejecting everything it sends.
+    ///
+    /// This is an escape hatch for interop with non-compliant peers. It can't
+    /// tell a peer starting elsewhere from a lost first frame, so if frame 0
+    /// was lost, its data is silently skipped.
+    #[must_use]
+    pub fn lenient_initial_seq(mut self, v: bool) -> ConnectionBuilder {
+        self.lenient_initial_seq = Some(v);
+        self
+    }
+
+    /// Treat the peer sending more than k frames without waiting for an ACK as
+    /// an error (DlError::P), instead of only more than the protocol max.
+    ///
+    /// Only useful if the peer is known to use the same k.
+    #[must_use]
+    pub fn strict_window(mut self, v: bool) -> ConnectionBuilder {
+        self.strict_window = Some(v);
+        self
+    }
+
+    /// Adapt the window size (k) to the link, growing it while frames are
+    /// acked, and shrinking it on retransmissions.
+    ///
+    /// A big window gives throughput on a clean link, while a small one makes
+    /// retransmissions cheaper on a lossy one.
+    #[must_use]
+    pub fn adaptive_window(mut self, v: bool) -> ConnectionBuilder {
+        self.adaptive_window = Some(v);
+        self
+    }
+
+    /// Set the highest modulus to use, 8 or 128.
+    ///
+    /// With 8, incoming extended (mod-128) connections are refused, and
+    /// outgoing connections don't try extended mode.
+    #[must_use]
+    pub fn max_modulus(mut self, v: u8) -> ConnectionBuilder {
+        self.max_modulus = Some(v);
+        self
+    }
+
+    /// Refuse to connect or accept if the local address is not an ITU-style
+    /// callsign, as checked by `Addr::new_strict()`.
+    ///
+    /// This catches configuring a tactical call, which is not legal to
+    /// transmit as.
+    #[must_use]
+    pub fn strict_source(mut self, v: bool) -> ConnectionBuilder {
+        self.strict_source = Some(v);
+        self
+    }
+
+    /// Set the max number of retries (N2). Default 10.
+    #[must_use]
+    pub fn n2(mut self, v: u8) -> ConnectionBuilder {
+        self.n2 = Some(v);
+        self
+    }
+
+    /// Set the max number of outstanding frames (k).
+    ///
+    /// Default is 4 for mod-8, and 32 for mod-128. Limited to 7 and 127
+    /// respectively.
+    #[must_use]
+    pub fn window(mut self, v: u8) -> ConnectionBuilder {
+        self.window = Some(v);
+        self
+    }
+
+    /// Accept incoming connections on a client created by `connect()`, once
+    /// it's disconnected.
+    ///
+    /// Off by default, refusing incoming SABM(E) with DM. `accept()` always
+    /// listens.
+    #[must_use]
+    pub fn listen(mut self, v: bool) -> ConnectionBuilder {
+        self.listen = Some(v);
+        self
+    }
+
+    /// Accept connections to any SSID of our callsign, answering as the SSID
+    /// that was dialed.
+    ///
+    /// Use `Client::local_addr()` to find out which one it was.
+    #[must_use]
+    pub fn accept_any_ssid(mut self, v: bool) -> ConnectionBuilder {
+        self.accept_any_ssid = Some(v);
+        self
+    }
+
+    /// Only accept connections from peers for which `f` returns true.
+    ///
+    /// Other peers get a DM in reply to their SABM(E).
+    #[must_use]
+    pub fn accept_filter(mut self, f: state::AcceptFilterFn) -> ConnectionBuilder {
+        self.accept_filter = Some(f);
+        self
+    }
+
+    /// Call `f` with the raw bytes and the error, for every received frame that
+    /// fails to parse.
+    ///
+    /// Such frames are otherwise dropped, with only a debug log.
+    #[must_use]
+    pub fn on_parse_error(mut self, f: ParseErrorFn) -> ConnectionBuilder {
+        self.on_parse_error = Some(f);
+        self
+    }
+
+    /// Call `f` for every data link error, such as `DlError::T` when the
+    /// peer stops responding.
+    ///
+    /// Without a callback, they're only logged.
+    #[must_use]
+    pub fn on_dl_error(mut self, f: state::DlErrorFn) -> ConnectionBuilder {
+        self.on_dl_error = Some(f);
+        self
+    }
+
+    /// Set max number of received frames queued for processing.
+    ///
+    /// Defaults to `DEFAULT_MAX_INCOMING_FRAMES`.
+    #[must_use]
+    pub fn max_incoming_frames(mut self, v: usize) -> ConnectionBuilder {
+        self.max_incoming_frames = Some(v);
+        self
+    }
+
+    /// Set max number of received bytes buffered while looking for complete
+    /// KISS frames.
+    ///
+    /// Defaults to `DEFAULT_MAX_INCOMING_KISS`. Setting it lower than a KISS
+    /// encoded max size frame means such frames can't be received.
+    #[must_use]
+    pub fn max_incoming_kiss(mut self, v: usize) -> ConnectionBuilder {
+        self.max_incoming_kiss = Some(v);
+        self
+    }
+
+    /// Set what to drop when incoming frame queues are full.
+    ///
+    /// The buffer of received KISS bytes always drops the oldest, to not get
+    /// stuck on noise.
+    #[must_use]
+    pub fn drop_policy(mut self, v: DropPolicy) -> ConnectionBuilder {
+        self.drop_policy = Some(v);
+        self
+    }
+
+    /// Set line ending translation for text connections.
+    ///
+    /// The translation is byte for byte, so write tokens and byte counts are
+    /// unaffected.
+    #[must_use]
+    pub fn line_ending(mut self, v: LineEnding) -> ConnectionBuilder {
+        self.line_ending = Some(v);
+        self
+    }
+
+    /// Expect received frames to include the FCS.
+    ///
+    /// Frames with a bad FCS are treated like any unparsable frame. Off by
+    /// default, since KISS TNCs normally check and strip it.
+    #[must_use]
+    pub fn fcs(mut self, v: bool) -> ConnectionBuilder {
+        self.fcs = Some(v);
+        self
+    }
+
+    /// Wait this long before the first frame of a burst.
+    ///
+    /// For half-duplex radios without a TNC that does it, so the transmitter
+    /// has time to key up before anything is sent.
+    #[must_use]
+    pub fn txdelay(mut self, v: std::time::Duration) -> ConnectionBuilder {
+        self.txdelay = Some(v);
+        self
+    }
+
+    /// Wait this long between frames sent back to back.
+    #[must_use]
+    pub fn frame_gap(mut self, v: std::time::Duration) -> ConnectionBuilder {
+        self.frame_gap = Some(v);
+        self
+    }
+
+    /// Keep a copy of every received frame, for `Client::read_frame()`.
+    ///
+    /// Up to `max_incoming_frames` not yet read frames are kept, subject to
+    /// the drop policy.
+    #[must_use]
+    pub fn tee_frames(mut self, v: bool) -> ConnectionBuilder {
+        self.tee_frames = Some(v);
+        self
+    }
+
+    /// Make `connect()`, `accept()`, and the resulting `Client`'s waiting
+    /// calls return `Cancelled` once the token is cancelled.
+    #[must_use]
+    pub fn cancel_token(mut self, cancel: CancelToken) -> ConnectionBuilder {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    fn open_capture(&self) -> Result<Option<Capture>> {
+        let link_type = self.capture_link_type.unwrap_or_default();
+        if let Some(path) = &self.capture_pcapng {
+            return Ok(Some(Capture::Pcapng(PcapngWriter::create_with_link_type(
+                path.clone(),
+                link_type,
+            )?)));
+        }
+        if let Some((dir, max_bytes)) = &self.capture_rotating {
+            return Ok(Some(Capture::Rotating(
+                RotatingPcapWriter::create_with_link_type(dir.clone(), *max_bytes, link_type)?,
+            )));
+        }
+        if let Some(path) = &self.capture {
+            return Ok(Some(Capture::File(PcapWriter::create_with_link_type(
+                path.clone(),
+                link_type,
+            )?)));
+        }
+        Ok(None)
+    }
+
+    /// Create client, with everything but the state machine data.
+    fn create_client(self, data: state::Data) -> Result<Client> {
+        if let Some(m) = self.max_modulus {
+            if m != 8 && m != 128 {
+                return Err(Error::msg(format!("invalid max modulus {m}")));
+            }
+        }
+        if self.strict_source.unwrap_or(false) {
+            Addr::new_strict(self.me.call())?;
+        }
+        let pcap = self.open_capture()?;
+        let mut cli = Client::internal_new(data, self.port);
+        cli.pcap = pcap;
+        cli.cancel = self.cancel;
+        cli.on_parse_error = self.on_parse_error;
+        cli.on_dl_error = self.on_dl_error;
+        cli.max_incoming_frames = self
+            .max_incoming_frames
+            .unwrap_or(DEFAULT_MAX_INCOMING_FRAMES);
+        cli.max_incoming_kiss = self.max_incoming_kiss.unwrap_or(DEFAULT_MAX_INCOMING_KISS);
+        cli.drop_policy = self.drop_policy.unwrap_or_default();
+        cli.line_ending = self.line_ending.unwrap_or_default();
+        cli.fcs = self.fcs.unwrap_or(false);
+        cli.txdelay = self.txdelay.unwrap_or_default();
+        cli.frame_gap = self.frame_gap.unwrap_or_default();
+        if self.tee_frames.unwrap_or(false) {
+            cli.tee = Some(VecDeque::new());
+        }
+        Ok(cli)
+    }
+
+    #[must_use]
+    fn create_data(&self) -> state::Data {
+        let mut data = state::Data::new(self.me.clone());
+        if let Some(v) = self.srt {
+            data.srt_default(v);
+        }
+        if let Some(v) = self.t3v {
+            data.t3v(v);
+        }
+        if let Some(v) = self.idle_timeout {
+            data.idle_timeout(v);
+        }
+        if let Some(v) = self.mtu {
+            data.mtu(v);
+        }
+        if let Some(v) = self.mtu_in {
+            data.mtu_in(v);
+        }
+        if let Some(v) = self.max_obuf {
+            data.max_obuf(v);
+        }
+        if let Some(v) = self.requeue_on_reset {
+            data.requeue_on_reset(v);
+        }
+        if let Some(v) = self.srej {
+            data.srej(v);
+        }
+        if let Some(v) = self.segmentation {
+            data.segmentation(v);
+        }
+        if let Some(v) = self.lenient_initial_seq {
+            data.lenient_initial_seq(v);
+        }
+        if let Some(v) = self.strict_window {
+            data.strict_window(v);
+        }
+        if let Some(v) = self.adaptive_window {
+            data.adaptive_window(v);
+        }
+        if let Some(v) = self.max_modulus {
+            data.max_modulus(v);
+        }
+        if let Some(v) = self.listen {
+            data.listen(v);
+        }
+        if let Some(v) = self.n2 {
+            data.n2(v);
+        }
+        if let Some(v) = self.window {
+            data.window(v);
+        }
+        data
+    }
+
+    /// Initiate a connection.
+    pub async fn connect(self, peer: Addr) -> Result<Client> {
+        let extended = match (self.extended, self.max_modulus) {
+            (Some(true), Some(8)) => {
+                return Err(Error::msg("extended mode requested with max modulus 8"));
+            }
+            (None, Some(8)) => Some(false),
+            (ext, _) => ext,
+        };
+        let data = self.create_data();
+        let mut cli = self.create_client(data)?;
+        let fallback_occurred = match extended {
+            Some(ext) => {
+                cli.connect(&peer, ext).await?.into_result()?;
+                false
+            }
+            None => match cli.connect_probe(&peer).await? {
+                ConnectOutcome::Connected => false,
+                outcome => {
+                    debug!("Extended mode failed ({outcome:?}), falling back to standard");
+                    cli.connect(&peer, false).await?.into_result()?;
+                    true
+                }
+            },
+        };
+        cli.connect_result = Some(ConnectResult {
+            modulus: if cli.data.ext() { 128 } else { 8 },
+            negotiated_via_xid: cli.data.xid_negotiated(),
+            fallback_occurred,
+        });
+        Ok(cli)
+    }
+
+    /// Accept a single connection.
+    ///
+    /// For production services this is probably not what you want, since a
+    /// server tends to want to serve more than one connection both sequentially
+    /// and concurrently.
+    ///
+    /// For that, use a `Listener`.
+    pub async fn accept(mut self) -> Result<Client> {
+        let mut data = self.create_data();
+        data.listen(true);
+        if let Some(f) = self.accept_filter.take() {
+            data.accept_filter(f);
+        }
+        // Extended attribute ignored. Should it be?
+        let accept_any_ssid = self.accept_any_ssid.unwrap_or(false);
+        let mut cli = self.create_client(data)?;
+        cli.accept_any_ssid = accept_any_ssid;
+        loop {
+            cli.wait_event().await?;
+            if cli.state.is_state_connected() {
+                return Ok(cli);
+            }
+        }
+    }
+}
+
+/// Accepts any number of concurrent connections on one port.
+///
+/// A background task reads the port, and routes frames to connections by
+/// their (local, remote) address pair. A SABM(E) from a new remote starts a
+/// new connection, returned by `accept()`. Each connection has its own state,
+/// but all write to the same port.
+///
+/// Must be created from within a tokio runtime.
+///
+/// ```no_run
+/// use rax25::r#async::{Listener, PortType};
+/// use rax25::Addr;
+///
+/// async fn serve(port: PortType) -> anyhow::Result<()> {
+///     let mut listener = Listener::new(Addr::new("M0THC-2")?, port);
+///     let mut first = listener.accept().await?;
+///     let mut second = listener.accept().await?;
+///     first.write(b"You were first\n").await?;
+///     second.write(b"You were second\n").await?;
+///     Ok(())
+/// }
+/// ```
+pub struct Listener {
+    me: Addr,
+    incoming: tokio::sync::mpsc::UnboundedReceiver<MuxPort>,
+}
+
+impl Listener {
+    /// Start listening for connections to `me` on a port.
+    #[must_use]
+    pub fn new(me: Addr, port: PortType) -> Self {
+        let (accept_tx, incoming) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(run_mux(me.clone(), port, accept_tx));
+        Self { me, incoming }
+    }
+
+    /// Wait for the next incoming connection to be established.
+    pub async fn accept(&mut self) -> Result<Client> {
+        self.accept_with(|b| b).await
+    }
+
+    /// Like `accept()`, but with the connection's builder settings adjusted by
+    /// `f`.
+    pub async fn accept_with<F>(&mut self, f: F) -> Result<Client>
+    where
+        F: FnOnce(ConnectionBuilder) -> ConnectionBuilder,
+    {
+        let port = self
+            .incoming
+            .recv()
+            .await
+            .ok_or_else(|| Error::msg("listener port closed"))?;
+        f(ConnectionBuilder::new(
+            self.me.clone(),
+            PortType::Mux(port),
+        )?)
+        .accept()
+        .await
+    }
+}
+
+/// Routing key of a frame. Local address first.
+type MuxKey = (String, String);
+
+/// Route a raw KISS frame, returning its key and whether it's a SABM(E).
+#[must_use]
+fn mux_route(frame: &[u8]) -> Option<(MuxKey, bool)> {
+    // Skip FEND and KISS command byte, and the closing FEND.
+    let pb = crate::unescape(frame.get(2..frame.len().checked_sub(1)?)?).ok()?;
+    let dst = Addr::parse(pb.get(..7)?).ok()?;
+    let src = Addr::parse(pb.get(7..14)?).ok()?;
+    let sabm = matches!(
+        Packet::parse(&pb, Some(false)).map(|p| p.packet_type),
+        Ok(PacketType::Sabm(_) | PacketType::Sabme(_))
+    );
+    Some(((dst.call().to_string(), src.call().to_string()), sabm))
+}
+
+/// Demultiplex a port shared by a `Listener` and its connections.
+///
+/// Runs until the port is closed, or until both the listener and all its
+/// connections are gone.
+async fn run_mux(
+    me: Addr,
+    mut port: PortType,
+    accept_tx: tokio::sync::mpsc::UnboundedSender<MuxPort>,
+) {
+    let (out_tx, mut out_rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+    // Dropped when the listener is gone, so that `out_rx` closes when the last
+    // connection is gone too.
+    let mut out_tx = Some(out_tx);
+    let mut routes: std::collections::HashMap<MuxKey, tokio::sync::mpsc::UnboundedSender<Vec<u8>>> =
+        std::collections::HashMap::new();
+    let mut ibuf = VecDeque::new();
+    let mut buf = [0; 1024];
+    loop {
+        tokio::select! {
+            res = port.read(&mut buf) => match res {
+                Ok(0) => {
+                    debug!("Listener port closed");
+                    return;
+                }
+                Ok(n) => ibuf.extend(&buf[..n]),
+                Err(e) => {
+                    error!("Error reading from listener port: {e:?}");
+                    return;
+                }
+            },
+            frame = out_rx.recv() => {
+                let Some(frame) = frame else {
+                    debug!("Listener and all its connections gone");
+                    return;
+                };
+                if let Err(e) = port.write_all(&frame).await {
+                    error!("Error writing to listener port: {e:?}");
+                    return;
+                }
+                if let Err(e) = port.flush().await {
+                    error!("Error flushing listener port: {e:?}");
+                    return;
+                }
+                continue;
+            },
+            _ = accept_tx.closed(), if out_tx.is_some() => {
+                out_tx = None;
+                continue;
+            },
+        }
+        while let Some((a, b)) = crate::find_frame(&ibuf) {
+            if b - a < 14 {
+                ibuf.drain(..(a + 1));
+                continue;
+            }
+            let mut frame: Vec<u8> = ibuf.drain(..b).skip(a).collect();
+            frame.push(crate::KISS_FEND);
+            let Some((key, sabm)) = mux_route(&frame) else {
+                debug!("Listener dropping unroutable frame");
+                continue;
+            };
+            if let Some(route) = routes.get(&key) {
+                match route.send(frame) {
+                    Ok(()) => continue,
+                    Err(e) => {
+                        // Connection gone. Maybe this is a new one.
+                        frame = e.0;
+                        routes.remove(&key);
+                    }
+                }
+            }
+            if !sabm || key.0 != me.call() {
+                debug!("Listener dropping frame {} -> {}", key.1, key.0);
+                continue;
+            }
+            let Some(tx) = out_tx.clone() else {
+                debug!("Listener gone, not accepting connection");
+                continue;
+            };
+            debug!("Listener got new connection from {}", key.1);
+            let (in_tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let _ = in_tx.send(frame);
+            let port = MuxPort {
+                rx,
+                tx,
+                pending: Vec::new(),
+            };
+            if accept_tx.send(port).is_err() {
+                debug!("Listener gone, not accepting connection");
+                continue;
+            }
+            routes.insert(key, in_tx);
+        }
+    }
+}
+
+/// Token identifying data passed to `Client::write()`.
+///
+/// It's the stream offset of the end of the written data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WriteToken(u64);
+
+/// What happened to the data passed to `Client::write_counted()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteResult {
+    /// Token for `Client::wait_acked()`.
+    pub token: WriteToken,
+
+    /// Bytes of the write that were transmitted right away.
+    pub sent: usize,
+
+    /// Bytes of the write left buffered, because the window was full or the
+    /// connection not yet up.
+    pub buffered: usize,
+}
+
+/// How an outgoing connection was established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectResult {
+    /// 8 for standard, 128 for extended mode.
+    pub modulus: u8,
+
+    /// Parameters were negotiated using XID.
+    ///
+    /// The client doesn't send XID itself, so this is only true if the peer
+    /// sent one while connecting.
+    pub negotiated_via_xid: bool,
+
+    /// Extended mode was tried first, but the peer refused it.
+    pub fallback_occurred: bool,
+}
+
+/// Result of a single connection attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectOutcome {
+    Connected,
+    /// Peer replied DM.
+    Refused,
+    /// No reply after N2 retries.
+    TimedOut,
+}
+
+impl ConnectOutcome {
+    fn into_result(self) -> Result<()> {
+        match self {
+            ConnectOutcome::Connected => Ok(()),
+            ConnectOutcome::Refused => Err(ConnectionRefused.into()),
+            ConnectOutcome::TimedOut => Err(Error::msg("connection timed out")),
+        }
+    }
+}
+
+/// An async AX.25 client.
+///
+/// Despite its name, it's used both for the initiating and listening side of a
+/// connection. Probably should be renamed.
+pub struct Client {
     state: Box<dyn state::State>,
     data: state::Data,
     port: PortType,
@@ -252,14 +1467,113 @@ pub struct Client {
     incoming_kiss: VecDeque<u8>,
     incoming_frames: VecDeque<Packet>,
 
-    pcap: Option<PcapWriter>,
+    /// Answer incoming connections as whichever SSID was dialed.
+    accept_any_ssid: bool,
+
+    /// Copies of received frames, for `read_frame()`. None if not enabled.
+    tee: Option<VecDeque<Packet>>,
+
+    pcap: Option<Capture>,
+
+    /// Receivers of copies of all frames, from `tap()`.
+    taps: Vec<tokio::sync::mpsc::Sender<(Direction, Vec<u8>)>>,
+
+    /// Receivers of received UI frames, from `ui_frames()`.
+    ui_frames: Vec<tokio::sync::mpsc::Sender<Packet>>,
+
+    cancel: Option<CancelToken>,
+    on_parse_error: Option<ParseErrorFn>,
+    on_dl_error: Option<state::DlErrorFn>,
+    connect_result: Option<ConnectResult>,
+
+    max_incoming_frames: usize,
+    max_incoming_kiss: usize,
+    drop_policy: DropPolicy,
+    line_ending: LineEnding,
+    fcs: bool,
+    stats: Stats,
+
+    /// Escaped frames waiting to be written to the port.
+    outgoing: VecDeque<u8>,
+
+    /// Bytes of each frame in `outgoing`, not yet written.
+    outgoing_frames: VecDeque<usize>,
+
+    txdelay: std::time::Duration,
+    frame_gap: std::time::Duration,
+
+    /// Timer for `txdelay` or `frame_gap`, before the next frame.
+    tx_timer: Option<Pin<Box<tokio::time::Sleep>>>,
+
+    /// Delay before the next frame is done.
+    tx_ready: bool,
+
+    /// Frames were sent since `outgoing` was last empty.
+    tx_burst: bool,
+
+    /// Timer for the `AsyncRead`/`AsyncWrite` implementations.
+    poll_timer: Option<Pin<Box<tokio::time::Sleep>>>,
+
+    /// DISC sent by `AsyncWrite::poll_shutdown()`.
+    shutdown: bool,
+}
+
+/// Packet capture destination.
+enum Capture {
+    File(PcapWriter),
+    Rotating(RotatingPcapWriter),
+    Pcapng(PcapngWriter),
+}
+
+impl Capture {
+    /// Write a frame. Only pcapng has a place to record the direction.
+    fn write(&mut self, direction: Direction, packet: &[u8]) -> Result<()> {
+        match self {
+            Capture::File(w) => w.write(packet),
+            Capture::Rotating(w) => w.write(packet),
+            Capture::Pcapng(w) => w.write_direction(direction, packet),
+        }
+    }
+
+    #[must_use]
+    fn path(&self) -> &std::path::Path {
+        match self {
+            Capture::File(w) => w.path(),
+            Capture::Rotating(w) => w.path(),
+            Capture::Pcapng(w) => w.path(),
+        }
+    }
 }
 
+/// Send a copy of the item to every receiver, forgetting closed ones.
+///
+/// Returns the number of receivers that were full, and didn't get it.
+fn send_all<T: Clone>(senders: &mut Vec<tokio::sync::mpsc::Sender<T>>, item: T) -> u64 {
+    let mut dropped = 0;
+    senders.retain(|tx| match tx.try_send(item.clone()) {
+        Ok(()) => true,
+        Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+            dropped += 1;
+            true
+        }
+        Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => false,
+    });
+    dropped
+}
+
+/// Callback for received frames that fail to parse.
+pub type ParseErrorFn = Box<dyn Fn(&[u8], &Error) + Send>;
+
 /// Turn bytes into frames.
 ///
 /// Given an input buffer `ibuf` of KISS data, drain all packets we can find.
 #[must_use]
-fn kisser_read(ibuf: &mut VecDeque<u8>, ext: Option<bool>) -> Vec<Packet> {
+fn kisser_read(
+    ibuf: &mut VecDeque<u8>,
+    ext: Option<bool>,
+    fcs: bool,
+    on_parse_error: Option<&ParseErrorFn>,
+) -> Vec<Packet> {
     let mut ret = Vec::new();
     while let Some((a, b)) = crate::find_frame(ibuf) {
         if b - a < 14 {
@@ -268,14 +1582,31 @@ fn kisser_read(ibuf: &mut VecDeque<u8>, ext: Option<bool>) -> Vec<Packet> {
         }
         let pb: Vec<_> = ibuf.iter().skip(a + 2).take(b - a - 2).cloned().collect();
         ibuf.drain(..b);
-        let pb = crate::unescape(&pb);
-        match Packet::parse(&pb, ext) {
+        let pb = match crate::unescape(&pb) {
+            Ok(pb) => pb,
+            Err(e) => {
+                debug!("Dropping frame: {e}");
+                if let Some(f) = on_parse_error {
+                    f(&pb, &e);
+                }
+                continue;
+            }
+        };
+        let parsed = if fcs {
+            Packet::parse_fcs(&pb, ext)
+        } else {
+            Packet::parse(&pb, ext)
+        };
+        match parsed {
             Ok(packet) => {
-                debug!("parsed {packet:?}");
+                debug!("parsed {packet}");
                 ret.push(packet);
             }
             Err(e) => {
                 debug!("Failed to parse packet: {e:?}");
+                if let Some(f) = on_parse_error {
+                    f(&pb, &e);
+                }
             }
         }
     }
@@ -291,52 +1622,317 @@ impl Client {
             incoming: VecDeque::new(),
             incoming_frames: VecDeque::new(),
             incoming_kiss: VecDeque::new(),
+            tee: None,
+            accept_any_ssid: false,
             port,
             state: state::new(),
             data,
             pcap: None,
+            taps: Vec::new(),
+            ui_frames: Vec::new(),
+            cancel: None,
+            on_parse_error: None,
+            on_dl_error: None,
+            connect_result: None,
+            max_incoming_frames: DEFAULT_MAX_INCOMING_FRAMES,
+            max_incoming_kiss: DEFAULT_MAX_INCOMING_KISS,
+            drop_policy: DropPolicy::default(),
+            line_ending: LineEnding::default(),
+            fcs: false,
+            stats: Stats::default(),
+            outgoing: VecDeque::new(),
+            outgoing_frames: VecDeque::new(),
+            txdelay: std::time::Duration::ZERO,
+            frame_gap: std::time::Duration::ZERO,
+            tx_timer: None,
+            tx_ready: false,
+            tx_burst: false,
+            poll_timer: None,
+            shutdown: false,
         }
     }
 
-    /// Initiate a connection.
-    async fn connect(mut self, peer: Addr, ext: bool) -> Result<Self> {
-        self.actions(Event::Connect { addr: peer, ext }).await?;
+    /// Try to connect.
+    ///
+    /// Refusal and timeout are not errors, so that the caller can retry in
+    /// standard mode.
+    async fn connect(&mut self, peer: &Addr, ext: bool) -> Result<ConnectOutcome> {
+        self.actions(Event::Connect {
+            addr: peer.clone(),
+            ext,
+        })
+        .await?;
+        self.wait_connected().await
+    }
+
+    /// Try connecting in extended mode, giving up after a few retries.
+    ///
+    /// A peer not supporting extended mode may not answer SABME at all, and
+    /// going through all N2 retries before falling back would take minutes.
+    async fn connect_probe(&mut self, peer: &Addr) -> Result<ConnectOutcome> {
+        self.actions(Event::Connect {
+            addr: peer.clone(),
+            ext: true,
+        })
+        .await?;
+        self.data.limit_retries(SABME_PROBE_RETRIES);
+        let outcome = self.wait_connected().await;
+        self.data.restore_n2();
+        outcome
+    }
+
+    /// Wait for a connection attempt to succeed or fail.
+    async fn wait_connected(&mut self) -> Result<ConnectOutcome> {
         loop {
             self.wait_event().await?;
             debug!("State after waiting: {}", self.state.name());
             if self.state.is_state_connected() {
-                return Ok(self);
+                return Ok(ConnectOutcome::Connected);
             }
             if self.state.is_state_disconnected() {
-                return Err(Error::msg("connection timed out"));
+                if self.data.connect_refused() {
+                    return Ok(ConnectOutcome::Refused);
+                }
+                return Ok(ConnectOutcome::TimedOut);
             }
         }
     }
-    fn capture(&mut self, filename: std::path::PathBuf) -> Result<()> {
-        let pcap = PcapWriter::create(filename)?;
-        self.pcap = Some(pcap);
-        Ok(())
+
+    /// Return client statistics.
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
     }
-    fn extract_packets(&mut self) {
-        self.incoming_frames
-            .extend(kisser_read(&mut self.incoming_kiss, Some(self.data.ext())));
+
+    /// Return how an outgoing connection was established.
+    ///
+    /// None for accepted connections.
+    #[must_use]
+    pub fn connect_result(&self) -> Option<ConnectResult> {
+        self.connect_result
     }
 
-    /// Wait for an event, and handle it.
+    /// Set whether to accept incoming connections while disconnected.
     ///
-    /// If there's a chance that the caller is interested, then return. If the
-    /// caller wants to wait more, they can call again.
-    async fn wait_event(&mut self) -> Result<()> {
-        let mut buf = [0; 1024];
+    /// When not listening, an incoming SABM(E) is refused with DM.
+    pub fn listen(&mut self, v: bool) {
+        self.data.listen(v);
+    }
 
-        let state_name = self.state.name();
-        // First process all incoming frames. This is non-blocking.
+    /// Return true if the connection is in extended (modulo 128) mode.
+    #[must_use]
+    pub fn extended(&self) -> bool {
+        self.data.ext()
+    }
+
+    /// Return the local address of the connection.
+    #[must_use]
+    pub fn local_addr(&self) -> &Addr {
+        &self.data.me
+    }
+
+    /// Return which state the connection is in.
+    #[must_use]
+    pub fn state_kind(&self) -> state::StateKind {
+        self.state.kind()
+    }
+
+    /// Return true if the connection is established, including while
+    /// recovering from a timeout.
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        self.state.is_state_connected()
+    }
+
+    /// Return the remote address of the current, or last, connection.
+    #[must_use]
+    pub fn peer(&self) -> Option<&Addr> {
+        self.data.peer()
+    }
+
+    /// Return the number of sent iframes not yet acked by the peer.
+    #[must_use]
+    pub fn outstanding(&self) -> usize {
+        self.data.outstanding()
+    }
+
+    /// Return how long since payload data was last sent or received.
+    #[must_use]
+    pub fn idle_time(&self) -> std::time::Duration {
+        self.data.idle_time()
+    }
+
+    /// Run a function with temporary access to the underlying port.
+    ///
+    /// This is an escape hatch for hardware quirks that the rest of the API
+    /// doesn't cover, like changing serial port settings mid-session.
+    ///
+    /// Reading or writing bytes directly on the port will corrupt the framing,
+    /// and likely break the connection.
+    pub fn with_port<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut PortType) -> R,
+    {
+        f(&mut self.port)
+    }
+
+    /// Set the DTR line of a serial port, e.g. for radios keying PTT with it.
+    ///
+    /// Errors if the port is not a serial port.
+    pub fn set_dtr(&mut self, v: bool) -> Result<()> {
+        use tokio_serial::SerialPort;
+        match &mut self.port {
+            PortType::Serial(p) => Ok(p.write_data_terminal_ready(v)?),
+            _ => Err(Error::msg("DTR only available on serial ports")),
+        }
+    }
+
+    /// Set the RTS line of a serial port, e.g. for radios keying PTT with it.
+    ///
+    /// Errors if the port is not a serial port.
+    pub fn set_rts(&mut self, v: bool) -> Result<()> {
+        use tokio_serial::SerialPort;
+        match &mut self.port {
+            PortType::Serial(p) => Ok(p.write_request_to_send(v)?),
+            _ => Err(Error::msg("RTS only available on serial ports")),
+        }
+    }
+
+    /// Return total payload bytes passed to `write()`.
+    #[must_use]
+    pub fn bytes_written(&self) -> u64 {
+        self.data.bytes_written()
+    }
+
+    /// Return total payload bytes acked by the peer.
+    ///
+    /// Together with `bytes_written()` this shows delivery progress, since
+    /// `write()` returns before the data is even sent.
+    #[must_use]
+    pub fn bytes_acked(&self) -> u64 {
+        self.data.bytes_acked()
+    }
+
+    /// Return the path of the pcap file currently being written, if any.
+    #[must_use]
+    pub fn capture_path(&self) -> Option<&std::path::Path> {
+        self.pcap.as_ref().map(|p| p.path())
+    }
+
+    /// Get a copy of every frame received and sent from now on.
+    ///
+    /// Frames are complete AX.25 frames, without KISS escaping. Frames are
+    /// dropped, and counted in `stats()`, if the receiver falls more than
+    /// `max_incoming_frames` behind.
+    ///
+    /// Like `read_frame()`, frames are only seen while the client is being
+    /// driven by some other call.
+    #[must_use]
+    pub fn tap(&mut self) -> tokio::sync::mpsc::Receiver<(Direction, Vec<u8>)> {
+        let (tx, rx) = tokio::sync::mpsc::channel(self.max_incoming_frames.max(1));
+        self.taps.push(tx);
+        rx
+    }
+
+    /// Get all UI frames received from now on.
+    ///
+    /// UI frames are connectionless, so this includes any UI frame heard,
+    /// not just those from the peer. Their payload is not part of the
+    /// connection's data returned by `read()`.
+    ///
+    /// Like `tap()`, frames are dropped if the receiver falls behind, and are
+    /// only seen while the client is being driven by some other call.
+    #[must_use]
+    pub fn ui_frames(&mut self) -> tokio::sync::mpsc::Receiver<Packet> {
+        let (tx, rx) = tokio::sync::mpsc::channel(self.max_incoming_frames.max(1));
+        self.ui_frames.push(tx);
+        rx
+    }
+
+    /// Send a UI frame, with no poll bit.
+    ///
+    /// UI frames are connectionless, so this works whether connected or not,
+    /// and to anyone.
+    pub async fn send_ui(&mut self, dst: &Addr, payload: &[u8], pid: crate::Pid) -> Result<()> {
+        let packet = Packet::ui(&self.data.me, dst, &[], pid.into(), payload);
+        self.send_raw(&packet).await
+    }
+
+    /// Send a frame as is, bypassing the state machine.
+    ///
+    /// E.g. a hand crafted frame, parsed with `Packet::parse()`.
+    ///
+    /// The state machine is not told about the frame, so e.g. sending an I
+    /// frame this way will confuse the peer about sequence numbers.
+    pub async fn send_raw(&mut self, packet: &Packet) -> Result<()> {
+        let frame = packet.serialize(self.data.ext());
+        self.queue_frame(&frame)?;
+        self.send_outgoing().await
+    }
+
+    /// Write a frame to the pcap file and taps, if any.
+    fn capture(&mut self, direction: Direction, frame: &[u8]) -> Result<()> {
+        if let Some(f) = &mut self.pcap {
+            f.write(direction, frame)?;
+        }
+        if !self.taps.is_empty() {
+            self.stats.dropped_tap_frames += send_all(&mut self.taps, (direction, frame.to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Add received bytes to the KISS buffer, and extract any frames.
+    ///
+    /// Unlike the frame queues, this always drops the oldest bytes when
+    /// full. Dropping the newest would wedge the buffer for good on noise
+    /// without a FEND, since a frame can then never complete.
+    fn push_kiss(&mut self, bytes: &[u8]) {
+        let dropped = push_bounded(
+            &mut self.incoming_kiss,
+            bytes.iter().copied(),
+            self.max_incoming_kiss,
+            DropPolicy::Oldest,
+        );
+        if dropped > 0 {
+            debug!("KISS buffer full, dropped {dropped} bytes");
+            self.stats.dropped_kiss_bytes += dropped;
+        }
+        self.extract_packets();
+    }
+
+    fn extract_packets(&mut self) {
+        let frames = kisser_read(
+            &mut self.incoming_kiss,
+            Some(self.data.ext()),
+            self.fcs,
+            self.on_parse_error.as_ref(),
+        );
+        let dropped = push_bounded(
+            &mut self.incoming_frames,
+            frames,
+            self.max_incoming_frames,
+            self.drop_policy,
+        );
+        if dropped > 0 {
+            debug!("Incoming frame queue full, dropped {dropped} frames");
+            self.stats.dropped_frames += dropped;
+        }
+    }
+
+    /// Run all received frames through the state machine.
+    fn process_incoming_frames(&mut self) -> Result<()> {
         while let Some(p) = self.incoming_frames.pop_front() {
-            debug!("processing packet {:?}", p.packet_type);
-            if let Some(f) = &mut self.pcap {
-                f.write(&p.serialize(self.data.ext()))?;
+            debug!("processing packet {p}");
+            self.capture(Direction::In, &p.serialize(self.data.ext()))?;
+            if let Some(tee) = &mut self.tee {
+                let dropped =
+                    push_bounded(tee, [p.clone()], self.max_incoming_frames, self.drop_policy);
+                self.stats.dropped_tee_frames += dropped;
+            }
+            if !self.ui_frames.is_empty() && matches!(p.packet_type, PacketType::Ui(_)) {
+                self.stats.dropped_ui_frames += send_all(&mut self.ui_frames, p.clone());
             }
-            self.actions_packet(&p).await?;
+            self.handle_packet(&p)?;
             debug!(
                 "post packet: {} {:?} {:?}",
                 self.state.name(),
@@ -344,13 +1940,47 @@ impl Client {
                 self.data.t3.remaining()
             );
         }
+        Ok(())
+    }
+
+    /// Wait for an event, and handle it.
+    ///
+    /// If there's a chance that the caller is interested, then return. If the
+    /// caller wants to wait more, they can call again.
+    async fn wait_event(&mut self) -> Result<()> {
+        self.wait_event_for(true).await
+    }
+
+    /// Wait for an event, and handle it.
+    ///
+    /// If `want_data` is false, then already received data doesn't cause an
+    /// immediate return.
+    async fn wait_event_for(&mut self, want_data: bool) -> Result<()> {
+        self.wait_event_with(want_data, true).await
+    }
+
+    /// Wait for an event, and handle it.
+    ///
+    /// If `cancellable` is false, then the `CancelToken` is ignored.
+    async fn wait_event_with(&mut self, want_data: bool, cancellable: bool) -> Result<()> {
+        let mut buf = [0; 1024];
+
+        let cancel = self.cancel.clone().filter(|_| cancellable);
+        if cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+            return Err(Cancelled.into());
+        }
+
+        let state_name = self.state.name();
+        // First process all incoming frames. This is non-blocking.
+        self.process_incoming_frames()?;
+        self.send_outgoing().await?;
 
         // wait_event is called when connecting, accepting, or attempting to
         // read. In the first two cases there's no incoming bytes. In the
         // last case we actually want to return the bytes ASAP. So we do that
         // here, without waiting for timers, more packets, or more serial
         // bytes.
-        if !self.incoming.is_empty() {
+        if want_data && !self.incoming.is_empty() {
             return Ok(());
         }
 
@@ -363,8 +1993,19 @@ impl Client {
         let (t1, t3) = self.timer_13();
         tokio::pin!(t1);
         tokio::pin!(t3);
+        let cancelled = async move {
+            match cancel {
+                Some(c) => c.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(cancelled);
 
         tokio::select! {
+            () = &mut cancelled => {
+                debug!("async con event: cancelled");
+                return Err(Cancelled.into());
+            },
             () = &mut t1 => {
                 debug!("async con event: T1");
                 self.actions(Event::T1).await?;
@@ -376,9 +2017,7 @@ impl Client {
             res = self.port.read(&mut buf) => match res {
             Ok(n) => {
                 debug!("Read {n} bytes from serial port");
-                let buf = &buf[..n];
-                self.incoming_kiss.extend(buf);
-                self.extract_packets();
+                self.push_kiss(&buf[..n]);
             },
             Err(e) => eprintln!("Error reading from serial port: {e:?}"),
             },
@@ -391,54 +2030,279 @@ impl Client {
         );
         Ok(())
     }
-    async fn actions_packet(&mut self, packet: &Packet) -> Result<()> {
-        match &packet.packet_type {
-            PacketType::Sabm(p) => self.actions(state::Event::Sabm(p.clone(), packet.src.clone())),
-            PacketType::Sabme(p) => {
-                self.actions(state::Event::Sabme(p.clone(), packet.src.clone()))
-            }
-            PacketType::Ua(ua) => self.actions(state::Event::Ua(ua.clone())),
-            PacketType::Disc(p) => self.actions(state::Event::Disc(p.clone())),
-            PacketType::Rnr(p) => self.actions(state::Event::Rnr(p.clone())),
-            PacketType::Rej(p) => self.actions(state::Event::Rej(p.clone())),
-            PacketType::Srej(p) => self.actions(state::Event::Srej(p.clone())),
-            PacketType::Frmr(p) => self.actions(state::Event::Frmr(p.clone())),
-            PacketType::Xid(p) => {
-                self.actions(state::Event::Xid(p.clone(), packet.command_response))
-            }
-            PacketType::Ui(p) => self.actions(state::Event::Ui(p.clone(), packet.command_response)),
-            PacketType::Test(p) => {
-                self.actions(state::Event::Test(p.clone(), packet.command_response))
-            }
-            PacketType::Dm(p) => self.actions(state::Event::Dm(p.clone())),
-            PacketType::Rr(rr) => {
-                self.actions(state::Event::Rr(rr.clone(), packet.command_response))
-            }
-            PacketType::Iframe(iframe) => self.actions(state::Event::Iframe(
-                iframe.clone(),
-                packet.command_response,
-            )),
-        }
-        .await
+    /// If accepting any SSID, switch to the one dialed by an incoming
+    /// SABM(E).
+    fn accept_as(&mut self, dst: &Addr) {
+        if !self.accept_any_ssid || !self.state.is_state_disconnected() {
+            return;
+        }
+        if dst.call() == self.data.me.call() || dst.base_call() != self.data.me.base_call() {
+            return;
+        }
+        match Addr::new(dst.call()) {
+            Ok(me) => {
+                debug!("Accepting connection as {}", me.call());
+                self.data.set_me(me);
+            }
+            Err(e) => debug!("Not accepting as {}: {e}", dst.call()),
+        }
+    }
+
+    fn handle_packet(&mut self, packet: &Packet) -> Result<()> {
+        if let PacketType::Sabm(_) | PacketType::Sabme(_) = &packet.packet_type {
+            self.accept_as(&packet.dst);
+            // Connections to others are none of our business, not even to
+            // refuse.
+            if packet.dst.call() != self.data.me.call() {
+                debug!("Ignoring connection to {}", packet.dst.call());
+                return Ok(());
+            }
+        }
+        match &packet.packet_type {
+            PacketType::Sabm(p) => {
+                self.handle_event(state::Event::Sabm(p.clone(), packet.src.clone()))
+            }
+            PacketType::Sabme(p) => {
+                self.handle_event(state::Event::Sabme(p.clone(), packet.src.clone()))
+            }
+            PacketType::Ua(ua) => self.handle_event(state::Event::Ua(ua.clone())),
+            PacketType::Disc(p) => self.handle_event(state::Event::Disc(p.clone())),
+            PacketType::Rnr(p) => {
+                self.handle_event(state::Event::Rnr(p.clone(), packet.command_response))
+            }
+            PacketType::Rej(p) => self.handle_event(state::Event::Rej(p.clone())),
+            PacketType::Srej(p) => self.handle_event(state::Event::Srej(p.clone())),
+            PacketType::Frmr(p) => self.handle_event(state::Event::Frmr(p.clone())),
+            PacketType::Invalid(p) => self.handle_event(state::Event::Invalid(
+                p.reason(),
+                packet.src.clone(),
+                packet.dst.clone(),
+            )),
+            PacketType::Xid(p) => self.handle_event(state::Event::Xid(
+                p.clone(),
+                packet.command_response,
+                packet.src.clone(),
+                packet.dst.clone(),
+            )),
+            PacketType::Ui(p) => self.handle_event(state::Event::Ui(
+                p.clone(),
+                packet.command_response,
+                packet.src.clone(),
+                packet.dst.clone(),
+            )),
+            PacketType::Test(p) => self.handle_event(state::Event::Test(
+                p.clone(),
+                packet.command_response,
+                packet.src.clone(),
+                packet.dst.clone(),
+            )),
+            PacketType::Dm(p) => self.handle_event(state::Event::Dm(p.clone())),
+            PacketType::Rr(rr) => {
+                self.handle_event(state::Event::Rr(rr.clone(), packet.command_response))
+            }
+            PacketType::Iframe(iframe) => self.handle_event(state::Event::Iframe(
+                iframe.clone(),
+                packet.command_response,
+            )),
+        }
+    }
+
+    /// Disconnect an established connection.
+    ///
+    /// Waits for the peer's UA, or for the retries to run out.
+    ///
+    /// This is not interrupted by the `CancelToken`, so that a cancelled
+    /// client can still disconnect gracefully.
+    pub async fn disconnect(mut self) -> Result<()> {
+        self.actions(Event::Disconnect).await?;
+        while !self.state.is_state_disconnected() {
+            self.wait_event_with(true, false).await?;
+        }
+        Ok(())
+    }
+
+    fn sync_disconnect(&mut self) {
+        if !self.state.is_state_disconnected() {
+            eprintln!("TODO: sync_disconnect")
+        }
+    }
+
+    /// Write data on an established connection.
+    ///
+    /// If the output buffer is full, this waits for room, so a fast writer is
+    /// throttled to link speed. Writes bigger than the whole output buffer are
+    /// added a piece at a time.
+    ///
+    /// Like `wait_acked()`, data received while waiting is kept for the next
+    /// `read()`.
+    ///
+    /// The returned token can be passed to `wait_acked()`, to wait for the
+    /// remote end to confirm it received the data.
+    pub async fn write(&mut self, data: &[u8]) -> Result<WriteToken> {
+        let data = self.line_ending.outgoing(data);
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            self.wait_obuf_room(rest.len()).await?;
+            let (chunk, tail) = rest.split_at(std::cmp::min(self.data.obuf_room(), rest.len()));
+            self.actions(Event::Data(chunk.to_vec())).await?;
+            rest = tail;
+        }
+        Ok(WriteToken(self.data.bytes_written()))
+    }
+
+    /// Wait until `len` bytes fit in the output buffer, or it's empty.
+    async fn wait_obuf_room(&mut self, len: usize) -> Result<()> {
+        while self.data.obuf_room() < len && self.data.obuf_len() > 0 {
+            if self.eof || self.state.is_state_disconnected() {
+                return Err(Error::msg("connection closed while writing"));
+            }
+            self.wait_event_for(false).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait until all of `len` bytes fit in the output buffer.
+    async fn wait_obuf_room_all(&mut self, len: usize) -> Result<()> {
+        self.wait_obuf_room(len).await?;
+        if self.data.obuf_room() < len {
+            return Err(Error::msg(format!(
+                "write of {len} bytes is bigger than the output buffer"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Write data for a layer 3 protocol, such as NET/ROM.
+    ///
+    /// Unlike `write()`, the data is sent in frames of its own, with no line
+    /// ending conversion.
+    pub async fn write_with_pid(&mut self, pid: crate::Pid, data: &[u8]) -> Result<WriteToken> {
+        self.data.check_data_with_pid(pid.into(), data)?;
+        self.wait_obuf_room_all(data.len()).await?;
+        self.actions(Event::DataWithPid(pid.into(), data.to_vec()))
+            .await?;
+        Ok(WriteToken(self.data.bytes_written()))
+    }
+
+    /// Write data ahead of data from `write()` still waiting to be sent.
+    ///
+    /// E.g. a keystroke can be sent without waiting for a file transfer's
+    /// buffered data to be sent first. Data already sent, even if not yet
+    /// acked, is not reordered. Nor are frames already queued whole, like
+    /// segments of a big write, or writes with a PID.
+    ///
+    /// Since this reorders the stream, tokens from earlier writes may be
+    /// reported acked by `wait_acked()` before all their data is acked.
+    pub async fn write_priority(&mut self, data: &[u8]) -> Result<WriteToken> {
+        let data = self.line_ending.outgoing(data);
+        self.wait_obuf_room_all(data.len()).await?;
+        self.actions(Event::PriorityData(data)).await?;
+        Ok(WriteToken(self.data.bytes_written()))
+    }
+
+    /// Write data, and report how much of it was transmitted right away.
+    ///
+    /// Like `write()`, but lets a producer see when the window is full, and
+    /// hold off on writing more.
+    pub async fn write_counted(&mut self, data: &[u8]) -> Result<WriteResult> {
+        let token = self.write(data).await?;
+        // The output buffer is FIFO, so whatever is left over is the tail of
+        // this write.
+        let buffered = std::cmp::min(self.data.obuf_len(), data.len());
+        Ok(WriteResult {
+            token,
+            sent: data.len() - buffered,
+            buffered,
+        })
+    }
+
+    /// Pause the connection, without disconnecting.
+    ///
+    /// The peer is told we're busy (RNR), so it stops sending. Data written
+    /// while paused is buffered, and the peer is not probed when idle.
+    ///
+    /// The state machine still needs to run, e.g. via `read()`, to handle
+    /// incoming frames and unacked data.
+    pub async fn pause(&mut self) -> Result<()> {
+        self.actions(Event::Pause).await
+    }
+
+    /// Resume a paused connection, sending any buffered data.
+    pub async fn resume(&mut self) -> Result<()> {
+        self.actions(Event::Resume).await
+    }
+
+    /// Return true if the connection is paused.
+    #[must_use]
+    pub fn is_paused(&self) -> bool {
+        self.data.paused()
+    }
+
+    /// Tell the peer to stop (RNR) or resume (RR) sending to us.
+    ///
+    /// This is backpressure for a slow consumer. Unlike `pause()`, data we
+    /// write is still sent.
+    ///
+    /// While busy, received iframes are discarded, and the peer will resend
+    /// them once no longer busy.
+    pub async fn set_receiver_busy(&mut self, busy: bool) -> Result<()> {
+        self.actions(Event::ReceiverBusy(busy)).await
+    }
+
+    /// Return true if `set_receiver_busy()` set us busy.
+    #[must_use]
+    pub fn is_receiver_busy(&self) -> bool {
+        self.data.receiver_busy()
     }
 
-    /// Disconnect an established connection.
+    /// Return the retry count (rc), and the max (n2).
     ///
-    /// This currently does not wait for the UA response.
-    pub async fn disconnect(mut self) -> Result<()> {
-        // TODO: wait for the UA
-        self.actions(Event::Disconnect).await
+    /// The connection is given up when rc reaches n2, so an application can
+    /// use this to warn that the link is struggling.
+    #[must_use]
+    pub fn retry_state(&self) -> (u8, u8) {
+        self.data.retry_state()
     }
 
-    fn sync_disconnect(&mut self) {
-        if !self.state.is_state_disconnected() {
-            eprintln!("TODO: sync_disconnect")
+    /// Wait until the data from a `write()` has been acked by the remote end.
+    ///
+    /// Like `read()`, this keeps the state machine running while waiting.
+    /// Received data is kept for the next `read()`.
+    ///
+    /// Returns an error if the connection goes away before the data is acked.
+    pub async fn wait_acked(&mut self, token: WriteToken) -> Result<()> {
+        loop {
+            if self.data.bytes_acked() >= token.0 {
+                return Ok(());
+            }
+            if self.eof || self.state.is_state_disconnected() {
+                return Err(Error::msg("connection closed before data was acked"));
+            }
+            self.wait_event_for(false).await?;
         }
     }
 
-    /// Write data on an established connection.
-    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
-        self.actions(Event::Data(data.to_vec())).await
+    /// Write a large buffer, one MTU sized piece at a time.
+    ///
+    /// Unlike `write()`, this waits for the output buffer to drain before
+    /// adding each piece, so at most one piece is buffered beyond what's in
+    /// flight. The transfer thus goes at link speed, without buffering it all.
+    ///
+    /// Like `wait_acked()`, received data is kept for the next `read()`.
+    ///
+    /// The returned token covers all of `data`.
+    pub async fn write_all_paced(&mut self, data: &[u8]) -> Result<WriteToken> {
+        let mut token = WriteToken(self.data.bytes_written());
+        for chunk in data.chunks(self.data.mtu_out().max(1)) {
+            while self.data.obuf_len() > 0 {
+                if self.eof || self.state.is_state_disconnected() {
+                    return Err(Error::msg("connection closed while writing"));
+                }
+                self.wait_event_for(false).await?;
+            }
+            token = self.write(chunk).await?;
+        }
+        Ok(token)
     }
 
     /// Get a pair of sleepers from the T1/T3 timers.
@@ -475,21 +2339,60 @@ impl Client {
                 return Ok(vec![]);
             }
             if !self.incoming.is_empty() {
-                let ret: Vec<_> = self.incoming.iter().cloned().collect();
+                let mut ret: Vec<_> = self.incoming.iter().cloned().collect();
                 self.incoming.clear();
+                self.line_ending.incoming(&mut ret);
                 return Ok(ret);
             }
         }
     }
 
+    /// Read the next received frame on the connection.
+    ///
+    /// The frame is still processed by the state machine as usual, so payload
+    /// data is also kept for the next `read()`. Requires the builder's
+    /// `tee_frames()`.
+    ///
+    /// Like `read()`, this keeps the state machine running while waiting.
+    pub async fn read_frame(&mut self) -> Result<Packet> {
+        loop {
+            let Some(tee) = &mut self.tee else {
+                return Err(Error::msg("frame tee not enabled"));
+            };
+            if let Some(p) = tee.pop_front() {
+                return Ok(p);
+            }
+            if self.eof || self.state.is_state_disconnected() {
+                return Err(Error::msg("connection closed"));
+            }
+            if !self.incoming_frames.is_empty() {
+                // Frames read by the last wait, that may be teed without
+                // waiting for more.
+                self.process_incoming_frames()?;
+                self.send_outgoing().await?;
+                continue;
+            }
+            self.wait_event_for(false).await?;
+        }
+    }
+
     async fn actions(&mut self, event: Event) -> Result<()> {
+        self.handle_event(event)?;
+        self.send_outgoing().await
+    }
+
+    /// Run an event through the state machine, queuing any frames to send.
+    fn handle_event(&mut self, event: Event) -> Result<()> {
         let (state, actions) = state::handle(&*self.state, &mut self.data, &event);
         if let Some(state) = state {
             let _ = std::mem::replace(&mut self.state, state);
         }
         for act in actions {
             match &act {
-                ReturnEvent::DlError(e) => eprintln!("DLError: {e:?}"),
+                ReturnEvent::DlError(e) => match &self.on_dl_error {
+                    Some(f) => f(*e),
+                    None => warn!("DLError: {e}"),
+                },
                 ReturnEvent::Data(res) => match res {
                     state::Res::None => {}
                     state::Res::EOF => self.eof = true,
@@ -500,16 +2403,222 @@ impl Client {
                 }
             }
             if let Some(frame) = act.serialize(self.data.ext()) {
-                if let Some(f) = &mut self.pcap {
-                    f.write(&frame)?;
-                }
-                let frame = crate::escape(&frame);
-                self.port.write_all(&frame).await?;
-                self.port.flush().await?;
+                self.queue_frame(&frame)?;
             }
         }
         Ok(())
     }
+
+    /// Capture a frame, and queue it to be written to the port.
+    fn queue_frame(&mut self, frame: &[u8]) -> Result<()> {
+        self.capture(Direction::Out, frame)?;
+        let escaped = crate::escape(frame, 0);
+        self.outgoing_frames.push_back(escaped.len());
+        self.outgoing.extend(escaped);
+        Ok(())
+    }
+
+    /// Write queued frames to the port.
+    async fn send_outgoing(&mut self) -> Result<()> {
+        std::future::poll_fn(|cx| self.poll_send_outgoing(cx)).await?;
+        Ok(())
+    }
+
+    /// Poll version of `send_outgoing()`.
+    ///
+    /// Frames are written one at a time, waiting `txdelay` before the first
+    /// one and `frame_gap` between the rest.
+    fn poll_send_outgoing(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        if self.outgoing_frames.is_empty() {
+            return std::task::Poll::Ready(Ok(()));
+        }
+        while let Some(&left) = self.outgoing_frames.front() {
+            if !self.tx_ready {
+                let delay = if self.tx_burst {
+                    self.frame_gap
+                } else {
+                    self.txdelay
+                };
+                if !delay.is_zero() {
+                    if self.tx_burst {
+                        // Make sure the last frame is out before the gap.
+                        std::task::ready!(Pin::new(&mut self.port).poll_flush(cx))?;
+                    }
+                    let timer = self
+                        .tx_timer
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(delay)));
+                    std::task::ready!(timer.as_mut().poll(cx));
+                    self.tx_timer = None;
+                }
+                self.tx_ready = true;
+            }
+            let chunk = self.outgoing.as_slices().0;
+            let chunk = &chunk[..std::cmp::min(chunk.len(), left)];
+            let n = std::task::ready!(Pin::new(&mut self.port).poll_write(cx, chunk))?;
+            if n == 0 {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.outgoing.drain(..n);
+            if n == left {
+                self.outgoing_frames.pop_front();
+                self.tx_ready = false;
+                self.tx_burst = true;
+            } else {
+                self.outgoing_frames[0] -= n;
+            }
+        }
+        self.tx_burst = false;
+        Pin::new(&mut self.port).poll_flush(cx)
+    }
+
+    /// Poll version of `wait_event_for()`, for the `AsyncRead` and
+    /// `AsyncWrite` implementations.
+    ///
+    /// Returns ready when something happened, so that the caller can check if
+    /// it can make progress. A `CancelToken` is only checked, not waited for.
+    fn poll_event(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<()>> {
+        use std::task::Poll;
+        if self.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+            return Poll::Ready(Err(Cancelled.into()));
+        }
+        if !self.incoming_frames.is_empty() {
+            self.process_incoming_frames()?;
+            return Poll::Ready(Ok(()));
+        }
+        // Sending is best effort. If the port is not writable yet, the waker
+        // is registered, and the rest is sent on the next poll.
+        if let Poll::Ready(Err(e)) = self.poll_send_outgoing(cx) {
+            return Poll::Ready(Err(e.into()));
+        }
+
+        // Timers.
+        if self.data.t1.remaining().is_some_and(|t| t.is_zero()) {
+            debug!("async con event: T1");
+            self.handle_event(Event::T1)?;
+            return Poll::Ready(Ok(()));
+        }
+        if self.data.t3.remaining().is_some_and(|t| t.is_zero()) {
+            debug!("async con event: T3");
+            self.handle_event(Event::T3)?;
+            return Poll::Ready(Ok(()));
+        }
+        let next = [self.data.t1.remaining(), self.data.t3.remaining()]
+            .into_iter()
+            .flatten()
+            .min();
+        if let Some(next) = next {
+            let deadline = tokio::time::Instant::now() + next;
+            let timer = self
+                .poll_timer
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+            timer.as_mut().reset(deadline);
+            if timer.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        // Port.
+        let mut buf = [0; 1024];
+        let mut rb = tokio::io::ReadBuf::new(&mut buf);
+        std::task::ready!(Pin::new(&mut self.port).poll_read(cx, &mut rb))?;
+        let n = rb.filled().len();
+        if n == 0 {
+            return Poll::Ready(Err(Error::msg("port closed")));
+        }
+        debug!("Read {n} bytes from serial port");
+        self.push_kiss(rb.filled());
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Turn an error into an `std::io::Error`, for the tokio traits.
+fn io_error(e: Error) -> std::io::Error {
+    std::io::Error::other(e)
+}
+
+/// Reads return data received on the connection, and 0 bytes once it's closed.
+///
+/// Reading keeps the state machine running, just like `Client::read()`.
+impl tokio::io::AsyncRead for Client {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.incoming.is_empty() {
+                let n = std::cmp::min(buf.remaining(), this.incoming.len());
+                let mut data: Vec<u8> = this.incoming.drain(..n).collect();
+                this.line_ending.incoming(&mut data);
+                buf.put_slice(&data);
+                return std::task::Poll::Ready(Ok(()));
+            }
+            if this.eof || this.state.is_state_disconnected() {
+                return std::task::Poll::Ready(Ok(()));
+            }
+            std::task::ready!(this.poll_event(cx)).map_err(io_error)?;
+        }
+    }
+}
+
+/// Writes are queued for sending like `Client::write()`, and block once the
+/// output buffer is full.
+///
+/// Shutdown waits for all written data to be acked, and then disconnects.
+impl tokio::io::AsyncWrite for Client {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.eof || this.state.is_state_disconnected() {
+                return std::task::Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "connection closed",
+                )));
+            }
+            let room = this.data.obuf_room();
+            if room > 0 {
+                let n = std::cmp::min(room, buf.len());
+                this.handle_event(Event::Data(this.line_ending.outgoing(&buf[..n])))
+                    .map_err(io_error)?;
+                if let std::task::Poll::Ready(Err(e)) = this.poll_send_outgoing(cx) {
+                    return std::task::Poll::Ready(Err(e));
+                }
+                return std::task::Poll::Ready(Ok(n));
+            }
+            std::task::ready!(this.poll_event(cx)).map_err(io_error)?;
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.get_mut().poll_send_outgoing(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        while !this.shutdown && !this.eof && !this.state.is_state_disconnected() {
+            if this.data.bytes_acked() >= this.data.bytes_written() {
+                this.handle_event(Event::Disconnect).map_err(io_error)?;
+                this.shutdown = true;
+                break;
+            }
+            std::task::ready!(this.poll_event(cx)).map_err(io_error)?;
+        }
+        this.poll_send_outgoing(cx)
+    }
 }
 
 impl Drop for Client {
@@ -517,5 +2626,1000 @@ impl Drop for Client {
         self.sync_disconnect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two ports connected to each other.
+    async fn port_pair() -> Result<(PortType, PortType)> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let a = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+        let (b, _) = listener.accept().await?;
+        Ok((PortType::Tcp(a), PortType::Tcp(b)))
+    }
+
+    #[tokio::test]
+    async fn txdelay() -> Result<()> {
+        use std::time::{Duration, Instant};
+        let (a, mut b) = duplex();
+        let mut cli = Client::internal_new(state::Data::new(Addr::new("M0THC-1")?), a);
+        cli.txdelay = Duration::from_millis(100);
+        cli.frame_gap = Duration::from_millis(50);
+        for _ in 0..3 {
+            cli.queue_frame(&[1, 2, 3])?;
+        }
+        let start = Instant::now();
+        let reader = async {
+            // Time of the end of each frame.
+            let mut times = Vec::new();
+            let mut buf = [0; 100];
+            let mut fends = 0;
+            while times.len() < 3 {
+                let n = b.read(&mut buf).await?;
+                for _ in buf[..n].iter().filter(|&&c| c == crate::KISS_FEND) {
+                    fends += 1;
+                    if fends % 2 == 0 {
+                        times.push(start.elapsed());
+                    }
+                }
+            }
+            Ok::<_, Error>(times)
+        };
+        let ((), times) = tokio::try_join!(cli.send_outgoing(), reader)?;
+        assert!(times[0] >= Duration::from_millis(100), "{times:?}");
+        assert!(
+            times[1] - times[0] >= Duration::from_millis(40),
+            "{times:?}"
+        );
+        assert!(
+            times[2] - times[1] >= Duration::from_millis(40),
+            "{times:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancel_then_disconnect() -> Result<()> {
+        let (a, b) = duplex();
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .accept()
+                .await?;
+            while !cli.read().await?.is_empty() {}
+            Ok::<_, Error>(cli.state_kind())
+        };
+        let client = async {
+            let cancel = CancelToken::new();
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .cancel_token(cancel.clone())
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            cancel.cancel();
+            let err = cli.read().await.unwrap_err();
+            assert!(err.is::<Cancelled>(), "{err}");
+            cli.disconnect().await
+        };
+        let (server, client) = tokio::join!(server, client);
+        client?;
+        assert_eq!(server?, state::StateKind::Disconnected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn duplex_echo() -> Result<()> {
+        let (a, b) = duplex();
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .accept()
+                .await?;
+            loop {
+                let data = cli.read().await?;
+                if data.is_empty() {
+                    // EOF.
+                    break;
+                }
+                cli.write(&data).await?;
+            }
+            Ok::<_, Error>(cli.state_kind())
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            assert!(cli.is_connected());
+            assert_eq!(cli.peer(), Some(&Addr::new("M0THC-2")?));
+            cli.write(b"hello").await?;
+            assert_eq!(cli.outstanding(), 1);
+            let mut got = Vec::new();
+            while got.len() < 5 {
+                got.extend(cli.read().await?);
+            }
+            cli.disconnect().await?;
+            Ok::<_, Error>(got)
+        };
+        let (server, client) = tokio::join!(server, client);
+        assert_eq!(client?, b"hello");
+        assert_eq!(server?, state::StateKind::Disconnected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_backpressure() -> Result<()> {
+        let (a, b) = duplex();
+        let data: Vec<u8> = (0..3000).map(|n| n as u8).collect();
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .accept()
+                .await?;
+            let mut got = Vec::new();
+            while got.len() < 2 * data.len() {
+                got.extend(cli.read().await?);
+            }
+            Ok::<_, Error>(got)
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .window(7)
+                .max_obuf(500)
+                // The peer only acks when polled, so poll quickly.
+                .srt_default(std::time::Duration::from_millis(20))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            let token = cli.write(&data).await?;
+            assert!(cli.data.obuf_len() <= 500);
+            assert_eq!(token, WriteToken(3000));
+            for chunk in data.chunks(700) {
+                assert!(cli.write(chunk).await.is_ok());
+                assert!(cli.data.obuf_len() <= 500);
+            }
+            assert!(cli
+                .write_with_pid(crate::Pid::from(0xCF), &[0; 501])
+                .await
+                .is_err());
+            Ok::<_, Error>(cli)
+        };
+        let (got, _cli) = tokio::try_join!(server, client)?;
+        assert_eq!(got, [&data[..], &data[..]].concat());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_all_paced() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let data: Vec<u8> = (0..550).map(|n| n as u8).collect();
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .accept()
+                .await?;
+            let mut got = Vec::new();
+            while got.len() < data.len() {
+                got.extend(cli.read().await?);
+            }
+            Ok::<_, Error>(got)
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .mtu(100)
+                .window(7)
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            let token = cli.write_all_paced(&data).await?;
+            assert_eq!(token, WriteToken(550));
+            Ok::<_, Error>(())
+        };
+        let (got, ()) = tokio::try_join!(server, client)?;
+        assert_eq!(got, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_frame() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .tee_frames(true)
+                .accept()
+                .await?;
+            let p = cli.read_frame().await?;
+            assert!(matches!(p.packet_type, PacketType::Sabm(_)), "{p:?}");
+            let p = cli.read_frame().await?;
+            let PacketType::Iframe(i) = p.packet_type else {
+                panic!("expected iframe, got {p:?}");
+            };
+            assert_eq!(i.ns, 0);
+            assert_eq!(i.payload, b"hello");
+
+            // Still delivered to the application.
+            assert_eq!(cli.read().await?, b"hello");
+            Ok::<_, Error>(())
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            assert!(cli.read_frame().await.is_err());
+            cli.write(b"hello").await?;
+            Ok::<_, Error>(())
+        };
+        tokio::try_join!(server, client)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_with_pid() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .tee_frames(true)
+                .accept()
+                .await?;
+            let mut pids = Vec::new();
+            while pids.len() < 2 {
+                if let PacketType::Iframe(i) = cli.read_frame().await?.packet_type() {
+                    pids.push((i.pid(), i.payload().to_vec()));
+                }
+            }
+            Ok::<_, Error>(pids)
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .srt_default(std::time::Duration::from_millis(50))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            // Segments are only made by the library, and without segmentation
+            // frames bigger than the MTU can't be sent.
+            assert!(cli.write_with_pid(crate::Pid::Segment, b"x").await.is_err());
+            let big = vec![0; cli.data.mtu_out() + 1];
+            assert!(cli.write_with_pid(crate::Pid::NetRom, &big).await.is_err());
+            cli.write_with_pid(crate::Pid::NetRom, b"\xFFnode").await?;
+            cli.write(b"text").await?;
+            // Keep the connection running until the server is done.
+            loop {
+                cli.read().await?;
+            }
+        };
+        let pids = tokio::select! {
+            pids = server => pids?,
+            r = client => return r,
+        };
+        assert_eq!(
+            pids,
+            [
+                (crate::Pid::NetRom, b"\xFFnode".to_vec()),
+                (crate::Pid::NoL3, b"text".to_vec())
+            ]
+        );
+        assert_eq!(u8::from(crate::Pid::NetRom), 0xCF);
+        assert_eq!(crate::Pid::from(0x42), crate::Pid::Unknown(0x42));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_raw_tap() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let ui = PacketType::Ui(crate::Ui {
+            push: false,
+            pid: 0xF0,
+            payload: b"raw".to_vec(),
+        });
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .tee_frames(true)
+                .accept()
+                .await?;
+            while cli.read_frame().await?.packet_type != ui {}
+            cli.write(b"pong").await?;
+            loop {
+                cli.read().await?;
+            }
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            let mut tap = cli.tap();
+            let packet = Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                digipeater: vec![],
+                rr_extseq: false,
+                command_response: true,
+                command_response_la: false,
+                rr_dist1: false,
+                packet_type: ui.clone(),
+            };
+            cli.send_raw(&packet).await?;
+            assert_eq!(tap.try_recv()?, (Direction::Out, packet.serialize(false)));
+            assert_eq!(cli.read().await?, b"pong");
+            let (dir, frame) = tap.try_recv()?;
+            assert_eq!(dir, Direction::In);
+            match Packet::parse(&frame, Some(false))?.packet_type {
+                PacketType::Iframe(i) => assert_eq!(i.payload, b"pong"),
+                other => panic!("expected I frame, got {other:?}"),
+            }
+            drop(tap);
+            cli.write(b"x").await?;
+            assert!(cli.taps.is_empty());
+            Ok::<_, Error>(())
+        };
+        tokio::select! {
+            r = server => r,
+            r = client => r,
+        }
+    }
+
+    #[tokio::test]
+    async fn ui_frames() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .accept()
+                .await?;
+            // Wait for the client to start listening.
+            assert_eq!(cli.read().await?, b"go");
+            let mut tap = cli.tap();
+            cli.send_ui(&Addr::new("APRS")?, b"hello", crate::Pid::NoL3)
+                .await?;
+            let (dir, frame) = tap.try_recv()?;
+            assert_eq!(dir, Direction::Out);
+            // Two addresses, then control field, PID, and payload.
+            assert_eq!(&frame[14..], b"\x03\xF0hello");
+            cli.write(b"done").await?;
+            loop {
+                cli.read().await?;
+            }
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            let mut ui = cli.ui_frames();
+            cli.write(b"go").await?;
+            assert_eq!(cli.read().await?, b"done");
+            let p = ui.try_recv()?;
+            assert_eq!(p.src().call(), "M0THC-2");
+            assert_eq!(p.dst().call(), "APRS");
+            match p.packet_type() {
+                PacketType::Ui(u) => {
+                    assert_eq!(u.pid(), crate::Pid::NoL3);
+                    assert_eq!(u.payload(), b"hello");
+                }
+                other => panic!("expected UI frame, got {other:?}"),
+            }
+            Ok::<_, Error>(())
+        };
+        tokio::select! {
+            r = server => r,
+            r = client => r,
+        }
+    }
+
+    #[tokio::test]
+    async fn write_counted() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .accept()
+                .await?;
+            let mut got = Vec::new();
+            while got.len() < 3 {
+                got.extend(cli.read().await?);
+            }
+            Ok::<_, Error>(got)
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .mtu(1)
+                .window(7)
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            // Window is 7 one byte frames.
+            let res = cli.write_counted(b"hello world").await?;
+            assert_eq!(res.token, WriteToken(11));
+            assert_eq!((res.sent, res.buffered), (7, 4));
+            let res = cli.write_counted(b"!").await?;
+            assert_eq!((res.sent, res.buffered), (0, 1));
+            Ok::<_, Error>(())
+        };
+        let (got, ()) = tokio::try_join!(server, client)?;
+        assert_eq!(got[..3], *b"hel");
+        Ok(())
+    }
+
+    #[test]
+    fn kiss_noise() -> Result<()> {
+        let (a, _b) = duplex();
+        let mut cli = Client::internal_new(state::Data::new(Addr::new("M0THC-1")?), a);
+        cli.max_incoming_kiss = 100;
+        cli.drop_policy = DropPolicy::Newest;
+        cli.push_kiss(&[0x55; 250]);
+        assert_eq!(cli.incoming_kiss.len(), 100);
+        assert_eq!(cli.stats.dropped_kiss_bytes, 150);
+
+        let frame = crate::escape(
+            &Packet {
+                src: Addr::new("M0THC-2")?,
+                dst: Addr::new("M0THC-1")?,
+                digipeater: vec![],
+                rr_extseq: false,
+                command_response: true,
+                command_response_la: false,
+                rr_dist1: false,
+                packet_type: PacketType::Sabm(crate::Sabm { poll: true }),
+            }
+            .serialize(false),
+            0,
+        );
+        cli.push_kiss(&frame);
+        assert_eq!(cli.incoming_frames.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn kisser_read_bad_escape() -> Result<()> {
+        let frame = crate::escape(
+            &Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                digipeater: vec![],
+                rr_extseq: false,
+                command_response: true,
+                command_response_la: false,
+                rr_dist1: false,
+                packet_type: PacketType::Sabm(crate::Sabm { poll: true }),
+            }
+            .serialize(false),
+            0,
+        );
+        let mut corrupt = frame.clone();
+        corrupt.insert(5, crate::KISS_FESC);
+        let mut ibuf: VecDeque<u8> = corrupt.into_iter().chain(frame).collect();
+        let errors = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let on_parse_error: ParseErrorFn = Box::new({
+            let errors = errors.clone();
+            move |_, _| {
+                errors.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        });
+        let got = kisser_read(&mut ibuf, Some(false), false, Some(&on_parse_error));
+        assert_eq!(got.len(), 1);
+        assert!(matches!(got[0].packet_type, PacketType::Sabm(_)));
+        assert_eq!(errors.load(std::sync::atomic::Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn line_ending() {
+        assert_eq!(LineEnding::None.outgoing(b"a\nb\r"), b"a\nb\r");
+        assert_eq!(LineEnding::Cr.outgoing(b"a\nb\r"), b"a\rb\r");
+        let mut data = b"a\rb\n\xC1".to_vec();
+        LineEnding::None.incoming(&mut data);
+        assert_eq!(data, b"a\rb\n\xC1");
+        LineEnding::Cr.incoming(&mut data);
+        assert_eq!(data, b"a\nb\n\xC1");
+        // CR with parity bit set.
+        let mut data = b"a\x8D\xC1".to_vec();
+        LineEnding::Cr7Bit.incoming(&mut data);
+        assert_eq!(data, b"a\nA");
+    }
+
+    #[tokio::test]
+    async fn with_port() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .accept()
+                .await?;
+            let local = cli.with_port(|p| match p {
+                PortType::Tcp(s) => s.local_addr().ok(),
+                _ => None,
+            });
+            assert!(local.is_some());
+            assert!(cli.set_dtr(true).is_err());
+            assert!(cli.set_rts(true).is_err());
+            Ok::<_, Error>(())
+        };
+        let client = async {
+            ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            Ok::<_, Error>(())
+        };
+        tokio::try_join!(server, client)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_refused() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let server = async {
+            ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .accept_filter(Box::new(|_| false))
+                .accept()
+                .await
+        };
+        let client = async {
+            let start = std::time::Instant::now();
+            let err = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .connect(Addr::new("M0THC-2")?)
+                .await
+                .err()
+                .expect("connect succeeded");
+            Ok::<_, Error>((err, start.elapsed()))
+        };
+        let (err, elapsed) = tokio::select! {
+            res = server => panic!("server returned {:?}", res.map(|_| ())),
+            res = client => res?,
+        };
+        assert_eq!(
+            err.downcast_ref::<ConnectionRefused>(),
+            Some(&ConnectionRefused)
+        );
+        // Well before the first T1 retry.
+        assert!(elapsed < state::DEFAULT_SRT, "took {elapsed:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extended_fallback() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let server = async {
+            let cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .max_modulus(8)
+                .accept()
+                .await?;
+            assert!(!cli.extended());
+            Ok::<_, Error>(())
+        };
+        let client = async {
+            let cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(None)
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            assert!(!cli.extended());
+            let res = cli.connect_result().expect("no connect result");
+            assert_eq!(res.modulus, 8);
+            assert!(res.fallback_occurred);
+            Ok::<_, Error>(())
+        };
+        tokio::try_join!(server, client)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extended_probe_timeout() -> Result<()> {
+        // Peer that never answers.
+        let (a, mut b) = duplex();
+        let client = async {
+            ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(None)
+                .srt_default(std::time::Duration::from_millis(10))
+                .connect(Addr::new("M0THC-2")?)
+                .await
+        };
+        let peer = async {
+            let mut ibuf = VecDeque::new();
+            let mut buf = [0; 100];
+            let mut sabmes = 0;
+            loop {
+                let n = b.read(&mut buf).await?;
+                ibuf.extend(&buf[..n]);
+                for p in kisser_read(&mut ibuf, None, false, None) {
+                    match p.packet_type {
+                        PacketType::Sabme(_) => sabmes += 1,
+                        PacketType::Sabm(_) => return Ok::<_, Error>(sabmes),
+                        _ => {}
+                    }
+                }
+            }
+        };
+        let sabmes = tokio::select! {
+            r = client => panic!("connect finished first: {:?}", r.map(|_| ())),
+            r = peer => r?,
+        };
+        assert_eq!(sabmes, SABME_PROBE_RETRIES);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bytes_acked() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .accept()
+                .await?;
+            let mut got = Vec::new();
+            loop {
+                let data = cli.read().await?;
+                if data.is_empty() {
+                    break;
+                }
+                got.extend(data);
+            }
+            Ok::<_, Error>(got)
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .srt_default(std::time::Duration::from_millis(50))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            let token = cli.write(b"hello").await?;
+            assert_eq!(cli.bytes_written(), 5);
+            cli.wait_acked(token).await?;
+            assert_eq!(cli.bytes_acked(), 5);
+            cli.disconnect().await?;
+            Ok::<_, Error>(())
+        };
+        let (got, ()) = tokio::try_join!(server, client)?;
+        assert_eq!(got, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn async_read_write() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (a, b) = port_pair().await?;
+        let data: Vec<u8> = (0..2000).map(|n| n as u8).collect();
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .accept()
+                .await?;
+            let mut got = Vec::new();
+            cli.read_to_end(&mut got).await?;
+            Ok::<_, Error>(got)
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .srt_default(std::time::Duration::from_millis(50))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            cli.write_all(&data).await?;
+            cli.shutdown().await?;
+            assert_eq!(cli.bytes_acked(), 2000);
+            Ok::<_, Error>(())
+        };
+        let (got, ()) = tokio::try_join!(server, client)?;
+        assert_eq!(got, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn on_dl_error() -> Result<()> {
+        let (mut a, b) = port_pair().await?;
+        let packet = |packet_type| {
+            let p = Packet {
+                src: Addr::new("M0THC-1").unwrap(),
+                dst: Addr::new("M0THC-2").unwrap(),
+                digipeater: vec![],
+                rr_extseq: false,
+                command_response: true,
+                command_response_la: false,
+                rr_dist1: false,
+                packet_type,
+            };
+            crate::escape(&p.serialize(false), 0)
+        };
+        a.write_all(&packet(PacketType::Sabm(crate::Sabm { poll: true })))
+            .await?;
+        let errors = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+            .on_dl_error(Box::new({
+                let errors = errors.clone();
+                move |e| errors.lock().unwrap().push(e)
+            }))
+            .accept()
+            .await?;
+
+        // Nothing has been sent, so N(R)=5 is out of range.
+        a.write_all(&packet(PacketType::Rr(crate::Rr { poll: false, nr: 5 })))
+            .await?;
+        while errors.lock().unwrap().is_empty() {
+            let _ = tokio::time::timeout(std::time::Duration::from_millis(10), cli.read()).await;
+        }
+        assert_eq!(*errors.lock().unwrap(), [state::DlError::J]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+                .t3v(std::time::Duration::from_millis(50))
+                .idle_timeout(std::time::Duration::from_millis(200))
+                .accept()
+                .await?;
+            loop {
+                cli.read().await?;
+            }
+        };
+        let client = async {
+            let start = std::time::Instant::now();
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            // Disconnected by the peer, with EOF.
+            assert_eq!(cli.read().await?, b"");
+            assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+            Ok::<_, Error>(())
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            tokio::select! {
+                r = server => r,
+                r = client => r,
+            }
+        })
+        .await?
+    }
+
+    #[tokio::test]
+    async fn mtu_in() -> Result<()> {
+        let (mut a, b) = port_pair().await?;
+        let packet = |packet_type| {
+            let p = Packet {
+                src: Addr::new("M0THC-1").unwrap(),
+                dst: Addr::new("M0THC-2").unwrap(),
+                digipeater: vec![],
+                rr_extseq: false,
+                command_response: true,
+                command_response_la: false,
+                rr_dist1: false,
+                packet_type,
+            };
+            crate::escape(&p.serialize(false), 0)
+        };
+        let iframe = |ns, len| {
+            packet(PacketType::Iframe(crate::Iframe {
+                nr: 0,
+                ns,
+                poll: false,
+                pid: 0xF0,
+                payload: vec![b'x'; len],
+            }))
+        };
+        a.write_all(&packet(PacketType::Sabm(crate::Sabm { poll: true })))
+            .await?;
+        let errors = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, b)?
+            .mtu_in(4)
+            .on_dl_error(Box::new({
+                let errors = errors.clone();
+                move |e| errors.lock().unwrap().push(e)
+            }))
+            .accept()
+            .await?;
+
+        a.write_all(&iframe(0, 4)).await?;
+        assert_eq!(cli.read().await?, b"xxxx");
+        assert!(errors.lock().unwrap().is_empty());
+
+        // One byte too many.
+        a.write_all(&iframe(1, 5)).await?;
+        while errors.lock().unwrap().is_empty() {
+            let _ = tokio::time::timeout(std::time::Duration::from_millis(10), cli.read()).await;
+        }
+        assert_eq!(*errors.lock().unwrap(), [state::DlError::O]);
+        assert_eq!(cli.state_kind(), state::StateKind::AwaitingConnection);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kiss_tcp_server() -> Result<()> {
+        let server = KissTcpServer::bind("127.0.0.1:0").await?;
+        let tnc = tokio::net::TcpStream::connect(server.local_addr()?).await?;
+        let (port, addr) = server.accept().await?;
+        assert_eq!(addr, tnc.local_addr()?);
+        let server = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-2")?, port)?
+                .accept()
+                .await?;
+            cli.read().await
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, PortType::Tcp(tnc))?
+                .extended(Some(false))
+                .connect(Addr::new("M0THC-2")?)
+                .await?;
+            cli.write(b"hello").await?;
+            Ok::<_, Error>(cli)
+        };
+        let (got, _cli) = tokio::try_join!(server, client)?;
+        assert_eq!(got, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sabm_for_others() -> Result<()> {
+        let (a, _b) = duplex();
+        let mut cli = Client::internal_new(state::Data::new(Addr::new("M0THC-1")?), a);
+        let sabm = |dst: &str| -> Result<Packet> {
+            Ok(Packet {
+                src: Addr::new("M0THC-2")?,
+                dst: Addr::new(dst)?,
+                command_response: true,
+                command_response_la: false,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Sabm(crate::Sabm { poll: true }),
+            })
+        };
+        cli.handle_packet(&sabm("M0THC-3")?)?;
+        assert!(cli.outgoing_frames.is_empty());
+
+        // Not listening, so refused, without becoming the peer.
+        cli.handle_packet(&sabm("M0THC-1")?)?;
+        assert_eq!(cli.outgoing_frames.len(), 1);
+        assert_eq!(cli.peer(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_any_ssid() -> Result<()> {
+        let (a, b) = port_pair().await?;
+        let server = async {
+            let cli = ConnectionBuilder::new(Addr::new("M0THC")?, b)?
+                .accept_any_ssid(true)
+                .accept()
+                .await?;
+            assert_eq!(cli.local_addr().call(), "M0THC-7");
+            Ok::<_, Error>(())
+        };
+        let client = async {
+            let mut cli = ConnectionBuilder::new(Addr::new("M0THC-1")?, a)?
+                .extended(Some(false))
+                .tee_frames(true)
+                .connect(Addr::new("M0THC-7")?)
+                .await?;
+            let p = cli.read_frame().await?;
+            assert!(matches!(p.packet_type, PacketType::Ua(_)), "{p:?}");
+            assert_eq!(p.src.call(), "M0THC-7");
+            Ok::<_, Error>(())
+        };
+        tokio::try_join!(server, client)?;
+        Ok(())
+    }
+
+    #[test]
+    fn push_bounded_policy() {
+        let mut q = VecDeque::new();
+        assert_eq!(push_bounded(&mut q, 1..=5, 3, DropPolicy::Oldest), 2);
+        assert_eq!(q, [3, 4, 5]);
+
+        let mut q = VecDeque::new();
+        assert_eq!(push_bounded(&mut q, 1..=5, 3, DropPolicy::Newest), 2);
+        assert_eq!(q, [1, 2, 3]);
+        assert_eq!(push_bounded(&mut q, [6], 3, DropPolicy::Oldest), 1);
+        assert_eq!(q, [2, 3, 6]);
+    }
+
+    #[tokio::test]
+    async fn listener() -> Result<()> {
+        let (mut a, b) = port_pair().await?;
+        let mut listener = Listener::new(Addr::new("M0THC-2")?, b);
+        let packet = |src: &str, packet_type: PacketType| -> Result<Vec<u8>> {
+            let p = Packet {
+                src: Addr::new(src)?,
+                dst: Addr::new("M0THC-2")?,
+                digipeater: vec![],
+                rr_extseq: false,
+                command_response: true,
+                command_response_la: false,
+                rr_dist1: false,
+                packet_type,
+            };
+            Ok(crate::escape(&p.serialize(false), 0))
+        };
+        let iframe = |ns: u8, payload: &[u8]| {
+            PacketType::Iframe(crate::Iframe {
+                nr: 0,
+                ns,
+                poll: false,
+                pid: crate::NO_L3,
+                payload: payload.to_vec(),
+            })
+        };
+        // Two peers, interleaving their connections.
+        for frame in [
+            packet("M0THC-1", PacketType::Sabm(crate::Sabm { poll: true }))?,
+            packet("M0THC-3", PacketType::Sabm(crate::Sabm { poll: true }))?,
+            packet("M0THC-1", iframe(0, b"one "))?,
+            packet("M0THC-3", iframe(0, b"three"))?,
+            packet("M0THC-1", iframe(1, b"two"))?,
+            // Not for the listener, so dropped.
+            packet("M0THC-4", iframe(0, b"nope"))?,
+        ] {
+            a.write_all(&frame).await?;
+        }
+        let mut first = listener.accept().await?;
+        let mut second = listener.accept().await?;
+        let mut got = Vec::new();
+        while got.len() < 7 {
+            got.extend(first.read().await?);
+        }
+        assert_eq!(got, b"one two");
+        assert_eq!(second.read().await?, b"three");
+
+        // Each connection replies with its own sequence numbers.
+        first.write(b"a").await?;
+        second.write(b"b").await?;
+        let mut ibuf = VecDeque::new();
+        let mut iframes = Vec::new();
+        while iframes.len() < 2 {
+            let mut buf = [0; 1024];
+            let n = a.read(&mut buf).await?;
+            assert_ne!(n, 0);
+            ibuf.extend(&buf[..n]);
+            for p in kisser_read(&mut ibuf, Some(false), false, None) {
+                assert_eq!(p.src.call(), "M0THC-2");
+                if let PacketType::Iframe(i) = &p.packet_type {
+                    iframes.push((p.dst.call().to_string(), i.ns, i.nr));
+                }
+            }
+        }
+        iframes.sort();
+        assert_eq!(
+            iframes,
+            [("M0THC-1".to_string(), 0, 2), ("M0THC-3".to_string(), 0, 1)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listener_exit() -> Result<()> {
+        use std::time::Duration;
+        let (mut a, b) = port_pair().await?;
+        let mut listener = Listener::new(Addr::new("M0THC-2")?, b);
+        let sabm = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type: PacketType::Sabm(crate::Sabm { poll: true }),
+        };
+        a.write_all(&crate::escape(&sabm.serialize(false), 0))
+            .await?;
+        let con = listener.accept().await?;
+        let mut ibuf = VecDeque::new();
+        let mut buf = [0; 1024];
+        while kisser_read(&mut ibuf, Some(false), false, None).is_empty() {
+            let n = a.read(&mut buf).await?;
+            assert_ne!(n, 0);
+            ibuf.extend(&buf[..n]);
+        }
+
+        // The connection still uses the port after the listener is gone.
+        drop(listener);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), a.read(&mut buf))
+                .await
+                .is_err()
+        );
+
+        // When it's gone too, so is the port, even though it's quiet.
+        drop(con);
+        let n = tokio::time::timeout(Duration::from_secs(1), a.read(&mut buf)).await??;
+        assert_eq!(n, 0);
+        Ok(())
+    }
+}
 /* vim: textwidth=80
  */
@@ -0,0 +1,180 @@
+//! Periodic UI beacons.
+//!
+//! An unattended station is usually required to identify itself every so
+//! often. This is normally done with a UI frame to a generic destination like
+//! `ID` or `BEACON`, carrying the callsign and maybe a comment.
+//!
+//! # Example
+//!
+#![cfg_attr(feature = "kiss", doc = "```no_run")]
+#![cfg_attr(not(feature = "kiss"), doc = "```ignore")]
+//! use std::sync::atomic::AtomicBool;
+//! use std::sync::Arc;
+//!
+//! use rax25::beacon::Beacon;
+//! use rax25::{Addr, Kiss};
+//!
+//! let done = Arc::new(AtomicBool::new(false));
+//! let beacon = Beacon::new(
+//!     Box::new(Kiss::new("/dev/rfcomm0")?),
+//!     Addr::new("M0THC-1")?,
+//!     Addr::new_dst("ID")?,
+//!     b"M0THC-1 unattended node".to_vec(),
+//!     std::time::Duration::from_secs(600),
+//! )?;
+//! let handle = beacon.spawn(done.clone());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Error, Result};
+use log::debug;
+
+use crate::{Addr, Hub, Packet};
+
+/// Send a UI frame on a timer, independent of any connection.
+pub struct Beacon {
+    hub: Box<dyn Hub + Send>,
+    src: Addr,
+    dst: Addr,
+    payload: Vec<u8>,
+    interval: std::time::Duration,
+
+    /// When the next beacon is due. None means right away.
+    next: Option<std::time::Instant>,
+}
+
+impl Beacon {
+    /// Create a new beacon.
+    ///
+    /// Nothing is sent until `send()`, `run()`, or `spawn()` is called.
+    ///
+    /// Fails if `interval` is zero, since that would flood the channel.
+    pub fn new(
+        hub: Box<dyn Hub + Send>,
+        src: Addr,
+        dst: Addr,
+        payload: Vec<u8>,
+        interval: std::time::Duration,
+    ) -> Result<Self> {
+        if interval.is_zero() {
+            return Err(Error::msg("beacon interval must not be zero"));
+        }
+        Ok(Self {
+            hub,
+            src,
+            dst,
+            payload,
+            interval,
+            next: None,
+        })
+    }
+
+    /// Build the beacon frame.
+    #[must_use]
+    fn packet(&self) -> Packet {
+        Packet::ui(&self.src, &self.dst, &[], 0xF0, &self.payload)
+    }
+
+    /// Send one beacon right now.
+    pub fn send(&mut self) -> Result<()> {
+        debug!("Sending beacon {} -> {}", self.src.call(), self.dst.call());
+        let frame = self.packet().serialize(false);
+        self.hub.send(&frame)
+    }
+
+    /// Send a beacon immediately, then every interval, until `done` is set.
+    ///
+    /// `done` is checked about once a second, so it may take that long to
+    /// return.
+    pub fn run(&mut self, done: Arc<AtomicBool>) -> Result<()> {
+        let tick = std::cmp::min(self.interval, std::time::Duration::from_secs(1));
+        while !done.load(Ordering::SeqCst) {
+            let now = std::time::Instant::now();
+            let next = self.tick(now)?;
+            std::thread::sleep(std::cmp::min(tick, next.saturating_duration_since(now)));
+        }
+        Ok(())
+    }
+
+    /// Send a beacon if one is due at `now`.
+    ///
+    /// Returns when the next one is due.
+    fn tick(&mut self, now: std::time::Instant) -> Result<std::time::Instant> {
+        match self.next {
+            Some(next) if now < next => Ok(next),
+            _ => {
+                self.send()?;
+                let next = now + self.interval;
+                self.next = Some(next);
+                Ok(next)
+            }
+        }
+    }
+
+    /// Run the beacon in a background thread.
+    pub fn spawn(mut self, done: Arc<AtomicBool>) -> std::thread::JoinHandle<Result<()>> {
+        std::thread::spawn(move || self.run(done))
+    }
+}
+
+#[cfg(all(test, feature = "bus"))]
+mod tests {
+    use super::*;
+    use crate::{BusHub, PacketType, Ui};
+    use std::sync::Mutex;
+
+    #[test]
+    fn beacon() -> Result<()> {
+        let bus = Arc::new(Mutex::new(bus::Bus::<crate::BusMessage>::new(10)));
+        let mut rx = BusHub::new(bus.clone());
+        let mut b = Beacon::new(
+            Box::new(BusHub::new(bus)),
+            Addr::new("M0THC-1")?,
+            Addr::new_dst("ID")?,
+            b"hello".to_vec(),
+            std::time::Duration::from_millis(10),
+        )?;
+        // Driven by a fake clock.
+        let start = std::time::Instant::now();
+        let ms = std::time::Duration::from_millis;
+        assert_eq!(b.tick(start)?, start + ms(10));
+        assert_eq!(b.tick(start + ms(5))?, start + ms(10));
+        assert_eq!(b.tick(start + ms(12))?, start + ms(22));
+        assert_eq!(b.tick(start + ms(21))?, start + ms(22));
+        let mut count = 0;
+        while let Ok(Some(frame)) = rx.recv_timeout(std::time::Duration::from_millis(1)) {
+            let p = Packet::parse(&frame, Some(false))?;
+            assert_eq!(p.src.call(), "M0THC-1");
+            assert_eq!(p.dst.call(), "ID");
+            assert_eq!(
+                p.packet_type,
+                PacketType::Ui(Ui {
+                    push: false,
+                    pid: 0xF0,
+                    payload: b"hello".to_vec(),
+                })
+            );
+            count += 1;
+        }
+        assert_eq!(count, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn beacon_zero_interval() -> Result<()> {
+        let bus = Arc::new(Mutex::new(bus::Bus::<crate::BusMessage>::new(10)));
+        assert!(Beacon::new(
+            Box::new(BusHub::new(bus)),
+            Addr::new("M0THC-1")?,
+            Addr::new_dst("ID")?,
+            b"hello".to_vec(),
+            std::time::Duration::ZERO,
+        )
+        .is_err());
+        Ok(())
+    }
+}
+/* vim: textwidth=80
+ */
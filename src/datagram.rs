@@ -0,0 +1,130 @@
+//! Connectionless UI frames.
+//!
+//! Send and receive UI frames directly on a hub, without any of the connected
+//! mode state machine. This is what e.g. APRS uses.
+//!
+//! # Example
+//!
+#![cfg_attr(feature = "kiss", doc = "```no_run")]
+#![cfg_attr(not(feature = "kiss"), doc = "```ignore")]
+//! use rax25::datagram::Datagram;
+//! use rax25::{Addr, Kiss};
+//!
+//! let mut dg = Datagram::new(Addr::new("M0THC-1")?, Box::new(Kiss::new("/dev/rfcomm0")?));
+//! dg.send(&Addr::new("M0THC-2")?, &[], 0xF0, b"hello")?;
+//! let (src, pid, payload) = dg.recv()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use anyhow::Result;
+use log::debug;
+
+use crate::{Addr, Hub, Packet, PacketType};
+
+/// Send and receive UI frames.
+pub struct Datagram {
+    me: Addr,
+    hub: Box<dyn Hub>,
+    promiscuous: bool,
+}
+
+impl Datagram {
+    /// Create a new datagram endpoint.
+    #[must_use]
+    pub fn new(me: Addr, hub: Box<dyn Hub>) -> Self {
+        Self {
+            me,
+            hub,
+            promiscuous: false,
+        }
+    }
+
+    /// Receive UI frames to any destination, not just our address.
+    ///
+    /// E.g. APRS frames are sent to a tocall, like `APRS`.
+    #[must_use]
+    pub fn promiscuous(mut self, v: bool) -> Self {
+        self.promiscuous = v;
+        self
+    }
+
+    /// Send a UI frame.
+    pub fn send(&mut self, dst: &Addr, path: &[Addr], pid: u8, payload: &[u8]) -> Result<()> {
+        let packet = Packet::ui(&self.me, dst, path, pid, payload);
+        self.hub.send(&packet.serialize(false))
+    }
+
+    /// Receive a UI frame, returning the source, PID, and payload.
+    ///
+    /// Blocks until one arrives. Other frames are ignored.
+    pub fn recv(&mut self) -> Result<(Addr, u8, Vec<u8>)> {
+        loop {
+            if let Some(ret) = self.recv_timeout(std::time::Duration::from_secs(1))? {
+                return Ok(ret);
+            }
+        }
+    }
+
+    /// Like `recv()`, but returns `Ok(None)` on timeout.
+    pub fn recv_timeout(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> Result<Option<(Addr, u8, Vec<u8>)>> {
+        let end = std::time::Instant::now() + timeout;
+        loop {
+            let now = std::time::Instant::now();
+            if now >= end {
+                return Ok(None);
+            }
+            let Some(frame) = self.hub.recv_timeout(end - now)? else {
+                return Ok(None);
+            };
+            let packet = match Packet::parse(&frame, None) {
+                Ok(p) => p,
+                Err(e) => {
+                    debug!("Ignoring unparsable frame: {e}");
+                    continue;
+                }
+            };
+            if !self.promiscuous && packet.dst.call() != self.me.call() {
+                continue;
+            }
+            if let PacketType::Ui(ui) = packet.packet_type {
+                return Ok(Some((packet.src, ui.pid, ui.payload)));
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "bus"))]
+mod tests {
+    use super::*;
+    use crate::BusHub;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn send_recv() -> Result<()> {
+        let bus = Arc::new(Mutex::new(bus::Bus::<crate::BusMessage>::new(10)));
+        let mut a = Datagram::new(Addr::new("M0THC-1")?, Box::new(BusHub::new(bus.clone())));
+        let mut b = Datagram::new(Addr::new("M0THC-2")?, Box::new(BusHub::new(bus.clone())));
+        let mut all =
+            Datagram::new(Addr::new("M0THC-3")?, Box::new(BusHub::new(bus))).promiscuous(true);
+
+        a.send(&Addr::new("APRS")?, &[], 0xF0, b"not for b")?;
+        a.send(&Addr::new("M0THC-2")?, &[], 0xCF, b"hello")?;
+
+        let timeout = std::time::Duration::from_millis(100);
+        let (src, pid, payload) = b.recv_timeout(timeout)?.expect("no frame");
+        assert_eq!(src.call(), "M0THC-1");
+        assert_eq!(pid, 0xCF);
+        assert_eq!(payload, b"hello");
+        assert_eq!(b.recv_timeout(timeout)?, None);
+
+        let (_, _, payload) = all.recv_timeout(timeout)?.expect("no frame");
+        assert_eq!(payload, b"not for b");
+        let (_, _, payload) = all.recv_timeout(timeout)?.expect("no frame");
+        assert_eq!(payload, b"hello");
+        Ok(())
+    }
+}
+/* vim: textwidth=80
+ */
@@ -0,0 +1,269 @@
+//! Digipeater.
+//!
+//! A digipeater repeats frames that are routed through it. Since many
+//! digipeaters may hear and repeat the same frame, care must be taken to not
+//! repeat the same frame more than once, or frames can bounce between
+//! digipeaters forever.
+//!
+//! This is the standard APRS "dupe check": a frame with the same source,
+//! destination, and payload as one recently repeated is dropped.
+//!
+//! A frame is repeated when the next hop in its path that has not yet
+//! repeated it is one of the digipeater's aliases. That hop is marked as
+//! repeated (the "H" bit), and the frame sent back out.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::debug;
+
+use crate::{Addr, Hub, Packet, PacketType};
+
+/// Default time within which an identical frame is considered a duplicate.
+///
+/// Same as the direwolf default.
+pub const DEFAULT_DEDUPE_WINDOW: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Default max number of digipeaters in a path.
+pub const DEFAULT_MAX_HOPS: u8 = 8;
+
+/// Software digipeater.
+pub struct Digipeater {
+    dedupe_window: std::time::Duration,
+    max_hops: u8,
+
+    /// Callsigns and aliases to repeat frames for.
+    aliases: Vec<Addr>,
+
+    /// Hashes of recently repeated frames, oldest first.
+    seen: VecDeque<(std::time::Instant, u64)>,
+}
+
+impl Default for Digipeater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Digipeater {
+    /// Create a new digipeater.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            dedupe_window: DEFAULT_DEDUPE_WINDOW,
+            max_hops: DEFAULT_MAX_HOPS,
+            aliases: Vec::new(),
+            seen: VecDeque::new(),
+        }
+    }
+
+    /// Set how long to remember repeated frames, to not repeat them again.
+    ///
+    /// Zero disables the dupe check.
+    #[must_use]
+    pub fn dedupe_window(mut self, v: std::time::Duration) -> Self {
+        self.dedupe_window = v;
+        self
+    }
+
+    /// Set the max number of digipeaters a frame's path may have, for it to
+    /// be repeated.
+    #[must_use]
+    pub fn max_hops(mut self, v: u8) -> Self {
+        self.max_hops = v;
+        self
+    }
+
+    /// Set the callsigns and aliases (e.g. `WIDE1-1`) to repeat frames for.
+    #[must_use]
+    pub fn aliases(mut self, v: Vec<Addr>) -> Self {
+        self.aliases = v;
+        self
+    }
+
+    #[must_use]
+    fn is_mine(&self, a: &Addr) -> bool {
+        self.aliases.iter().any(|m| m.call() == a.call())
+    }
+
+    /// If the frame is to be repeated by us, return it with our hop marked as
+    /// repeated.
+    ///
+    /// Frames addressed to us, and frames whose next hop is someone else, are
+    /// not repeated. Neither are dupes, as per `should_repeat()`.
+    pub fn repeat(&mut self, p: &Packet) -> Option<Packet> {
+        if self.is_mine(&p.dst) {
+            return None;
+        }
+        let hop = p.digipeater.iter().position(|a| !a.repeated())?;
+        if !self.is_mine(&p.digipeater[hop]) || !self.should_repeat(p) {
+            return None;
+        }
+        let mut ret = p.clone();
+        ret.digipeater[hop].set_repeated(true);
+        Some(ret)
+    }
+
+    /// Repeat frames received on the hub, until `done` is set.
+    ///
+    /// `done` is checked about once a second, so it may take that long to
+    /// return.
+    pub fn run(&mut self, hub: &mut dyn Hub, done: Arc<AtomicBool>) -> Result<()> {
+        while !done.load(Ordering::SeqCst) {
+            let Some(frame) = hub.recv_timeout(std::time::Duration::from_secs(1))? else {
+                continue;
+            };
+            let p = match Packet::parse(&frame, None) {
+                Ok(p) => p,
+                Err(e) => {
+                    debug!("Not repeating unparsable frame: {e}");
+                    continue;
+                }
+            };
+            if let Some(r) = self.repeat(&p) {
+                debug!("Repeating {r}");
+                hub.send(&r.serialize(r.rr_extseq))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Return true if the frame should be repeated.
+    ///
+    /// If so, it's also remembered, so that the same frame is not repeated
+    /// again within the dedupe window.
+    pub fn should_repeat(&mut self, p: &Packet) -> bool {
+        if p.digipeater.len() > self.max_hops as usize {
+            debug!(
+                "Not repeating frame from {} with {} hops",
+                p.src.call(),
+                p.digipeater.len()
+            );
+            return false;
+        }
+        let now = std::time::Instant::now();
+        while let Some(&(t, _)) = self.seen.front() {
+            if now.duration_since(t) < self.dedupe_window {
+                break;
+            }
+            self.seen.pop_front();
+        }
+        let hash = dupe_hash(p);
+        if self.seen.iter().any(|&(_, h)| h == hash) {
+            debug!("Not repeating dupe frame from {}", p.src.call());
+            return false;
+        }
+        if !self.dedupe_window.is_zero() {
+            self.seen.push_back((now, hash));
+        }
+        true
+    }
+}
+
+/// Hash the parts of a frame that don't change as it's repeated.
+#[must_use]
+fn dupe_hash(p: &Packet) -> u64 {
+    let mut h = DefaultHasher::new();
+    p.src.call().hash(&mut h);
+    p.dst.call().hash(&mut h);
+    match &p.packet_type {
+        PacketType::Ui(ui) => ui.payload.hash(&mut h),
+        PacketType::Iframe(i) => i.payload.hash(&mut h),
+        PacketType::Test(t) => t.payload.hash(&mut h),
+        // No payload, so compare the frame type too.
+        other => std::mem::discriminant(other).hash(&mut h),
+    }
+    h.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Ui;
+
+    fn ui(src: &str, digis: usize, payload: &[u8]) -> Result<Packet> {
+        Ok(Packet {
+            src: Addr::new(src)?,
+            dst: Addr::new("APRS")?,
+            command_response: true,
+            command_response_la: false,
+            digipeater: vec![Addr::new("WIDE1-1")?; digis],
+            rr_dist1: false,
+            rr_extseq: false,
+            packet_type: PacketType::Ui(Ui {
+                push: false,
+                pid: 0xF0,
+                payload: payload.to_vec(),
+            }),
+        })
+    }
+
+    #[test]
+    fn dedupe() -> Result<()> {
+        let mut d = Digipeater::new().dedupe_window(std::time::Duration::from_secs(60));
+        assert!(d.should_repeat(&ui("M0THC-1", 1, b"hello")?));
+        // Same frame again, maybe with the path changed by another digi.
+        assert!(!d.should_repeat(&ui("M0THC-1", 1, b"hello")?));
+        assert!(!d.should_repeat(&ui("M0THC-1", 2, b"hello")?));
+
+        // Other frames are fine.
+        assert!(d.should_repeat(&ui("M0THC-1", 1, b"world")?));
+        assert!(d.should_repeat(&ui("M0THC-2", 1, b"hello")?));
+
+        // Without dupe check.
+        let mut d = Digipeater::new().dedupe_window(std::time::Duration::ZERO);
+        assert!(d.should_repeat(&ui("M0THC-1", 1, b"hello")?));
+        assert!(d.should_repeat(&ui("M0THC-1", 1, b"hello")?));
+        Ok(())
+    }
+
+    #[test]
+    fn repeat() -> Result<()> {
+        let mut d = Digipeater::new().aliases(vec![Addr::new("M0THC-9")?, Addr::new("WIDE1-1")?]);
+        let mut p = ui("M0THC-1", 0, b"hello")?;
+        p.digipeater = vec![Addr::new("M0THC-9")?];
+        let r = d.repeat(&p).expect("not repeated");
+        assert!(
+            r.to_string().starts_with("M0THC-1 > APRS via M0THC-9* "),
+            "{r}"
+        );
+        assert!(Packet::parse(&r.serialize(false), None)?.digipeater[0].repeated());
+
+        // Already repeated by us.
+        assert!(Digipeater::new()
+            .aliases(vec![Addr::new("M0THC-9")?])
+            .repeat(&r)
+            .is_none());
+
+        // Next hop is someone else.
+        let mut p = ui("M0THC-1", 0, b"world")?;
+        p.digipeater = vec![Addr::new("M0THC-8")?, Addr::new("M0THC-9")?];
+        assert!(d.repeat(&p).is_none());
+        p.digipeater[0].set_repeated(true);
+        let r = d.repeat(&p).expect("not repeated");
+        assert!(r.digipeater.iter().all(Addr::repeated));
+
+        // Addressed to us.
+        let mut p = ui("M0THC-1", 1, b"direct")?;
+        p.dst = Addr::new("M0THC-9")?;
+        assert!(d.repeat(&p).is_none());
+
+        // Alias, but a dupe.
+        assert!(d.repeat(&ui("M0THC-1", 1, b"hi")?).is_some());
+        assert!(d.repeat(&ui("M0THC-1", 1, b"hi")?).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn max_hops() -> Result<()> {
+        let mut d = Digipeater::new().max_hops(2);
+        assert!(d.should_repeat(&ui("M0THC-1", 2, b"hello")?));
+        assert!(!d.should_repeat(&ui("M0THC-1", 3, b"world")?));
+        Ok(())
+    }
+}
+/* vim: textwidth=80
+ */
@@ -16,22 +16,43 @@
 //!
 //! * Linux kernel
 //! * Direwolf
+//!
+//! # Features
+//!
+//! * `async` (default): The tokio based async API, in [`r#async`].
+//! * `kiss` (default): KISS serial port support, [`Kiss`].
+//! * `bus` (default): [`BusHub`], and with `kiss` also [`BusKiss`].
+//!
+//! With default features off, the protocol state machine ([`state`]),
+//! [`Packet`], and [`Addr`] build without tokio, serialport, or bus.
 use anyhow::{Error, Result};
+#[cfg(all(feature = "bus", feature = "kiss"))]
 use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(any(feature = "bus", feature = "kiss"))]
 use std::sync::{Arc, Mutex};
 
 use log::debug;
+#[cfg(feature = "kiss")]
 use std::io::{Read, Write};
 
 mod fcs;
 pub mod state;
 
-const USE_FCS: bool = false;
+/// Add FCS to sent frames. KISS TNCs add it themselves.
+const SEND_FCS: bool = false;
 
+#[cfg(feature = "async")]
 pub mod r#async;
 pub mod sync;
 
+pub mod aprs;
+pub mod beacon;
+pub mod datagram;
+pub mod digipeater;
+#[cfg(target_os = "linux")]
+pub mod linux;
 pub mod pcap;
+pub mod session;
 
 /// Helper function for use with clap to parse command line durations.
 pub fn parse_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntError> {
@@ -58,19 +79,82 @@ pub struct Addr {
 impl Addr {
     /// Create a new Addr from string. The extra bits are all clear.
     pub fn new(s: &str) -> Result<Self> {
+        static RE: std::sync::LazyLock<regex::Regex> = std::sync::LazyLock::new(|| {
+            regex::Regex::new(r"^[A-Z0-9]{3,6}(?:-(?:[0-9]|1[0-5]))?$")
+                .expect("can't happen: Regex compile fail")
+        });
         let s = s.to_uppercase();
-        let re = regex::Regex::new(r"^[A-Z0-9]{3,6}(?:-(?:[0-9]|1[0-5]))?$")
-            .expect("can't happen: Regex compile fail");
-        if !re.is_match(&s) {
+        if !RE.is_match(&s) {
             return Err(Error::msg(format!("invalid callsign: {s}")));
         }
-        Ok(Self {
-            t: s,
+        Ok(Self::from_valid(s))
+    }
+
+    /// Create a new destination Addr from string.
+    ///
+    /// Like `new()`, but also allows short non-callsign destinations like
+    /// `ID` or `CQ`.
+    pub fn new_dst(s: &str) -> Result<Self> {
+        static RE: std::sync::LazyLock<regex::Regex> = std::sync::LazyLock::new(|| {
+            regex::Regex::new(r"^[A-Z0-9]{1,6}(?:-(?:[0-9]|1[0-5]))?$")
+                .expect("can't happen: Regex compile fail")
+        });
+        let s = s.to_uppercase();
+        if !RE.is_match(&s) {
+            return Err(Error::msg(format!("invalid destination: {s}")));
+        }
+        Ok(Self::from_valid(s))
+    }
+
+    /// Create a new Addr from string, only allowing ITU-style callsigns.
+    ///
+    /// That is, a one or two character prefix, a digit, and a suffix of
+    /// letters. E.g. `M0THC`, `2E0ABC`, or `W1AW`. Tactical calls like `ID`
+    /// or `NODE1` are rejected, since they're not legal as the source of
+    /// transmissions.
+    ///
+    /// This doesn't check that the prefix is actually allocated.
+    pub fn new_strict(s: &str) -> Result<Self> {
+        static RE: std::sync::LazyLock<regex::Regex> = std::sync::LazyLock::new(|| {
+            regex::Regex::new(r"^(?:[A-Z]{1,2}|[0-9][A-Z]|[A-Z][0-9])[0-9][A-Z]{1,4}(?:-|$)")
+                .expect("can't happen: Regex compile fail")
+        });
+        let a = Self::new(s)?;
+        if !RE.is_match(a.call()) {
+            return Err(Error::msg(format!("not a valid callsign: {}", a.call())));
+        }
+        Ok(a)
+    }
+
+    /// Create a new Addr from base callsign and SSID. The extra bits are all
+    /// clear.
+    ///
+    /// Same rules as `new_dst()`, but faster since there's no string to
+    /// parse.
+    pub fn from_parts(base: &str, ssid: u8) -> Result<Self> {
+        if base.is_empty() || base.len() > 6 || !base.bytes().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::msg(format!("invalid callsign: {base}")));
+        }
+        if ssid > 15 {
+            return Err(Error::msg(format!("invalid SSID: {ssid}")));
+        }
+        let base = base.to_ascii_uppercase();
+        Ok(Self::from_valid(if ssid > 0 {
+            format!("{base}-{ssid}")
+        } else {
+            base
+        }))
+    }
+
+    #[must_use]
+    fn from_valid(t: String) -> Self {
+        Self {
+            t,
             rbit_ext: false,
             highbit: false,
             lowbit: false,
             rbit_dama: false,
-        })
+        }
     }
 
     /// Create a new Addr from string and the extra bits.
@@ -95,6 +179,34 @@ impl Addr {
         &self.t
     }
 
+    /// Get the callsign without the SSID.
+    #[must_use]
+    pub fn base_call(&self) -> &str {
+        self.t.split_once('-').map_or(&self.t, |(base, _)| base)
+    }
+
+    /// Get the SSID, 0-15. No SSID suffix means 0.
+    #[must_use]
+    pub fn ssid(&self) -> u8 {
+        self.t.split_once('-').map_or(0, |(_, ssid)| {
+            ssid.parse()
+                .expect("can't happen: SSID validated on creation")
+        })
+    }
+
+    /// For a digipeater address, return if the frame has been repeated by it.
+    ///
+    /// This is the "H" bit. Page 15 (2.2 spec).
+    #[must_use]
+    pub fn repeated(&self) -> bool {
+        self.highbit
+    }
+
+    /// For a digipeater address, mark the frame as repeated (or not) by it.
+    pub fn set_repeated(&mut self, v: bool) {
+        self.highbit = v;
+    }
+
     /// Parse the callsign and the extra bits from the packet format.
     pub fn parse(bytes: &[u8]) -> Result<Self> {
         if bytes.len() != 7 {
@@ -102,28 +214,17 @@ impl Addr {
                 "invalid serialized callsign: {bytes:?}"
             )));
         }
-        let call = {
-            let call = bytes
-                .iter()
-                .take(6)
-                .map(|&c| (c >> 1) as char)
-                .collect::<String>()
-                .trim_end()
-                .to_string();
-            let ssid = (bytes[6] >> 1) & 15;
-            if ssid > 0 {
-                call + "-" + &ssid.to_string()
-            } else {
-                call
-            }
-        };
-        Self::new_bits(
-            &call,
-            bytes[6] & 1 != 0,
-            bytes[6] & 0x80 != 0,
-            bytes[6] & 0b0100_0000 == 0,
-            bytes[6] & 0b0010_0000 == 0,
-        )
+        let call = bytes
+            .iter()
+            .take(6)
+            .map(|&c| (c >> 1) as char)
+            .collect::<String>();
+        let mut a = Self::from_parts(call.trim_end(), (bytes[6] >> 1) & 15)?;
+        a.lowbit = bytes[6] & 1 != 0;
+        a.highbit = bytes[6] & 0x80 != 0;
+        a.rbit_ext = bytes[6] & 0b0100_0000 == 0;
+        a.rbit_dama = bytes[6] & 0b0010_0000 == 0;
+        Ok(a)
     }
 
     /// Serialize the callsign and SSID, plus explicit bits.
@@ -137,21 +238,10 @@ impl Addr {
     ) -> Vec<u8> {
         // TODO: confirm format.
         let mut ret = vec![b' ' << 1; 7];
-        for (i, ch) in self.t.chars().take(6).enumerate() {
-            if ch == '-' {
-                break;
-            }
+        for (i, ch) in self.base_call().chars().enumerate() {
             ret[i] = (ch as u8) << 1;
         }
-        let ssid = {
-            let s: Vec<_> = self.t.split('-').collect();
-            if s.len() == 1 {
-                0
-            } else {
-                s[1].parse::<u8>().unwrap()
-            }
-        };
-        ret[6] = (ssid << 1)
+        ret[6] = (self.ssid() << 1)
             | (if rbit_ext { 0 } else { 0b0100_0000 })
             | (if rbit_dama { 0 } else { 0b0010_0000 })
             | (if lowbit { 1 } else { 0 })
@@ -192,6 +282,46 @@ pub enum PacketType {
     Xid(Xid),
     Ui(Ui),
     Test(Test),
+    Invalid(Invalid),
+}
+
+/// Frame that's invalid for its type, or of an unknown type.
+///
+/// Kept instead of failing the parse, so that the state machine can report
+/// DlError L, M, or N, and digipeaters can still repeat it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invalid {
+    reason: InvalidReason,
+
+    /// Control and info fields, as received.
+    bytes: Vec<u8>,
+}
+
+/// What's wrong with an `Invalid` frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidReason {
+    /// Control field invalid or not implemented.
+    Control,
+
+    /// Info field in a U frame that doesn't allow one.
+    InfoNotPermitted,
+
+    /// Length incorrect for the frame type. E.g. an S frame with an info
+    /// field, or an I frame without a PID.
+    Length,
+}
+
+impl Invalid {
+    /// The first byte of the control field.
+    #[must_use]
+    pub fn control(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    #[must_use]
+    pub fn reason(&self) -> InvalidReason {
+        self.reason
+    }
 }
 
 /// SABM - Set Asynchronous Balanced Mode (4.3.3.1, page 23)
@@ -238,9 +368,119 @@ pub struct Srej {
 ///
 /// The AX.25 2.2 spec deprecates this, and says to not generate these frames. But
 /// it does specify what to do when receiving one.
-#[derive(Debug, PartialEq, Clone)]
+///
+/// The info field is the three byte modulo 8 format from AX.25 2.0, which is
+/// what older stations that send FRMR use.
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct Frmr {
     poll: bool,
+
+    /// Control field of the rejected frame.
+    rejected_control: u8,
+
+    /// V(S) and V(R) of the station sending the FRMR.
+    vs: u8,
+    vr: u8,
+
+    /// The rejected frame was a response.
+    rejected_response: bool,
+
+    /// Reason bits. See `FRMR_W` and friends.
+    reason: u8,
+}
+
+/// FRMR reason: Control field invalid or not implemented.
+pub const FRMR_W: u8 = 1;
+
+/// FRMR reason: Info field in a frame that doesn't allow one. Sent with W.
+pub const FRMR_X: u8 = 2;
+
+/// FRMR reason: Info field too long.
+pub const FRMR_Y: u8 = 4;
+
+/// FRMR reason: Invalid N(R).
+pub const FRMR_Z: u8 = 8;
+
+impl Frmr {
+    /// Create an FRMR rejecting a frame with the given control field.
+    ///
+    /// `reason` is a combination of `FRMR_W`, `FRMR_X`, `FRMR_Y`, and
+    /// `FRMR_Z`.
+    #[must_use]
+    pub fn new(
+        poll: bool,
+        rejected_control: u8,
+        vs: u8,
+        vr: u8,
+        rejected_response: bool,
+        reason: u8,
+    ) -> Self {
+        Self {
+            poll,
+            rejected_control,
+            vs: vs & 7,
+            vr: vr & 7,
+            rejected_response,
+            reason: reason & 0x0F,
+        }
+    }
+
+    /// Control field of the rejected frame.
+    #[must_use]
+    pub fn rejected_control(&self) -> u8 {
+        self.rejected_control
+    }
+
+    /// V(S) of the station sending the FRMR.
+    #[must_use]
+    pub fn vs(&self) -> u8 {
+        self.vs
+    }
+
+    /// V(R) of the station sending the FRMR.
+    #[must_use]
+    pub fn vr(&self) -> u8 {
+        self.vr
+    }
+
+    /// True if the rejected frame was a response, false if a command.
+    #[must_use]
+    pub fn rejected_response(&self) -> bool {
+        self.rejected_response
+    }
+
+    /// Reason bits, a combination of `FRMR_W`, `FRMR_X`, `FRMR_Y`, and
+    /// `FRMR_Z`.
+    #[must_use]
+    pub fn reason(&self) -> u8 {
+        self.reason
+    }
+
+    /// Serialize the info field.
+    #[must_use]
+    fn info(&self) -> [u8; 3] {
+        [
+            self.rejected_control,
+            (self.vs << 1) | if self.rejected_response { 0x10 } else { 0 } | (self.vr << 5),
+            self.reason,
+        ]
+    }
+
+    /// Parse FRMR from its info field.
+    ///
+    /// Some stations send FRMR without the info field, so a missing or short
+    /// one is read as zeroes.
+    fn parse(poll: bool, bytes: &[u8]) -> Self {
+        let b = |n| bytes.get(n).copied().unwrap_or(0);
+        Self {
+            poll,
+            rejected_control: b(0),
+            vs: (b(1) >> 1) & 7,
+            vr: (b(1) >> 5) & 7,
+            rejected_response: b(1) & 0x10 != 0,
+            reason: b(2) & 0x0F,
+        }
+    }
 }
 
 /// Test - Test frame (4.3.3.8, page 28)
@@ -260,10 +500,216 @@ pub struct Test {
 /// ISO 8885 exchange of capabilities, like extended sequence numbers,
 /// max IFRAME size ("MTU"), and lots of other stuff.
 ///
-/// TODO: Currently not implemented.
-#[derive(Debug, PartialEq, Clone)]
+/// All parameters are optional. Absent ones keep their current (or default)
+/// value when negotiating.
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct Xid {
     poll: bool,
+    classes_of_procedures: Option<u16>,
+    optional_functions: Option<u32>,
+
+    /// In bits.
+    i_field_length_rx: Option<u32>,
+    window_size_rx: Option<u8>,
+
+    /// In milliseconds.
+    ack_timer: Option<u32>,
+    retries: Option<u8>,
+}
+
+// XID format identifier and group identifier. Page 28.
+const XID_FI: u8 = 0x82;
+const XID_GI: u8 = 0x80;
+
+// XID parameter identifiers. Page 29.
+const XID_PI_CLASSES_OF_PROCEDURES: u8 = 2;
+const XID_PI_OPTIONAL_FUNCTIONS: u8 = 3;
+const XID_PI_I_FIELD_LENGTH_RX: u8 = 6;
+const XID_PI_WINDOW_SIZE_RX: u8 = 8;
+const XID_PI_ACK_TIMER: u8 = 9;
+const XID_PI_RETRIES: u8 = 10;
+
+// Classes of procedures bits.
+const XID_CLASSES_BALANCED_ABM: u16 = 0x0100;
+const XID_CLASSES_HALF_DUPLEX: u16 = 0x2000;
+const XID_CLASSES_FULL_DUPLEX: u16 = 0x4000;
+
+// HDLC optional functions bits.
+const XID_OPT_REJ: u32 = 0x02_0000;
+const XID_OPT_SREJ: u32 = 0x04_0000;
+const XID_OPT_EXTENDED_ADDRESS: u32 = 0x80_0000;
+const XID_OPT_MODULO_8: u32 = 0x00_0400;
+const XID_OPT_MODULO_128: u32 = 0x00_0800;
+const XID_OPT_TEST: u32 = 0x00_2000;
+const XID_OPT_FCS_16: u32 = 0x00_0080;
+const XID_OPT_SYNC_TX: u32 = 0x00_0002;
+
+impl Xid {
+    /// Create an XID with our capabilities.
+    ///
+    /// `mtu` is the max I field we're willing to receive, and `k` the max
+    /// number of outstanding frames we'll accept.
+    #[must_use]
+    pub fn new(poll: bool, extended: bool, srej: bool, mtu: usize, k: u8) -> Self {
+        Self {
+            poll,
+            classes_of_procedures: Some(XID_CLASSES_BALANCED_ABM | XID_CLASSES_HALF_DUPLEX),
+            optional_functions: Some(
+                XID_OPT_EXTENDED_ADDRESS
+                    | XID_OPT_TEST
+                    | XID_OPT_FCS_16
+                    | XID_OPT_SYNC_TX
+                    | if srej { XID_OPT_SREJ } else { XID_OPT_REJ }
+                    | if extended {
+                        XID_OPT_MODULO_128
+                    } else {
+                        XID_OPT_MODULO_8
+                    },
+            ),
+            i_field_length_rx: Some(u32::try_from(mtu * 8).unwrap_or(u32::MAX)),
+            window_size_rx: Some(k),
+            ack_timer: None,
+            retries: None,
+        }
+    }
+
+    /// Half or full duplex, if the classes of procedures are given.
+    #[must_use]
+    pub fn half_duplex(&self) -> Option<bool> {
+        let c = self.classes_of_procedures?;
+        if c & XID_CLASSES_FULL_DUPLEX != 0 {
+            Some(false)
+        } else {
+            Some(c & XID_CLASSES_HALF_DUPLEX != 0)
+        }
+    }
+
+    /// If modulo 128 is supported, if the optional functions are given.
+    #[must_use]
+    pub fn extended(&self) -> Option<bool> {
+        Some(self.optional_functions? & XID_OPT_MODULO_128 != 0)
+    }
+
+    /// If SREJ is supported, if the optional functions are given.
+    #[must_use]
+    pub fn srej(&self) -> Option<bool> {
+        Some(self.optional_functions? & XID_OPT_SREJ != 0)
+    }
+
+    /// Max I field length in bytes the sender is willing to receive.
+    #[must_use]
+    pub fn mtu(&self) -> Option<usize> {
+        Some(self.i_field_length_rx? as usize / 8)
+    }
+
+    /// Max outstanding I frames the sender is willing to receive.
+    #[must_use]
+    pub fn window_size(&self) -> Option<u8> {
+        self.window_size_rx
+    }
+
+    /// Wait for acknowledgement timer (T1).
+    #[must_use]
+    pub fn ack_timer(&self) -> Option<std::time::Duration> {
+        Some(std::time::Duration::from_millis(u64::from(self.ack_timer?)))
+    }
+
+    /// Retry count (N2).
+    #[must_use]
+    pub fn retries(&self) -> Option<u8> {
+        self.retries
+    }
+
+    /// Serialize the info field.
+    ///
+    /// An XID without any parameters has an empty info field.
+    #[must_use]
+    fn info(&self) -> Vec<u8> {
+        let mut params = Vec::new();
+        let mut param = |pi: u8, len: usize, v: u32| {
+            params.push(pi);
+            params.push(len as u8);
+            params.extend(&v.to_be_bytes()[4 - len..]);
+        };
+        if let Some(v) = self.classes_of_procedures {
+            param(XID_PI_CLASSES_OF_PROCEDURES, 2, u32::from(v));
+        }
+        if let Some(v) = self.optional_functions {
+            param(XID_PI_OPTIONAL_FUNCTIONS, 3, v);
+        }
+        if let Some(v) = self.i_field_length_rx {
+            param(XID_PI_I_FIELD_LENGTH_RX, if v > 0xFFFF { 4 } else { 2 }, v);
+        }
+        if let Some(v) = self.window_size_rx {
+            param(XID_PI_WINDOW_SIZE_RX, 1, u32::from(v));
+        }
+        if let Some(v) = self.ack_timer {
+            param(XID_PI_ACK_TIMER, if v > 0xFFFF { 4 } else { 2 }, v);
+        }
+        if let Some(v) = self.retries {
+            param(XID_PI_RETRIES, 1, u32::from(v));
+        }
+        if params.is_empty() {
+            return params;
+        }
+        let mut ret = vec![XID_FI, XID_GI];
+        ret.extend((params.len() as u16).to_be_bytes());
+        ret.extend(params);
+        ret
+    }
+
+    /// Parse XID from its info field.
+    ///
+    /// Unknown parameters are ignored.
+    fn parse(poll: bool, bytes: &[u8]) -> Result<Self> {
+        let mut ret = Self {
+            poll,
+            ..Default::default()
+        };
+        if bytes.is_empty() {
+            return Ok(ret);
+        }
+        let [fi, gi, gl0, gl1, rest @ ..] = bytes else {
+            return Err(Error::msg(format!("XID header too short: {bytes:?}")));
+        };
+        if (*fi, *gi) != (XID_FI, XID_GI) {
+            return Err(Error::msg(format!(
+                "unsupported XID format {fi:#04x} group {gi:#04x}"
+            )));
+        }
+        let gl = usize::from(u16::from_be_bytes([*gl0, *gl1]));
+        let Some(mut params) = rest.get(..gl) else {
+            return Err(Error::msg(format!(
+                "XID group length {gl} longer than the {} bytes left",
+                rest.len()
+            )));
+        };
+        while let [pi, pl, tail @ ..] = params {
+            let pl = usize::from(*pl);
+            let Some(pv) = tail.get(..pl) else {
+                return Err(Error::msg(format!("XID parameter {pi} truncated")));
+            };
+            params = &tail[pl..];
+            if pl > 4 {
+                debug!("Ignoring too long XID parameter {pi}: {pv:?}");
+                continue;
+            }
+            let v = pv.iter().fold(0u32, |acc, &b| (acc << 8) | u32::from(b));
+            match *pi {
+                XID_PI_CLASSES_OF_PROCEDURES => ret.classes_of_procedures = Some(v as u16),
+                XID_PI_OPTIONAL_FUNCTIONS => ret.optional_functions = Some(v),
+                XID_PI_I_FIELD_LENGTH_RX => ret.i_field_length_rx = Some(v),
+                XID_PI_WINDOW_SIZE_RX => ret.window_size_rx = Some(v as u8),
+                XID_PI_ACK_TIMER => ret.ack_timer = Some(v),
+                XID_PI_RETRIES => ret.retries = Some(v as u8),
+                _ => debug!("Ignoring unknown XID parameter {pi}: {pv:?}"),
+            }
+        }
+        if !params.is_empty() {
+            return Err(Error::msg(format!("trailing XID bytes: {params:?}")));
+        }
+        Ok(ret)
+    }
 }
 
 /// RNR - Receiver Not Ready (4.3.2.2, page 21)
@@ -315,9 +761,47 @@ pub struct Iframe {
 #[derive(Clone, Debug, PartialEq)]
 pub struct Ui {
     push: bool,
+    pid: u8,
     payload: Vec<u8>,
 }
 
+impl Iframe {
+    /// Layer 3 protocol of the payload.
+    #[must_use]
+    pub fn pid(&self) -> Pid {
+        self.pid.into()
+    }
+
+    #[must_use]
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+impl Ui {
+    /// Layer 3 protocol of the payload.
+    #[must_use]
+    pub fn pid(&self) -> Pid {
+        self.pid.into()
+    }
+
+    #[must_use]
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+impl Default for Ui {
+    /// Empty payload, with no layer 3 protocol (PID 0xF0), as used by APRS.
+    fn default() -> Self {
+        Self {
+            push: false,
+            pid: NO_L3,
+            payload: vec![],
+        }
+    }
+}
+
 /// DM - Disconnected Mode (4.3.3.5, page 23)
 ///
 /// The reply if the incoming packet implies a connection is active, or if
@@ -369,16 +853,250 @@ const NR_MASK: u8 = 0b1110_0000;
 const TYPE_MASK: u8 = 0b0000_0011;
 const NO_L3: u8 = 0xF0;
 
+/// Layer 3 protocol of I and UI frames.
+///
+/// The well known values from the AX.25 spec, plus `Unknown` for the rest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Pid {
+    /// ISO 8208/CCITT X.25 PLP.
+    X25,
+    /// Compressed TCP/IP packet. Van Jacobson (RFC 1144).
+    CompressedTcpIp,
+    /// Uncompressed TCP/IP packet. Van Jacobson (RFC 1144).
+    UncompressedTcpIp,
+    /// Segmentation fragment.
+    Segment,
+    Texnet,
+    LinkQuality,
+    Appletalk,
+    AppletalkArp,
+    Ip,
+    Arp,
+    FlexNet,
+    NetRom,
+    /// No layer 3 protocol implemented. Used for plain text and APRS.
+    NoL3,
+    /// Escape character. Next octet contains more layer 3 protocol
+    /// information.
+    Escape,
+    Unknown(u8),
+}
+
+impl From<u8> for Pid {
+    fn from(v: u8) -> Self {
+        match v {
+            0x01 => Pid::X25,
+            0x06 => Pid::CompressedTcpIp,
+            0x07 => Pid::UncompressedTcpIp,
+            0x08 => Pid::Segment,
+            0xC3 => Pid::Texnet,
+            0xC4 => Pid::LinkQuality,
+            0xCA => Pid::Appletalk,
+            0xCB => Pid::AppletalkArp,
+            0xCC => Pid::Ip,
+            0xCD => Pid::Arp,
+            0xCE => Pid::FlexNet,
+            0xCF => Pid::NetRom,
+            NO_L3 => Pid::NoL3,
+            0xFF => Pid::Escape,
+            v => Pid::Unknown(v),
+        }
+    }
+}
+
+impl From<Pid> for u8 {
+    fn from(v: Pid) -> Self {
+        match v {
+            Pid::X25 => 0x01,
+            Pid::CompressedTcpIp => 0x06,
+            Pid::UncompressedTcpIp => 0x07,
+            Pid::Segment => 0x08,
+            Pid::Texnet => 0xC3,
+            Pid::LinkQuality => 0xC4,
+            Pid::Appletalk => 0xCA,
+            Pid::AppletalkArp => 0xCB,
+            Pid::Ip => 0xCC,
+            Pid::Arp => 0xCD,
+            Pid::FlexNet => 0xCE,
+            Pid::NetRom => 0xCF,
+            Pid::NoL3 => NO_L3,
+            Pid::Escape => 0xFF,
+            Pid::Unknown(v) => v,
+        }
+    }
+}
+
+/// Max number of digipeaters in a frame's path.
+///
+/// Page 15 (2.2 spec).
+pub const MAX_DIGIPEATERS: usize = 8;
+
+/// Default time from keying up the transmitter until data is sent.
+///
+/// This is the KISS default TXDELAY of 50, in units of 10ms.
+pub const DEFAULT_TXDELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Expected time on the air to send an I frame with the given payload size,
+/// including TX delay.
+///
+/// Like `Packet::airtime()`, this includes the KISS framing on the serial
+/// link, not just what goes over the air. This assumes no digipeaters. Use
+/// `Packet::airtime()` for the general case. Fails if `baud` is zero.
+pub fn airtime(
+    payload_len: usize,
+    ext: bool,
+    baud: u32,
+    txdelay: std::time::Duration,
+) -> Result<std::time::Duration> {
+    let addr = Addr::new("NOCALL").expect("can't happen: NOCALL is valid");
+    // Payload left empty, to not allocate it only to count it.
+    let p = Packet {
+        src: addr.clone(),
+        dst: addr,
+        digipeater: vec![],
+        rr_extseq: false,
+        command_response: true,
+        command_response_la: false,
+        rr_dist1: false,
+        packet_type: PacketType::Iframe(Iframe {
+            nr: 0,
+            ns: 0,
+            poll: false,
+            pid: NO_L3,
+            payload: vec![],
+        }),
+    };
+    Ok(txdelay + bits_airtime((p.wire_len(ext) + payload_len + FRAMING_LEN) * 8, baud)?)
+}
+
+/// Opening and closing HDLC flags.
+///
+/// Back to back frames can share flags, so this is a slight overestimate for
+/// bursts.
+const HDLC_FLAGS_LEN: usize = 2;
+
+/// KISS FENDs and command byte. Escaping is data dependent, and ignored.
+const KISS_OVERHEAD_LEN: usize = 3;
+
+/// Bytes added around a frame, on top of `Packet::wire_len()`.
+///
+/// Only the HDLC flags go over the air. The KISS bytes are counted since
+/// `airtime()` models the serial link to the TNC plus RF, at the same baud.
+const FRAMING_LEN: usize = HDLC_FLAGS_LEN + KISS_OVERHEAD_LEN;
+
+/// Length of the FCS. If we don't send it, the TNC adds it.
+const FCS_LEN: usize = 2;
+
+fn bits_airtime(bits: usize, baud: u32) -> Result<std::time::Duration> {
+    if baud == 0 {
+        return Err(Error::msg("baud rate must not be zero"));
+    }
+    Ok(std::time::Duration::from_nanos(
+        bits as u64 * 1_000_000_000 / u64::from(baud),
+    ))
+}
+
 impl Packet {
+    /// Build a UI frame.
+    ///
+    /// It's a command, without poll, since no reply is expected.
+    #[must_use]
+    pub fn ui(src: &Addr, dst: &Addr, path: &[Addr], pid: u8, payload: &[u8]) -> Self {
+        Packet {
+            src: src.clone(),
+            dst: dst.clone(),
+            command_response: true,
+            command_response_la: false,
+            digipeater: path.to_vec(),
+            rr_dist1: false,
+            rr_extseq: false,
+            packet_type: PacketType::Ui(Ui {
+                push: false,
+                pid,
+                payload: payload.to_vec(),
+            }),
+        }
+    }
+
+    /// The frame type, and its type specific fields.
+    #[must_use]
+    pub fn packet_type(&self) -> &PacketType {
+        &self.packet_type
+    }
+
+    /// Source address.
+    #[must_use]
+    pub fn src(&self) -> &Addr {
+        &self.src
+    }
+
+    /// Destination address.
+    #[must_use]
+    pub fn dst(&self) -> &Addr {
+        &self.dst
+    }
+
+    /// Digipeater path.
+    #[must_use]
+    pub fn digipeater(&self) -> &[Addr] {
+        &self.digipeater
+    }
+
+    /// Length of the frame as sent on the air.
+    ///
+    /// That's the address field (including digipeaters), control field, PID,
+    /// info field, and FCS. HDLC flags and bit stuffing are not included. Nor
+    /// is the KISS framing, since that never leaves the serial link to the
+    /// TNC. `airtime()` adds both the flags and the KISS framing.
+    #[must_use]
+    pub fn wire_len(&self, ext: bool) -> usize {
+        let addr = 7 * (2 + self.digipeater.len());
+        let seq_control = if ext { 2 } else { 1 };
+        let rest = match &self.packet_type {
+            PacketType::Iframe(s) => seq_control + 1 + s.payload.len(),
+            PacketType::Ui(s) => 1 + 1 + s.payload.len(),
+            PacketType::Test(s) => 1 + s.payload.len(),
+            PacketType::Invalid(s) => s.bytes.len(),
+            PacketType::Xid(s) => 1 + s.info().len(),
+            PacketType::Frmr(s) => 1 + s.info().len(),
+            PacketType::Rr(_) | PacketType::Rnr(_) | PacketType::Rej(_) | PacketType::Srej(_) => {
+                seq_control
+            }
+            PacketType::Sabm(_)
+            | PacketType::Sabme(_)
+            | PacketType::Ua(_)
+            | PacketType::Dm(_)
+            | PacketType::Disc(_) => 1,
+        };
+        addr + rest + FCS_LEN
+    }
+
+    /// Expected time on the air to send this frame, including TX delay.
+    ///
+    /// This models the serial link to the TNC plus RF, both at `baud`, so
+    /// the KISS framing is counted even though it never goes over the air.
+    ///
+    /// Bit stuffing is data dependent, and ignored. Worst case it adds 20%.
+    /// Fails if `baud` is zero.
+    pub fn airtime(
+        &self,
+        ext: bool,
+        baud: u32,
+        txdelay: std::time::Duration,
+    ) -> Result<std::time::Duration> {
+        Ok(txdelay + bits_airtime((self.wire_len(ext) + FRAMING_LEN) * 8, baud)?)
+    }
+
     /// Serialize a packet, either as standard mod-8, or extended mod-128.
     #[must_use]
     pub fn serialize(&self, ext: bool) -> Vec<u8> {
         let mut ret = Vec::with_capacity(
-            14 + 2
-                + if let PacketType::Iframe(s) = &self.packet_type {
-                    s.payload.len() + 1
-                } else {
-                    0
+            7 * (2 + self.digipeater.len())
+                + 2
+                + match &self.packet_type {
+                    PacketType::Iframe(s) => s.payload.len() + 1,
+                    PacketType::Ui(s) => s.payload.len() + 1,
+                    _ => 0,
                 },
         );
         ret.extend(
@@ -392,6 +1110,14 @@ impl Packet {
             ext, // Setting this bit for extseq seems to be a de facto standard.
             false,
         ));
+        for (n, digi) in self.digipeater.iter().enumerate() {
+            ret.extend(digi.serialize(
+                n == self.digipeater.len() - 1,
+                digi.highbit,
+                digi.rbit_ext,
+                digi.rbit_dama,
+            ));
+        }
 
         match &self.packet_type {
             // U frames. Control always one byte.
@@ -408,16 +1134,24 @@ impl Packet {
                 ret.push(CONTROL_DISC | if disc.poll { CONTROL_POLL } else { 0 })
             }
             PacketType::Dm(s) => ret.push(CONTROL_DM | if s.poll { CONTROL_POLL } else { 0 }),
-            // TODO: FRMR data too.
-            PacketType::Frmr(s) => ret.push(CONTROL_FRMR | if s.poll { CONTROL_POLL } else { 0 }),
-            // TODO: UI data too.
-            PacketType::Ui(s) => ret.push(CONTROL_UI | if s.push { CONTROL_POLL } else { 0 }),
-            // TODO: XID data too.
-            PacketType::Xid(s) => ret.push(CONTROL_XID | if s.poll { CONTROL_POLL } else { 0 }),
+            PacketType::Frmr(s) => {
+                ret.push(CONTROL_FRMR | if s.poll { CONTROL_POLL } else { 0 });
+                ret.extend(s.info());
+            }
+            PacketType::Ui(s) => {
+                ret.push(CONTROL_UI | if s.push { CONTROL_POLL } else { 0 });
+                ret.push(s.pid);
+                ret.extend(&s.payload);
+            }
+            PacketType::Xid(s) => {
+                ret.push(CONTROL_XID | if s.poll { CONTROL_POLL } else { 0 });
+                ret.extend(s.info());
+            }
             PacketType::Test(s) => {
                 ret.push(CONTROL_TEST | if s.poll { CONTROL_POLL } else { 0 });
                 ret.extend(&s.payload);
             }
+            PacketType::Invalid(s) => ret.extend(&s.bytes),
 
             // S frames.
             PacketType::Rr(s) => {
@@ -449,15 +1183,23 @@ impl Packet {
                     ret.push(CONTROL_REJ);
                     ret.push((s.nr << 1) & 0xFE | if s.poll { 1 } else { 0 });
                 } else {
-                    ret.push(CONTROL_REJ | if s.poll { CONTROL_POLL } else { 0 });
-                }
-            }
+                    ret.push(
+                        CONTROL_REJ
+                            | if s.poll { CONTROL_POLL } else { 0 }
+                            | ((s.nr << 5) & NR_MASK),
+                    );
+                }
+            }
             PacketType::Srej(s) => {
                 if ext {
                     ret.push(CONTROL_SREJ);
                     ret.push((s.nr << 1) & 0xFE | if s.poll { 1 } else { 0 });
                 } else {
-                    ret.push(CONTROL_SREJ | if s.poll { CONTROL_POLL } else { 0 });
+                    ret.push(
+                        CONTROL_SREJ
+                            | if s.poll { CONTROL_POLL } else { 0 }
+                            | ((s.nr << 5) & NR_MASK),
+                    );
                 }
             }
             PacketType::Iframe(iframe) => {
@@ -476,7 +1218,7 @@ impl Packet {
                 ret.extend(&iframe.payload);
             }
         };
-        if USE_FCS {
+        if SEND_FCS {
             let crc = fcs::fcs(&ret);
             ret.push(crc[0]);
             ret.push(crc[1]);
@@ -484,6 +1226,56 @@ impl Packet {
         ret
     }
 
+    /// Parse a frame that still has the FCS at the end, verifying it.
+    ///
+    /// See `strip_fcs()`.
+    pub fn parse_fcs(bytes: &[u8], ext: Option<bool>) -> Result<Self> {
+        Self::parse(strip_fcs(bytes)?, ext)
+    }
+
+    /// Parse a frame that still has the KISS command/port byte in front, as
+    /// in captures with LINKTYPE_AX25_KISS.
+    ///
+    /// See `strip_kiss_byte()`.
+    pub fn parse_kiss(bytes: &[u8], ext: Option<bool>) -> Result<Self> {
+        let (_port, bytes) = strip_kiss_byte(bytes)?;
+        Self::parse(bytes, ext)
+    }
+
+    /// Parse the digipeater addresses following the source address.
+    ///
+    /// The address list ends with the first address that has the address
+    /// extension (low) bit set, which may be the source address itself.
+    /// Returns the digipeaters, and the rest of the frame starting with the
+    /// control field.
+    ///
+    /// Page 15 (2.2 spec, section 3.12.4).
+    fn parse_digipeaters<'a>(src: &Addr, mut bytes: &'a [u8]) -> Result<(Vec<Addr>, &'a [u8])> {
+        let mut digipeater = Vec::new();
+        let mut last = src.lowbit;
+        while !last {
+            if digipeater.len() == MAX_DIGIPEATERS {
+                return Err(Error::msg(format!(
+                    "more than {MAX_DIGIPEATERS} digipeaters"
+                )));
+            }
+            if bytes.len() < 7 {
+                return Err(Error::msg(format!(
+                    "digipeater address {} truncated",
+                    digipeater.len() + 1
+                )));
+            }
+            let mut digi = Addr::parse(&bytes[..7])?;
+            last = digi.lowbit;
+            // Which address is last is implied by the path length, and set
+            // again when serializing.
+            digi.lowbit = false;
+            digipeater.push(digi);
+            bytes = &bytes[7..];
+        }
+        Ok((digipeater, bytes))
+    }
+
     /// Parse packet from bytes.
     ///
     /// A packet with sequence numbers in it (S and I frames) cannot be parsed
@@ -503,19 +1295,12 @@ impl Packet {
     /// This code supports using the Linux bit, by providing `None` as `ext`, as
     /// opposed to `Some(bool)`.
     pub fn parse(bytes: &[u8], ext: Option<bool>) -> Result<Self> {
-        let do_fcs = USE_FCS;
-        if bytes.len() < if do_fcs { 17 } else { 15 } {
+        if bytes.len() < 15 {
             return Err(Error::msg(format!(
                 "packet too short: {} bytes",
                 bytes.len()
             )));
         }
-        // TODO: check FCS.
-        let bytes = if do_fcs {
-            &bytes[..(bytes.len() - 2)]
-        } else {
-            bytes
-        };
         let dst = Addr::parse(&bytes[0..7])?;
         let src = Addr::parse(&bytes[7..14])?;
 
@@ -524,8 +1309,16 @@ impl Packet {
             None => src.rbit_ext,
         };
 
-        // TODO: parse digipeater.
-        let control1 = bytes[14];
+        let (digipeater, bytes) = Self::parse_digipeaters(&src, &bytes[14..])?;
+        let Some(&control1) = bytes.first() else {
+            return Err(Error::msg("packet has no control field"));
+        };
+        let invalid = |reason| {
+            PacketType::Invalid(Invalid {
+                reason,
+                bytes: bytes.to_vec(),
+            })
+        };
         let (poll, nr, ns, bytes) = {
             if !ext || control1 & TYPE_MASK == 3 {
                 // NOTE: ns/nr will be nonsense for U frames.
@@ -534,18 +1327,18 @@ impl Packet {
                     control1 & CONTROL_POLL == CONTROL_POLL,
                     (control1 >> 5) & 7,
                     (control1 >> 1) & 7,
-                    &bytes[15..],
+                    &bytes[1..],
                 )
             } else {
-                if bytes.len() < 16 {
+                if bytes.len() < 2 {
                     return Err(Error::msg("AX.25 in ext mode, but S/U frame is too short"));
                 }
-                let control2 = bytes[15];
+                let control2 = bytes[1];
                 (
                     control2 & 1 == 1,
                     (control2 >> 1) & 127,
                     (control1 >> 1) & 127,
-                    &bytes[16..],
+                    &bytes[2..],
                 )
             }
         };
@@ -556,18 +1349,21 @@ impl Packet {
             command_response_la: src.highbit,
             rr_dist1: dst.rbit_ext,
             rr_extseq: ext,
-            digipeater: vec![],
+            digipeater,
             packet_type: match control1 & TYPE_MASK {
                 // I frames. Second control byte, with NR and NS.
-                // TODO: confirm pid is NO_L3
-                0 | 2 => PacketType::Iframe(Iframe {
-                    ns,
-                    nr,
-                    poll,
-                    pid: NO_L3,
-                    payload: bytes[1..].to_vec(),
-                }),
+                0 | 2 => match bytes.split_first() {
+                    Some((&pid, payload)) => PacketType::Iframe(Iframe {
+                        ns,
+                        nr,
+                        poll,
+                        pid,
+                        payload: payload.to_vec(),
+                    }),
+                    None => invalid(InvalidReason::Length),
+                },
                 // S frames. Second control byte, with NR.
+                1 if !bytes.is_empty() => invalid(InvalidReason::Length),
                 1 => match control1 & !NR_MASK & !CONTROL_POLL {
                     CONTROL_RR => PacketType::Rr(Rr { nr, poll }),
                     CONTROL_RNR => PacketType::Rnr(Rnr { nr, poll }),
@@ -577,22 +1373,29 @@ impl Packet {
                 },
                 // U frames. No second control byte.
                 3 => match !CONTROL_POLL & control1 {
+                    CONTROL_SABME | CONTROL_SABM | CONTROL_UA | CONTROL_DISC | CONTROL_DM
+                        if !bytes.is_empty() =>
+                    {
+                        invalid(InvalidReason::InfoNotPermitted)
+                    }
                     CONTROL_SABME => PacketType::Sabme(Sabme { poll }),
                     CONTROL_SABM => PacketType::Sabm(Sabm { poll }),
                     CONTROL_UA => PacketType::Ua(Ua { poll }),
                     CONTROL_DISC => PacketType::Disc(Disc { poll }),
                     CONTROL_DM => PacketType::Dm(Dm { poll }),
-                    CONTROL_FRMR => PacketType::Frmr(Frmr { poll }),
+                    CONTROL_FRMR => PacketType::Frmr(Frmr::parse(poll, bytes)),
+                    // First byte is the PID.
                     CONTROL_UI => PacketType::Ui(Ui {
                         push: poll,
-                        payload: bytes.to_vec(),
+                        pid: bytes.first().copied().unwrap_or(NO_L3),
+                        payload: bytes.get(1..).unwrap_or_default().to_vec(),
                     }),
-                    CONTROL_XID => PacketType::Xid(Xid { poll }),
+                    CONTROL_XID => PacketType::Xid(Xid::parse(poll, bytes)?),
                     CONTROL_TEST => PacketType::Test(Test {
                         poll,
                         payload: bytes.to_vec(),
                     }),
-                    c => todo!("Control {c:b} not implemented"),
+                    _ => invalid(InvalidReason::Control),
                 },
                 _ => panic!("Logic error: {control1} & 3 > 3"),
             },
@@ -600,6 +1403,99 @@ impl Packet {
     }
 }
 
+/// Human readable one-line summary, for logging.
+///
+/// E.g. `M0THC-1 > M0THC-2 via DIGI* I C P N(R)=3 N(S)=5 pid=F0 len=120`.
+///
+/// The frame class (I, S, or U) is followed by the name for S and U frames,
+/// then C or R for command or response, and P or F if the poll/final bit is
+/// set. Version 1 frames don't say if they're commands or responses, so that
+/// and whether it's P or F are left out.
+impl std::fmt::Display for Packet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} > {}", self.src.call(), self.dst.call())?;
+        for (n, digi) in self.digipeater.iter().enumerate() {
+            let sep = if n == 0 { " via " } else { "," };
+            let star = if digi.repeated() { "*" } else { "" };
+            write!(f, "{sep}{}{star}", digi.call())?;
+        }
+        let (class, name, poll) = match &self.packet_type {
+            PacketType::Iframe(p) => ("I", None, p.poll),
+            PacketType::Rr(p) => ("S", Some("RR"), p.poll),
+            PacketType::Rnr(p) => ("S", Some("RNR"), p.poll),
+            PacketType::Rej(p) => ("S", Some("REJ"), p.poll),
+            PacketType::Srej(p) => ("S", Some("SREJ"), p.poll),
+            PacketType::Sabm(p) => ("U", Some("SABM"), p.poll),
+            PacketType::Sabme(p) => ("U", Some("SABME"), p.poll),
+            PacketType::Ua(p) => ("U", Some("UA"), p.poll),
+            PacketType::Dm(p) => ("U", Some("DM"), p.poll),
+            PacketType::Disc(p) => ("U", Some("DISC"), p.poll),
+            PacketType::Frmr(p) => ("U", Some("FRMR"), p.poll),
+            PacketType::Ui(p) => ("U", Some("UI"), p.push),
+            PacketType::Xid(p) => ("U", Some("XID"), p.poll),
+            PacketType::Test(p) => ("U", Some("TEST"), p.poll),
+            PacketType::Invalid(p) => {
+                let class = match p.control() & TYPE_MASK {
+                    0 | 2 => "I",
+                    1 => "S",
+                    _ => "U",
+                };
+                (class, Some("INVALID"), false)
+            }
+        };
+        write!(f, " {class}")?;
+        if let Some(name) = name {
+            write!(f, " {name}")?;
+        }
+        // Command/response bits (6.1.2, page 35).
+        let command = match (self.command_response, self.command_response_la) {
+            (true, false) => Some(true),
+            (false, true) => Some(false),
+            _ => None,
+        };
+        match command {
+            Some(true) => write!(f, " C{}", if poll { " P" } else { "" })?,
+            Some(false) => write!(f, " R{}", if poll { " F" } else { "" })?,
+            None if poll => write!(f, " P/F")?,
+            None => {}
+        }
+        match &self.packet_type {
+            PacketType::Iframe(p) => write!(
+                f,
+                " N(R)={} N(S)={} pid={:02X} len={}",
+                p.nr,
+                p.ns,
+                p.pid,
+                p.payload.len()
+            ),
+            PacketType::Rr(Rr { nr, .. })
+            | PacketType::Rnr(Rnr { nr, .. })
+            | PacketType::Rej(Rej { nr, .. })
+            | PacketType::Srej(Srej { nr, .. }) => write!(f, " N(R)={nr}"),
+            PacketType::Ui(p) => write!(f, " pid={:02X} len={}", p.pid, p.payload.len()),
+            PacketType::Test(p) => write!(f, " len={}", p.payload.len()),
+            PacketType::Invalid(p) => write!(
+                f,
+                " {:?} control={:02X} len={}",
+                p.reason,
+                p.control(),
+                p.bytes.len()
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether a frame was received or sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Received from the port.
+    In,
+
+    /// Sent to the port.
+    Out,
+}
+
 /// Hub packet serializer/deserializer.
 ///
 /// Hub reads and writes packets. Normally to a KISS serial port. But
@@ -703,99 +1599,528 @@ impl Hub for FakeKiss {
     }
 }
 
+#[cfg(feature = "bus")]
 #[derive(Clone)]
 pub struct BusMessage {
+    // Only `BusKiss` uses the sender, to not echo its own frames.
+    #[cfg_attr(not(feature = "kiss"), allow(dead_code))]
     sender: usize,
     data: Vec<u8>,
 }
 
+/// Default time to wait for slow bus readers, before giving up on a frame.
+#[cfg(feature = "bus")]
+pub const DEFAULT_BUS_FULL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// What to do when the bus is full, because some reader is behind.
+#[cfg(feature = "bus")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusFullPolicy {
+    /// Wait up to this long for readers to catch up, then fail.
+    Block(std::time::Duration),
+
+    /// Drop the frame right away.
+    Drop,
+}
+
+#[cfg(feature = "bus")]
+impl Default for BusFullPolicy {
+    fn default() -> Self {
+        Self::Block(DEFAULT_BUS_FULL_TIMEOUT)
+    }
+}
+
+/// Broadcast on the bus, according to the policy.
+///
+/// Returns false if the frame was dropped.
+#[cfg(feature = "bus")]
+fn broadcast(
+    bus: &Mutex<bus::Bus<BusMessage>>,
+    policy: BusFullPolicy,
+    msg: BusMessage,
+) -> Result<bool> {
+    let deadline = match policy {
+        BusFullPolicy::Block(t) => Some(std::time::Instant::now() + t),
+        BusFullPolicy::Drop => None,
+    };
+    let mut msg = msg;
+    loop {
+        // Don't hold the lock while waiting.
+        msg = match bus.lock().unwrap().try_broadcast(msg) {
+            Ok(()) => return Ok(true),
+            Err(msg) => msg,
+        };
+        match deadline {
+            None => {
+                debug!("Bus full, dropping frame");
+                return Ok(false);
+            }
+            Some(d) if std::time::Instant::now() >= d => {
+                return Err(Error::msg("failed to broadcast: bus full"));
+            }
+            Some(_) => std::thread::sleep(std::time::Duration::from_millis(1)),
+        }
+    }
+}
+
+#[cfg(feature = "bus")]
 pub struct BusHub {
     rx: bus::BusReader<BusMessage>,
     bus: Arc<Mutex<bus::Bus<BusMessage>>>,
+    full_policy: BusFullPolicy,
 }
 
+#[cfg(feature = "bus")]
 impl BusHub {
     pub fn new(bus: Arc<Mutex<bus::Bus<BusMessage>>>) -> Self {
         let rx = {
             let bus = bus.lock();
             bus.unwrap().add_rx()
         };
-        Self { rx, bus }
+        Self {
+            rx,
+            bus,
+            full_policy: BusFullPolicy::default(),
+        }
+    }
+
+    /// Set what to do when a slow reader has filled up the bus.
+    #[must_use]
+    pub fn full_policy(mut self, v: BusFullPolicy) -> Self {
+        self.full_policy = v;
+        self
     }
 }
 
+#[cfg(feature = "bus")]
 impl Hub for BusHub {
     fn send(&mut self, frame: &[u8]) -> Result<()> {
-        let bus = self.bus.lock();
-        bus.unwrap()
-            .try_broadcast(BusMessage {
+        broadcast(
+            &self.bus,
+            self.full_policy,
+            BusMessage {
                 sender: 0,
                 data: frame.to_vec(),
-            })
-            .map_err(|_| Error::msg("failed to broadcast"))?;
+            },
+        )?;
         Ok(())
     }
 
     fn recv_timeout(&mut self, timeout: std::time::Duration) -> Result<Option<Vec<u8>>> {
-        Ok(Some(self.rx.recv_timeout(timeout)?.data))
+        match self.rx.recv_timeout(timeout) {
+            Ok(m) => Ok(Some(m.data)),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
     }
 
     fn clone(&self) -> Box<dyn Hub> {
-        Box::new(Self::new(self.bus.clone()))
+        Box::new(Self::new(self.bus.clone()).full_policy(self.full_policy))
+    }
+}
+
+/// Serial settings for the host link to a KISS TNC.
+///
+/// This is the rate between the computer and the TNC, not the RF rate.
+#[cfg(feature = "kiss")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KissConfig {
+    pub baud: u32,
+    pub data_bits: serialport::DataBits,
+    pub parity: serialport::Parity,
+    pub stop_bits: serialport::StopBits,
+}
+
+#[cfg(feature = "kiss")]
+impl Default for KissConfig {
+    /// 9600bps 8N1.
+    fn default() -> Self {
+        Self::new(9600)
+    }
+}
+
+#[cfg(feature = "kiss")]
+impl KissConfig {
+    /// Create 8N1 settings with the given baud rate.
+    #[must_use]
+    pub fn new(baud: u32) -> Self {
+        Self {
+            baud,
+            data_bits: serialport::DataBits::Eight,
+            parity: serialport::Parity::None,
+            stop_bits: serialport::StopBits::One,
+        }
+    }
+
+    /// Return a builder for opening the named port with these settings.
+    ///
+    /// Also usable with `tokio_serial::SerialPortBuilderExt`, for the async
+    /// API.
+    #[must_use]
+    pub fn builder(&self, port: &str) -> serialport::SerialPortBuilder {
+        serialport::new(port, self.baud)
+            .flow_control(serialport::FlowControl::None)
+            .parity(self.parity)
+            .data_bits(self.data_bits)
+            .stop_bits(self.stop_bits)
+    }
+}
+
+/// Frames received on a shared KISS port, not yet returned by a `Kiss`
+/// handle. Still escaped, and with the command byte.
+#[cfg(feature = "kiss")]
+type KissQueue = Arc<Mutex<std::collections::VecDeque<Vec<u8>>>>;
+
+/// Max received frames queued for a `Kiss` handle that's not reading.
+#[cfg(feature = "kiss")]
+const MAX_KISS_QUEUE: usize = 1000;
+
+/// Longest time to hold the port lock while waiting for received bytes, so
+/// that other handles can send.
+#[cfg(feature = "kiss")]
+const KISS_READ_SLICE: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// The part of a `Kiss` shared between its clones.
+#[cfg(feature = "kiss")]
+struct KissShared {
+    port: Box<dyn serialport::SerialPort>,
+    buf: std::collections::VecDeque<u8>,
+
+    /// Receive queues of all handles.
+    queues: Vec<std::sync::Weak<Mutex<std::collections::VecDeque<Vec<u8>>>>>,
+}
+
+#[cfg(feature = "kiss")]
+impl KissShared {
+    /// Move all complete frames in the read buffer to all handles' queues.
+    fn distribute(&mut self) {
+        while let Some((a, b)) = find_frame(&self.buf) {
+            if b - a < 2 {
+                // No command byte, e.g. back to back FENDs.
+                self.buf.drain(..(a + 1));
+                continue;
+            }
+            let bytes: Vec<_> = self
+                .buf
+                .iter()
+                .skip(a + 1)
+                .take(b - a - 1)
+                .cloned()
+                .collect();
+            self.buf.drain(..b);
+            debug!("After drain: {:?}", self.buf);
+            self.queues.retain(|q| q.strong_count() > 0);
+            for q in &self.queues {
+                let Some(q) = q.upgrade() else {
+                    continue;
+                };
+                let Ok(mut q) = q.lock() else {
+                    continue;
+                };
+                if q.len() >= MAX_KISS_QUEUE {
+                    debug!("KISS receive queue full, dropping oldest frame");
+                    q.pop_front();
+                }
+                q.push_back(bytes.clone());
+            }
+        }
     }
 }
 
 /// Kiss reads and writes packets on a KISS serial port.
 ///
+/// Clones (using `Hub::clone()`) share the port. All of them receive all
+/// frames, and can send.
+///
 /// https://en.wikipedia.org/wiki/KISS_(amateur_radio_protocol)
+#[cfg(feature = "kiss")]
 pub struct Kiss {
-    buf: std::collections::VecDeque<u8>,
-    port: Box<dyn serialport::SerialPort>,
+    shared: Arc<Mutex<KissShared>>,
+    queue: KissQueue,
+    fcs: bool,
+
+    /// TNC port to send on.
+    kiss_port: u8,
+
+    /// Received non-data command frames, oldest first.
+    commands: std::collections::VecDeque<KissCommand>,
+
+    /// Received frames dropped because they couldn't be decoded.
+    dropped_frames: u64,
 }
 
+#[cfg(feature = "kiss")]
 impl Kiss {
-    /// Create new Kiss connected to the named port.
-    ///
-    /// Currently hard coded to 9600bps 8N1.
+    /// Create new Kiss connected to the named port, at 9600bps 8N1.
     pub fn new(port: &str) -> Result<Self> {
+        Self::with_config(port, &KissConfig::default())
+    }
+
+    /// Create new Kiss connected to the named port, with the given serial
+    /// settings.
+    pub fn with_config(port: &str, config: &KissConfig) -> Result<Self> {
         //            let mut stream = std::net::TcpStream::connect("127.0.0.1:8001")?;
-        let port = serialport::new(port, 9600)
-            .flow_control(serialport::FlowControl::None)
-            .parity(serialport::Parity::None)
-            .data_bits(serialport::DataBits::Eight)
-            .stop_bits(serialport::StopBits::One)
-            .open()?;
+        let port = config.builder(port).open()?;
         port.clear(serialport::ClearBuffer::All)?;
-        Ok(Self {
-            buf: std::collections::VecDeque::new(),
+        let shared = Arc::new(Mutex::new(KissShared {
             port,
             //        port: Box::new(stream),
+            buf: std::collections::VecDeque::new(),
+            queues: Vec::new(),
+        }));
+        Self::new_handle(shared, false, 0)
+    }
+
+    /// Create a new handle to a shared port, with its own receive queue.
+    fn new_handle(shared: Arc<Mutex<KissShared>>, fcs: bool, kiss_port: u8) -> Result<Self> {
+        let queue = KissQueue::default();
+        lock(&shared)?.queues.push(Arc::downgrade(&queue));
+        Ok(Self {
+            shared,
+            queue,
+            fcs,
+            kiss_port,
+            commands: std::collections::VecDeque::new(),
+            dropped_frames: 0,
         })
     }
+
+    /// Write raw bytes to the port.
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut shared = lock(&self.shared)?;
+        shared.port.write_all(bytes)?;
+        shared.port.flush()?;
+        Ok(())
+    }
+
+    /// Expect received frames to include the FCS.
+    ///
+    /// Frames with a bad FCS are dropped, and the FCS is stripped from the
+    /// rest. Off by default, since KISS TNCs normally strip it.
+    #[must_use]
+    pub fn fcs(mut self, v: bool) -> Self {
+        self.fcs = v;
+        self
+    }
+
+    /// Send on a port of a multi-port TNC. Default is port 0.
+    pub fn kiss_port(mut self, port: u8) -> Result<Self> {
+        if port > MAX_KISS_PORT {
+            return Err(Error::msg(format!("invalid KISS port {port}")));
+        }
+        self.kiss_port = port;
+        Ok(self)
+    }
+
+    /// Send a KISS command frame to the TNC, on the port set by
+    /// `kiss_port()`.
+    pub fn send_command(&mut self, command: u8, data: &[u8]) -> Result<()> {
+        self.write(&kiss_frame(self.kiss_port, command, data))
+    }
+
+    /// Set the time to wait after keying up the transmitter, before sending
+    /// data. In 10ms units.
+    pub fn set_txdelay(&mut self, v: u8) -> Result<()> {
+        self.send_command(KISS_CMD_TXDELAY, &[v])
+    }
+
+    /// Set the p-persistence parameter, as `p * 256 - 1`.
+    pub fn set_persistence(&mut self, v: u8) -> Result<()> {
+        self.send_command(KISS_CMD_PERSISTENCE, &[v])
+    }
+
+    /// Set the slot interval for p-persistence. In 10ms units.
+    pub fn set_slottime(&mut self, v: u8) -> Result<()> {
+        self.send_command(KISS_CMD_SLOTTIME, &[v])
+    }
+
+    /// Set the time to keep the transmitter keyed after the frame. In 10ms
+    /// units. Obsolete, but some TNCs still use it.
+    pub fn set_txtail(&mut self, v: u8) -> Result<()> {
+        self.send_command(KISS_CMD_TXTAIL, &[v])
+    }
+
+    /// Set full duplex, meaning transmit without waiting for a clear channel.
+    pub fn set_fullduplex(&mut self, v: bool) -> Result<()> {
+        self.send_command(KISS_CMD_FULLDUPLEX, &[u8::from(v)])
+    }
+
+    /// Send TNC specific settings.
+    pub fn set_hardware(&mut self, data: &[u8]) -> Result<()> {
+        self.send_command(KISS_CMD_SETHARDWARE, data)
+    }
+
+    /// Return the number of received frames dropped because they were
+    /// corrupt, e.g. with bad escapes, bad FCS, or not parsing as AX.25.
+    #[must_use]
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    /// Return the oldest received non-data command frame, like a response to
+    /// `set_hardware()`.
+    ///
+    /// Only the most recent ones are kept, so call this regularly if the TNC
+    /// sends them.
+    pub fn pop_command(&mut self) -> Option<KissCommand> {
+        self.commands.pop_front()
+    }
+
+    /// Like `recv_timeout()`, but also return which TNC port the frame was
+    /// received on.
+    pub fn recv_port_timeout(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> Result<Option<(u8, Vec<u8>)>> {
+        let end = std::time::Instant::now() + timeout;
+        loop {
+            let queued = lock(&self.queue)?.pop_front();
+            if let Some(bytes) = queued {
+                if let Some(frame) = self.decode(&bytes) {
+                    return Ok(Some(frame));
+                }
+                continue;
+            }
+            let left = end.saturating_duration_since(std::time::Instant::now());
+            let mut shared = lock(&self.shared)?;
+            shared.port.set_timeout(left.min(KISS_READ_SLICE))?;
+            let mut buf = [0u8; 1];
+            let buf = match shared.port.read(&mut buf) {
+                Ok(n) => &buf[..n],
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut && !left.is_zero() => {
+                    continue;
+                }
+                Err(e) => {
+                    if false {
+                        debug!("TODO: Read error: {e}, assuming timeout");
+                    }
+                    break;
+                }
+            };
+            //debug!("Got {} bytes from serial", buf.len());
+            shared.buf.extend(buf);
+            shared.distribute();
+        }
+        Ok(None)
+    }
+
+    /// Decode a received frame, returning the TNC port and AX.25 frame.
+    ///
+    /// Returns None for non-data frames, and for frames that are dropped.
+    fn decode(&mut self, bytes: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let bytes = match unescape(bytes) {
+            Ok(b) => b,
+            Err(e) => {
+                debug!("Dropping frame: {e}");
+                self.dropped_frames += 1;
+                return None;
+            }
+        };
+        if bytes[0] & 0x0F != KISS_CMD_DATA {
+            let cmd = KissCommand {
+                port: bytes[0] >> 4,
+                command: bytes[0] & 0x0F,
+                data: bytes[1..].to_vec(),
+            };
+            debug!("Got KISS command frame {cmd:?}");
+            if self.commands.len() >= MAX_KISS_COMMANDS {
+                self.commands.pop_front();
+            }
+            self.commands.push_back(cmd);
+            return None;
+        }
+        let (port, bytes) = match strip_kiss_byte(&bytes) {
+            Ok((port, b)) => (port, b.to_vec()),
+            Err(e) => {
+                debug!("Dropping frame: {e}");
+                self.dropped_frames += 1;
+                return None;
+            }
+        };
+        let bytes = if self.fcs {
+            match strip_fcs(&bytes) {
+                Ok(b) => b.to_vec(),
+                Err(e) => {
+                    debug!("Dropping frame: {e}");
+                    self.dropped_frames += 1;
+                    return None;
+                }
+            }
+        } else {
+            bytes
+        };
+        if bytes.len() <= 14 {
+            debug!("Dropping short frame: {bytes:?}");
+            self.dropped_frames += 1;
+            return None;
+        }
+        debug!("Found frame: {bytes:?}");
+        match Packet::parse(&bytes, None) {
+            Ok(packet) => debug!("... Decoded as: {:?}", packet),
+            Err(e) => {
+                debug!("... Failed to decode, dropping: {:?}", e);
+                self.dropped_frames += 1;
+                return None;
+            }
+        }
+        Some((port, bytes))
+    }
 }
 
+/// Lock a mutex, turning poisoning into an error.
+#[cfg(feature = "kiss")]
+fn lock<T>(m: &Mutex<T>) -> Result<std::sync::MutexGuard<'_, T>> {
+    m.lock()
+        .map_err(|_| Error::msg("lock poisoned by a panicking thread"))
+}
+
+#[cfg(all(feature = "bus", feature = "kiss"))]
 static BUSKISS_ID: AtomicUsize = AtomicUsize::new(1);
 
 /// Send data between bus and KISS interface.
+#[cfg(all(feature = "bus", feature = "kiss"))]
 pub struct BusKiss {
     rx: bus::BusReader<BusMessage>,
     bus: Arc<Mutex<bus::Bus<BusMessage>>>,
     kiss: Kiss,
     id: usize,
+    full_policy: BusFullPolicy,
 }
+#[cfg(all(feature = "bus", feature = "kiss"))]
 impl BusKiss {
     pub fn new(port: &str, bus: Arc<Mutex<bus::Bus<BusMessage>>>) -> Result<Self> {
+        Self::with_config(port, &KissConfig::default(), bus)
+    }
+
+    /// Like `new()`, but with the given serial settings.
+    pub fn with_config(
+        port: &str,
+        config: &KissConfig,
+        bus: Arc<Mutex<bus::Bus<BusMessage>>>,
+    ) -> Result<Self> {
         let rx = {
             let bus = bus.lock();
             bus.unwrap().add_rx()
         };
         Ok(Self {
             id: BUSKISS_ID.fetch_add(1, Ordering::SeqCst),
-            kiss: Kiss::new(port)?,
+            kiss: Kiss::with_config(port, config)?,
             rx,
             bus,
+            full_policy: BusFullPolicy::default(),
         })
     }
+
+    /// Set what to do when a slow reader has filled up the bus.
+    ///
+    /// Either way, a full bus only drops the frame, and doesn't stop the
+    /// bridge.
+    #[must_use]
+    pub fn full_policy(mut self, v: BusFullPolicy) -> Self {
+        self.full_policy = v;
+        self
+    }
+
     pub fn run(&mut self) {
         loop {
             let d = std::time::Duration::from_millis(10);
@@ -805,35 +2130,113 @@ impl BusKiss {
                 }
             }
             if let Ok(Some(rx)) = self.kiss.recv_timeout(d) {
-                self.bus
-                    .lock()
-                    .unwrap()
-                    .try_broadcast(BusMessage {
+                if let Err(e) = broadcast(
+                    &self.bus,
+                    self.full_policy,
+                    BusMessage {
                         sender: self.id,
                         data: rx,
-                    })
-                    .map_err(|_| Error::msg("queue full"))
-                    .expect("failed to broadcast");
+                    },
+                ) {
+                    log::warn!("Dropping frame from KISS port: {e}");
+                }
             }
         }
     }
 }
 
+/// Highest KISS TNC port number.
+pub const MAX_KISS_PORT: u8 = 15;
+
+/// KISS commands, in the low nibble of the command byte.
+pub const KISS_CMD_DATA: u8 = 0;
+pub const KISS_CMD_TXDELAY: u8 = 1;
+pub const KISS_CMD_PERSISTENCE: u8 = 2;
+pub const KISS_CMD_SLOTTIME: u8 = 3;
+pub const KISS_CMD_TXTAIL: u8 = 4;
+pub const KISS_CMD_FULLDUPLEX: u8 = 5;
+pub const KISS_CMD_SETHARDWARE: u8 = 6;
+
+/// Max received command frames kept for `Kiss::pop_command()`.
+#[cfg(feature = "kiss")]
+const MAX_KISS_COMMANDS: usize = 100;
+
+/// A non-data KISS frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KissCommand {
+    /// TNC port.
+    pub port: u8,
+
+    /// Command, e.g. `KISS_CMD_SETHARDWARE`.
+    pub command: u8,
+
+    /// Command parameters, unescaped.
+    pub data: Vec<u8>,
+}
+
 const KISS_FEND: u8 = 0xC0;
 const KISS_FESC: u8 = 0xDB;
 const KISS_TFEND: u8 = 0xDC;
 const KISS_TFESC: u8 = 0xDD;
 
-/// Escape KISS data stream.
+/// Split off the KISS command/port byte from the front of a frame.
+///
+/// Returns the port, and the AX.25 frame. Only data frames (command 0) are
+/// accepted, since other commands (TXDELAY etc) don't carry AX.25 frames.
+pub fn strip_kiss_byte(bytes: &[u8]) -> Result<(u8, &[u8])> {
+    let Some((&cmd, rest)) = bytes.split_first() else {
+        return Err(Error::msg("empty KISS frame"));
+    };
+    if cmd & 0x0F != KISS_CMD_DATA {
+        return Err(Error::msg(format!(
+            "not a KISS data frame: command byte {cmd:#04x}"
+        )));
+    }
+    Ok((cmd >> 4, rest))
+}
+
+/// Verify and split off the FCS from the end of a frame.
+///
+/// KISS TNCs normally check and strip the FCS, so this is only needed for raw
+/// TNCs that pass it along.
+pub fn strip_fcs(bytes: &[u8]) -> Result<&[u8]> {
+    let Some(split) = bytes.len().checked_sub(FCS_LEN) else {
+        return Err(Error::msg(format!(
+            "frame too short for FCS: {} bytes",
+            bytes.len()
+        )));
+    };
+    let (frame, got) = bytes.split_at(split);
+    let want = fcs::fcs(frame);
+    if got != want {
+        return Err(Error::msg(format!(
+            "bad FCS: got {got:02x?}, want {want:02x?}"
+        )));
+    }
+    Ok(frame)
+}
+
+/// Escape KISS data stream, as a data frame for TNC port `port` (0-15).
 ///
 /// https://en.wikipedia.org/wiki/KISS_(amateur_radio_protocol)
+#[cfg(feature = "kiss")]
+#[must_use]
+pub(crate) fn escape(bytes: &[u8], port: u8) -> Vec<u8> {
+    kiss_frame(port, KISS_CMD_DATA, bytes)
+}
+
+/// Create an escaped KISS frame with the given port and command.
+#[cfg(feature = "kiss")]
 #[must_use]
-pub(crate) fn escape(bytes: &[u8]) -> Vec<u8> {
+fn kiss_frame(port: u8, command: u8, bytes: &[u8]) -> Vec<u8> {
+    debug_assert!(port <= MAX_KISS_PORT, "invalid KISS port {port}");
+    debug_assert!(command <= 0x0F, "invalid KISS command {command}");
     // Add 10% capacity to leave room for escaped
     let mut ret = Vec::with_capacity((3 + bytes.len()) * 110 / 100);
     ret.push(KISS_FEND);
-    ret.push(0); // TODO: port
-    for &b in bytes {
+    // High nibble is the port, low nibble the command. Port 12 makes it a
+    // FEND, so it's escaped too.
+    for &b in std::iter::once(&(port << 4 | command)).chain(bytes) {
         match b {
             KISS_FEND => ret.extend(vec![KISS_FESC, KISS_TFEND]),
             KISS_FESC => ret.extend(vec![KISS_FESC, KISS_TFESC]),
@@ -867,10 +2270,10 @@ pub(crate) fn find_frame(vec: &std::collections::VecDeque<u8>) -> Option<(usize,
     None // Return None if no valid subrange is found
 }
 
-/// Unescape KISS data stream.
+/// Unescape KISS data stream, returning error on invalid escapes.
+///
 /// https://en.wikipedia.org/wiki/KISS_(amateur_radio_protocol)
-#[must_use]
-pub(crate) fn unescape(data: &[u8]) -> Vec<u8> {
+pub(crate) fn unescape(data: &[u8]) -> Result<Vec<u8>> {
     let mut unescaped = Vec::with_capacity(data.len());
     let mut is_escaped = false;
     for &byte in data {
@@ -878,7 +2281,9 @@ pub(crate) fn unescape(data: &[u8]) -> Vec<u8> {
             unescaped.push(match byte {
                 KISS_TFESC => KISS_FESC,
                 KISS_TFEND => KISS_FEND,
-                other => panic!("TODO: kiss unescape error: escaped {other}"),
+                other => {
+                    return Err(Error::msg(format!("invalid KISS escape of {other:#04x}")));
+                }
             });
             is_escaped = false;
         } else if byte == KISS_FESC {
@@ -889,68 +2294,50 @@ pub(crate) fn unescape(data: &[u8]) -> Vec<u8> {
             unescaped.push(byte);
         }
     }
-    assert!(!is_escaped);
-    unescaped
+    if is_escaped {
+        return Err(Error::msg("KISS frame ends in escape"));
+    }
+    Ok(unescaped)
+}
+
+/// Decode all KISS frames in a buffer, e.g. read from a file.
+///
+/// Every frame is decoded on its own, so malformed frames don't stop the rest
+/// from being decoded. Empty frames (back to back FENDs) are skipped, as is
+/// anything before the first and after the last FEND.
+#[must_use]
+pub fn decode_all(bytes: &[u8]) -> Vec<Result<Packet>> {
+    let mut buf: std::collections::VecDeque<u8> = bytes.iter().copied().collect();
+    let mut ret = Vec::new();
+    while let Some((a, b)) = find_frame(&buf) {
+        let frame: Vec<u8> = buf.range(a + 1..b).copied().collect();
+        buf.drain(..b);
+        if frame.is_empty() {
+            continue;
+        }
+        ret.push(unescape(&frame).and_then(|frame| {
+            let (_port, frame) = strip_kiss_byte(&frame)?;
+            Packet::parse(frame, None)
+        }));
+    }
+    ret
 }
 
+#[cfg(feature = "kiss")]
 impl Hub for Kiss {
     fn clone(&self) -> Box<dyn Hub> {
-        todo!()
+        // Can only fail if another handle panicked while holding the lock.
+        let kiss = Kiss::new_handle(self.shared.clone(), self.fcs, self.kiss_port)
+            .expect("KISS port lock poisoned");
+        Box::new(kiss)
     }
     fn send(&mut self, frame: &[u8]) -> Result<()> {
         let parsed = Packet::parse(frame, None)?;
         debug!("Sending frame… {frame:?}: {parsed:?}");
-        self.port.write_all(&escape(frame))?;
-        self.port.flush()?;
-        Ok(())
+        self.write(&escape(frame, self.kiss_port))
     }
     fn recv_timeout(&mut self, timeout: std::time::Duration) -> Result<Option<Vec<u8>>> {
-        let end = std::time::Instant::now() + timeout;
-        loop {
-            self.port
-                .set_timeout(end.saturating_duration_since(std::time::Instant::now()))?;
-            let mut buf = [0u8; 1];
-            let buf = match self.port.read(&mut buf) {
-                Ok(n) => &buf[..n],
-                Err(e) => {
-                    if false {
-                        debug!("TODO: Read error: {e}, assuming timeout");
-                    }
-                    break;
-                }
-            };
-            //debug!("Got {} bytes from serial", buf.len());
-            self.buf.extend(buf);
-            while let Some((a, b)) = find_frame(&self.buf) {
-                if b - a < 14 {
-                    debug!("short packet {a} {b}");
-                    self.buf.drain(..(a + 1));
-                    continue;
-                }
-                let bytes: Vec<_> = self
-                    .buf
-                    .iter()
-                    .skip(a + 2)
-                    .take(b - a - 2)
-                    .cloned()
-                    .collect();
-                self.buf.drain(..b);
-                debug!("After drain: {:?}", self.buf);
-                let bytes = unescape(&bytes);
-                if bytes.len() > 14 {
-                    debug!("Found from (not yet unescaped) from {a} to {b}: {bytes:?}");
-                    match Packet::parse(&bytes, None) {
-                        Ok(packet) => debug!("... Decoded as: {:?}", packet),
-                        Err(e) => {
-                            debug!("... Failed to decode: {:?}", e);
-                            panic!();
-                        }
-                    }
-                    return Ok(Some(bytes.to_vec()));
-                }
-            }
-        }
-        Ok(None)
+        Ok(self.recv_port_timeout(timeout)?.map(|(_, frame)| frame))
     }
 }
 
@@ -959,11 +2346,818 @@ mod tests {
     use super::*;
 
     #[test]
-    fn addr_serial() -> Result<()> {
-        // TODO: test invalid calls.
-        let a = Addr::new("M0THC")?.serialize(true, false, false, false);
-        assert_eq!(a, vec![154, 96, 168, 144, 134, 64, 97]);
-        assert_eq!(Addr::parse(&a)?.call(), "M0THC");
+    fn airtime() -> Result<()> {
+        let no_delay = std::time::Duration::ZERO;
+        // 14 address + 1 control + 1 PID + 256 payload + 2 FCS + 2 flags + 3
+        // KISS, is 279 bytes, or 2232 bits.
+        assert_eq!(
+            super::airtime(256, false, 1200, no_delay)?,
+            std::time::Duration::from_millis(1860)
+        );
+        assert_eq!(
+            super::airtime(256, false, 9600, no_delay)?,
+            std::time::Duration::from_micros(232_500)
+        );
+        assert_eq!(
+            super::airtime(256, false, 1200, DEFAULT_TXDELAY)?,
+            std::time::Duration::from_millis(500 + 1860)
+        );
+        // Extended mode has a two byte control field.
+        assert_eq!(
+            super::airtime(256, true, 9600, no_delay)?,
+            std::time::Duration::from_nanos(233_333_333)
+        );
+        assert!(super::airtime(256, false, 0, no_delay).is_err());
+
+        // Digipeaters and TX delay.
+        let p = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![Addr::new("WIDE1-1")?, Addr::new("WIDE2-1")?],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type: PacketType::Iframe(Iframe {
+                nr: 0,
+                ns: 0,
+                poll: false,
+                pid: NO_L3,
+                payload: vec![0; 256],
+            }),
+        };
+        // 293 bytes including flags and KISS, or 2344 bits.
+        assert_eq!(p.wire_len(false), 274 + 14);
+        assert_eq!(
+            p.airtime(false, 1200, DEFAULT_TXDELAY)?,
+            std::time::Duration::from_nanos(500_000_000 + 1_953_333_333)
+        );
+        assert!(p.airtime(false, 0, DEFAULT_TXDELAY).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn addr_from_parts() -> Result<()> {
+        assert_eq!(Addr::from_parts("m0thc", 1)?, Addr::new("M0THC-1")?);
+        assert_eq!(Addr::from_parts("M0THC", 0)?, Addr::new("M0THC")?);
+        assert_eq!(Addr::from_parts("ID", 15)?, Addr::new_dst("ID-15")?);
+        assert!(Addr::from_parts("M0THC", 16).is_err());
+        assert!(Addr::from_parts("", 0).is_err());
+        assert!(Addr::from_parts("M0THC12", 0).is_err());
+        assert!(Addr::from_parts("M0-HC", 0).is_err());
+        assert!(Addr::from_parts("M0THÖ", 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_digipeaters() -> Result<()> {
+        let frame = |digis: usize| -> Result<Vec<u8>> {
+            let mut f = Addr::new("APRS")?.serialize(false, true, false, false);
+            f.extend(Addr::new("M0THC-1")?.serialize(digis == 0, false, false, false));
+            for n in 0..digis {
+                // First one has been repeated.
+                f.extend(Addr::new(&format!("WIDE{n}-1"))?.serialize(
+                    n == digis - 1,
+                    n == 0,
+                    false,
+                    false,
+                ));
+            }
+            f.extend([CONTROL_UI, NO_L3]);
+            f.extend(b"hello");
+            Ok(f)
+        };
+        for digis in 0..=MAX_DIGIPEATERS {
+            let p = Packet::parse(&frame(digis)?, Some(false))?;
+            assert_eq!(p.src.call(), "M0THC-1");
+            assert_eq!(p.digipeater.len(), digis);
+            for (n, d) in p.digipeater.iter().enumerate() {
+                assert_eq!(d.call(), format!("WIDE{n}-1"));
+                assert_eq!(d.highbit, n == 0, "H bit of digipeater {n}");
+            }
+            assert_eq!(
+                p.packet_type,
+                PacketType::Ui(Ui {
+                    push: false,
+                    pid: NO_L3,
+                    payload: b"hello".to_vec(),
+                })
+            );
+        }
+
+        // Too many.
+        let err = Packet::parse(&frame(MAX_DIGIPEATERS + 1)?, Some(false)).unwrap_err();
+        assert!(err.to_string().contains("more than 8"), "{err}");
+
+        // Address list never ends.
+        let mut f = frame(2)?;
+        f.truncate(14 + 7 + 3);
+        f[20] &= !1;
+        assert!(Packet::parse(&f, Some(false)).is_err());
+
+        // Control field missing.
+        f.truncate(14 + 7);
+        f[20] |= 1;
+        let err = Packet::parse(&f, Some(false)).unwrap_err();
+        assert!(err.to_string().contains("no control field"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn serialize_digipeaters() -> Result<()> {
+        let mut done = Addr::new("M0THC-5")?;
+        done.set_repeated(true);
+        let p = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            command_response: true,
+            command_response_la: false,
+            digipeater: vec![done, Addr::new("WIDE2-1")?],
+            rr_dist1: false,
+            rr_extseq: false,
+            packet_type: PacketType::Sabm(Sabm { poll: true }),
+        };
+        let frame = p.serialize(false);
+        assert_eq!(frame.len(), p.wire_len(false) - FCS_LEN);
+
+        // Only the last address has the extension bit set.
+        let lowbits: Vec<_> = frame[..28].chunks(7).map(|a| a[6] & 1).collect();
+        assert_eq!(lowbits, [0, 0, 0, 1]);
+
+        let got = Packet::parse(&frame, Some(false))?;
+        assert_eq!(got.digipeater, p.digipeater);
+        assert!(got.digipeater[0].repeated());
+        assert!(!got.digipeater[1].repeated());
+        assert_eq!(got.packet_type, p.packet_type);
+        Ok(())
+    }
+
+    #[test]
+    fn display() -> Result<()> {
+        let mut p = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type: PacketType::Iframe(Iframe {
+                nr: 3,
+                ns: 5,
+                poll: true,
+                pid: 0xF0,
+                payload: vec![0; 120],
+            }),
+        };
+        assert_eq!(
+            p.to_string(),
+            "M0THC-1 > M0THC-2 I C P N(R)=3 N(S)=5 pid=F0 len=120"
+        );
+
+        let mut digi = Addr::new("DIGI")?;
+        digi.set_repeated(true);
+        p.digipeater = vec![digi, Addr::new("WIDE2-1")?];
+        p.command_response = false;
+        p.command_response_la = true;
+        p.packet_type = PacketType::Rr(Rr { poll: true, nr: 2 });
+        assert_eq!(
+            p.to_string(),
+            "M0THC-1 > M0THC-2 via DIGI*,WIDE2-1 S RR R F N(R)=2"
+        );
+
+        p.digipeater = vec![];
+        p.packet_type = PacketType::Rej(Rej { poll: false, nr: 7 });
+        assert_eq!(p.to_string(), "M0THC-1 > M0THC-2 S REJ R N(R)=7");
+
+        // Version 1, neither command nor response.
+        p.command_response = false;
+        p.command_response_la = false;
+        p.packet_type = PacketType::Sabm(Sabm { poll: true });
+        assert_eq!(p.to_string(), "M0THC-1 > M0THC-2 U SABM P/F");
+
+        p.command_response = true;
+        p.packet_type = PacketType::Ui(Ui {
+            push: false,
+            pid: NO_L3,
+            payload: b"hello".to_vec(),
+        });
+        assert_eq!(p.to_string(), "M0THC-1 > M0THC-2 U UI C pid=F0 len=5");
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_frames() -> Result<()> {
+        let p = |packet_type| Packet {
+            src: Addr::new("M0THC-1").unwrap(),
+            dst: Addr::new("M0THC-2").unwrap(),
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type,
+        };
+        let reason = |frame: &[u8], ext| match Packet::parse(frame, Some(ext)).unwrap().packet_type
+        {
+            PacketType::Invalid(i) => Some(i.reason()),
+            _ => None,
+        };
+
+        // RR with extra bytes.
+        for ext in [false, true] {
+            let mut frame = p(PacketType::Rr(Rr { poll: false, nr: 1 })).serialize(ext);
+            assert_eq!(reason(&frame, ext), None);
+            frame.push(0x42);
+            assert_eq!(reason(&frame, ext), Some(InvalidReason::Length));
+            // Still repeatable as is.
+            assert_eq!(Packet::parse(&frame, Some(ext))?.serialize(ext), frame);
+        }
+
+        // SABM with info.
+        let mut frame = p(PacketType::Sabm(Sabm { poll: true })).serialize(false);
+        frame.push(0x42);
+        assert_eq!(reason(&frame, false), Some(InvalidReason::InfoNotPermitted));
+
+        // I frame without PID.
+        let mut frame = p(PacketType::Iframe(Iframe {
+            nr: 0,
+            ns: 0,
+            poll: false,
+            pid: NO_L3,
+            payload: vec![],
+        }))
+        .serialize(false);
+        assert_eq!(reason(&frame, false), None);
+        frame.pop();
+        assert_eq!(reason(&frame, false), Some(InvalidReason::Length));
+        Ok(())
+    }
+
+    #[test]
+    fn frmr() -> Result<()> {
+        let mut p = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: false,
+            command_response_la: true,
+            rr_dist1: false,
+            packet_type: PacketType::Frmr(Frmr::new(true, 0x03, 3, 5, true, FRMR_W | FRMR_X)),
+        };
+        let frame = p.serialize(false);
+        assert_eq!(frame.len(), p.wire_len(false) - FCS_LEN);
+        assert_eq!(&frame[14..], &[0x97, 0x03, 0xB6, 0x03][..]);
+        let got = Packet::parse(&frame, Some(false))?;
+        assert_eq!(got.packet_type, p.packet_type);
+        let PacketType::Frmr(frmr) = got.packet_type else {
+            panic!("not FRMR");
+        };
+        assert_eq!(frmr.rejected_control(), 0x03);
+        assert_eq!(frmr.vs(), 3);
+        assert_eq!(frmr.vr(), 5);
+        assert!(frmr.rejected_response());
+        assert_eq!(frmr.reason(), FRMR_W | FRMR_X);
+
+        // Unknown control field is kept, not a parse error.
+        let mut unknown = frame.clone();
+        unknown[14] = 0xFF;
+        let got = Packet::parse(&unknown, Some(false))?;
+        let PacketType::Invalid(u) = &got.packet_type else {
+            panic!("not invalid: {got:?}");
+        };
+        assert_eq!(u.control(), 0xFF);
+        assert_eq!(u.reason(), InvalidReason::Control);
+        assert_eq!(got.serialize(false), unknown);
+
+        // Missing info field.
+        p.packet_type = PacketType::Frmr(Frmr::default());
+        let mut frame = p.serialize(false);
+        frame.truncate(15);
+        assert_eq!(
+            Packet::parse(&frame, Some(false))?.packet_type,
+            p.packet_type
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn xid() -> Result<()> {
+        let mut p = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type: PacketType::Xid(Xid::new(true, true, true, 256, 32)),
+        };
+        let frame = p.serialize(false);
+        assert_eq!(frame.len(), p.wire_len(false) - FCS_LEN);
+        assert_eq!(
+            &frame[15..],
+            &[
+                0x82, 0x80, 0x00, 0x10, // Header.
+                0x02, 0x02, 0x21, 0x00, // Balanced ABM, half duplex.
+                0x03, 0x03, 0x84, 0x28, 0x82, // SREJ, mod 128, etc.
+                0x06, 0x02, 0x08, 0x00, // 2048 bits.
+                0x08, 0x01, 0x20, // k=32.
+            ][..]
+        );
+        let got = Packet::parse(&frame, Some(false))?;
+        assert_eq!(got.packet_type, p.packet_type);
+        let PacketType::Xid(xid) = got.packet_type else {
+            panic!("not XID");
+        };
+        assert_eq!(xid.half_duplex(), Some(true));
+        assert_eq!(xid.extended(), Some(true));
+        assert_eq!(xid.srej(), Some(true));
+        assert_eq!(xid.mtu(), Some(256));
+        assert_eq!(xid.window_size(), Some(32));
+        assert_eq!(xid.retries(), None);
+
+        // Empty.
+        p.packet_type = PacketType::Xid(Xid::default());
+        let frame = p.serialize(false);
+        assert_eq!(frame.len(), 15);
+        assert_eq!(
+            Packet::parse(&frame, Some(false))?.packet_type,
+            p.packet_type
+        );
+
+        // Unknown parameter ignored, and retries in between.
+        let mut frame = frame;
+        frame.extend([
+            0x82, 0x80, 0x00, 0x07, 0x42, 0x02, 0x12, 0x34, 0x0A, 0x01, 0x05,
+        ]);
+        let PacketType::Xid(xid) = Packet::parse(&frame, Some(false))?.packet_type else {
+            panic!("not XID");
+        };
+        assert_eq!(xid.retries(), Some(5));
+        assert_eq!(xid.mtu(), None);
+
+        // Truncated.
+        frame.pop();
+        assert!(Packet::parse(&frame, Some(false)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn ui_round_trip() -> Result<()> {
+        let ui = Ui {
+            payload: b"!5132.07N/00007.50W-".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(ui.pid, 0xF0);
+        let p = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("APRS")?,
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type: PacketType::Ui(ui.clone()),
+        };
+        let frame = p.serialize(false);
+        assert_eq!(&frame[14..16], &[CONTROL_UI, 0xF0]);
+        let got = Packet::parse(&frame, Some(false))?;
+        assert_eq!(got.packet_type, PacketType::Ui(ui));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_forced_mode() -> Result<()> {
+        let p = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![],
+            rr_extseq: true,
+            command_response: false,
+            command_response_la: true,
+            rr_dist1: false,
+            packet_type: PacketType::Rr(Rr {
+                poll: true,
+                nr: 100,
+            }),
+        };
+        let mut frame = p.serialize(true);
+        // A TNC that doesn't set the nonstandard extended mode bit.
+        frame[13] |= 0b0100_0000;
+
+        let got = Packet::parse(&frame, Some(true))?;
+        assert_eq!(got.packet_type, p.packet_type);
+        assert!(got.rr_extseq);
+
+        // Guessed wrong, as mod 8.
+        let got = Packet::parse(&frame, None)?;
+        assert_ne!(got.packet_type, p.packet_type);
+        assert!(!got.rr_extseq);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_fcs() -> Result<()> {
+        // CRC-16/X.25 check value, sent low byte first.
+        assert_eq!(fcs::fcs(b"123456789"), [0x6E, 0x90]);
+
+        let p = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type: PacketType::Ui(Ui {
+                push: false,
+                pid: NO_L3,
+                payload: b"hello".to_vec(),
+            }),
+        };
+        let mut frame = p.serialize(false);
+        frame.extend(fcs::fcs(&frame));
+        let got = Packet::parse_fcs(&frame, Some(false))?;
+        assert_eq!(got.packet_type, p.packet_type);
+
+        *frame.last_mut().unwrap() ^= 1;
+        let err = Packet::parse_fcs(&frame, Some(false)).unwrap_err();
+        assert!(err.to_string().contains("bad FCS"), "{err}");
+        assert!(strip_fcs(&[1]).is_err());
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "kiss"))]
+    #[test]
+    fn kiss_config() -> Result<()> {
+        assert_eq!(KissConfig::default().baud, 9600);
+        use serialport::SerialPort;
+        let (mut tnc, pts) = serialport::TTYPort::pair()?;
+        let name = pts.name().expect("pty has no name");
+        let mut kiss = Kiss::with_config(&name, &KissConfig::new(115200))?;
+        assert_eq!(lock(&kiss.shared)?.port.baud_rate()?, 115200);
+
+        let frame = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type: PacketType::Sabm(Sabm { poll: true }),
+        }
+        .serialize(false);
+        tnc.write_all(&escape(&frame, 0))?;
+        let got = kiss.recv_timeout(std::time::Duration::from_secs(5))?;
+        assert_eq!(got, Some(frame));
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "kiss"))]
+    #[test]
+    fn kiss_port() -> Result<()> {
+        use serialport::SerialPort;
+        let frame = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type: PacketType::Sabm(Sabm { poll: true }),
+        }
+        .serialize(false);
+        assert_eq!(escape(&frame, 2)[..2], [KISS_FEND, 0x20]);
+        // Port 12 makes the command byte a FEND.
+        assert_eq!(escape(&frame, 12)[..3], [KISS_FEND, KISS_FESC, KISS_TFEND]);
+
+        let (mut tnc, pts) = serialport::TTYPort::pair()?;
+        let name = pts.name().expect("pty has no name");
+        assert!(Kiss::new(&name)?.kiss_port(16).is_err());
+        let mut kiss = Kiss::new(&name)?.kiss_port(2)?;
+        kiss.send(&frame)?;
+        let want = escape(&frame, 2);
+        let mut got = vec![0; want.len()];
+        tnc.set_timeout(std::time::Duration::from_secs(5))?;
+        tnc.read_exact(&mut got)?;
+        assert_eq!(got, want);
+
+        // Non-data frames are dropped, and the port is reported.
+        let mut txdelay = vec![KISS_FEND, 0x21];
+        txdelay.extend(&frame);
+        txdelay.push(KISS_FEND);
+        tnc.write_all(&txdelay)?;
+        tnc.write_all(&escape(&frame, 12))?;
+        let got = kiss.recv_port_timeout(std::time::Duration::from_secs(5))?;
+        assert_eq!(got, Some((12, frame)));
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "kiss"))]
+    #[test]
+    fn kiss_commands() -> Result<()> {
+        use serialport::SerialPort;
+        let (mut tnc, pts) = serialport::TTYPort::pair()?;
+        tnc.set_timeout(std::time::Duration::from_secs(5))?;
+        let name = pts.name().expect("pty has no name");
+        let mut kiss = Kiss::new(&name)?;
+        let mut got = [0; 4];
+        kiss.set_txdelay(50)?;
+        tnc.read_exact(&mut got)?;
+        assert_eq!(got, [0xC0, 0x01, 0x32, 0xC0]);
+        kiss.set_fullduplex(true)?;
+        tnc.read_exact(&mut got)?;
+        assert_eq!(got, [0xC0, 0x05, 0x01, 0xC0]);
+        let mut kiss = kiss.kiss_port(1)?;
+        kiss.set_persistence(63)?;
+        tnc.read_exact(&mut got)?;
+        assert_eq!(got, [0xC0, 0x12, 0x3F, 0xC0]);
+
+        // Command frames are not parsed as AX.25.
+        let frame = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type: PacketType::Sabm(Sabm { poll: true }),
+        }
+        .serialize(false);
+        tnc.write_all(&kiss_frame(0, KISS_CMD_SETHARDWARE, &[0xC0, 1]))?;
+        tnc.write_all(&escape(&frame, 0))?;
+        assert_eq!(
+            kiss.recv_timeout(std::time::Duration::from_secs(5))?,
+            Some(frame)
+        );
+        assert_eq!(
+            kiss.pop_command(),
+            Some(KissCommand {
+                port: 0,
+                command: KISS_CMD_SETHARDWARE,
+                data: vec![0xC0, 1],
+            })
+        );
+        assert_eq!(kiss.pop_command(), None);
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "kiss"))]
+    #[test]
+    fn kiss_junk() -> Result<()> {
+        let (mut tnc, pts) = serialport::TTYPort::pair()?;
+        let name = {
+            use serialport::SerialPort;
+            pts.name().expect("pty has no name")
+        };
+        let mut kiss = Kiss::new(&name)?;
+        let frame = |payload: &[u8]| -> Result<Vec<u8>> {
+            Ok(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                digipeater: vec![],
+                rr_extseq: false,
+                command_response: true,
+                command_response_la: false,
+                rr_dist1: false,
+                packet_type: PacketType::Ui(Ui {
+                    push: false,
+                    pid: NO_L3,
+                    payload: payload.to_vec(),
+                }),
+            }
+            .serialize(false))
+        };
+        // Not valid callsign characters, so fails to parse.
+        let junk = [0x01; 20];
+        tnc.write_all(&escape(&frame(b"one")?, 0))?;
+        tnc.write_all(&escape(&junk, 0))?;
+        tnc.write_all(&escape(&frame(b"two")?, 0))?;
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(kiss.recv_timeout(timeout)?, Some(frame(b"one")?));
+        assert_eq!(kiss.recv_timeout(timeout)?, Some(frame(b"two")?));
+        assert_eq!(kiss.dropped_frames(), 1);
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "kiss"))]
+    #[test]
+    fn kiss_clone() -> Result<()> {
+        use serialport::SerialPort;
+        let (mut tnc, pts) = serialport::TTYPort::pair()?;
+        tnc.set_timeout(std::time::Duration::from_secs(5))?;
+        let name = pts.name().expect("pty has no name");
+        let mut a = Kiss::new(&name)?;
+        let mut b = Hub::clone(&a);
+        let frame = |payload: &[u8]| -> Result<Vec<u8>> {
+            Ok(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                digipeater: vec![],
+                rr_extseq: false,
+                command_response: true,
+                command_response_la: false,
+                rr_dist1: false,
+                packet_type: PacketType::Ui(Ui {
+                    push: false,
+                    pid: NO_L3,
+                    payload: payload.to_vec(),
+                }),
+            }
+            .serialize(false))
+        };
+        let timeout = std::time::Duration::from_secs(5);
+
+        // Both handles get all frames.
+        tnc.write_all(&escape(&frame(b"one")?, 0))?;
+        tnc.write_all(&escape(&frame(b"two")?, 0))?;
+        assert_eq!(a.recv_timeout(timeout)?, Some(frame(b"one")?));
+        assert_eq!(b.recv_timeout(timeout)?, Some(frame(b"one")?));
+        assert_eq!(b.recv_timeout(timeout)?, Some(frame(b"two")?));
+        assert_eq!(a.recv_timeout(timeout)?, Some(frame(b"two")?));
+
+        // A dropped handle doesn't keep getting frames.
+        drop(b);
+        tnc.write_all(&escape(&frame(b"three")?, 0))?;
+        assert_eq!(a.recv_timeout(timeout)?, Some(frame(b"three")?));
+        assert_eq!(lock(&a.shared)?.queues.len(), 1);
+
+        // Both can send.
+        let mut b = Hub::clone(&a);
+        let mut got = vec![0; escape(&frame(b"four")?, 0).len()];
+        a.send(&frame(b"four")?)?;
+        tnc.read_exact(&mut got)?;
+        assert_eq!(got, escape(&frame(b"four")?, 0));
+        b.send(&frame(b"five")?)?;
+        tnc.read_exact(&mut got)?;
+        assert_eq!(got, escape(&frame(b"five")?, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_kiss() -> Result<()> {
+        let p = Packet {
+            src: Addr::new("M0THC-1")?,
+            dst: Addr::new("M0THC-2")?,
+            digipeater: vec![],
+            rr_extseq: false,
+            command_response: true,
+            command_response_la: false,
+            rr_dist1: false,
+            packet_type: PacketType::Ui(Ui {
+                push: false,
+                pid: NO_L3,
+                payload: b"hello".to_vec(),
+            }),
+        };
+        let frame = p.serialize(false);
+        let mut kiss = vec![0x00];
+        kiss.extend(&frame);
+        let got = Packet::parse_kiss(&kiss, Some(false))?;
+        assert_eq!(got.src.call(), "M0THC-1");
+        assert_eq!(got.dst.call(), "M0THC-2");
+        assert_eq!(got.packet_type, p.packet_type);
+        assert_eq!(strip_kiss_byte(&kiss)?, (0, &frame[..]));
+
+        // Port 3.
+        kiss[0] = 0x30;
+        assert_eq!(strip_kiss_byte(&kiss)?, (3, &frame[..]));
+
+        // Not data.
+        kiss[0] = 0x01;
+        assert!(Packet::parse_kiss(&kiss, Some(false)).is_err());
+        assert!(strip_kiss_byte(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unescape_invalid() -> Result<()> {
+        assert_eq!(
+            unescape(&[1, KISS_FESC, KISS_TFEND, KISS_FESC, KISS_TFESC])?,
+            [1, KISS_FEND, KISS_FESC]
+        );
+        assert!(unescape(&[KISS_FESC, 0x00]).is_err());
+        assert!(unescape(&[1, KISS_FESC]).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "kiss")]
+    #[test]
+    fn decode_all() -> Result<()> {
+        let frame = |payload: &[u8]| {
+            escape(
+                &Packet {
+                    src: Addr::new("M0THC-1").unwrap(),
+                    dst: Addr::new("M0THC-2").unwrap(),
+                    digipeater: vec![],
+                    rr_extseq: false,
+                    command_response: true,
+                    command_response_la: false,
+                    rr_dist1: false,
+                    packet_type: PacketType::Ui(Ui {
+                        push: false,
+                        pid: NO_L3,
+                        payload: payload.to_vec(),
+                    }),
+                }
+                .serialize(false),
+                0,
+            )
+        };
+        let mut buf = Vec::new();
+        buf.extend(frame(b"one"));
+        // Escaped bytes in the payload.
+        buf.extend(frame(&[KISS_FEND, KISS_FESC]));
+        // Too short.
+        buf.extend([KISS_FEND, 0, 1, 2, 3, KISS_FEND]);
+        buf.extend(frame(b"three"));
+        let got = super::decode_all(&buf);
+        assert_eq!(got.len(), 4);
+        let payload = |r: &Result<Packet>| match &r.as_ref().unwrap().packet_type {
+            PacketType::Ui(ui) => ui.payload.clone(),
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(payload(&got[0]), b"one");
+        assert_eq!(payload(&got[1]), [KISS_FEND, KISS_FESC]);
+        assert!(got[2].is_err());
+        assert_eq!(payload(&got[3]), b"three");
+        Ok(())
+    }
+
+    #[test]
+    fn addr_ssid() -> Result<()> {
+        for (call, base, ssid) in [
+            ("M0THC", "M0THC", 0),
+            ("M0THC-0", "M0THC", 0),
+            ("M0THC-15", "M0THC", 15),
+            ("id-1", "ID", 1),
+        ] {
+            let a = Addr::new_dst(call)?;
+            assert_eq!(a.base_call(), base, "{call}");
+            assert_eq!(a.ssid(), ssid, "{call}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn addr_strict() -> Result<()> {
+        for call in [
+            "M0THC",
+            "m0thc-1",
+            "2E0ABC-15",
+            "W1AW",
+            "VK2ABC",
+            "9A1AA",
+            "K1A",
+        ] {
+            assert_eq!(Addr::new_strict(call)?, Addr::new(call)?, "{call}");
+        }
+        for call in ["APRS", "NODE1", "WIDE1-1", "1ABC", "M0THC1"] {
+            assert!(Addr::new(call).is_ok(), "{call}");
+            assert!(Addr::new_strict(call).is_err(), "{call}");
+        }
+        for call in ["ID", "M0", "M0-1"] {
+            assert!(Addr::new(call).is_err(), "{call}");
+            assert!(Addr::new_dst(call).is_ok(), "{call}");
+            assert!(Addr::new_strict(call).is_err(), "{call}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn addr_roundtrip() -> Result<()> {
+        for base in ["M0THC", "A", "ABCDEF", "ID"] {
+            for ssid in 0..=15 {
+                let a = Addr::from_parts(base, ssid)?;
+                for bits in 0..16 {
+                    let (lowbit, highbit, rbit_ext, rbit_dama) =
+                        (bits & 1 != 0, bits & 2 != 0, bits & 4 != 0, bits & 8 != 0);
+                    let got = Addr::parse(&a.serialize(lowbit, highbit, rbit_ext, rbit_dama))?;
+                    let want = Addr {
+                        lowbit,
+                        highbit,
+                        rbit_ext,
+                        rbit_dama,
+                        ..a.clone()
+                    };
+                    assert_eq!(got, want, "{base}-{ssid} bits {bits:04b}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn addr_serial() -> Result<()> {
+        // TODO: test invalid calls.
+        let a = Addr::new("M0THC")?.serialize(true, false, false, false);
+        assert_eq!(a, vec![154, 96, 168, 144, 134, 64, 97]);
+        assert_eq!(Addr::parse(&a)?.call(), "M0THC");
 
         let a = Addr::new("M0THC-0")?.serialize(true, false, false, false);
         assert_eq!(a, vec![154, 96, 168, 144, 134, 64, 97]);
@@ -1021,4 +3215,96 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn serialize_sframes() -> Result<()> {
+        let packet = |packet_type| -> Result<Packet> {
+            Ok(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                command_response: true,
+                command_response_la: false,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type,
+            })
+        };
+        #[rustfmt::skip]
+        let tests = [
+            // Mod-8.
+            (PacketType::Rr(Rr { poll: false, nr: 0 }), false, vec![0x01]),
+            (PacketType::Rr(Rr { poll: true, nr: 5 }), false, vec![0xB1]),
+            (PacketType::Rnr(Rnr { poll: false, nr: 0 }), false, vec![0x05]),
+            (PacketType::Rnr(Rnr { poll: true, nr: 5 }), false, vec![0xB5]),
+            (PacketType::Rej(Rej { poll: false, nr: 0 }), false, vec![0x09]),
+            (PacketType::Rej(Rej { poll: true, nr: 5 }), false, vec![0xB9]),
+            (PacketType::Srej(Srej { poll: false, nr: 0 }), false, vec![0x0D]),
+            (PacketType::Srej(Srej { poll: true, nr: 5 }), false, vec![0xBD]),
+            (PacketType::Rr(Rr { poll: false, nr: 7 }), false, vec![0xE1]),
+            // Mod-128.
+            (PacketType::Rr(Rr { poll: false, nr: 0 }), true, vec![0x01, 0x00]),
+            (PacketType::Rr(Rr { poll: true, nr: 100 }), true, vec![0x01, 0xC9]),
+            (PacketType::Rnr(Rnr { poll: false, nr: 0 }), true, vec![0x05, 0x00]),
+            (PacketType::Rnr(Rnr { poll: true, nr: 100 }), true, vec![0x05, 0xC9]),
+            (PacketType::Rej(Rej { poll: false, nr: 0 }), true, vec![0x09, 0x00]),
+            (PacketType::Rej(Rej { poll: true, nr: 100 }), true, vec![0x09, 0xC9]),
+            (PacketType::Srej(Srej { poll: false, nr: 0 }), true, vec![0x0D, 0x00]),
+            (PacketType::Srej(Srej { poll: true, nr: 100 }), true, vec![0x0D, 0xC9]),
+            (PacketType::Rr(Rr { poll: false, nr: 127 }), true, vec![0x01, 0xFE]),
+        ];
+        for (packet_type, ext, want) in tests {
+            let bytes = packet(packet_type.clone())?.serialize(ext);
+            assert_eq!(&bytes[14..], &want, "serializing {packet_type:?} ext={ext}");
+            // Linux extended mode bit in source SSID.
+            assert_eq!(bytes[13] & 0b0100_0000 == 0, ext);
+
+            for mode in [Some(ext), None] {
+                let parsed = Packet::parse(&bytes, mode)?;
+                assert_eq!(parsed.packet_type, packet_type, "parsing with {mode:?}");
+                assert_eq!(parsed.rr_extseq, ext);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "bus")]
+    #[test]
+    fn bus_full() -> Result<()> {
+        let msg = |data: &[u8]| BusMessage {
+            sender: 0,
+            data: data.to_vec(),
+        };
+        let bus = Arc::new(Mutex::new(bus::Bus::<BusMessage>::new(1)));
+        // Doesn't read until later.
+        let mut slow = BusHub::new(bus.clone());
+
+        assert!(broadcast(&bus, BusFullPolicy::Drop, msg(b"one"))?);
+        assert!(!broadcast(&bus, BusFullPolicy::Drop, msg(b"two"))?);
+        assert!(broadcast(
+            &bus,
+            BusFullPolicy::Block(std::time::Duration::from_millis(10)),
+            msg(b"three")
+        )
+        .is_err());
+
+        // Slow reader catches up while blocked.
+        let reader = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            slow.recv_timeout(std::time::Duration::from_secs(1))
+        });
+        assert!(broadcast(
+            &bus,
+            BusFullPolicy::Block(std::time::Duration::from_secs(10)),
+            msg(b"four")
+        )?);
+        assert_eq!(reader.join().unwrap()?, Some(b"one".to_vec()));
+
+        // Sending on a hub doesn't fail with the drop policy.
+        let bus = Arc::new(Mutex::new(bus::Bus::<BusMessage>::new(1)));
+        let mut hub = BusHub::new(bus).full_policy(BusFullPolicy::Drop);
+        hub.send(b"one")?;
+        hub.send(b"two")?;
+        Ok(())
+    }
 }
@@ -0,0 +1,358 @@
+//! Linux kernel AX.25 interface backend.
+//!
+//! Instead of talking KISS to a TNC directly, this reads and writes raw AX.25
+//! frames on a kernel AX.25 network interface (e.g. `ax0`, as created by
+//! `kissattach`), using a packet socket.
+//!
+//! The kernel AX.25 stack still sees all incoming frames on the interface, so
+//! use a callsign that the kernel is not also listening on. Otherwise both
+//! will reply.
+//!
+//! Opening a packet socket requires `CAP_NET_RAW`.
+//!
+//! # Example
+//!
+#![cfg_attr(feature = "async", doc = "```no_run")]
+#![cfg_attr(not(feature = "async"), doc = "```ignore")]
+//! use rax25::linux::AsyncRawSocket;
+//! use rax25::r#async::{ConnectionBuilder, PortType};
+//! use rax25::Addr;
+//!
+//! #[tokio::main]
+//! async fn main() -> anyhow::Result<()> {
+//!     let port = PortType::Kernel(AsyncRawSocket::new("ax0")?);
+//!     let mut client = ConnectionBuilder::new(Addr::new("M0THC-1")?, port)?
+//!         .connect(Addr::new("M0THC-2")?)
+//!         .await?;
+//!     client.write(b"hello world").await?;
+//!     Ok(())
+//! }
+//! ```
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{ready, Context, Poll};
+
+use anyhow::{Context as _, Error, Result};
+use log::debug;
+#[cfg(feature = "async")]
+use tokio::io::unix::AsyncFd;
+
+use crate::Hub;
+
+/// Packet type of frames we sent ourselves.
+const PACKET_OUTGOING: u8 = 4;
+
+/// The kernel keeps the KISS command byte in front of the AX.25 frame.
+///
+/// Zero means data frame, on KISS port zero.
+const KISS_DATA: u8 = 0;
+
+/// Max frame size we'll read.
+const MAX_FRAME: usize = 2048;
+
+/// Raw AX.25 frames on a kernel AX.25 interface.
+pub struct RawSocket {
+    ifname: String,
+    fd: OwnedFd,
+}
+
+impl RawSocket {
+    /// Open a packet socket bound to the given interface.
+    pub fn new(ifname: &str) -> Result<Self> {
+        Self::open(ifname, false)
+    }
+
+    fn open(ifname: &str, nonblock: bool) -> Result<Self> {
+        let name = std::ffi::CString::new(ifname)?;
+        // SAFETY: name is a valid NUL terminated string.
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("looking up interface {ifname}"));
+        }
+        let proto = (libc::ETH_P_AX25 as u16).to_be();
+        let flags = libc::SOCK_CLOEXEC | if nonblock { libc::SOCK_NONBLOCK } else { 0 };
+        // SAFETY: plain syscall, the result is checked.
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | flags, proto.into()) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("creating packet socket");
+        }
+        // SAFETY: fd was just created, and nothing else owns it.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_ll is plain old data, so all zeroes is valid.
+        let mut sll: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        sll.sll_family = libc::AF_PACKET as u16;
+        sll.sll_protocol = proto;
+        sll.sll_ifindex = ifindex as i32;
+        // SAFETY: sll is a valid sockaddr_ll, and the size matches.
+        let rc = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &sll as *const libc::sockaddr_ll as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("binding packet socket to {ifname}"));
+        }
+        debug!("Opened packet socket on {ifname} (ifindex {ifindex})");
+        Ok(Self {
+            ifname: ifname.to_string(),
+            fd,
+        })
+    }
+
+    /// Send one AX.25 frame.
+    fn send_frame(&self, frame: &[u8]) -> std::io::Result<()> {
+        let mut buf = Vec::with_capacity(frame.len() + 1);
+        buf.push(KISS_DATA);
+        buf.extend(frame);
+        // SAFETY: buf is valid for buf.len() bytes.
+        let rc = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if rc < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Receive one AX.25 frame.
+    ///
+    /// Returns Ok(None) for frames that should be ignored, such as our own
+    /// outgoing frames, or non-data KISS frames.
+    fn recv_frame(&self) -> std::io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; MAX_FRAME];
+        // SAFETY: sockaddr_ll is plain old data, so all zeroes is valid.
+        let mut sll: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+        // SAFETY: buf and sll are valid for the sizes given.
+        let rc = unsafe {
+            libc::recvfrom(
+                self.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+                &mut sll as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                &mut len,
+            )
+        };
+        if rc < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        buf.truncate(rc as usize);
+        if sll.sll_pkttype == PACKET_OUTGOING {
+            return Ok(None);
+        }
+        match buf.first() {
+            Some(&KISS_DATA) => Ok(Some(buf.split_off(1))),
+            other => {
+                debug!("Ignoring non-data frame from kernel: {other:?}");
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl AsRawFd for RawSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Hub for RawSocket {
+    fn clone(&self) -> Box<dyn Hub> {
+        // A new socket, not a dup(), so that every clone gets every packet.
+        Box::new(ReopenedRawSocket::new(&self.ifname))
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<()> {
+        Ok(self.send_frame(frame)?)
+    }
+
+    fn recv_timeout(&mut self, timeout: std::time::Duration) -> Result<Option<Vec<u8>>> {
+        let end = std::time::Instant::now() + timeout;
+        loop {
+            let left = end.saturating_duration_since(std::time::Instant::now());
+            let mut pfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // Round up, so that we don't busy loop the last millisecond.
+            let ms = left.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+            // SAFETY: pfd is a valid pollfd, and we pass exactly one.
+            let rc = unsafe { libc::poll(&mut pfd, 1, ms) };
+            if rc < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Error::new(e).context("polling packet socket"));
+            }
+            if rc == 0 {
+                return Ok(None);
+            }
+            if let Some(frame) = self.recv_frame()? {
+                return Ok(Some(frame));
+            }
+        }
+    }
+}
+
+/// Clone of a `RawSocket`.
+///
+/// Reopening the socket can fail, e.g. if the interface went away. `clone()`
+/// can't return that, so it's returned by the first use instead, and opening is
+/// retried on every use until it works.
+struct ReopenedRawSocket {
+    ifname: String,
+    sock: Option<RawSocket>,
+}
+
+impl ReopenedRawSocket {
+    fn new(ifname: &str) -> Self {
+        let sock = RawSocket::new(ifname)
+            .map_err(|e| debug!("Failed to reopen packet socket on {ifname}: {e:#}"))
+            .ok();
+        Self {
+            ifname: ifname.to_string(),
+            sock,
+        }
+    }
+
+    fn sock(&mut self) -> Result<&mut RawSocket> {
+        if self.sock.is_none() {
+            self.sock = Some(RawSocket::new(&self.ifname)?);
+        }
+        Ok(self.sock.as_mut().expect("can't happen: just opened"))
+    }
+}
+
+impl Hub for ReopenedRawSocket {
+    fn clone(&self) -> Box<dyn Hub> {
+        Box::new(ReopenedRawSocket::new(&self.ifname))
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<()> {
+        self.sock()?.send(frame)
+    }
+
+    fn recv_timeout(&mut self, timeout: std::time::Duration) -> Result<Option<Vec<u8>>> {
+        self.sock()?.recv_timeout(timeout)
+    }
+}
+
+/// Async kernel AX.25 interface, for use as `PortType::Kernel`.
+///
+/// The async client speaks KISS to its port, so this presents the raw frames
+/// as a KISS byte stream.
+#[cfg(feature = "async")]
+pub struct AsyncRawSocket {
+    port: crate::r#async::HubPort,
+}
+
+#[cfg(feature = "async")]
+impl AsyncRawSocket {
+    /// Open a packet socket bound to the given interface.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn new(ifname: &str) -> Result<Self> {
+        Ok(Self {
+            port: crate::r#async::HubPort::new(Box::new(AsyncRawHub {
+                fd: AsyncFd::new(RawSocket::open(ifname, true)?)?,
+            })),
+        })
+    }
+}
+
+/// Packet socket frames, for `HubPort` to do the KISS framing.
+#[cfg(feature = "async")]
+struct AsyncRawHub {
+    fd: AsyncFd<RawSocket>,
+}
+
+#[cfg(feature = "async")]
+impl crate::r#async::AsyncHub for AsyncRawHub {
+    fn poll_send(&mut self, cx: &mut Context<'_>, frame: &[u8]) -> Poll<std::io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            if let Ok(res) = guard.try_io(|inner| inner.get_ref().send_frame(frame)) {
+                return Poll::Ready(res);
+            }
+        }
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<Option<Vec<u8>>>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            match guard.try_io(|inner| inner.get_ref().recv_frame()) {
+                Ok(Ok(Some(frame))) => return Poll::Ready(Ok(Some(frame))),
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => {}
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio::io::AsyncRead for AsyncRawSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.port).poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio::io::AsyncWrite for AsyncRawSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.port).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.port).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.port).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_such_interface() {
+        assert!(RawSocket::new("rax25-nonexistent").is_err());
+    }
+
+    #[test]
+    fn failed_reopen() {
+        let mut sock = ReopenedRawSocket::new("rax25-nonexistent");
+        assert!(sock.send(&[]).is_err());
+        assert!(sock
+            .recv_timeout(std::time::Duration::from_millis(1))
+            .is_err());
+        assert!(sock.clone().send(&[]).is_err());
+    }
+}
+/* vim: textwidth=80
+ */
@@ -1,25 +1,57 @@
-//! pcap writer
+//! pcap writer and reader
 //!
 //! The pcap format is very simple, so no need for an external crate or linking
 //! to libpcap.
 //!
-//! This implementation writes little endian pcap files on all platforms.
+//! This implementation writes little endian pcap files on all platforms, and
+//! reads both little and big endian files.
+//!
+//! Classic pcap has 32 bit timestamps, wrapping in 2106, or in 2038 for tools
+//! reading them as signed. `PcapngWriter` writes pcapng, which has 64 bit
+//! timestamps.
 //!
 //! Useful resources:
 //! * https://wiki.wireshark.org/Development/LibpcapFileFormat
 //! * https://www.ietf.org/archive/id/draft-gharris-opsawg-pcap-01.html
 //! * https://www.tcpdump.org/linktypes.html
+//! * https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-02.html
 
 use std::io::BufWriter;
-use std::io::Write;
+use std::io::{Read, Write};
+
+use anyhow::{Error, Result};
 
-use anyhow::Result;
+use crate::Direction;
 
 // Little endian magic.
 const MAGIC: [u8; 4] = [0xd4, 0xc3, 0xb2, 0xa1];
+
+// Magic for files with nanosecond timestamps, as written by some tools.
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
 const VERSION_MAJOR: u16 = 2;
 const VERSION_MINOR: u16 = 4;
 const LINKTYPE_AX25: u32 = 3;
+const LINKTYPE_AX25_KISS: u32 = 202;
+
+// Size of the global header, and per packet record header.
+const FILE_HEADER_SIZE: u64 = 24;
+const RECORD_HEADER_SIZE: u64 = 16;
+
+// pcapng block types, and the section header byte order magic.
+const PCAPNG_SHB: u32 = 0x0A0D0D0A;
+const PCAPNG_IDB: u32 = 0x00000001;
+const PCAPNG_EPB: u32 = 0x00000006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+// Enhanced Packet Block flags option, and its direction bits.
+const PCAPNG_OPT_ENDOFOPT: u16 = 0;
+const PCAPNG_EPB_FLAGS: u16 = 2;
+const PCAPNG_EPB_FLAGS_INBOUND: u32 = 1;
+const PCAPNG_EPB_FLAGS_OUTBOUND: u32 = 2;
+
+// Biggest record to read. Same as libpcap's limit, to not allocate
+// gigabytes because of a corrupt file.
+const MAX_RECORD_SIZE: u32 = 262144;
 
 fn write_u16(mut w: impl std::io::Write, v: u16) -> Result<()> {
     w.write_all(&[(v & 0xff) as u8, ((v >> 8) & 0xFF) as u8])?;
@@ -36,23 +68,77 @@ fn write_u32(mut w: impl std::io::Write, v: u32) -> Result<()> {
     Ok(())
 }
 
+/// What each pcap record contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkType {
+    /// LINKTYPE_AX25: The AX.25 frame.
+    #[default]
+    Ax25,
+
+    /// LINKTYPE_AX25_KISS: The AX.25 frame, preceded by the KISS command byte.
+    ///
+    /// Wireshark then shows the KISS port of each frame. Frames are recorded
+    /// as sent on KISS port 0, which is what this crate's clients use.
+    Ax25Kiss,
+}
+
+impl LinkType {
+    #[must_use]
+    fn value(self) -> u32 {
+        match self {
+            LinkType::Ax25 => LINKTYPE_AX25,
+            LinkType::Ax25Kiss => LINKTYPE_AX25_KISS,
+        }
+    }
+
+    /// Bytes to put before the frame in each record.
+    #[must_use]
+    fn prefix(self) -> &'static [u8] {
+        match self {
+            LinkType::Ax25 => &[],
+            LinkType::Ax25Kiss => &[crate::KISS_CMD_DATA],
+        }
+    }
+
+    #[must_use]
+    fn from_value(v: u32) -> Option<Self> {
+        match v {
+            LINKTYPE_AX25 => Some(LinkType::Ax25),
+            LINKTYPE_AX25_KISS => Some(LinkType::Ax25Kiss),
+            _ => None,
+        }
+    }
+}
+
 /// PcapWriter writes AX.25 pcap files.
 ///
 /// It writes them buffered, for efficiency, so a crash could lose the last
 /// packets.
 pub struct PcapWriter {
     f: BufWriter<std::fs::File>,
+    path: std::path::PathBuf,
+    size: u64,
+    link_type: LinkType,
 }
 
 impl PcapWriter {
     /// Create a new pcap file. Fails if the file already exists.
     pub fn create(filename: std::path::PathBuf) -> Result<Self> {
+        Self::create_with_link_type(filename, LinkType::default())
+    }
+
+    /// Create a new pcap file with the given link type. Fails if the file
+    /// already exists.
+    pub fn create_with_link_type(
+        filename: std::path::PathBuf,
+        link_type: LinkType,
+    ) -> Result<Self> {
         let mut f = BufWriter::new(
             std::fs::File::options()
                 .read(false)
                 .write(true)
                 .create_new(true)
-                .open(filename)?,
+                .open(&filename)?,
         );
         f.write_all(&MAGIC)?;
         write_u16(&mut f, VERSION_MAJOR)?;
@@ -80,13 +166,27 @@ impl PcapWriter {
         // Apparently 65535 is a normal value to use.
         write_u32(&mut f, 65535)?;
 
-        // Another option is LINKTYPE_AX25_KISS. But currently we have nothing
-        // interesting to populate there, so just using LINKTYPE_AX25 for now.
-        //
         // Here's also where some FCS bits could be set, but we're currently
         // running without FCS.
-        write_u32(&mut f, LINKTYPE_AX25)?;
-        Ok(Self { f })
+        write_u32(&mut f, link_type.value())?;
+        Ok(Self {
+            f,
+            path: filename,
+            size: FILE_HEADER_SIZE,
+            link_type,
+        })
+    }
+
+    /// Path of the file being written.
+    #[must_use]
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// Number of bytes written to the file so far, including headers.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.size
     }
 
     /// Write a blob as a new packet entry.
@@ -94,14 +194,498 @@ impl PcapWriter {
     /// If this write fails, no further writes can be made, as the added record
     /// is now only partially added.
     pub fn write(&mut self, packet: &[u8]) -> Result<()> {
-        let len = packet.len() as u32;
+        let kiss = self.link_type.prefix();
+        let len = (kiss.len() + packet.len()) as u32;
         let now = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?;
-        // TODO: Ugh, the pcap format is not Y2036 safe. What do we do here?
+        // The pcap format is not Y2106 safe, so the seconds wrap. Use
+        // `PcapngWriter` to avoid this.
         write_u32(&mut self.f, now.as_secs() as u32)?;
         write_u32(&mut self.f, (now.as_micros() % 1000000) as u32)?;
         write_u32(&mut self.f, len)?;
         write_u32(&mut self.f, len)?;
+        self.f.write_all(kiss)?;
         self.f.write_all(packet)?;
+        self.size += RECORD_HEADER_SIZE + u64::from(len);
+        Ok(())
+    }
+}
+
+/// RotatingPcapWriter writes AX.25 pcap files, starting a new file when the
+/// current one grows too big.
+///
+/// Files are named `rax25-NNNNNN.pcap` in the given directory. Numbering
+/// starts after the highest existing file, so existing captures are never
+/// overwritten.
+pub struct RotatingPcapWriter {
+    dir: std::path::PathBuf,
+    max_bytes: u64,
+    n: u64,
+    cur: PcapWriter,
+    link_type: LinkType,
+}
+
+impl RotatingPcapWriter {
+    /// Start writing pcap files in `dir`, each at most `max_bytes` big.
+    ///
+    /// A single packet bigger than `max_bytes` still gets written, alone in
+    /// its own file.
+    pub fn create(dir: std::path::PathBuf, max_bytes: u64) -> Result<Self> {
+        Self::create_with_link_type(dir, max_bytes, LinkType::default())
+    }
+
+    /// Like `create()`, but with the given link type.
+    pub fn create_with_link_type(
+        dir: std::path::PathBuf,
+        max_bytes: u64,
+        link_type: LinkType,
+    ) -> Result<Self> {
+        let mut n = 0;
+        for entry in std::fs::read_dir(&dir)? {
+            if let Some(num) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("rax25-"))
+                .and_then(|name| name.strip_suffix(".pcap"))
+                .and_then(|num| num.parse::<u64>().ok())
+            {
+                n = std::cmp::max(n, num + 1);
+            }
+        }
+        let cur = PcapWriter::create_with_link_type(Self::filename(&dir, n), link_type)?;
+        Ok(Self {
+            dir,
+            max_bytes,
+            n,
+            cur,
+            link_type,
+        })
+    }
+
+    #[must_use]
+    fn filename(dir: &std::path::Path, n: u64) -> std::path::PathBuf {
+        dir.join(format!("rax25-{n:06}.pcap"))
+    }
+
+    /// Path of the file currently being written.
+    #[must_use]
+    pub fn path(&self) -> &std::path::Path {
+        self.cur.path()
+    }
+
+    /// Write a blob as a new packet entry, rotating the file first if needed.
+    pub fn write(&mut self, packet: &[u8]) -> Result<()> {
+        let kiss = self.link_type.prefix().len() as u64;
+        let record = RECORD_HEADER_SIZE + kiss + packet.len() as u64;
+        if self.cur.size() > FILE_HEADER_SIZE && self.cur.size() + record > self.max_bytes {
+            self.n += 1;
+            self.cur = PcapWriter::create_with_link_type(
+                Self::filename(&self.dir, self.n),
+                self.link_type,
+            )?;
+        }
+        self.cur.write(packet)
+    }
+}
+
+/// PcapngWriter writes AX.25 pcapng files.
+///
+/// Unlike `PcapWriter`, timestamps are 64 bit microseconds, so they don't wrap
+/// in 2038 or 2106. Packets can also be marked as inbound or outbound.
+///
+/// It writes them buffered, for efficiency, so a crash could lose the last
+/// packets.
+pub struct PcapngWriter {
+    f: BufWriter<std::fs::File>,
+    path: std::path::PathBuf,
+    size: u64,
+    link_type: LinkType,
+}
+
+impl PcapngWriter {
+    /// Create a new pcapng file. Fails if the file already exists.
+    pub fn create(filename: std::path::PathBuf) -> Result<Self> {
+        Self::create_with_link_type(filename, LinkType::default())
+    }
+
+    /// Create a new pcapng file with the given link type. Fails if the file
+    /// already exists.
+    pub fn create_with_link_type(
+        filename: std::path::PathBuf,
+        link_type: LinkType,
+    ) -> Result<Self> {
+        let mut f = BufWriter::new(
+            std::fs::File::options()
+                .read(false)
+                .write(true)
+                .create_new(true)
+                .open(&filename)?,
+        );
+
+        // Section Header Block, with unknown section length.
+        write_u32(&mut f, PCAPNG_SHB)?;
+        write_u32(&mut f, 28)?;
+        write_u32(&mut f, PCAPNG_BYTE_ORDER_MAGIC)?;
+        write_u16(&mut f, 1)?;
+        write_u16(&mut f, 0)?;
+        write_u32(&mut f, 0xFFFFFFFF)?;
+        write_u32(&mut f, 0xFFFFFFFF)?;
+        write_u32(&mut f, 28)?;
+
+        // Interface Description Block. Without an if_tsresol option,
+        // timestamps are in microseconds.
+        write_u32(&mut f, PCAPNG_IDB)?;
+        write_u32(&mut f, 20)?;
+        write_u16(&mut f, link_type.value() as u16)?;
+        write_u16(&mut f, 0)?;
+        write_u32(&mut f, 65535)?;
+        write_u32(&mut f, 20)?;
+        Ok(Self {
+            f,
+            path: filename,
+            size: 28 + 20,
+            link_type,
+        })
+    }
+
+    /// Path of the file being written.
+    #[must_use]
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// Number of bytes written to the file so far, including headers.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Write a blob as a new packet entry.
+    ///
+    /// If this write fails, no further writes can be made, as the added block
+    /// is now only partially added.
+    pub fn write(&mut self, packet: &[u8]) -> Result<()> {
+        let now = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?;
+        self.write_at(now, None, packet)
+    }
+
+    /// Write a blob as a new packet entry, marked as received or sent.
+    ///
+    /// Wireshark shows this as the packet's direction.
+    pub fn write_direction(&mut self, direction: Direction, packet: &[u8]) -> Result<()> {
+        let now = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?;
+        self.write_at(now, Some(direction), packet)
+    }
+
+    /// Write a packet with the given time since the epoch.
+    fn write_at(
+        &mut self,
+        ts: std::time::Duration,
+        direction: Option<Direction>,
+        packet: &[u8],
+    ) -> Result<()> {
+        let kiss = self.link_type.prefix();
+        let len = kiss.len() + packet.len();
+        let pad = (4 - len % 4) % 4;
+        // epb_flags option, and the end of options.
+        let options = if direction.is_some() { 8 + 4 } else { 0 };
+        let block_len = (32 + len + pad + options) as u32;
+        let ts = ts.as_micros() as u64;
+
+        // Enhanced Packet Block, on interface 0.
+        write_u32(&mut self.f, PCAPNG_EPB)?;
+        write_u32(&mut self.f, block_len)?;
+        write_u32(&mut self.f, 0)?;
+        write_u32(&mut self.f, (ts >> 32) as u32)?;
+        write_u32(&mut self.f, ts as u32)?;
+        write_u32(&mut self.f, len as u32)?;
+        write_u32(&mut self.f, len as u32)?;
+        self.f.write_all(kiss)?;
+        self.f.write_all(packet)?;
+        self.f.write_all(&[0; 3][..pad])?;
+        if let Some(direction) = direction {
+            write_u16(&mut self.f, PCAPNG_EPB_FLAGS)?;
+            write_u16(&mut self.f, 4)?;
+            write_u32(
+                &mut self.f,
+                match direction {
+                    Direction::In => PCAPNG_EPB_FLAGS_INBOUND,
+                    Direction::Out => PCAPNG_EPB_FLAGS_OUTBOUND,
+                },
+            )?;
+            write_u16(&mut self.f, PCAPNG_OPT_ENDOFOPT)?;
+            write_u16(&mut self.f, 0)?;
+        }
+        write_u32(&mut self.f, block_len)?;
+        self.size += u64::from(block_len);
+        Ok(())
+    }
+}
+
+/// PcapReader reads AX.25 pcap files, such as those written by `PcapWriter`.
+///
+/// Iterating yields the timestamp and frame of each record. For
+/// `LinkType::Ax25Kiss` files the frames still have the KISS command byte, so
+/// parse them with `Packet::parse_kiss()`.
+pub struct PcapReader<R> {
+    r: R,
+    big_endian: bool,
+    nanos: bool,
+    link_type: LinkType,
+}
+
+impl PcapReader<std::io::BufReader<std::fs::File>> {
+    /// Open a pcap file.
+    pub fn open(filename: &std::path::Path) -> Result<Self> {
+        Self::new(std::io::BufReader::new(std::fs::File::open(filename)?))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Start reading pcap data, by parsing the global header.
+    ///
+    /// Fails unless the link type is `LINKTYPE_AX25` or `LINKTYPE_AX25_KISS`.
+    pub fn new(mut r: R) -> Result<Self> {
+        let mut header = [0; FILE_HEADER_SIZE as usize];
+        r.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes(header[..4].try_into()?);
+        let (big_endian, nanos) = if magic == u32::from_le_bytes(MAGIC) {
+            (false, false)
+        } else if magic == u32::from_be_bytes(MAGIC) {
+            (true, false)
+        } else if magic == MAGIC_NANOS {
+            (false, true)
+        } else if magic == MAGIC_NANOS.swap_bytes() {
+            (true, true)
+        } else {
+            return Err(Error::msg(format!("not a pcap file, magic {magic:08x}")));
+        };
+        let mut ret = Self {
+            r,
+            big_endian,
+            nanos,
+            link_type: LinkType::default(),
+        };
+        let major = ret.u16(&header[4..6]);
+        if major != VERSION_MAJOR {
+            return Err(Error::msg(format!("unsupported pcap version {major}")));
+        }
+        let linktype = ret.u32(&header[20..24]);
+        ret.link_type = LinkType::from_value(linktype)
+            .ok_or_else(|| Error::msg(format!("unsupported pcap link type {linktype}")))?;
+        Ok(ret)
+    }
+
+    /// Link type of the file.
+    #[must_use]
+    pub fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    #[must_use]
+    fn u16(&self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    }
+
+    #[must_use]
+    fn u32(&self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    /// Read the next record. Ok(None) on end of file.
+    fn read_record(&mut self) -> Result<Option<(std::time::SystemTime, Vec<u8>)>> {
+        let mut header = [0; RECORD_HEADER_SIZE as usize];
+        let mut got = 0;
+        while got < header.len() {
+            match self.r.read(&mut header[got..]) {
+                Ok(0) if got == 0 => return Ok(None),
+                Ok(0) => return Err(Error::msg("truncated pcap record header")),
+                Ok(n) => got += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let secs = self.u32(&header[0..4]);
+        let frac = self.u32(&header[4..8]);
+        let len = self.u32(&header[8..12]);
+        if len > MAX_RECORD_SIZE {
+            return Err(Error::msg(format!("pcap record too big: {len} bytes")));
+        }
+        let frac = if self.nanos {
+            std::time::Duration::from_nanos(frac.into())
+        } else {
+            std::time::Duration::from_micros(frac.into())
+        };
+        let ts =
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs.into()) + frac;
+        let mut packet = vec![0; len as usize];
+        self.r.read_exact(&mut packet)?;
+        Ok(Some((ts, packet)))
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = Result<(std::time::SystemTime, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_back() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("rax25-test-read-{}.pcap", std::process::id()));
+        let packets: Vec<Vec<u8>> = (0..5u8).map(|n| vec![n; 20 + n as usize]).collect();
+        let start = std::time::SystemTime::now() - std::time::Duration::from_secs(1);
+        {
+            let mut w = PcapWriter::create(path.clone())?;
+            for p in &packets {
+                w.write(p)?;
+            }
+        }
+        let got: Vec<_> = PcapReader::open(&path)?.collect::<Result<_>>()?;
+        std::fs::remove_file(&path)?;
+        assert!(got.iter().all(|(ts, _)| *ts >= start));
+        let got: Vec<_> = got.into_iter().map(|(_, p)| p).collect();
+        assert_eq!(got, packets);
+        Ok(())
+    }
+
+    #[test]
+    fn pcapng() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("rax25-test-ng-{}.pcapng", std::process::id()));
+        // 2040-01-01 00:00:00.5 UTC.
+        let ts = std::time::Duration::from_micros(2_208_988_800_500_000);
+        {
+            let mut w = PcapngWriter::create(path.clone())?;
+            w.write_at(ts, None, &[1, 2, 3, 4, 5])?;
+            assert_eq!(w.size(), 28 + 20 + 32 + 8);
+            w.write_at(ts, Some(Direction::Out), &[6])?;
+        }
+        let data = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        let u32_at = |n: usize| u32::from_le_bytes(data[n..n + 4].try_into().unwrap());
+        assert_eq!(data.len(), 28 + 20 + 32 + 8 + 32 + 4 + 12);
+        assert_eq!(u32_at(0), PCAPNG_SHB);
+        assert_eq!(u32_at(8), PCAPNG_BYTE_ORDER_MAGIC);
+        assert_eq!(u32_at(28), PCAPNG_IDB);
+        assert_eq!(u16::from_le_bytes([data[36], data[37]]), 3);
+
+        // Enhanced Packet Block.
+        let epb = 28 + 20;
+        assert_eq!(u32_at(epb), PCAPNG_EPB);
+        assert_eq!(u32_at(epb + 4), 40);
+        let got = u64::from(u32_at(epb + 12)) << 32 | u64::from(u32_at(epb + 16));
+        assert_eq!(got, ts.as_micros() as u64);
+        assert_eq!(u32_at(epb + 20), 5);
+        assert_eq!(&data[epb + 28..epb + 36], &[1, 2, 3, 4, 5, 0, 0, 0]);
+        assert_eq!(u32_at(epb + 36), 40);
+
+        // With direction.
+        let epb = epb + 40;
+        assert_eq!(u32_at(epb + 4), 48);
+        assert_eq!(&data[epb + 28..epb + 32], &[6, 0, 0, 0]);
+        assert_eq!(
+            u16::from_le_bytes([data[epb + 32], data[epb + 33]]),
+            PCAPNG_EPB_FLAGS
+        );
+        assert_eq!(u32_at(epb + 36), PCAPNG_EPB_FLAGS_OUTBOUND);
+        assert_eq!(u32_at(epb + 40), 0);
+        assert_eq!(u32_at(epb + 44), 48);
+        Ok(())
+    }
+
+    #[test]
+    fn kiss_link_type() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("rax25-test-kiss-{}.pcap", std::process::id()));
+        {
+            let mut w = PcapWriter::create_with_link_type(path.clone(), LinkType::Ax25Kiss)?;
+            w.write(&[1, 2, 3])?;
+            assert_eq!(w.size(), 24 + 16 + 4);
+        }
+        let r = PcapReader::open(&path)?;
+        assert_eq!(r.link_type(), LinkType::Ax25Kiss);
+        let got: Vec<_> = r.map(|r| r.map(|(_, p)| p)).collect::<Result<_>>()?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(got, vec![vec![crate::KISS_CMD_DATA, 1, 2, 3]]);
+        Ok(())
+    }
+
+    #[test]
+    fn read_big_endian() -> Result<()> {
+        let mut data = vec![
+            0xa1, 0xb2, 0xc3, 0xd4, // Magic.
+            0, 2, 0, 4, // Version.
+            0, 0, 0, 0, // GMT offset.
+            0, 0, 0, 0, // Accuracy.
+            0, 0, 0xff, 0xff, // Snaplen.
+            0, 0, 0, 3, // Link type.
+            0, 0, 0, 10, // Seconds.
+            0, 0, 0, 20, // Microseconds.
+            0, 0, 0, 2, // Captured length.
+            0, 0, 0, 2, // Original length.
+            0xab, 0xcd,
+        ];
+        let got: Vec<_> = PcapReader::new(&data[..])?.collect::<Result<_>>()?;
+        assert_eq!(
+            got,
+            vec![(
+                std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_micros(10_000_020),
+                vec![0xab, 0xcd]
+            )]
+        );
+
+        // Truncated record.
+        data.pop();
+        assert!(PcapReader::new(&data[..])?.next().unwrap().is_err());
+
+        // Wrong link type.
+        data[23] = 1;
+        assert!(PcapReader::new(&data[..]).is_err());
+
+        // Not pcap.
+        assert!(PcapReader::new(&b"hello world, this is not pcap"[..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rotate() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("rax25-test-rotate-{}", std::process::id()));
+        std::fs::create_dir(&dir)?;
+        {
+            let mut w = RotatingPcapWriter::create(dir.clone(), 100)?;
+            assert_eq!(w.path(), dir.join("rax25-000000.pcap"));
+            w.write(&[0; 30])?;
+            assert_eq!(w.path(), dir.join("rax25-000000.pcap"));
+            w.write(&[0; 30])?;
+            assert_eq!(w.path(), dir.join("rax25-000001.pcap"));
+        }
+        {
+            // Don't reuse existing files.
+            let w = RotatingPcapWriter::create(dir.clone(), 100)?;
+            assert_eq!(w.path(), dir.join("rax25-000002.pcap"));
+        }
+        let sizes: Vec<_> = (0..3)
+            .map(|n| std::fs::metadata(RotatingPcapWriter::filename(&dir, n)).map(|m| m.len()))
+            .collect::<std::io::Result<_>>()?;
+        assert_eq!(sizes, vec![24 + 16 + 30, 24 + 16 + 30, 24]);
+        std::fs::remove_dir_all(&dir)?;
         Ok(())
     }
 }
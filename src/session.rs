@@ -0,0 +1,548 @@
+//! Connection session analyzer.
+//!
+//! Given the frames of a connection, in the order they were seen on the air,
+//! reconstruct what happened: connect, data with sequence numbers,
+//! retransmissions, and disconnect. Things that look wrong are flagged as
+//! anomalies.
+//!
+//! This is a diagnostic tool, for interop debugging without having to eyeball
+//! every frame in Wireshark. Frames can be added as they're received, or all
+//! at once from a capture.
+//!
+//! Only one connection is tracked. Feeding it frames from several connections
+//! at once will produce confusing results.
+//!
+//! # Example
+//!
+//! ```
+//! use rax25::session::Session;
+//! # let frames: Vec<rax25::Packet> = vec![];
+//! let mut session = Session::new();
+//! for frame in &frames {
+//!     session.add(frame);
+//! }
+//! let report = session.report();
+//! for a in &report.anomalies {
+//!     println!("Frame {}: {:?}", a.frame, a.kind);
+//! }
+//! ```
+use std::collections::HashMap;
+
+use crate::{Packet, PacketType};
+
+/// Number of retransmissions of the same frame considered a storm.
+pub const RETRANSMIT_STORM: usize = 3;
+
+/// Something that happened in the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// SABM(E) sent, asking to connect.
+    Connect {
+        from: String,
+        to: String,
+        extended: bool,
+    },
+
+    /// Connection accepted with UA.
+    Connected,
+
+    /// Connection refused with DM.
+    Refused,
+
+    /// New data sent.
+    Data {
+        from: String,
+        ns: u8,
+        nr: u8,
+        len: usize,
+    },
+
+    /// Data sent again.
+    Retransmit { from: String, ns: u8 },
+
+    /// RR or RNR acking data up to, but not including, `nr`.
+    Ack { from: String, nr: u8, busy: bool },
+
+    /// REJ or SREJ, asking for data to be sent again.
+    Reject {
+        from: String,
+        nr: u8,
+        selective: bool,
+    },
+
+    /// DISC sent, asking to disconnect.
+    Disconnect { from: String },
+
+    /// Connection torn down, with UA or DM.
+    Disconnected,
+}
+
+/// An event, and which frame caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelineEntry {
+    /// Index of the frame, counting from zero.
+    pub frame: usize,
+    pub event: SessionEvent,
+}
+
+/// Something that looks wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnomalyKind {
+    /// N(R) went backwards, acking less than an earlier frame did.
+    AckBackwards { from: String, nr: u8, prev: u8 },
+
+    /// N(R) acked data that was never sent.
+    NrError { from: String, nr: u8 },
+
+    /// The same frame was retransmitted `RETRANSMIT_STORM` times.
+    RetransmitStorm { from: String, ns: u8 },
+
+    /// A frame that only makes sense on an established connection, seen
+    /// without one.
+    NotConnected { from: String },
+}
+
+/// An anomaly, and which frame caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Anomaly {
+    /// Index of the frame, counting from zero.
+    pub frame: usize,
+    pub kind: AnomalyKind,
+}
+
+/// What happened in a session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionReport {
+    pub timeline: Vec<TimelineEntry>,
+    pub anomalies: Vec<Anomaly>,
+
+    /// Number of frames analyzed.
+    pub frames: usize,
+
+    /// Number of new I frames, not counting retransmissions.
+    pub data_frames: usize,
+
+    /// Payload bytes in new I frames.
+    pub data_bytes: usize,
+
+    /// Number of retransmitted I frames.
+    pub retransmissions: usize,
+}
+
+/// What we've seen one end send.
+#[derive(Default)]
+struct Side {
+    /// Next new N(S).
+    vs: u8,
+
+    /// Last N(R) sent, acking the other side's data.
+    acked: u8,
+
+    /// Number of times each N(S) was retransmitted, since it was last sent as
+    /// new data.
+    retransmits: HashMap<u8, usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pending {
+    None,
+    Connect,
+    Disconnect,
+}
+
+/// Session analyzer.
+pub struct Session {
+    report: SessionReport,
+    connected: bool,
+    pending: Pending,
+    modulus: u8,
+    sides: HashMap<String, Side>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    /// Create a new, empty, session.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            report: SessionReport::default(),
+            connected: false,
+            pending: Pending::None,
+            modulus: 8,
+            sides: HashMap::new(),
+        }
+    }
+
+    /// Add the next frame of the session.
+    pub fn add(&mut self, p: &Packet) {
+        let frame = self.report.frames;
+        self.report.frames += 1;
+        let from = p.src.call().to_string();
+        let to = p.dst.call().to_string();
+        match &p.packet_type {
+            PacketType::Sabm(_) | PacketType::Sabme(_) => {
+                let extended = matches!(p.packet_type, PacketType::Sabme(_));
+                self.modulus = if extended { 128 } else { 8 };
+                self.sides.clear();
+                self.connected = false;
+                self.pending = Pending::Connect;
+                self.event(frame, SessionEvent::Connect { from, to, extended });
+            }
+            PacketType::Ua(_) => match self.pending {
+                Pending::Connect => {
+                    self.connected = true;
+                    self.pending = Pending::None;
+                    self.event(frame, SessionEvent::Connected);
+                }
+                Pending::Disconnect => {
+                    self.connected = false;
+                    self.pending = Pending::None;
+                    self.event(frame, SessionEvent::Disconnected);
+                }
+                // Probably a duplicate.
+                Pending::None => {}
+            },
+            PacketType::Dm(_) => {
+                let event = match self.pending {
+                    Pending::Connect => SessionEvent::Refused,
+                    _ => SessionEvent::Disconnected,
+                };
+                self.connected = false;
+                self.pending = Pending::None;
+                self.event(frame, event);
+            }
+            PacketType::Disc(_) => {
+                self.pending = Pending::Disconnect;
+                self.event(frame, SessionEvent::Disconnect { from });
+            }
+            PacketType::Iframe(i) => {
+                if !self.check_connected(frame, &from) {
+                    return;
+                }
+                self.ack(frame, &from, &to, i.nr);
+                let modulus = self.modulus;
+                let side = self.sides.entry(from.clone()).or_default();
+                if i.ns == side.vs {
+                    side.vs = (side.vs + 1) % modulus;
+                    // Sequence numbers wrap, so earlier frames with this N(S)
+                    // don't count.
+                    side.retransmits.remove(&i.ns);
+                    self.report.data_frames += 1;
+                    self.report.data_bytes += i.payload.len();
+                    self.event(
+                        frame,
+                        SessionEvent::Data {
+                            from,
+                            ns: i.ns,
+                            nr: i.nr,
+                            len: i.payload.len(),
+                        },
+                    );
+                    return;
+                }
+                let count = side.retransmits.entry(i.ns).or_default();
+                *count += 1;
+                let storm = *count == RETRANSMIT_STORM;
+                self.report.retransmissions += 1;
+                if storm {
+                    self.anomaly(
+                        frame,
+                        AnomalyKind::RetransmitStorm {
+                            from: from.clone(),
+                            ns: i.ns,
+                        },
+                    );
+                }
+                self.event(frame, SessionEvent::Retransmit { from, ns: i.ns });
+            }
+            PacketType::Rr(r) => self.sframe(frame, from, to, r.nr, false, None),
+            PacketType::Rnr(r) => self.sframe(frame, from, to, r.nr, true, None),
+            PacketType::Rej(r) => self.sframe(frame, from, to, r.nr, false, Some(false)),
+            PacketType::Srej(r) => self.sframe(frame, from, to, r.nr, false, Some(true)),
+            PacketType::Ui(_)
+            | PacketType::Xid(_)
+            | PacketType::Test(_)
+            | PacketType::Frmr(_)
+            | PacketType::Invalid(_) => {}
+        }
+    }
+
+    /// Return the report of the session so far.
+    #[must_use]
+    pub fn report(&self) -> SessionReport {
+        self.report.clone()
+    }
+
+    fn event(&mut self, frame: usize, event: SessionEvent) {
+        self.report.timeline.push(TimelineEntry { frame, event });
+    }
+
+    fn anomaly(&mut self, frame: usize, kind: AnomalyKind) {
+        self.report.anomalies.push(Anomaly { frame, kind });
+    }
+
+    fn check_connected(&mut self, frame: usize, from: &str) -> bool {
+        if !self.connected {
+            self.anomaly(
+                frame,
+                AnomalyKind::NotConnected {
+                    from: from.to_string(),
+                },
+            );
+        }
+        self.connected
+    }
+
+    fn sframe(
+        &mut self,
+        frame: usize,
+        from: String,
+        to: String,
+        nr: u8,
+        busy: bool,
+        reject: Option<bool>,
+    ) {
+        if !self.check_connected(frame, &from) {
+            return;
+        }
+        // SREJ N(R) is the missing frame, not an ack.
+        if reject != Some(true) {
+            self.ack(frame, &from, &to, nr);
+        }
+        let event = match reject {
+            None => SessionEvent::Ack { from, nr, busy },
+            Some(selective) => SessionEvent::Reject {
+                from,
+                nr,
+                selective,
+            },
+        };
+        self.event(frame, event);
+    }
+
+    /// Check N(R) sent by `from`, acking data sent by `to`.
+    fn ack(&mut self, frame: usize, from: &str, to: &str, nr: u8) {
+        let modulus = self.modulus;
+        let vs = self.sides.get(to).map(|s| s.vs).unwrap_or(0);
+        let side = self.sides.entry(from.to_string()).or_default();
+        let prev = side.acked;
+        let ahead = (nr + modulus - prev) % modulus;
+        let outstanding = (vs + modulus - prev) % modulus;
+        if ahead <= outstanding {
+            side.acked = nr;
+            if let Some(sender) = self.sides.get_mut(to) {
+                for n in 0..ahead {
+                    sender.retransmits.remove(&((prev + n) % modulus));
+                }
+            }
+            return;
+        }
+        let kind = if (prev + modulus - nr) % modulus < modulus / 2 {
+            AnomalyKind::AckBackwards {
+                from: from.to_string(),
+                nr,
+                prev,
+            }
+        } else {
+            AnomalyKind::NrError {
+                from: from.to_string(),
+                nr,
+            }
+        };
+        self.anomaly(frame, kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Addr, Disc, Iframe, Rr, Sabm, Ua};
+    use anyhow::Result;
+
+    fn packet(src: &str, dst: &str, packet_type: PacketType) -> Result<Packet> {
+        Ok(Packet {
+            src: Addr::new(src)?,
+            dst: Addr::new(dst)?,
+            command_response: true,
+            command_response_la: false,
+            digipeater: vec![],
+            rr_dist1: false,
+            rr_extseq: false,
+            packet_type,
+        })
+    }
+
+    #[test]
+    fn session() -> Result<()> {
+        let a = "M0THC-1";
+        let b = "M0THC-2";
+        let i = |ns, nr| {
+            PacketType::Iframe(Iframe {
+                ns,
+                nr,
+                poll: false,
+                pid: 0xF0,
+                payload: vec![1, 2, 3],
+            })
+        };
+        let rr = |nr| PacketType::Rr(Rr { poll: false, nr });
+        let frames = vec![
+            packet(a, b, PacketType::Sabm(Sabm { poll: true }))?,
+            packet(b, a, PacketType::Ua(Ua { poll: true }))?,
+            packet(a, b, i(0, 0))?,
+            packet(a, b, i(1, 0))?,
+            packet(b, a, rr(1))?,
+            packet(a, b, i(1, 0))?,
+            packet(a, b, i(1, 0))?,
+            packet(a, b, i(1, 0))?,
+            packet(b, a, rr(2))?,
+            // Backwards.
+            packet(b, a, rr(1))?,
+            // Never sent.
+            packet(b, a, rr(5))?,
+            packet(a, b, PacketType::Disc(Disc { poll: true }))?,
+            packet(b, a, PacketType::Ua(Ua { poll: true }))?,
+        ];
+        let mut session = Session::new();
+        for f in &frames {
+            session.add(f);
+        }
+        let report = session.report();
+        assert_eq!(report.frames, 13);
+        assert_eq!(report.data_frames, 2);
+        assert_eq!(report.data_bytes, 6);
+        assert_eq!(report.retransmissions, 3);
+
+        let events: Vec<_> = report.timeline.iter().map(|e| &e.event).collect();
+        assert_eq!(
+            events[..3],
+            [
+                &SessionEvent::Connect {
+                    from: a.to_string(),
+                    to: b.to_string(),
+                    extended: false
+                },
+                &SessionEvent::Connected,
+                &SessionEvent::Data {
+                    from: a.to_string(),
+                    ns: 0,
+                    nr: 0,
+                    len: 3
+                },
+            ]
+        );
+        assert_eq!(report.timeline[5].frame, 5);
+        assert_eq!(
+            report.timeline[5].event,
+            SessionEvent::Retransmit {
+                from: a.to_string(),
+                ns: 1
+            }
+        );
+        assert_eq!(
+            events[events.len() - 2..],
+            [
+                &SessionEvent::Disconnect {
+                    from: a.to_string()
+                },
+                &SessionEvent::Disconnected,
+            ]
+        );
+
+        assert_eq!(
+            report.anomalies,
+            vec![
+                Anomaly {
+                    frame: 7,
+                    kind: AnomalyKind::RetransmitStorm {
+                        from: a.to_string(),
+                        ns: 1
+                    },
+                },
+                Anomaly {
+                    frame: 9,
+                    kind: AnomalyKind::AckBackwards {
+                        from: b.to_string(),
+                        nr: 1,
+                        prev: 2
+                    },
+                },
+                Anomaly {
+                    frame: 10,
+                    kind: AnomalyKind::NrError {
+                        from: b.to_string(),
+                        nr: 5
+                    },
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retransmits_across_wrap() -> Result<()> {
+        let a = "M0THC-1";
+        let b = "M0THC-2";
+        let i = |ns| {
+            PacketType::Iframe(Iframe {
+                ns,
+                nr: 0,
+                poll: false,
+                pid: 0xF0,
+                payload: vec![1],
+            })
+        };
+        let rr = |nr| PacketType::Rr(Rr { poll: false, nr });
+        let mut frames = vec![
+            packet(a, b, PacketType::Sabm(Sabm { poll: true }))?,
+            packet(b, a, PacketType::Ua(Ua { poll: true }))?,
+        ];
+        // N(S) 0 is retransmitted once before being acked, every time around.
+        for _ in 0..RETRANSMIT_STORM {
+            for ns in 0..8 {
+                frames.push(packet(a, b, i(ns))?);
+                if ns == 0 {
+                    frames.push(packet(a, b, i(ns))?);
+                }
+                frames.push(packet(b, a, rr((ns + 1) % 8))?);
+            }
+        }
+        let mut session = Session::new();
+        for f in &frames {
+            session.add(f);
+        }
+        let report = session.report();
+        assert_eq!(report.retransmissions, RETRANSMIT_STORM);
+        assert_eq!(report.anomalies, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn not_connected() -> Result<()> {
+        let mut session = Session::new();
+        session.add(&packet(
+            "M0THC-1",
+            "M0THC-2",
+            PacketType::Rr(Rr { poll: false, nr: 0 }),
+        )?);
+        let report = session.report();
+        assert!(report.timeline.is_empty());
+        assert_eq!(
+            report.anomalies,
+            vec![Anomaly {
+                frame: 0,
+                kind: AnomalyKind::NotConnected {
+                    from: "M0THC-1".to_string()
+                },
+            }]
+        );
+        Ok(())
+    }
+}
+/* vim: textwidth=80
+ */
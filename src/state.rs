@@ -12,14 +12,14 @@
 //!
 //! There's also isomer's useful notes at the top of
 //! https://github.com/isomer/ax25embed/blob/main/ax25/ax25_dl.c
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 use anyhow::Result;
 use log::{debug, error, warn};
 
 use crate::{
-    Addr, Disc, Dm, Frmr, Iframe, Packet, PacketType, Rej, Rnr, Rr, Sabm, Sabme, Srej, Test, Ua,
-    Ui, Xid,
+    Addr, Disc, Dm, Frmr, Iframe, InvalidReason, Packet, PacketType, Rej, Rnr, Rr, Sabm, Sabme,
+    Srej, Test, Ua, Ui, Xid, NO_L3,
 };
 
 /// Incoming events to the state machine.
@@ -28,9 +28,17 @@ use crate::{
 /// like "connect", or "send this data".
 #[derive(Debug, PartialEq)]
 pub enum Event {
-    Connect { addr: Addr, ext: bool },
+    Connect {
+        addr: Addr,
+        ext: bool,
+    },
     Disconnect,
     Data(Vec<u8>),
+    DataWithPid(u8, Vec<u8>),
+    PriorityData(Vec<u8>),
+    Pause,
+    Resume,
+    ReceiverBusy(bool),
     T1,
     T3,
 
@@ -43,14 +51,31 @@ pub enum Event {
     Dm(Dm),
     Ua(Ua),
     Frmr(Frmr),
+    /// Frame invalid for its type, or of an unknown type.
+    Invalid(InvalidReason, /* src */ Addr, /* dst */ Addr),
     // Commands or responses.
-    Ui(Ui, /* command */ bool),
-    Test(Test, /* command */ bool),
-    Xid(Xid, /* command */ bool),
+    Ui(
+        Ui,
+        /* command */ bool,
+        /* src */ Addr,
+        /* dst */ Addr,
+    ),
+    Test(
+        Test,
+        /* command */ bool,
+        /* src */ Addr,
+        /* dst */ Addr,
+    ),
+    Xid(
+        Xid,
+        /* command */ bool,
+        /* src */ Addr,
+        /* dst */ Addr,
+    ),
 
     // S frames.
     Rr(Rr, /* command */ bool),
-    Rnr(Rnr),
+    Rnr(Rnr, /* command */ bool),
     Rej(Rej),
     Srej(Srej),
 
@@ -77,10 +102,7 @@ impl ReturnEvent {
     pub fn serialize(&self, ext: bool) -> Option<Vec<u8>> {
         match self {
             ReturnEvent::Packet(p) => Some(p.serialize(ext)),
-            ReturnEvent::DlError(e) => {
-                eprintln!("TODO: DLERROR: {e}");
-                None
-            }
+            ReturnEvent::DlError(_) => None,
             ReturnEvent::Data(d) => {
                 debug!("Data received: {d:?}");
                 None
@@ -92,6 +114,15 @@ impl ReturnEvent {
 /// DLErrors (C4.3, page 81)
 ///
 /// Error codes of all kinds.
+///
+/// Not all of them are produced by this implementation:
+/// * B: A DM in a connected state is always E.
+/// * L, M, N: Only for what `Packet::parse()` can tell from the frame alone.
+///   Frames that fail to parse never reach the state machine.
+/// * P: Only for peers sending more than the window without waiting for an
+///   ACK. See `Data::strict_window()`.
+///   Frames otherwise out of order are discarded, and REJed or SREJed.
+/// * V: There's no limit on the number of connections.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum DlError {
     A,
@@ -118,6 +149,9 @@ pub enum DlError {
     V,
 }
 
+/// Callback for data link errors, e.g. to react to a link timeout.
+pub type DlErrorFn = Box<dyn Fn(DlError) + Send>;
+
 // Page 81.
 impl std::fmt::Display for DlError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
@@ -165,14 +199,53 @@ impl std::fmt::Display for DlError {
 pub enum Action {
     State(Box<dyn State>),
     DlError(DlError),
-    SendUa { pf: bool },
-    SendRr { pf: bool, nr: u8, command: bool },
-    SendRej { pf: bool, nr: u8 },
-    SendRnr { pf: bool, nr: u8, command: bool },
-    SendDisc { pf: bool },
+    SendUa {
+        pf: bool,
+    },
+    SendRr {
+        pf: bool,
+        nr: u8,
+        command: bool,
+    },
+    SendRej {
+        pf: bool,
+        nr: u8,
+    },
+    SendSrej {
+        pf: bool,
+        nr: u8,
+    },
+    // Connectionless, so the destination can't be taken from the connection.
+    SendTest {
+        dst: Addr,
+        pf: bool,
+        payload: Vec<u8>,
+    },
+    // Also sent before a connection exists, so addressed like TEST.
+    SendXid {
+        dst: Addr,
+        pf: bool,
+    },
+    SendRnr {
+        pf: bool,
+        nr: u8,
+        command: bool,
+    },
+    SendDisc {
+        pf: bool,
+    },
     SendIframe(Iframe),
-    SendDm { pf: bool },
-    SendSabm { pf: bool },
+    SendDm {
+        pf: bool,
+    },
+    // Refusal of someone who is not, and won't become, the peer.
+    SendDmTo {
+        dst: Addr,
+        pf: bool,
+    },
+    SendSabm {
+        pf: bool,
+    },
     Deliver(Vec<u8>),
     EOF,
 }
@@ -182,6 +255,18 @@ pub enum Action {
 /// Linux uses 10s, but I feel that's too long.
 pub const DEFAULT_SRT: std::time::Duration = std::time::Duration::from_secs(3);
 
+/// Highest T1V is allowed to back off to.
+///
+/// The spec formula doubles T1V on every retry, with no limit. With the
+/// default SRT and N2 that would end up waiting for over an hour.
+pub const MAX_T1V: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Lowest the smoothed roundtrip time is allowed to go.
+///
+/// On a fast link, like a local pipe, the measured roundtrip can be close to
+/// zero, and scheduling jitter would then cause needless retransmissions.
+pub const MIN_SRT: std::time::Duration = std::time::Duration::from_millis(10);
+
 /// Default maximum outgoing frame size.
 ///
 /// This is the transmitting part of what the spec calls `N1`.
@@ -203,11 +288,29 @@ pub const DEFAULT_MTU_OUT: usize = 256;
 /// Default maximum incoming frame size.
 pub const DEFAULT_MTU_IN: usize = 65535;
 
-// Output buffer size is kept in RAM, so should not grow unbounded.
-//
-// At the expected speeds, 100MB is way more than what we should expect to
-// send in any connection.
-const MAX_OBUF_SIZE: usize = 100_000_000;
+/// Default max size of the output buffer.
+///
+/// Output buffer is kept in RAM, so should not grow unbounded. At the expected
+/// speeds, 100MB is way more than what we should expect to send in any
+/// connection.
+pub const MAX_OBUF_SIZE: usize = 100_000_000;
+
+/// PID of I frames carrying a segment of a larger message.
+const PID_SEGMENT: u8 = 0x08;
+
+/// Application bytes in an I frame payload, not counting segment headers.
+#[must_use]
+fn app_len(pid: u8, payload: &[u8]) -> usize {
+    match (pid, payload.first()) {
+        (PID_SEGMENT, Some(h)) if h & 0x80 != 0 => payload.len().saturating_sub(2),
+        (PID_SEGMENT, _) => payload.len().saturating_sub(1),
+        _ => payload.len(),
+    }
+}
+
+/// Max number of segments in a message, since the segment header has 7 bits
+/// for how many are left.
+const MAX_SEGMENTS: usize = 128;
 
 /// "T3 should be greater than T1". 6.7.1.3.
 /// Linux uses 5min.
@@ -290,6 +393,17 @@ impl Timer {
     }
 }
 
+/// Callback deciding if a peer is allowed to connect.
+pub type AcceptFilterFn = Box<dyn Fn(&Addr) -> bool + Send>;
+
+struct AcceptFilter(AcceptFilterFn);
+
+impl std::fmt::Debug for AcceptFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "AcceptFilter")
+    }
+}
+
 /// Connection (or socket, if you will) extra data.
 ///
 /// The state object only carries the state itself. Further data is in this
@@ -332,6 +446,11 @@ pub struct Data {
     /// The name here is made up.
     t3v: std::time::Duration,
 
+    /// Disconnect after being idle this long, as per `idle_time()`.
+    ///
+    /// Not in the spec.
+    idle_timeout: Option<std::time::Duration>,
+
     /// Send state variable.
     ///
     /// This is the sequence number of the next frame that this node will send,
@@ -360,10 +479,17 @@ pub struct Data {
 
     /// Smoothed round trip time.
     ///
-    /// TODO: Don't just keep this fixed.
+    /// Starts out as `srt_default`, and is then updated from measured
+    /// roundtrips.
     srt: std::time::Duration,
 
-    /// Next value for T1; default initial value is initial value of SRT.
+    /// SRT has been set from at least one measured roundtrip.
+    srt_sampled: bool,
+
+    /// Next value for T1.
+    ///
+    /// Derived from SRT when things go well, and backed off on retries. All
+    /// starts of T1 use this value.
     t1v: std::time::Duration,
 
     /// Max incoming packet payload size.
@@ -380,6 +506,9 @@ pub struct Data {
     /// (SABM(E)) or connection (other frames) is aborted.
     n2: u8,
 
+    /// N2 set by the application, instead of the default.
+    n2_config: Option<u8>,
+
     /// Current retry counter.
     ///
     /// The current value counting towards N2.
@@ -403,22 +532,49 @@ pub struct Data {
     /// In a modern spec, able to establish and not would be separate states.
     pub(crate) able_to_establish: bool,
 
-    /// An SREJ has been sent to the remote end.
+    /// Decide which peers are allowed to connect.
+    ///
+    /// If set, and it returns false, then incoming connections are answered
+    /// with DM.
+    accept_filter: Option<AcceptFilter>,
+
+    /// Frames we've sent SREJ for, and are still waiting for.
     ///
-    /// TODO: this counts outstanding SREJs?
-    sreject_exception: u32,
+    /// The spec counts these as `SReject Exception`, but to not request the
+    /// same frame twice we need to know which they are.
+    srej_requested: BTreeSet<u8>,
+
+    /// Received out of order frames (PID and payload), waiting for the gap
+    /// before them to be filled.
+    reorder_buffer: BTreeMap<u8, (u8, Vec<u8>)>,
 
-    /// We are busy.
+    /// Frames delivered from the reorder buffer.
     ///
-    /// TODO: check if we'd ever actually set this to true. The receive window
-    /// is so small that the NR/NS will wrap around way before we get "full".
+    /// Without SREJ the peer goes back and resends everything after the gap,
+    /// so these will arrive again, and should be silently discarded.
+    reorder_delivered: BTreeSet<u8>,
+
+    /// We are busy, either because the application said so, or because the
+    /// connection is paused.
     own_receiver_busy: bool,
 
+    /// The application has set us busy, to stop the peer from sending.
+    receiver_busy: bool,
+
     /// ACK, like RR, RNR, or IFRAME, pending.
     acknowledge_pending: bool,
 
-    /// This implementation doesn't yet implement SREJ, so this
-    /// is always falso for now.
+    /// N(R) last sent to the peer.
+    ///
+    /// The peer may not send more than k frames past this, before receiving a
+    /// newer N(R).
+    nr_sent: u8,
+
+    /// Use SREJ to request only the missing frames, instead of REJ
+    /// requesting everything from the first missing frame.
+    ///
+    /// If the peer has sent an XID without SREJ, REJ is used anyway. Without
+    /// an XID from the peer, it's assumed to support SREJ.
     srej_enabled: bool,
 
     /// Maximum number of iframes outstanding.
@@ -437,11 +593,13 @@ pub struct Data {
     ///   hilighted.
     k: u8,
 
-    // TODO: not the right type. Should be VecDeque<u8> or VecDeque<Iframe>
-    //
-    // TODO: this is not currently used, but should be. Either as is, or
-    // a byte queue maximizing packet size.
-    iframe_queue: Vec<Vec<u8>>,
+    /// Window size set by the application, instead of the default for the
+    /// modulus. Limited to the max allowed by the modulus in use.
+    window_config: Option<u8>,
+
+    /// Payload buffers of acked iframes, reused by `flush()` for the copy of
+    /// new iframes kept in the resend queue.
+    spare_payloads: Vec<Vec<u8>>,
 
     /// Output buffer of application payload bytes.
     ///
@@ -449,12 +607,100 @@ pub struct Data {
     /// transmitter business allows.
     obuf: VecDeque<u8>,
 
-    /// MTU for this connection.
+    /// Max bytes waiting to be sent. Clients wait for, or refuse, writes
+    /// that don't fit.
+    max_obuf: usize,
+
+    /// Frames (PID and payload) to send before anything in `obuf`.
+    ///
+    /// Segments must be sent as is, and so do unacked frames requeued after a
+    /// reset, since they may be segments.
+    oframes: VecDeque<(u8, Vec<u8>)>,
+
+    /// Split application writes bigger than the MTU into segments.
+    segmentation: bool,
+
+    /// Segmented message being reassembled, with the number of segments
+    /// left.
+    reassembly: Option<(u8, Vec<u8>)>,
+
+    /// MTU set by the application.
+    mtu_config: usize,
+
+    /// MTU for this connection. Lowered by XID from the peer.
     mtu_out: usize,
 
     /// When an IFRAME is sent out, it's stared in this queue, until it's been
     /// acked. When a resend is required, it's sent from here.
     iframe_resend_queue: VecDeque<Iframe>,
+
+    /// When each iframe in the resend queue was sent, by N(S).
+    ///
+    /// Retransmitted frames are removed, since it's then not known which
+    /// transmission an ack is for (Karn's algorithm).
+    iframe_sent_at: BTreeMap<u8, std::time::Instant>,
+
+    /// Total application payload bytes accepted for sending.
+    bytes_written: u64,
+
+    /// Total application payload bytes acked by the remote end.
+    ///
+    /// Since the bytes are sent in order, this is also the stream offset up to
+    /// which everything has been delivered.
+    bytes_acked: u64,
+
+    /// On peer initiated link reset, put unacked data back in the output
+    /// buffer instead of dropping it.
+    requeue_on_reset: bool,
+
+    /// The last connection attempt was refused with a DM.
+    connect_refused: bool,
+
+    /// When application payload was last sent or received.
+    last_activity: std::time::Instant,
+
+    /// The link was established by the peer UAing our SABM(E).
+    ///
+    /// Nothing else we send while connected is answered with UA, so any UA
+    /// received after that is a duplicate. E.g. the peer answering a
+    /// retransmitted SABM(E), or a digipeater repeating the UA twice.
+    connected_by_ua: bool,
+
+    /// Our DISC crossed with the peer's, and we went disconnected without
+    /// waiting for the UA to ours. That UA is expected, and not an error.
+    disc_ua_pending: bool,
+
+    /// The application has paused the connection.
+    ///
+    /// While paused, the peer is told we're busy, written data is only
+    /// buffered, and T3 doesn't probe the peer.
+    paused: bool,
+
+    /// Adopt the peer's N(S) of the first iframe as V(R), if it's not zero.
+    lenient_initial_seq: bool,
+
+    /// An iframe has been received since the link was set up.
+    iframe_received: bool,
+
+    /// Hold the peer to our k, not just to the protocol max window.
+    strict_window: bool,
+
+    /// Adapt k to the link, growing it while frames are acked, and
+    /// shrinking it on retransmissions.
+    adaptive_window: bool,
+
+    /// Frames acked since k was last changed. Only used for adaptive window.
+    acked_since_resize: usize,
+
+    /// V(S) when k was last shrunk by SREJ. Frames sent before that are
+    /// from the same loss event, and don't shrink it again.
+    srej_resize_vs: Option<u8>,
+
+    /// Highest modulus to accept incoming connections with. 8 refuses SABME.
+    max_modulus: u8,
+
+    /// Parameters from the last XID received, and who sent it.
+    peer_xid: Option<(Addr, Xid)>,
 }
 
 impl Data {
@@ -471,28 +717,59 @@ impl Data {
             va: 0,
             vr: 0,
             srt_default: DEFAULT_SRT,
+            srt_sampled: false,
             srt: DEFAULT_SRT,
-            t1v: DEFAULT_SRT,
+            t1v: DEFAULT_SRT + DEFAULT_SRT,
             t3v: DEFAULT_T3V,
+            idle_timeout: None,
             n2: DEFAULT_N2,
+            n2_config: None,
             rc: 0,
             k: 7,
+            window_config: None,
             modulus: 8,
             peer_receiver_busy: false,
             reject_exception: false,
-            sreject_exception: 0,
+            srej_requested: BTreeSet::new(),
+            reorder_buffer: BTreeMap::new(),
+            reorder_delivered: BTreeSet::new(),
             srej_enabled: false,
             acknowledge_pending: false,
+            nr_sent: 0,
             own_receiver_busy: false,
-            iframe_queue: Vec::new(),
+            receiver_busy: false,
+            spare_payloads: Vec::new(),
+            mtu_config: DEFAULT_MTU_OUT,
             mtu_out: DEFAULT_MTU_OUT,
+            max_obuf: MAX_OBUF_SIZE,
             obuf: VecDeque::new(),
+            oframes: VecDeque::new(),
+            segmentation: false,
+            reassembly: None,
             iframe_resend_queue: VecDeque::new(),
+            iframe_sent_at: BTreeMap::new(),
             able_to_establish: false,
+            accept_filter: None,
+            bytes_written: 0,
+            bytes_acked: 0,
+            requeue_on_reset: false,
+            connect_refused: false,
+            last_activity: std::time::Instant::now(),
+            connected_by_ua: false,
+            disc_ua_pending: false,
+            paused: false,
+            lenient_initial_seq: false,
+            strict_window: false,
+            adaptive_window: false,
+            acked_since_resize: 0,
+            srej_resize_vs: None,
+            max_modulus: 128,
+            peer_xid: None,
+            iframe_received: false,
         }
     }
 
-    /// Set default T1 timer / smoothed roundtrip.
+    /// Set default smoothed roundtrip. T1 starts out at twice this.
     pub fn srt_default(&mut self, v: std::time::Duration) {
         self.srt_default = v;
     }
@@ -502,11 +779,236 @@ impl Data {
         self.t3v = v;
     }
 
+    /// Set idle timeout, after which the connection is disconnected.
+    ///
+    /// Idle as in `idle_time()`, so T3 polls don't count as activity. Idleness
+    /// is checked when T3 expires, so the disconnect can come up to T3 later
+    /// than this.
+    pub fn idle_timeout(&mut self, v: std::time::Duration) {
+        self.idle_timeout = Some(v);
+    }
+
     /// Set MTU.
     pub fn mtu(&mut self, v: usize) {
+        self.mtu_config = v;
         self.mtu_out = v;
     }
 
+    /// Set max size of the output buffer. Default `MAX_OBUF_SIZE`.
+    pub fn max_obuf(&mut self, v: usize) {
+        self.max_obuf = v;
+    }
+
+    /// Set max incoming payload size (N1).
+    ///
+    /// Bigger I frames reset the link, with DlError::O. Bigger UI frames are
+    /// dropped, with DlError::R.
+    pub fn mtu_in(&mut self, v: usize) {
+        self.n1 = v;
+    }
+
+    /// Set whether unacked data should be retransmitted after the peer
+    /// resets the link with a SABM(E), instead of being dropped.
+    ///
+    /// The spec says to discard it, but that breaks the illusion of a
+    /// reliable stream for the application.
+    pub fn requeue_on_reset(&mut self, v: bool) {
+        self.requeue_on_reset = v;
+    }
+
+    /// Set whether to use SREJ to recover from lost incoming frames.
+    ///
+    /// The peer must support SREJ. REJ is used instead if disabled, or if the
+    /// peer's XID says it doesn't support SREJ. REJ makes the peer resend
+    /// everything from the first lost frame.
+    pub fn srej(&mut self, v: bool) {
+        self.srej_enabled = v;
+    }
+
+    /// Enable segmentation of writes bigger than the MTU.
+    ///
+    /// The peer must support reassembly. Received segments are always
+    /// reassembled.
+    pub fn segmentation(&mut self, v: bool) {
+        self.segmentation = v;
+    }
+
+    /// Set whether to accept a peer starting its sequence numbers at
+    /// something other than zero.
+    ///
+    /// Non-compliant peers have been seen doing this, and without this option
+    /// no data can be received from them.
+    ///
+    /// The first N(S) received is adopted, so if the peer's first frame was
+    /// lost, its data is skipped.
+    pub fn lenient_initial_seq(&mut self, v: bool) {
+        self.lenient_initial_seq = v;
+    }
+
+    /// Set whether the peer may have at most k unacknowledged frames
+    /// outstanding, as opposed to the protocol max of 7 (or 127).
+    ///
+    /// Without XID there's no way to tell the peer our k, so only enable
+    /// this when the peer is known to be configured with the same k.
+    pub fn strict_window(&mut self, v: bool) {
+        self.strict_window = v;
+    }
+
+    /// Set whether to adapt the window size (k) to the link.
+    ///
+    /// k starts at 1 when the link is set up, grows by one for every k frames
+    /// acked, up to the protocol max or the peer's XID window, and halves on
+    /// retransmission. This is like TCP congestion control.
+    pub fn adaptive_window(&mut self, v: bool) {
+        self.adaptive_window = v;
+    }
+
+    /// Set the max number of outstanding frames (k), instead of the default 4
+    /// for mod-8 and 32 for mod-128.
+    ///
+    /// Limited to 7 for mod-8, and 127 for mod-128. With adaptive window this
+    /// is the max the window grows to.
+    pub fn window(&mut self, k: u8) {
+        self.window_config = Some(k);
+        self.k = self.configured_window(self.k);
+    }
+
+    /// Set the max number of retries (N2), instead of the default 10.
+    pub fn n2(&mut self, v: u8) {
+        let v = std::cmp::max(1, v);
+        self.n2_config = Some(v);
+        self.n2 = v;
+    }
+
+    /// Return the window size set by the application, or `default`.
+    ///
+    /// Limited to what the current modulus allows.
+    #[must_use]
+    fn configured_window(&self, default: u8) -> u8 {
+        self.window_config
+            .unwrap_or(default)
+            .clamp(1, self.modulus - 1)
+    }
+
+    /// Set the highest modulus to accept incoming connections with.
+    ///
+    /// With 8, extended (mod-128) connections are refused with a DM, making
+    /// the peer fall back to mod-8.
+    pub fn max_modulus(&mut self, v: u8) {
+        self.max_modulus = v;
+    }
+
+    /// Change the local address.
+    ///
+    /// All frames sent after this use the new address.
+    #[cfg(feature = "async")]
+    pub(crate) fn set_me(&mut self, me: Addr) {
+        self.me = me;
+    }
+
+    /// Set whether to accept incoming connections.
+    ///
+    /// When not listening, an incoming SABM(E) is refused with DM. Off by
+    /// default.
+    pub fn listen(&mut self, v: bool) {
+        self.able_to_establish = v;
+    }
+
+    /// Set a filter for which peers are allowed to connect.
+    pub fn accept_filter(&mut self, f: AcceptFilterFn) {
+        self.accept_filter = Some(AcceptFilter(f));
+    }
+
+    /// Return true if the peer is allowed to connect.
+    #[must_use]
+    fn accept_allowed(&self, peer: &Addr) -> bool {
+        self.accept_filter.as_ref().is_none_or(|f| (f.0)(peer))
+    }
+
+    /// Return true if the last connection attempt was refused by the peer, as
+    /// opposed to timing out.
+    #[must_use]
+    pub fn connect_refused(&self) -> bool {
+        self.connect_refused
+    }
+
+    /// Time since an I-frame with payload was last sent or received, or the
+    /// connection was established.
+    ///
+    /// Unlike T3, this is not reset by protocol traffic such as polls, so it
+    /// measures application level idleness. Retransmissions don't count.
+    #[must_use]
+    pub fn idle_time(&self) -> std::time::Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Return true if the application has set us busy.
+    #[must_use]
+    pub fn receiver_busy(&self) -> bool {
+        self.receiver_busy
+    }
+
+    /// Return true if the connection is paused.
+    #[must_use]
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Return the retry count, and how many retries until giving up.
+    ///
+    /// The link is torn down when the first reaches the second.
+    #[must_use]
+    pub fn retry_state(&self) -> (u8, u8) {
+        (self.rc, self.n2)
+    }
+
+    /// Return the peer of the current, or last, connection.
+    #[must_use]
+    pub fn peer(&self) -> Option<&Addr> {
+        self.peer.as_ref()
+    }
+
+    /// Number of iframes sent but not yet acked.
+    #[must_use]
+    pub fn outstanding(&self) -> usize {
+        self.iframe_resend_queue.len()
+    }
+
+    /// Bytes written by the application, but not yet sent in any frame.
+    #[must_use]
+    pub fn obuf_len(&self) -> usize {
+        self.obuf.len()
+            + self
+                .oframes
+                .iter()
+                .map(|(pid, payload)| app_len(*pid, payload))
+                .sum::<usize>()
+    }
+
+    /// Bytes that can be written before the output buffer is full.
+    #[must_use]
+    pub fn obuf_room(&self) -> usize {
+        self.max_obuf.saturating_sub(self.obuf_len())
+    }
+
+    /// Max outgoing payload size.
+    #[must_use]
+    pub fn mtu_out(&self) -> usize {
+        self.mtu_out
+    }
+
+    /// Total payload bytes written by the application.
+    #[must_use]
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Total payload bytes acked by the remote end.
+    #[must_use]
+    pub fn bytes_acked(&self) -> u64 {
+        self.bytes_acked
+    }
+
     /// Return true if using 128 modulus.
     #[must_use]
     pub fn ext(&self) -> bool {
@@ -561,17 +1063,17 @@ impl Data {
     #[must_use]
     fn ui_check(&self, command: bool, len: usize) -> Vec<Action> {
         if !command {
-            // 1998 Spec bug: error Q says this is also for UI frames with Poll set.
+            // 1998 Spec bug: error Q says this is also for UI frames with Poll
+            // set.
             //
-            // But 4.3.3.6 says command+poll is just fine, and should just trigger
-            // DM.
-            //
-            // So probably the code as-is, is correct, and the Q error message
-            // should be changed.
+            // But 4.3.3.6 says command+poll is just fine, and should just
+            // trigger a response. So Q is only for UI responses.
             return vec![Action::DlError(DlError::Q)];
         }
         if len > self.n1 {
-            return vec![Action::DlError(DlError::K)];
+            // 1998 & 2017 spec says K, which is undocumented. R is the
+            // documented error for too big UI frames.
+            return vec![Action::DlError(DlError::R)];
         }
         debug!("DL-UNIT_DATA indication");
         vec![]
@@ -667,6 +1169,10 @@ impl Data {
     /// Page 107.
     #[must_use]
     fn invoke_retransmission(&mut self, _nr: u8) -> Vec<Action> {
+        if !self.iframe_resend_queue.is_empty() {
+            self.window_loss();
+        }
+        self.iframe_sent_at.clear();
         self.iframe_resend_queue
             .iter()
             .map(|i| Action::SendIframe(i.clone()))
@@ -675,10 +1181,8 @@ impl Data {
 
     /// Select a new T1 value based off of the roundtrip time.
     ///
-    /// TODO: actually implement this. Maybe the algorithm in the spec, maybe
-    /// something better.
-    ///
-    /// TODO: Is this supposed to set only SRT, or also T1V?
+    /// SRT is the smoothed roundtrip time, and T1V is derived from it. On
+    /// retries T1V backs off, but SRT is left alone.
     ///
     /// Page 109.
     fn select_t1_value(&mut self) {
@@ -687,19 +1191,72 @@ impl Data {
         //
         // Or maybe we set rc=0 everywhere we enter Connected?
         if self.rc == 0 {
-            // TODO: the real formula is stranger.
-            self.srt = self.srt_default;
-        } else if self.t1_expired() {
+            // The spec smoothes SRT here, using the time left on T1 as the
+            // roundtrip. That's done in `update_srt()` instead, with the
+            // actual time each frame took to be acked.
+            self.t1v = self.srt + self.srt;
+        } else if self.t1_passed() {
             // 1998 spec says:
-            // self.srt = self.srt * (2 ** (rc + 1));
+            // self.t1v = self.srt * (2 ** (rc + 1));
 
             // 2017 spec formula.
             // It's unclear what unit `rc` is supposed to be. It's retry
             // counter. I'll assume seconds, to millisecond resolution.
-            // SRT = RC / 4 + SRT*2
+            // T1V = RC / 4 + T1V*2
             let t = std::time::Duration::from_millis(self.rc as u64 * 250);
-            self.srt = t + self.srt + self.srt;
+            self.t1v = std::cmp::min(MAX_T1V, t + self.t1v + self.t1v);
+        }
+    }
+
+    /// Give up the connection attempt in progress after `n2` retries.
+    ///
+    /// Stays in effect until the next connection or `restore_n2()`.
+    #[cfg(feature = "async")]
+    pub(crate) fn limit_retries(&mut self, n2: u8) {
+        self.n2 = std::cmp::min(self.n2, n2);
+    }
+
+    /// Go back to the configured N2, after `limit_retries()`.
+    #[cfg(feature = "async")]
+    pub(crate) fn restore_n2(&mut self) {
+        self.n2 = self.n2_config.unwrap_or(DEFAULT_N2);
+        self.apply_peer_xid();
+    }
+
+    /// Start over with the default SRT, and T1 derived from it.
+    fn reset_srt(&mut self) {
+        self.srt = self.srt_default;
+        self.srt_sampled = false;
+        self.t1v = self.srt + self.srt;
+    }
+
+    /// Update SRT with a measured roundtrip.
+    ///
+    /// Same smoothing as the spec (page 109): SRT = 7/8 * SRT + 1/8 * RTT.
+    /// Except the first measurement replaces the default outright, like TCP
+    /// does (RFC 6298), since the default may be way off.
+    fn update_srt(&mut self, rtt: std::time::Duration) {
+        let srt = if self.srt_sampled {
+            (self.srt * 7 + rtt) / 8
+        } else {
+            rtt
+        };
+        self.srt = std::cmp::max(MIN_SRT, srt);
+        self.srt_sampled = true;
+        if self.rc == 0 {
+            // Not backing off, so T1 follows SRT right away.
+            self.t1v = self.srt + self.srt;
         }
+        debug!("Roundtrip {rtt:?}, SRT now {:?}", self.srt);
+    }
+
+    /// Return true if T1 has run to completion.
+    ///
+    /// Unlike `t1_expired()`, this is also true if T1 has been stopped since.
+    /// T1 expiry handlers stop the timer before selecting a new T1 value.
+    #[must_use]
+    fn t1_passed(&self) -> bool {
+        std::time::Instant::now() >= self.t1.expiry
     }
 
     /// Ask remote end if they're there, what they heard last.
@@ -739,7 +1296,7 @@ impl Data {
             // with 2017.
             self.t3.stop();
             if !self.t1.running {
-                self.t1.start(self.srt); // srt or t1v?
+                self.t1.start(self.t1v);
             }
             self.update_ack(nr)
         } else if nr == self.vs {
@@ -750,7 +1307,7 @@ impl Data {
         } else if nr != self.va {
             // 1998 spec says "restart", 2017 spec just "start". They probably
             // mean the same thing, right?
-            self.t1.restart(self.srt);
+            self.t1.restart(self.t1v);
             self.update_ack(nr)
         } else {
             vec![]
@@ -765,43 +1322,226 @@ impl Data {
     /// complexity.
     #[must_use]
     fn update_ack(&mut self, nr: u8) -> Vec<Action> {
+        self.prune_acked(nr);
+        self.flush()
+    }
+
+    /// Like `update_ack()`, but without sending anything new.
+    ///
+    /// For when unacked frames are to be retransmitted first, since they'd
+    /// otherwise include the new ones.
+    fn prune_acked(&mut self, nr: u8) {
         // dbg!(self.va, nr);
         // debug!("Updating ack to {} {}", self.va, nr);
+        let mut rtt = None;
         while self.va != nr {
-            assert!(!self.iframe_resend_queue.is_empty());
-            self.iframe_resend_queue.pop_front();
+            let acked = self
+                .iframe_resend_queue
+                .pop_front()
+                .expect("acked iframe not in resend queue");
+            // The newest frame acked is the best measurement, since the
+            // others may have waited for it to be acked together.
+            if let Some(sent) = self.iframe_sent_at.remove(&acked.ns) {
+                rtt = Some(sent.elapsed());
+            }
+            self.bytes_acked += app_len(acked.pid, &acked.payload) as u64;
+            self.recycle_payload(acked.payload);
             self.va = (self.va + 1) % self.modulus;
+            if self.srej_resize_vs == Some(self.va) {
+                self.srej_resize_vs = None;
+            }
+            self.window_acked();
         }
-        self.flush()
+        if let Some(rtt) = rtt {
+            self.update_srt(rtt);
+        }
+    }
+
+    /// With adaptive window, grow k after a window's worth of frames acked.
+    ///
+    /// Never past the configured window, or the one the peer sent in XID.
+    fn window_acked(&mut self) {
+        if !self.adaptive_window {
+            return;
+        }
+        let max = std::cmp::min(
+            self.configured_window(self.modulus - 1),
+            self.current_peer_xid()
+                .and_then(Xid::window_size)
+                .map_or(u8::MAX, |k| std::cmp::max(1, k)),
+        );
+        self.acked_since_resize += 1;
+        if self.acked_since_resize >= self.k as usize && self.k < max {
+            self.k += 1;
+            self.acked_since_resize = 0;
+            debug!("Adaptive window grew to {}", self.k);
+        }
+    }
+
+    /// With adaptive window, halve k because frames were lost.
+    fn window_loss(&mut self) {
+        if !self.adaptive_window {
+            return;
+        }
+        self.k = std::cmp::max(1, self.k / 2);
+        self.acked_since_resize = 0;
+        debug!("Adaptive window shrunk to {}", self.k);
+    }
+
+    /// Like `window_loss()`, but for iframe `ns` being SREJed.
+    ///
+    /// Several frames lost in one go are all SREJed, but only shrink k once.
+    fn window_loss_srej(&mut self, ns: u8) {
+        let m = self.modulus;
+        if let Some(vs) = self.srej_resize_vs {
+            if (ns + m - self.va) % m < (vs + m - self.va) % m {
+                return;
+            }
+        }
+        self.window_loss();
+        self.srej_resize_vs = Some(self.vs);
     }
 
     /// Clear iframe queue.
     ///
     /// This probably means connection shutdown.
     fn clear_iframe_queue(&mut self) {
-        self.iframe_queue.clear();
         self.iframe_resend_queue.clear();
+        self.iframe_sent_at.clear();
+    }
+
+    /// Drop all data waiting to be sent, and start the byte counters over.
+    ///
+    /// Data written while connecting must not be sent on a later connection,
+    /// possibly to another peer, if this attempt is given up.
+    fn clear_obuf(&mut self) {
+        self.obuf.clear();
+        self.oframes.clear();
+        self.bytes_written = 0;
+        self.bytes_acked = 0;
+    }
+
+    /// Keep the payload buffer of an acked iframe for reuse.
+    ///
+    /// No more than a full extended mode window is kept.
+    fn recycle_payload(&mut self, mut payload: Vec<u8>) {
+        if self.spare_payloads.len() < 127 {
+            payload.clear();
+            self.spare_payloads.push(payload);
+        }
+    }
+
+    /// Put all unacked iframes back at the front of the output queue, in
+    /// order.
+    fn requeue_unacked(&mut self) {
+        self.iframe_sent_at.clear();
+        for i in self.iframe_resend_queue.drain(..).rev() {
+            self.oframes.push_front((i.pid, i.payload));
+        }
+    }
+
+    /// Store an out of order iframe, and SREJ any newly found gaps before it.
+    ///
+    /// All the SREJs have F=0, except the last which has F=1 if the iframe
+    /// was a poll. If there's no new gap to request, a poll is answered with
+    /// RR instead.
+    ///
+    /// Frames further ahead than the window are discarded. Mostly they'd be
+    /// duplicates of already received frames.
+    #[must_use]
+    fn srej_gaps(&mut self, p: &Iframe) -> Vec<Action> {
+        self.acknowledge_pending = false;
+        let ahead = (p.ns + self.modulus - self.vr) % self.modulus;
+        let mut act = Vec::new();
+        // A resend of a frame already delivered looks like it's ahead of V(R)
+        // too, so only frames certainly ahead are stored.
+        if self.reorder_unambiguous(p.ns) {
+            self.srej_requested.remove(&p.ns);
+            self.reorder_buffer.insert(p.ns, (p.pid, p.payload.clone()));
+            let missing: Vec<u8> = (0..ahead)
+                .map(|n| (self.vr + n) % self.modulus)
+                .filter(|ns| !self.reorder_buffer.contains_key(ns))
+                .filter(|ns| !self.srej_requested.contains(ns))
+                .collect();
+            let last = missing.len();
+            for (n, nr) in missing.into_iter().enumerate() {
+                debug!("Requesting missing iframe {nr}");
+                self.srej_requested.insert(nr);
+                act.push(Action::SendSrej {
+                    pf: p.poll && n + 1 == last,
+                    nr,
+                });
+            }
+        } else {
+            debug!(
+                "Discarding iframe {}, a duplicate or outside receive window",
+                p.ns
+            );
+        }
+        if act.is_empty() && p.poll {
+            act.push(Action::SendRr {
+                pf: true,
+                nr: self.vr,
+                command: false,
+            });
+        }
+        act
+    }
+
+    /// Return true if the peer sent more frames than the window allows.
+    ///
+    /// Frames already received, and maybe retransmitted because our ACK got
+    /// lost, are not overruns.
+    #[must_use]
+    fn window_overrun(&self, ns: u8) -> bool {
+        let m = self.modulus;
+        let k = if self.strict_window { self.k } else { m - 1 };
+        let ahead = (ns + m - self.nr_sent) % m;
+        let behind = (self.vr + m - 1 - ns) % m;
+        ahead >= k && behind >= k
+    }
+
+    /// Check if an out of order N(S) is certainly ahead of V(R).
+    ///
+    /// It could also be a resend of a frame already received, up to `k`
+    /// behind. Storing one of those would deliver it again when the sequence
+    /// numbers wrap around. E.g. with modulus 8 and k=7 it's never certain.
+    #[must_use]
+    fn reorder_unambiguous(&self, ns: u8) -> bool {
+        let m = self.modulus;
+        let ahead = (ns + m - self.vr) % m;
+        ahead < std::cmp::min(self.k, m - self.k)
     }
 
     /// Clear exception conditions as a new connection is established.
     fn clear_exception_conditions(&mut self) {
+        // Not an exception condition, but a new connection isn't idle.
+        self.last_activity = std::time::Instant::now();
+
         self.peer_receiver_busy = false;
         self.reject_exception = false;
         self.own_receiver_busy = false;
+        self.receiver_busy = false;
         self.acknowledge_pending = false;
 
-        // The following added in 2017 spec.
-        self.sreject_exception = 0;
+        self.iframe_received = false;
 
-        // Huh? Clearing the iframe queue inside a subroutine called "clear
-        // exception conditions"? That doesn't seem right.
-        //
-        // This is new in the 2017 spec.
-        //
-        // I'm going to leave it here because when exception conditions are
-        // unconditionally cleared, it's because a connection was just reset in
-        // one way or another.
-        self.iframe_queue.clear();
+        if self.adaptive_window {
+            self.k = 1;
+            self.acked_since_resize = 0;
+            self.srej_resize_vs = None;
+        }
+
+        // The following added in 2017 spec.
+        self.srej_requested.clear();
+        self.reorder_buffer.clear();
+        self.reorder_delivered.clear();
+        self.reassembly = None;
+
+        // The 2017 spec also clears the iframe queue here, but that's data the
+        // application has written and not yet sent. When exception conditions
+        // are cleared it's because the connection was just reset, so the
+        // resend queue is dealt with by the caller, and unsent data stays.
     }
 
     /// Establish data link.
@@ -818,65 +1558,385 @@ impl Data {
         self.rc = 1;
         self.t3.stop();
         // Again 1998 spec says restart, 2017 says start.
-        self.t1.restart(self.srt); // TODO: srt or t1v?
+        self.t1.restart(self.t1v);
 
         // SendSabm actually sends SABME if modulus is 128.
         Action::SendSabm { pf: true }
     }
 
+    /// Change the modulus, unless there are frames in flight.
+    ///
+    /// Frames in the resend queue are numbered with the old modulus, so
+    /// changing it under them would corrupt `update_ack` and `in_range`. The
+    /// caller must first clear or requeue them. Returns false, leaving the
+    /// modulus unchanged, if there are still frames in flight.
+    fn set_modulus(&mut self, modulus: u8) -> bool {
+        if self.modulus == modulus {
+            return true;
+        }
+        if !self.iframe_resend_queue.is_empty() {
+            warn!(
+                "Refusing modulus change {} -> {} with {} frames in flight",
+                self.modulus,
+                modulus,
+                self.iframe_resend_queue.len()
+            );
+            return false;
+        }
+        self.modulus = modulus;
+        true
+    }
+
     /// Set values for extended sequence number connection.
     ///
     /// Page 109.
     pub(crate) fn set_version_2_2(&mut self) {
         // TODO: set half duplex SREJ
-        self.modulus = 128;
+        if !self.set_modulus(128) {
+            return;
+        }
         // TODO: n1r = 2048
 
         // 1998 Spec bug: Spec says `kr`. Surely it means `k`?
-        self.k = 32;
+        self.k = self.configured_window(32);
 
         // TODO: self.t2.set(3000);
-        self.n2 = 10;
+        self.n2 = self.n2_config.unwrap_or(DEFAULT_N2);
+        self.apply_peer_xid();
     }
 
     /// Set values for mod-8 connections.
     ///
     /// Page 109.
     fn set_version_2(&mut self) {
-        self.modulus = 8;
+        if !self.set_modulus(8) {
+            return;
+        }
         // TODO: n1r = 2048
 
         // 1998 Spec bug: Spec says `kr`. Surely it means `k`?
-        self.k = 4;
+        self.k = self.configured_window(4);
 
         // TODO: self.t2.set(3000);
-        self.n2 = 10;
+        self.n2 = self.n2_config.unwrap_or(DEFAULT_N2);
+        self.apply_peer_xid();
     }
 
-    // If sequence numbers allow, write as many packets as possible.
-    //
-    // Page 92, "I frame pops off queue".
-    #[must_use]
-    fn flush(&mut self) -> Vec<Action> {
-        if self.peer_receiver_busy {
-            return vec![];
+    /// Store parameters negotiated with XID from `src`.
+    ///
+    /// They stay in effect for the rest of the connection, even across
+    /// resets, but only while `src` is the peer.
+    pub(crate) fn negotiate_xid(&mut self, src: &Addr, xid: &Xid) {
+        self.peer_xid = Some((src.clone(), xid.clone()));
+        self.apply_peer_xid();
+    }
+
+    /// Forget XID parameters, when the link they were negotiated for is
+    /// gone.
+    pub(crate) fn clear_peer_xid(&mut self) {
+        self.peer_xid = None;
+        self.mtu_out = self.mtu_config;
+    }
+
+    /// XID parameters received from `addr`, if any.
+    fn xid_from(&self, addr: &Addr) -> Option<&Xid> {
+        self.peer_xid
+            .as_ref()
+            .filter(|(src, _)| src.call() == addr.call())
+            .map(|(_, xid)| xid)
+    }
+
+    /// XID parameters from the current peer, if any.
+    fn current_peer_xid(&self) -> Option<&Xid> {
+        self.xid_from(self.peer.as_ref()?)
+    }
+
+    /// Return true if parameters with the peer were negotiated with XID.
+    #[must_use]
+    pub fn xid_negotiated(&self) -> bool {
+        self.current_peer_xid().is_some()
+    }
+
+    /// If extended mode is acceptable with `addr`.
+    ///
+    /// Needs both our configuration and the XID from the peer, if any, to
+    /// allow it.
+    fn accepts_extended(&self, addr: &Addr) -> bool {
+        self.max_modulus >= 128 && self.xid_from(addr).and_then(Xid::extended) != Some(false)
+    }
+
+    /// If SREJ is to be used, on our side and per the XID from the peer.
+    fn srej_active(&self) -> bool {
+        self.srej_enabled && self.current_peer_xid().and_then(Xid::srej) != Some(false)
+    }
+
+    /// XID with our parameters, to answer the one from `dst`.
+    fn local_xid(&self, dst: &Addr, poll: bool) -> Xid {
+        Xid::new(
+            poll,
+            self.accepts_extended(dst),
+            self.srej_enabled && self.xid_from(dst).and_then(Xid::srej) != Some(false),
+            self.n1,
+            self.k,
+        )
+    }
+
+    /// Apply XID parameters from the peer on top of the current ones.
+    ///
+    /// Per 6.3.2 the lesser of the window and I field sizes win, and the
+    /// greater of the timer and retries. Features are only used if both
+    /// sides support them.
+    ///
+    /// The modulus is only changed by the next connection or reset, since
+    /// the sequence numbers in use are in the current one. Modulus and SREJ
+    /// are checked via `accepts_extended()` and `srej_active()`, so that the
+    /// configuration is left as the application set it.
+    fn apply_peer_xid(&mut self) {
+        self.mtu_out = self.mtu_config;
+        let Some(xid) = self.current_peer_xid().cloned() else {
+            return;
+        };
+        if let Some(mtu) = xid.mtu() {
+            self.mtu_out = std::cmp::min(self.mtu_out, std::cmp::max(1, mtu));
+        }
+        if let Some(k) = xid.window_size() {
+            self.k = std::cmp::min(self.k, std::cmp::max(1, k));
+        }
+        if let Some(t1) = xid.ack_timer() {
+            self.t1v = std::cmp::max(self.t1v, t1);
+        }
+        if let Some(n2) = xid.retries() {
+            self.n2 = std::cmp::max(self.n2, n2);
+        }
+    }
+
+    /// Add application data to the output buffer, without sending anything.
+    fn buffer_data(&mut self, payload: &[u8]) {
+        if self.segmentation && payload.len() > self.mtu_out {
+            if let Some(segments) = self.segment(NO_L3, payload) {
+                self.obuf_to_oframes();
+                self.oframes.extend(segments);
+                self.buffered(payload.len());
+                return;
+            }
+            warn!(
+                "Write of {} bytes too big to segment, sending unsegmented",
+                payload.len()
+            );
+        }
+        self.obuf.extend(payload);
+        self.buffered(payload.len());
+    }
+
+    /// Check that a write for a layer 3 protocol can be sent in frames of its
+    /// own.
+    ///
+    /// The segmentation PID is reserved, and data bigger than the MTU needs
+    /// segmentation, or the frame boundaries the protocol relies on are lost.
+    pub(crate) fn check_data_with_pid(&self, pid: u8, payload: &[u8]) -> Result<()> {
+        if pid == PID_SEGMENT {
+            return Err(anyhow::Error::msg(format!(
+                "PID {pid:02X} is reserved for segmentation"
+            )));
+        }
+        if pid == NO_L3 || payload.len() <= self.mtu_out {
+            return Ok(());
+        }
+        if self.segmentation && self.segment(pid, payload).is_some() {
+            return Ok(());
+        }
+        Err(anyhow::Error::msg(format!(
+            "write of {} bytes with PID {pid:02X} is bigger than MTU {}, and can't be segmented",
+            payload.len(),
+            self.mtu_out
+        )))
+    }
+
+    /// Add application data for a layer 3 protocol to the output, in frames of
+    /// its own.
+    ///
+    /// It's sent as one frame if it fits the MTU, else as segments if enabled.
+    /// Writes are expected to have passed `check_data_with_pid()`.
+    fn buffer_data_with_pid(&mut self, pid: u8, payload: &[u8]) {
+        if pid == NO_L3 {
+            return self.buffer_data(payload);
+        }
+        // Data written before has to be sent first.
+        self.obuf_to_oframes();
+        if payload.len() > self.mtu_out {
+            match self.segment(pid, payload).filter(|_| self.segmentation) {
+                Some(segments) => self.oframes.extend(segments),
+                None => {
+                    error!(
+                        "Write of {} bytes with PID {pid:02X} is bigger than MTU {}, dropping",
+                        payload.len(),
+                        self.mtu_out
+                    );
+                    return;
+                }
+            }
+        } else {
+            self.oframes.push_back((pid, payload.to_vec()));
+        }
+        self.buffered(payload.len());
+    }
+
+    /// Move the output buffer into frames, ahead of frames with other PIDs.
+    fn obuf_to_oframes(&mut self) {
+        while !self.obuf.is_empty() {
+            let chunk = self
+                .obuf
+                .drain(..std::cmp::min(self.mtu_out, self.obuf.len()))
+                .collect();
+            self.oframes.push_back((NO_L3, chunk));
+        }
+    }
+
+    /// Split a write into segment frames.
+    ///
+    /// The first byte of the reassembled data is the PID of the message, and
+    /// each segment starts with a header of if it's the first, and the
+    /// number of segments left after it.
+    ///
+    /// Returns None if it would take too many segments.
+    #[must_use]
+    fn segment(&self, pid: u8, payload: &[u8]) -> Option<Vec<(u8, Vec<u8>)>> {
+        let chunk = self.mtu_out.checked_sub(1).filter(|&c| c > 0)?;
+        let mut data = Vec::with_capacity(payload.len() + 1);
+        data.push(pid);
+        data.extend(payload);
+        let n = data.len().div_ceil(chunk);
+        if n > MAX_SEGMENTS {
+            return None;
+        }
+        Some(
+            data.chunks(chunk)
+                .enumerate()
+                .map(|(i, c)| {
+                    let first = if i == 0 { 0x80 } else { 0 };
+                    let mut info = vec![first | (n - 1 - i) as u8];
+                    info.extend(c);
+                    (PID_SEGMENT, info)
+                })
+                .collect(),
+        )
+    }
+
+    /// Deliver a received in order frame, reassembling segments.
+    ///
+    /// Broken segment sequences are dropped, since there's no way to ask for
+    /// just the missing part.
+    #[must_use]
+    fn deliver(&mut self, pid: u8, payload: Vec<u8>) -> Option<Action> {
+        if pid != PID_SEGMENT {
+            if self.reassembly.take().is_some() {
+                warn!("Unsegmented frame during reassembly, dropping partial message");
+            }
+            return Some(Action::Deliver(payload));
+        }
+        let Some((&header, rest)) = payload.split_first() else {
+            warn!("Dropping empty segment");
+            return None;
+        };
+        let left = header & 0x7F;
+        let buf = if header & 0x80 != 0 {
+            if self.reassembly.is_some() {
+                warn!("New segmented message before the last one completed");
+            }
+            // Skip the PID of the message.
+            let Some(rest) = rest.get(1..) else {
+                warn!("Dropping first segment without PID");
+                self.reassembly = None;
+                return None;
+            };
+            rest.to_vec()
+        } else {
+            match self.reassembly.take() {
+                Some((l, mut buf)) if l == left + 1 => {
+                    buf.extend(rest);
+                    buf
+                }
+                Some((l, _)) => {
+                    warn!(
+                        "Got segment with {left} left, expected {}. Dropping message",
+                        l - 1
+                    );
+                    return None;
+                }
+                None => {
+                    warn!("Dropping segment without a first segment");
+                    return None;
+                }
+            }
+        };
+        if left == 0 {
+            return Some(Action::Deliver(buf));
+        }
+        self.reassembly = Some((left, buf));
+        None
+    }
+
+    /// Add application data to the front of the output buffer, ahead of other
+    /// data written without a PID.
+    ///
+    /// Frames already queued in `oframes` are still sent first. They may be
+    /// segments of a partly sent message, or unacked frames requeued after a
+    /// reset, and sending anything between them would break them up. Data
+    /// already sent is not reordered either.
+    fn buffer_data_priority(&mut self, payload: &[u8]) {
+        for &b in payload.iter().rev() {
+            self.obuf.push_front(b);
+        }
+        self.buffered(payload.len());
+    }
+
+    fn buffered(&mut self, len: usize) {
+        self.bytes_written += len as u64;
+        // The max isn't enforced here. Clients check `obuf_room()` before
+        // writing, so this only happens when the state machine is fed data
+        // directly without that check.
+        if self.obuf_len() > self.max_obuf {
+            warn!(
+                "Output buffer is over its max size. {} > {}",
+                self.obuf_len(),
+                self.max_obuf
+            );
+        }
+    }
+
+    // If sequence numbers allow, write as many packets as possible.
+    //
+    // Page 92, "I frame pops off queue".
+    #[must_use]
+    fn flush(&mut self) -> Vec<Action> {
+        if self.peer_receiver_busy || self.paused {
+            return vec![];
         }
         let mut act = Vec::new();
         loop {
-            if self.obuf.is_empty() {
+            if self.oframes.is_empty() && self.obuf.is_empty() {
                 break;
             }
-            if self.vs == (self.va + self.k) % self.modulus {
+            // Not just comparing to va+k, since k may have shrunk below what's
+            // already outstanding.
+            if (self.vs + self.modulus - self.va) % self.modulus >= self.k {
                 debug!(
                     "tx window full with more data ({} bytes) to send!",
-                    self.obuf.len()
+                    self.obuf_len()
                 );
                 break;
             }
-            let payload = self
-                .obuf
-                .drain(..std::cmp::min(self.mtu_out, self.obuf.len()))
-                .collect::<Vec<_>>();
+            let (pid, payload) = self.oframes.pop_front().unwrap_or_else(|| {
+                // Reuse the buffer of an acked frame for the copy kept in the
+                // resend queue. The clone in the action is still a new
+                // allocation, so this only saves one of the two per frame.
+                let mut payload = self.spare_payloads.pop().unwrap_or_default();
+                payload.extend(
+                    self.obuf
+                        .drain(..std::cmp::min(self.mtu_out, self.obuf.len())),
+                );
+                (NO_L3, payload)
+            });
             let ns = self.vs;
             self.vs = (self.vs + 1) % self.modulus;
             self.acknowledge_pending = false;
@@ -884,22 +1944,39 @@ impl Data {
             // here. I'm not sure yet.
             if !self.t1.running {
                 self.t3.stop();
-                self.t1.start(self.srt);
+                self.t1.start(self.t1v);
             }
             let i = Iframe {
                 ns,
                 nr: self.vr,
                 poll: false,
-                pid: 0xF0,
+                pid,
                 payload,
             };
-            self.iframe_resend_queue.push_back(i.clone());
-            act.push(Action::SendIframe(i));
+            if !i.payload.is_empty() {
+                self.last_activity = std::time::Instant::now();
+            }
+            self.iframe_sent_at.insert(ns, std::time::Instant::now());
+            act.push(Action::SendIframe(i.clone()));
+            self.iframe_resend_queue.push_back(i);
         }
         act
     }
 }
 
+/// Which state a connection is in.
+///
+/// For when the application needs to know, without having to match on state
+/// names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateKind {
+    Disconnected,
+    AwaitingConnection,
+    AwaitingRelease,
+    Connected,
+    TimerRecovery,
+}
+
 /// State machine for an AX.25 connection.
 ///
 /// Not all events are implemented in all states, but enough.
@@ -908,6 +1985,7 @@ impl Data {
 /// deliberate.
 pub trait State {
     fn name(&self) -> String;
+    fn kind(&self) -> StateKind;
     fn is_state_connected(&self) -> bool {
         false
     }
@@ -936,6 +2014,41 @@ pub trait State {
         vec![]
     }
 
+    /// User initiates sending data with a layer 3 protocol.
+    #[must_use]
+    fn data_with_pid(&self, _data: &mut Data, _pid: u8, _payload: &[u8]) -> Vec<Action> {
+        warn!("Writing data while not connected, in state {}", self.name());
+        vec![]
+    }
+
+    /// User initiates sending data, ahead of already buffered data.
+    #[must_use]
+    fn priority_data(&self, _data: &mut Data, _payload: &[u8]) -> Vec<Action> {
+        warn!("Writing data while not connected, in state {}", self.name());
+        vec![]
+    }
+
+    /// User pauses the connection.
+    #[must_use]
+    fn pause(&self, _data: &mut Data) -> Vec<Action> {
+        debug!("Ignoring pause in state {}", self.name());
+        vec![]
+    }
+
+    /// User resumes a paused connection.
+    #[must_use]
+    fn resume(&self, _data: &mut Data) -> Vec<Action> {
+        debug!("Ignoring resume in state {}", self.name());
+        vec![]
+    }
+
+    /// User sets or clears own receiver busy.
+    #[must_use]
+    fn receiver_busy(&self, _data: &mut Data, _busy: bool) -> Vec<Action> {
+        debug!("Ignoring receiver busy in state {}", self.name());
+        vec![]
+    }
+
     /// Timer T1 (pending ack) expires.
     #[must_use]
     fn t1(&self, data: &mut Data) -> Vec<Action> {
@@ -966,18 +2079,78 @@ pub trait State {
         vec![]
     }
 
-    /// XID received from peer.
+    /// XID received.
+    ///
+    /// Only XIDs sent to us, and from the peer if there is one, are used.
+    /// Before a connection the parameters are kept for when the sender
+    /// connects. A command is answered with a response carrying our
+    /// parameters.
+    ///
+    /// 4.3.3.7, page 24.
     #[must_use]
-    fn xid(&self, _data: &mut Data, _packet: &Xid, _cr: bool) -> Vec<Action> {
-        eprintln!("TODO: unexpected XID");
-        vec![]
+    fn xid(
+        &self,
+        data: &mut Data,
+        packet: &Xid,
+        cr: bool,
+        src: &Addr,
+        unicast: bool,
+    ) -> Vec<Action> {
+        if !unicast {
+            return vec![];
+        }
+        // The peer is left over from the last connection when disconnected.
+        if self.kind() != StateKind::Disconnected
+            && data.peer.as_ref().is_some_and(|p| p.call() != src.call())
+        {
+            debug!("Ignoring XID from non-peer {}", src.call());
+            return vec![];
+        }
+        debug!("Negotiating with XID {packet:?}");
+        data.negotiate_xid(src, packet);
+        if !cr {
+            return vec![];
+        }
+        vec![Action::SendXid {
+            dst: src.clone(),
+            pf: packet.poll,
+        }]
     }
 
-    /// TEST received from peer.
+    /// TEST received.
+    ///
+    /// TEST is connectionless, so this is the same in all states. A command
+    /// sent to us is answered with a response carrying the same payload, or
+    /// an empty one if it doesn't fit in our MTU.
+    ///
+    /// 4.3.3.8, page 28.
     #[must_use]
-    fn test(&self, _data: &mut Data, _packet: &Test, _cr: bool) -> Vec<Action> {
-        eprintln!("TODO: unexpected TEST");
-        vec![]
+    fn test(
+        &self,
+        data: &mut Data,
+        cr: bool,
+        src: &Addr,
+        unicast: bool,
+        packet: &Test,
+    ) -> Vec<Action> {
+        if !cr || !unicast {
+            // We never send TEST commands, so responses are not for us.
+            return vec![];
+        }
+        let payload = if packet.payload.len() > data.mtu_out {
+            debug!(
+                "TEST payload of {} bytes exceeds MTU, replying empty",
+                packet.payload.len()
+            );
+            vec![]
+        } else {
+            packet.payload.clone()
+        };
+        vec![Action::SendTest {
+            dst: src.clone(),
+            pf: packet.poll,
+            payload,
+        }]
     }
 
     /// SREJ received from peer.
@@ -996,9 +2169,22 @@ pub trait State {
         vec![]
     }
 
+    /// Invalid frame, or one of unknown type, received.
+    ///
+    /// `from_peer` is true if it was sent from the peer to us. Others are
+    /// not part of any connection of ours.
+    #[must_use]
+    fn invalid(&self, _data: &mut Data, reason: InvalidReason, _from_peer: bool) -> Vec<Action> {
+        debug!(
+            "Ignoring invalid frame ({reason:?}) in state {}",
+            self.name()
+        );
+        vec![]
+    }
+
     /// RNR received from peer.
     #[must_use]
-    fn rnr(&self, _data: &mut Data, _packet: &Rnr) -> Vec<Action> {
+    fn rnr(&self, _data: &mut Data, _packet: &Rnr, _command: bool) -> Vec<Action> {
         eprintln!("TODO: unexpected RNR");
         vec![]
     }
@@ -1024,9 +2210,19 @@ pub trait State {
         vec![]
     }
 
-    /// UI received from peer.
+    /// UI received.
+    ///
+    /// `unicast` is true if it was addressed to us, as opposed to e.g. an
+    /// APRS broadcast.
     #[must_use]
-    fn ui(&self, _data: &mut Data, _cr: bool, _packet: &Ui) -> Vec<Action> {
+    fn ui(
+        &self,
+        _data: &mut Data,
+        _cr: bool,
+        _src: &Addr,
+        _unicast: bool,
+        _packet: &Ui,
+    ) -> Vec<Action> {
         vec![]
     }
 
@@ -1070,17 +2266,26 @@ impl Disconnected {
     fn sabm_and_sabme(&self, data: &mut Data, src: Addr, pf: bool) -> Vec<Action> {
         debug!("DL-Connect indication");
         if !data.able_to_establish {
-            return vec![Action::SendDm { pf }];
+            debug!("Not listening, refusing connection from {}", src.call());
+            return vec![Action::SendDmTo { dst: src, pf }];
+        }
+        if !data.accept_allowed(&src) {
+            debug!("Refusing connection from {}", src.call());
+            return vec![Action::SendDmTo { dst: src, pf }];
         }
         data.clear_exception_conditions();
         data.vs = 0;
         data.va = 0;
         data.vr = 0;
-        data.srt = data.srt_default;
-        data.t1v = data.srt + data.srt;
+        data.nr_sent = 0;
+        data.reset_srt();
         data.t3.start(data.t3v);
         data.rc = 0;
         data.peer = Some(src);
+        // Now that the peer is known, XID from it applies.
+        data.apply_peer_xid();
+        data.connected_by_ua = false;
+        data.disc_ua_pending = false;
         vec![
             Action::SendUa { pf },
             Action::State(Box::new(Connected::new(ConnectedState::Connected))),
@@ -1097,21 +2302,31 @@ impl State for Disconnected {
         "Disconnected".to_string()
     }
 
+    fn kind(&self) -> StateKind {
+        StateKind::Disconnected
+    }
+
     fn is_state_disconnected(&self) -> bool {
         true
     }
 
     // Page 85.
     fn connect(&self, data: &mut Data, addr: &Addr, ext: bool) -> Vec<Action> {
-        data.modulus = match ext {
-            true => 128,
-            false => 8,
-        };
         // It says "SAT" in the PDF, but surely means SRT?
         data.peer = Some(addr.clone());
-        data.srt = data.srt_default;
-        data.t1v = 2 * data.srt;
+        data.clear_peer_xid();
+        data.clear_obuf();
+        // Also re-derives k, which was limited by the old modulus.
+        if ext {
+            data.set_version_2_2();
+        } else {
+            data.set_version_2();
+        }
+        data.reset_srt();
         data.layer3_initiated = true;
+        data.connect_refused = false;
+        data.connected_by_ua = false;
+        data.disc_ua_pending = false;
         vec![
             Action::State(Box::new(AwaitingConnection::new())),
             data.establish_data_link(),
@@ -1125,10 +2340,15 @@ impl State for Disconnected {
     }
 
     // Page 84.
-    fn ui(&self, data: &mut Data, cr: bool, packet: &Ui) -> Vec<Action> {
+    //
+    // Only a command with P=1 gets a reply, and only if it was sent to us.
+    fn ui(&self, data: &mut Data, cr: bool, src: &Addr, unicast: bool, packet: &Ui) -> Vec<Action> {
         let mut ret = data.ui_check(cr, packet.payload.len());
-        if packet.push {
-            ret.push(Action::SendDm { pf: true });
+        if cr && packet.push && unicast {
+            ret.push(Action::SendDmTo {
+                dst: src.clone(),
+                pf: true,
+            });
         }
         ret
     }
@@ -1140,7 +2360,16 @@ impl State for Disconnected {
     }
 
     // Page 85.
+    //
+    // Refusing extended mode is like being a v2.0 station.
     fn sabme(&self, data: &mut Data, src: &Addr, packet: &Sabme) -> Vec<Action> {
+        if !data.accepts_extended(src) {
+            debug!("Refusing extended connection from {}", src.call());
+            return vec![Action::SendDmTo {
+                dst: src.clone(),
+                pf: packet.poll,
+            }];
+        }
         data.set_version_2_2();
         self.sabm_and_sabme(data, src.clone(), packet.poll)
     }
@@ -1151,7 +2380,12 @@ impl State for Disconnected {
     }
 
     // Page 84.
-    fn ua(&self, _data: &mut Data, _packet: &Ua) -> Vec<Action> {
+    fn ua(&self, data: &mut Data, _packet: &Ua) -> Vec<Action> {
+        if data.disc_ua_pending {
+            debug!("UA to our DISC after simultaneous disconnect");
+            data.disc_ua_pending = false;
+            return vec![];
+        }
         // 1998 & 2017 bug: C and D make no sense here.
         vec![Action::DlError(DlError::C), Action::DlError(DlError::D)]
     }
@@ -1178,12 +2412,17 @@ impl State for AwaitingConnection {
         "AwaitingConnection".to_string()
     }
 
+    fn kind(&self) -> StateKind {
+        StateKind::AwaitingConnection
+    }
+
     // Page 88.
     fn t1(&self, data: &mut Data) -> Vec<Action> {
         eprintln!("t1 expired while connecting, retrying");
         data.t1.stop();
         if data.rc == data.n2 {
             data.clear_iframe_queue();
+            data.clear_obuf();
             vec![
                 // Typo in 1998 spec: G, not g.
                 Action::DlError(DlError::G),
@@ -1192,7 +2431,7 @@ impl State for AwaitingConnection {
         } else {
             data.rc += 1;
             data.select_t1_value();
-            data.t1.start(data.srt);
+            data.t1.start(data.t1v);
             vec![Action::SendSabm { pf: true }]
         }
     }
@@ -1221,8 +2460,7 @@ impl State for AwaitingConnection {
             //
             // In addition, there's a bug in the 2017 spec. This path says to
             // start T1, then immediately stop it again.
-            data.srt = data.srt_default;
-            data.t1v = data.srt + data.srt;
+            data.reset_srt();
             debug!("DL-CONNECT CONFIRM, vs!=va");
             warn!("Strange state entered: UA received while vs != va");
         }
@@ -1235,22 +2473,89 @@ impl State for AwaitingConnection {
         data.vs = 0;
         data.va = 0;
         data.vr = 0;
+        data.nr_sent = 0;
         data.rc = 0; // Missing from 1998 & 2017 spec, but done by direwolf.
         data.select_t1_value();
-        vec![Action::State(Box::new(Connected::new(
+        data.connected_by_ua = true;
+        let mut act = vec![Action::State(Box::new(Connected::new(
             ConnectedState::Connected,
-        )))]
+        )))];
+        // Send anything written while we were connecting.
+        act.extend(data.flush());
+        act
+    }
+
+    // Not in the spec, which says to queue the data but is vague about what
+    // happens to it.
+    //
+    // Buffer it, to be sent once the connection is up.
+    fn data(&self, data: &mut Data, payload: &[u8]) -> Vec<Action> {
+        data.buffer_data(payload);
+        vec![]
+    }
+
+    fn priority_data(&self, data: &mut Data, payload: &[u8]) -> Vec<Action> {
+        data.buffer_data_priority(payload);
+        vec![]
     }
 
     // Page 86.
-    fn sabm(&self, _data: &mut Data, _src: &Addr, packet: &Sabm) -> Vec<Action> {
+    //
+    // Other stations can't connect to us while we're connecting.
+    fn sabm(&self, data: &mut Data, src: &Addr, packet: &Sabm) -> Vec<Action> {
+        if data.peer.as_ref() != Some(src) {
+            return vec![Action::SendDmTo {
+                dst: src.clone(),
+                pf: packet.poll,
+            }];
+        }
         vec![Action::SendUa { pf: packet.poll }]
     }
 
+    // Page 86.
+    //
+    // Connection refused. A DM without F is not a reply to our SABM(E).
+    fn dm(&self, data: &mut Data, packet: &Dm) -> Vec<Action> {
+        if !packet.poll {
+            return vec![];
+        }
+        debug!("DL-DISCONNECT indication: connection refused");
+        data.clear_iframe_queue();
+        data.clear_obuf();
+        data.t1.stop();
+        data.connect_refused = true;
+        vec![Action::State(Box::new(Disconnected::new()))]
+    }
+
+    // 6.3.1 says a version 2.0 station may answer SABME with FRMR. Either
+    // way the connection was refused.
+    fn frmr(&self, data: &mut Data) -> Vec<Action> {
+        debug!("DL-DISCONNECT indication: connection refused with FRMR");
+        data.clear_iframe_queue();
+        data.clear_obuf();
+        data.t1.stop();
+        data.connect_refused = true;
+        vec![Action::State(Box::new(Disconnected::new()))]
+    }
+
     // Page 88.
-    fn sabme(&self, _data: &mut Data, _src: &Addr, packet: &Sabme) -> Vec<Action> {
-        // TODO: This is supposed to transition to "awaiting connect 2.2".
-        vec![Action::SendDm { pf: packet.poll }]
+    //
+    // The spec has a separate "awaiting connection 2.2" state for when we
+    // sent a SABME, but the modulus in Data already tells them apart.
+    //
+    // If we sent a SABME too, both ends are connecting at once. Like with SABM
+    // on page 86, ack theirs and keep waiting for the UA to ours, so both end
+    // up connected in extended mode. If we sent a SABM, refuse, and leave it
+    // to the peer to fall back to SABM. Other stations are always refused.
+    fn sabme(&self, data: &mut Data, src: &Addr, packet: &Sabme) -> Vec<Action> {
+        if data.ext() && data.peer.as_ref() == Some(src) {
+            vec![Action::SendUa { pf: packet.poll }]
+        } else {
+            vec![Action::SendDmTo {
+                dst: src.clone(),
+                pf: packet.poll,
+            }]
+        }
     }
 
     // Page 86.
@@ -1273,6 +2578,19 @@ impl AwaitingRelease {
     fn new() -> Self {
         Self {}
     }
+
+    // Page 90.
+    //
+    // Polling supervisory commands are answered with DM, so that the peer
+    // knows the link is going away. Anything else is ignored.
+    #[must_use]
+    fn dm_if_polled(poll: bool) -> Vec<Action> {
+        if poll {
+            vec![Action::SendDm { pf: true }]
+        } else {
+            vec![]
+        }
+    }
 }
 
 // Starting on page 89.
@@ -1281,6 +2599,10 @@ impl State for AwaitingRelease {
         "AwaitingRelease".to_string()
     }
 
+    fn kind(&self) -> StateKind {
+        StateKind::AwaitingRelease
+    }
+
     // Page 91.
     fn dm(&self, data: &mut Data, p: &Dm) -> Vec<Action> {
         if !p.poll {
@@ -1332,7 +2654,82 @@ impl State for AwaitingRelease {
         ]
     }
 
-    // TODO: More handlers.
+    // Page 89.
+    //
+    // Both ends sent DISC at the same time. The spec says to UA and keep
+    // waiting for our own UA, but there's no point. The peer has told us it's
+    // going away, and it'll not be acking anything anymore.
+    fn disc(&self, data: &mut Data, p: &Disc) -> Vec<Action> {
+        debug!("DL-DISCONNECT confirm");
+        data.t1.stop();
+        data.t3.stop();
+        data.disc_ua_pending = true;
+        vec![
+            Action::SendUa { pf: p.poll },
+            Action::State(Box::new(Disconnected::new())),
+        ]
+    }
+
+    // Page 90.
+    //
+    // The peer hasn't seen our DISC yet. The data is discarded, since we've
+    // already told the application the connection is going away.
+    //
+    // The spec only replies with DM if polled. Otherwise it does nothing, and
+    // the peer keeps retransmitting until our T1 resends the DISC. Resend it
+    // right away instead, to hurry the teardown along.
+    fn iframe(&self, _data: &mut Data, p: &Iframe, _cr: bool) -> Vec<Action> {
+        debug!("Discarding iframe while awaiting release");
+        if p.poll {
+            vec![Action::SendDm { pf: true }]
+        } else {
+            vec![Action::SendDisc { pf: true }]
+        }
+    }
+
+    // Page 89.
+    //
+    // The peer wants a new connection while we're still tearing down the old
+    // one. Refuse, and keep waiting for the reply to our DISC.
+    //
+    // The DM goes to whoever sent the SABM, which may not be the peer.
+    fn sabm(&self, _data: &mut Data, src: &Addr, p: &Sabm) -> Vec<Action> {
+        vec![Action::SendDmTo {
+            dst: src.clone(),
+            pf: p.poll,
+        }]
+    }
+
+    // Page 89.
+    fn sabme(&self, _data: &mut Data, src: &Addr, p: &Sabme) -> Vec<Action> {
+        vec![Action::SendDmTo {
+            dst: src.clone(),
+            pf: p.poll,
+        }]
+    }
+
+    // Page 90.
+    fn rr(&self, _data: &mut Data, p: &Rr, command: bool) -> Vec<Action> {
+        Self::dm_if_polled(command && p.poll)
+    }
+
+    // Page 90.
+    fn rnr(&self, _data: &mut Data, p: &Rnr, command: bool) -> Vec<Action> {
+        Self::dm_if_polled(command && p.poll)
+    }
+
+    // Page 90.
+    //
+    // Whether it's a command isn't passed along for REJ and SREJ, so all
+    // polls are answered. An extra DM does no harm while releasing.
+    fn rej(&self, _data: &mut Data, p: &Rej) -> Vec<Action> {
+        Self::dm_if_polled(p.poll)
+    }
+
+    // Page 90.
+    fn srej(&self, _data: &mut Data, p: &Srej) -> Vec<Action> {
+        Self::dm_if_polled(p.poll)
+    }
 }
 
 enum ConnectedState {
@@ -1365,27 +2762,90 @@ impl Connected {
         act
     }
 
-    // Page 99.
+    // Page 95.
+    //
+    // Same as RR, except the peer is now busy.
     #[must_use]
-    fn rr_timer_recovery(&self, data: &mut Data, packet: &Rr, cr: bool) -> Vec<Action> {
-        data.peer_receiver_busy = false;
-        if !cr && packet.poll {
-            data.t1.stop();
-            data.select_t1_value();
-            if !in_range(data.va, packet.nr, data.vs, data.modulus) {
-                let mut act = data.nr_error_recovery();
-                act.push(Action::State(Box::new(AwaitingConnection::new())));
-                return act;
+    fn rnr_connected(&self, data: &mut Data, packet: &Rnr, cr: bool) -> Vec<Action> {
+        data.peer_receiver_busy = true;
+        let mut act = data.check_need_for_response(cr, packet.poll);
+        if !in_range(data.va, packet.nr, data.vs, data.modulus) {
+            act.extend(data.nr_error_recovery());
+            act.push(Action::State(Box::new(AwaitingConnection::new())));
+        } else {
+            act.extend(data.check_iframe_acked(packet.nr));
+        }
+        act
+    }
+
+    // Page 100.
+    //
+    // Same as RR, except the peer is now busy. So instead of retransmitting,
+    // keep polling until it's not busy anymore. If it stays busy, T1
+    // eventually gives up with DlError::U.
+    #[must_use]
+    fn rnr_timer_recovery(&self, data: &mut Data, packet: &Rnr, cr: bool) -> Vec<Action> {
+        data.peer_receiver_busy = true;
+        if !cr && packet.poll {
+            data.t1.stop();
+            data.select_t1_value();
+            if !in_range(data.va, packet.nr, data.vs, data.modulus) {
+                let mut act = data.nr_error_recovery();
+                act.push(Action::State(Box::new(AwaitingConnection::new())));
+                return act;
             }
             let mut act = data.update_ack(packet.nr);
+            if data.vs == data.va {
+                data.t3.start(data.t3v);
+                data.rc = 0;
+                act.push(Action::State(Box::new(Connected::new(
+                    ConnectedState::Connected,
+                ))));
+            } else {
+                data.t3.stop();
+                data.t1.start(data.t1v);
+            }
+            return act;
+        }
+        let mut act = Vec::new();
+        if cr && packet.poll {
+            act.push(data.enquiry_response(true));
+        }
+        if in_range(data.va, packet.nr, data.vs, data.modulus) {
+            act.extend(data.update_ack(packet.nr));
+        } else {
+            act.extend(data.nr_error_recovery());
+            act.push(Action::State(Box::new(AwaitingConnection::new())));
+        }
+        act
+    }
+
+    // Page 99.
+    #[must_use]
+    fn rr_timer_recovery(&self, data: &mut Data, packet: &Rr, cr: bool) -> Vec<Action> {
+        data.peer_receiver_busy = false;
+        if !cr && packet.poll {
+            data.t1.stop();
+            data.select_t1_value();
+            if !in_range(data.va, packet.nr, data.vs, data.modulus) {
+                let mut act = data.nr_error_recovery();
+                act.push(Action::State(Box::new(AwaitingConnection::new())));
+                return act;
+            }
+            // Retransmission is decided before sending anything new, or the
+            // new frames would be sent twice.
+            data.prune_acked(packet.nr);
+            let mut act;
             if data.vs == data.va {
                 data.t3.start(data.t3v);
                 data.rc = 0; // Added in 2017 spec, page 95.
+                act = data.flush();
                 act.push(Action::State(Box::new(Connected::new(
                     ConnectedState::Connected,
                 ))));
             } else {
-                act.extend(data.invoke_retransmission(packet.nr));
+                act = data.invoke_retransmission(packet.nr);
+                act.extend(data.flush());
 
                 // The following added in 2017 spec, page 95.
                 data.t3.stop();
@@ -1424,12 +2884,29 @@ impl Connected {
     }
 
     // Page 93 and page 99.
-    fn sabm_or_sabme(&self, data: &mut Data, poll: bool) -> Vec<Action> {
-        data.clear_exception_conditions();
+    //
+    // The in-flight frames are dropped or requeued before applying the new
+    // version, since its modulus may differ from the one they were numbered
+    // with.
+    fn sabm_or_sabme(&self, data: &mut Data, poll: bool, extended: bool) -> Vec<Action> {
         if data.vs != data.va {
-            data.iframe_queue.clear();
             debug!("DL-Connect indication");
         }
+        // Sequence numbers restart from zero, so whatever is in the resend
+        // queue can never be acked.
+        if data.requeue_on_reset {
+            data.requeue_unacked();
+        } else {
+            data.iframe_resend_queue.clear();
+            data.iframe_sent_at.clear();
+        }
+        if extended {
+            data.set_version_2_2();
+        } else {
+            data.set_version_2();
+        }
+        data.clear_exception_conditions();
+        data.connected_by_ua = false;
         data.t1.stop();
 
         // 2017 spec says to stop both T1 and T3 in state timer recovery. That
@@ -1438,16 +2915,19 @@ impl Connected {
         data.va = 0;
         data.vs = 0;
         data.vr = 0; // 1998 spec typos this as another vs=0.
+        data.nr_sent = 0;
         if let ConnectedState::Connected = self.connected_state {
             // Added in 2017 spec, but only for Connected.
             // TODO: should this be set also for TimerRecovery?
             data.rc = 0;
         }
-        vec![
+        let mut act = vec![
             Action::DlError(DlError::F),
             Action::SendUa { pf: poll },
             Action::State(Box::new(Connected::new(ConnectedState::Connected))),
-        ]
+        ];
+        act.extend(data.flush());
+        act
     }
 }
 
@@ -1458,6 +2938,12 @@ impl State for Connected {
             ConnectedState::TimerRecovery => "TimerRecovery".to_string(),
         }
     }
+    fn kind(&self) -> StateKind {
+        match self.connected_state {
+            ConnectedState::Connected => StateKind::Connected,
+            ConnectedState::TimerRecovery => StateKind::TimerRecovery,
+        }
+    }
     fn is_state_connected(&self) -> bool {
         true
     }
@@ -1466,7 +2952,7 @@ impl State for Connected {
     fn disconnect(&self, data: &mut Data) -> Vec<Action> {
         data.clear_iframe_queue();
         data.rc = 0;
-        data.t1.start(data.srt); // TODO: with what timer?
+        data.t1.start(data.t1v);
         data.t3.stop();
         vec![
             Action::SendDisc { pf: true },
@@ -1477,39 +2963,128 @@ impl State for Connected {
     // Page 92 & 98.
     //
     // This implementation deliberately doesn't preserve the application's
-    // frame boundaries.
-    //
-    // This seems like the right thing to do. But in the future maybe we'll
-    // implement what Linux would call SEQPACKET, that AX.25 would call
-    // segmentation.
+    // frame boundaries, unless segmentation is enabled and the write is bigger
+    // than the MTU.
     fn data(&self, data: &mut Data, payload: &[u8]) -> Vec<Action> {
-        data.obuf.extend(payload);
-        if data.obuf.len() > MAX_OBUF_SIZE {
-            panic!(
-                "TODO: handle better. Output buffer got too large. {} > {}",
-                data.obuf.len(),
-                MAX_OBUF_SIZE
-            );
-        }
+        data.buffer_data(payload);
+        data.flush()
+    }
+
+    // Not in the spec. Data for another layer 3 protocol keeps its frame
+    // boundaries, since e.g. NET/ROM relies on them.
+    fn data_with_pid(&self, data: &mut Data, pid: u8, payload: &[u8]) -> Vec<Action> {
+        data.buffer_data_with_pid(pid, payload);
         data.flush()
     }
 
+    // Not in the spec. AX.25 has no expedited data, but data not yet sent can
+    // still be reordered.
+    fn priority_data(&self, data: &mut Data, payload: &[u8]) -> Vec<Action> {
+        data.buffer_data_priority(payload);
+        data.flush()
+    }
+
+    // Not in the spec as such, but built from its "set own receiver busy"
+    // (page 93 & 99), plus not probing the peer while paused.
+    //
+    // T1 keeps running if there's unacked data, since that needs to be
+    // resolved either way.
+    fn pause(&self, data: &mut Data) -> Vec<Action> {
+        if data.paused {
+            return vec![];
+        }
+        data.paused = true;
+        data.t3.stop();
+        if data.own_receiver_busy {
+            // Peer already told.
+            return vec![];
+        }
+        data.own_receiver_busy = true;
+        data.acknowledge_pending = false;
+        vec![Action::SendRnr {
+            pf: false,
+            nr: data.vr,
+            command: false,
+        }]
+    }
+
+    // "Clear own receiver busy" (page 93 & 99), plus restarting what pause
+    // stopped.
+    fn resume(&self, data: &mut Data) -> Vec<Action> {
+        if !data.paused {
+            return vec![];
+        }
+        data.paused = false;
+        if !data.t1.running {
+            data.t3.start(data.t3v);
+        }
+        let mut act = vec![];
+        if !data.receiver_busy {
+            data.own_receiver_busy = false;
+            data.acknowledge_pending = false;
+            act.push(Action::SendRr {
+                pf: false,
+                nr: data.vr,
+                command: false,
+            });
+        }
+        act.extend(data.flush());
+        act
+    }
+
+    // "Set own receiver busy" and "clear own receiver busy" (page 93 & 99).
+    //
+    // Unlike pause, this only stops the peer from sending. Our own data keeps
+    // flowing.
+    fn receiver_busy(&self, data: &mut Data, busy: bool) -> Vec<Action> {
+        if data.receiver_busy == busy {
+            return vec![];
+        }
+        data.receiver_busy = busy;
+        if data.paused {
+            // Already told the peer we're busy, and resume will tell it
+            // otherwise if needed.
+            return vec![];
+        }
+        data.own_receiver_busy = busy;
+        data.acknowledge_pending = false;
+        if busy {
+            vec![Action::SendRnr {
+                pf: false,
+                nr: data.vr,
+                command: false,
+            }]
+        } else {
+            vec![Action::SendRr {
+                pf: false,
+                nr: data.vr,
+                command: false,
+            }]
+        }
+    }
+
     // Page 93.
     //
     // src is ignored, because it's presumed to already have been checked, in
     // this state.
     fn sabm(&self, data: &mut Data, _src: &Addr, packet: &Sabm) -> Vec<Action> {
-        data.set_version_2();
-        self.sabm_or_sabme(data, packet.poll)
+        self.sabm_or_sabme(data, packet.poll, false)
     }
 
     // Page 93.
     //
-    // src is ignored, because it's presumed to already have been checked, in
-    // this state.
-    fn sabme(&self, data: &mut Data, _src: &Addr, packet: &Sabme) -> Vec<Action> {
-        data.set_version_2_2();
-        self.sabm_or_sabme(data, packet.poll)
+    // src is presumed to already have been checked, in this state. But if
+    // extended mode isn't acceptable, refuse like in Disconnected, and leave
+    // the connection as is for the peer to reset with SABM instead.
+    fn sabme(&self, data: &mut Data, src: &Addr, packet: &Sabme) -> Vec<Action> {
+        if !data.accepts_extended(src) {
+            debug!("Refusing extended mode reset from {}", src.call());
+            return vec![Action::SendDmTo {
+                dst: src.clone(),
+                pf: packet.poll,
+            }];
+        }
+        self.sabm_or_sabme(data, packet.poll, true)
     }
 
     // Page 93 & 101.
@@ -1541,8 +3116,6 @@ impl State for Connected {
     }
 
     // Page 96 & 102.
-    //
-    // TODO; implement segment reassembly.
     fn iframe(&self, data: &mut Data, p: &Iframe, command_response: bool) -> Vec<Action> {
         if !command_response {
             // 2017 spec page 93 says to DlError::O if the iframe *is* a
@@ -1609,21 +3182,48 @@ impl State for Connected {
             return actions;
         }
 
+        if data.lenient_initial_seq && !data.iframe_received && p.ns != data.vr {
+            warn!(
+                "Peer started sending at N(S)={}, not {}. Adopting it, skipping any lost data before it.",
+                p.ns, data.vr
+            );
+            data.vr = p.ns;
+            data.nr_sent = p.ns;
+        }
+        data.iframe_received = true;
+
+        if data.window_overrun(p.ns) {
+            warn!(
+                "Peer sent N(S)={} past its window, with N(R)={} last sent",
+                p.ns, data.nr_sent
+            );
+            actions.push(Action::DlError(DlError::P));
+            if p.poll {
+                actions.push(Action::SendRr {
+                    pf: true,
+                    nr: data.vr,
+                    command: false,
+                });
+                data.acknowledge_pending = false;
+            }
+            return actions;
+        }
+
         if p.ns == data.vr {
             debug!("iframe in order {}", p.ns);
             // Frame is in order.
             data.vr = (data.vr + 1) % data.modulus;
             data.reject_exception = false;
-            if data.sreject_exception > 0 {
-                data.sreject_exception -= 1;
+            data.srej_requested.remove(&p.ns);
+            data.reorder_delivered.remove(&p.ns);
+            if !p.payload.is_empty() {
+                data.last_activity = std::time::Instant::now();
             }
-            actions.push(Action::Deliver(p.payload.clone()));
-            // TODO: check for stored out of order frames
-            while
-            /* i frame stored */
-            false {
-                // retrieve stored vr in frame
-                // Deliver
+            actions.extend(data.deliver(p.pid, p.payload.clone()));
+            while let Some((pid, payload)) = data.reorder_buffer.remove(&data.vr) {
+                debug!("delivering stored iframe {}", data.vr);
+                actions.extend(data.deliver(pid, payload));
+                data.reorder_delivered.insert(data.vr);
                 data.vr = (data.vr + 1) % data.modulus;
             }
             if p.poll {
@@ -1642,6 +3242,24 @@ impl State for Connected {
             return actions;
         }
         debug!("Iframe not in order got={} want={}", p.ns, data.vr);
+        if data.reorder_delivered.remove(&p.ns) {
+            debug!("Discarding duplicate of stored iframe {}", p.ns);
+            if p.poll {
+                actions.push(Action::SendRr {
+                    pf: true,
+                    nr: data.vr,
+                    command: false,
+                });
+                data.acknowledge_pending = false;
+            }
+            return actions;
+        }
+        let unambiguous = data.reorder_unambiguous(p.ns);
+        if !data.srej_active() && unambiguous {
+            // Keep it, so that once the gap is filled it can be delivered
+            // without waiting for the peer to resend it.
+            data.reorder_buffer.insert(p.ns, (p.pid, p.payload.clone()));
+        }
         if data.reject_exception {
             // discard frame (implicit)
             if p.poll {
@@ -1654,7 +3272,9 @@ impl State for Connected {
             }
             return actions;
         }
-        if !data.srej_enabled {
+        // SREJ needs to know which frames are missing, which it can't if
+        // the iframe may be a resend. Fall back to REJ then.
+        if !data.srej_active() || !unambiguous {
             // discard iframe (implicit)
             //
             // TODO: should we maybe wait a bit with sending a REJ?
@@ -1670,27 +3290,7 @@ impl State for Connected {
             data.acknowledge_pending = false;
             return actions;
         }
-        // TODO: save contents of iframe
-        if data.sreject_exception > 0 {
-            data.sreject_exception += 1;
-            // TODO: actions.push(Action::SendSrej(final=false, nr=p.ns));
-            data.acknowledge_pending = false;
-            return actions;
-        }
-        // if ns > vr + 1
-        // TODO: Maybe a version of if in_range(p.ns) {
-        if p.ns != (data.vr + 1) % data.modulus {
-            // discard iframe (implicit)
-            actions.push(Action::SendRej {
-                pf: p.poll,
-                nr: data.vr,
-            });
-            data.acknowledge_pending = false;
-            return actions;
-        }
-        data.sreject_exception += 1;
-        // TODO: actions.push(Action::SendSrej(final=false, nr=data.vr));
-        data.acknowledge_pending = false;
+        actions.extend(data.srej_gaps(p));
         actions
     }
 
@@ -1702,6 +3302,9 @@ impl State for Connected {
             ConnectedState::TimerRecovery => data.rc + 1,
         };
         if data.rc != data.n2 {
+            // Not in the spec, but without it there's no backoff while
+            // polling the peer.
+            data.select_t1_value();
             return vec![
                 data.transmit_enquiry(),
                 Action::State(Box::new(Connected::new(ConnectedState::TimerRecovery))),
@@ -1725,6 +3328,17 @@ impl State for Connected {
     // Page 93 (Connected only).
     fn t3(&self, data: &mut Data) -> Vec<Action> {
         data.t3.stop();
+        if data
+            .idle_timeout
+            .is_some_and(|t| data.last_activity.elapsed() >= t)
+        {
+            debug!("Disconnecting idle connection");
+            return self.disconnect(data);
+        }
+        if data.paused {
+            debug!("Not probing peer while paused");
+            return vec![];
+        }
         if let ConnectedState::TimerRecovery = self.connected_state {
             error!("T3 should not be running in TimerRecovery");
         }
@@ -1739,7 +3353,14 @@ impl State for Connected {
     // Page 93 & 100.
     //
     // 2017 spec says DlError::K, which is undocumented.
-    fn ua(&self, data: &mut Data, _ua: &Ua) -> Vec<Action> {
+    //
+    // The spec resets the link on any UA, but a duplicate of the UA that
+    // established the link is harmless, so that's just ignored.
+    fn ua(&self, data: &mut Data, ua: &Ua) -> Vec<Action> {
+        if data.connected_by_ua && ua.poll {
+            debug!("Ignoring duplicate UA");
+            return vec![];
+        }
         data.layer3_initiated = false;
         vec![
             Action::DlError(DlError::C),
@@ -1748,6 +3369,28 @@ impl State for Connected {
         ]
     }
 
+    // "Control field error", "info not permitted in frame", and "incorrect U
+    // or S frame length", in the connected and timer recovery state diagrams.
+    //
+    // Only the peer can break the connection like this. Anything else on
+    // the channel is not ours to act on.
+    fn invalid(&self, data: &mut Data, reason: InvalidReason, from_peer: bool) -> Vec<Action> {
+        if !from_peer {
+            debug!("Ignoring invalid frame ({reason:?}) not from the peer");
+            return vec![];
+        }
+        data.layer3_initiated = false;
+        vec![
+            Action::DlError(match reason {
+                InvalidReason::Control => DlError::L,
+                InvalidReason::InfoNotPermitted => DlError::M,
+                InvalidReason::Length => DlError::N,
+            }),
+            data.establish_data_link(),
+            Action::State(Box::new(AwaitingConnection::new())),
+        ]
+    }
+
     // Page 94 & 101.
     //
     // For TimerRecovery, see note K.
@@ -1761,9 +3404,16 @@ impl State for Connected {
     }
 
     // Page 94 & 100.
-    fn ui(&self, data: &mut Data, cr: bool, packet: &Ui) -> Vec<Action> {
+    //
+    // UI frames from anyone but the peer are not part of this connection, so
+    // are left for whatever else is listening, such as an APRS handler.
+    fn ui(&self, data: &mut Data, cr: bool, src: &Addr, unicast: bool, packet: &Ui) -> Vec<Action> {
+        if !unicast || data.peer.as_ref().is_none_or(|p| p.call() != src.call()) {
+            debug!("Ignoring UI from {} not for this connection", src.call());
+            return vec![];
+        }
         let mut act = data.ui_check(cr, packet.payload.len());
-        if packet.push {
+        if cr && packet.push {
             act.push(data.enquiry_response(true));
         }
         act
@@ -1775,6 +3425,43 @@ impl State for Connected {
             ConnectedState::TimerRecovery => self.rr_timer_recovery(data, packet, cr),
         }
     }
+
+    fn rnr(&self, data: &mut Data, packet: &Rnr, cr: bool) -> Vec<Action> {
+        match self.connected_state {
+            ConnectedState::Connected => self.rnr_connected(data, packet, cr),
+            ConnectedState::TimerRecovery => self.rnr_timer_recovery(data, packet, cr),
+        }
+    }
+
+    // The spec takes F=1 to also acknowledge everything before N(R). But when
+    // the peer sends a batch of SREJs, only the last one has F=1, and there
+    // are still earlier frames missing. So SREJ never acks anything here, and
+    // acks are left to RR.
+    fn srej(&self, data: &mut Data, packet: &Srej) -> Vec<Action> {
+        data.peer_receiver_busy = false;
+        if !in_range(data.va, packet.nr, data.vs, data.modulus) {
+            let mut act = data.nr_error_recovery();
+            act.push(Action::State(Box::new(AwaitingConnection::new())));
+            return act;
+        }
+        let vr = data.vr;
+        match data.iframe_resend_queue.iter().find(|i| i.ns == packet.nr) {
+            Some(i) => {
+                debug!("Resending iframe {} on SREJ", i.ns);
+                let act = vec![Action::SendIframe(Iframe {
+                    nr: vr,
+                    ..i.clone()
+                })];
+                data.iframe_sent_at.remove(&packet.nr);
+                data.window_loss_srej(packet.nr);
+                act
+            }
+            None => {
+                debug!("SREJ for iframe {} not in resend queue", packet.nr);
+                vec![]
+            }
+        }
+    }
 }
 
 /// Ugly range checker.
@@ -1830,28 +3517,48 @@ pub fn handle(
         Event::Connect { addr, ext } => state.connect(data, addr, *ext),
         Event::Disconnect => state.disconnect(data),
         Event::Data(payload) => state.data(data, payload),
+        Event::DataWithPid(pid, payload) => state.data_with_pid(data, *pid, payload),
+        Event::PriorityData(payload) => state.priority_data(data, payload),
+        Event::Pause => state.pause(data),
+        Event::Resume => state.resume(data),
+        Event::ReceiverBusy(busy) => state.receiver_busy(data, *busy),
         Event::T1 => state.t1(data),
         Event::T3 => state.t3(data),
         Event::Sabm(p, src) => state.sabm(data, src, p),
         Event::Sabme(p, src) => state.sabme(data, src, p),
         Event::Dm(dm) => state.dm(data, dm),
-        Event::Ui(p, cr) => state.ui(data, *cr, p),
+        Event::Ui(p, cr, src, dst) => state.ui(data, *cr, src, dst.call() == data.me.call(), p),
         Event::Disc(p) => state.disc(data, p),
         Event::Iframe(p, command_response) => state.iframe(data, p, *command_response),
         Event::Ua(p) => state.ua(data, p),
         Event::Rr(p, command) => state.rr(data, p, *command),
-        Event::Rnr(p) => state.rnr(data, p),
+        Event::Rnr(p, command) => state.rnr(data, p, *command),
         Event::Frmr(_) => state.frmr(data),
+        Event::Invalid(reason, src, dst) => {
+            let from_peer = dst.call() == data.me.call()
+                && data.peer.as_ref().is_some_and(|p| p.call() == src.call());
+            state.invalid(data, *reason, from_peer)
+        }
         Event::Rej(p) => state.rej(data, p),
         Event::Srej(p) => state.srej(data, p),
-        Event::Xid(p, command) => state.xid(data, p, *command),
-        Event::Test(p, command) => state.test(data, p, *command),
+        Event::Xid(p, command, src, dst) => {
+            state.xid(data, p, *command, src, dst.call() == data.me.call())
+        }
+        Event::Test(p, command, src, dst) => {
+            state.test(data, *command, src, dst.call() == data.me.call(), p)
+        }
     };
     let mut ret = Vec::new();
 
     // Save non-state actions.
     for act in &actions {
         use Action::*;
+        // Remember what the peer has been told, to check its window.
+        match act {
+            SendRr { nr, .. } | SendRnr { nr, .. } | SendRej { nr, .. } => data.nr_sent = *nr,
+            SendIframe(iframe) => data.nr_sent = iframe.nr,
+            _ => {}
+        }
         match act {
             Action::State(_) => {} // Ignore state change at this stage.
             DlError(code) => ret.push(ReturnEvent::DlError(*code)),
@@ -1900,6 +3607,16 @@ pub fn handle(
                 rr_extseq: false,
                 packet_type: PacketType::Dm(Dm { poll: *pf }),
             })),
+            SendDmTo { dst, pf } => ret.push(ReturnEvent::Packet(Packet {
+                src: data.me.clone(),
+                dst: dst.clone(),
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Dm(Dm { poll: *pf }),
+            })),
             // S frames.
             SendRej { pf, nr } => ret.push(ReturnEvent::Packet(Packet {
                 src: data.me.clone(),
@@ -1912,6 +3629,39 @@ pub fn handle(
                 rr_extseq: false,
                 packet_type: PacketType::Rej(Rej { poll: *pf, nr: *nr }),
             })),
+            SendSrej { pf, nr } => ret.push(ReturnEvent::Packet(Packet {
+                src: data.me.clone(),
+                dst: data.peer.clone().unwrap().clone(),
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Srej(Srej { poll: *pf, nr: *nr }),
+            })),
+            SendTest { dst, pf, payload } => ret.push(ReturnEvent::Packet(Packet {
+                src: data.me.clone(),
+                dst: dst.clone(),
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Test(Test {
+                    poll: *pf,
+                    payload: payload.clone(),
+                }),
+            })),
+            SendXid { dst, pf } => ret.push(ReturnEvent::Packet(Packet {
+                src: data.me.clone(),
+                dst: dst.clone(),
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Xid(data.local_xid(dst, *pf)),
+            })),
             SendRr { pf, nr, command } => ret.push(ReturnEvent::Packet(Packet {
                 src: data.me.clone(),
                 dst: data.peer.clone().unwrap().clone(),
@@ -1954,6 +3704,11 @@ pub fn handle(
     for act in actions {
         // Non-statechange actions handled above.
         if let Action::State(new_state) = act {
+            if new_state.kind() == StateKind::Disconnected
+                && state.kind() != StateKind::Disconnected
+            {
+                data.clear_peer_xid();
+            }
             return (Some(new_state), ret);
         }
     }
@@ -2046,7 +3801,7 @@ mod tests {
     #[test]
     fn disconnected_incoming() -> Result<()> {
         let mut data = Data::new(Addr::new("M0THC-1")?);
-        data.able_to_establish = true; // TODO: implement some sort of listen()
+        data.listen(true);
         let con = Disconnected::new();
 
         let (con, events) = handle(
@@ -2075,24 +3830,223 @@ mod tests {
     }
 
     #[test]
-    fn connected() -> Result<()> {
+    fn timer_recovery_no_duplicates() -> Result<()> {
+        let sent = |events: &[ReturnEvent]| -> Vec<u8> {
+            events
+                .iter()
+                .filter_map(|e| match e {
+                    ReturnEvent::Packet(Packet {
+                        packet_type: PacketType::Iframe(i),
+                        ..
+                    }) => Some(i.ns),
+                    _ => None,
+                })
+                .collect()
+        };
+        for (nr, want) in [(1, vec![1, 2]), (2, vec![2, 3])] {
+            let mut data = Data::new(Addr::new("M0THC-1")?);
+            data.peer = Some(Addr::new("M0THC-2")?);
+            data.mtu(10);
+            data.window(2);
+            let con = Connected::new(ConnectedState::Connected);
+            let (_, events) = handle(&con, &mut data, &Event::Data(vec![1; 40]));
+            assert_eq!(sent(&events), vec![0, 1]);
+            let (rec, _) = handle(&con, &mut data, &Event::T1);
+            let (_, events) = handle(
+                &*rec.unwrap(),
+                &mut data,
+                &Event::Rr(Rr { poll: true, nr }, false),
+            );
+            assert_eq!(sent(&events), want, "nr={nr}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn retry_state() -> Result<()> {
         let mut data = Data::new(Addr::new("M0THC-1")?);
         data.peer = Some(Addr::new("M0THC-2")?);
+        assert_eq!(data.retry_state(), (0, DEFAULT_N2));
         let con = Connected::new(ConnectedState::Connected);
+        let rec = Connected::new(ConnectedState::TimerRecovery);
+        let _ = handle(&con, &mut data, &Event::Data(vec![1]));
+        let _ = handle(&con, &mut data, &Event::T1);
+        assert_eq!(data.retry_state(), (1, DEFAULT_N2));
+        let _ = handle(&rec, &mut data, &Event::T1);
+        let _ = handle(&rec, &mut data, &Event::T1);
+        assert_eq!(data.retry_state(), (3, DEFAULT_N2));
+        Ok(())
+    }
 
-        eprintln!("Receive data packet");
-        let (c2, events) = handle(
+    #[test]
+    fn max_modulus() -> Result<()> {
+        let peer = Addr::new("M0THC-2")?;
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.listen(true);
+        data.max_modulus(8);
+        let con = Disconnected::new();
+
+        let (c, events) = handle(
             &con,
             &mut data,
-            &Event::Iframe(
-                Iframe {
-                    nr: 0,
-                    ns: 0,
-                    poll: true, // TODO: poll or no?
-                    pid: 0xF0,
-                    payload: vec![1, 2, 3],
-                },
-                true,
+            &Event::Sabme(Sabme { poll: true }, peer.clone()),
+        );
+        assert!(c.is_none());
+        assert_eq!(
+            events,
+            vec![ReturnEvent::Packet(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: peer.clone(),
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Dm(Dm { poll: true }),
+            })]
+        );
+        assert!(!data.ext());
+        assert!(data.peer.is_none());
+
+        let (c, events) = handle(
+            &con,
+            &mut data,
+            &Event::Sabm(Sabm { poll: true }, peer.clone()),
+        );
+        assert_eq!(c.map(|c| c.kind()), Some(StateKind::Connected));
+        assert!(matches!(
+            events.as_slice(),
+            [ReturnEvent::Packet(Packet {
+                packet_type: PacketType::Ua(_),
+                ..
+            })]
+        ));
+        assert!(!data.ext());
+
+        // Nor can a connection be reset into extended mode.
+        let con = Connected::new(ConnectedState::Connected);
+        let (c, events) = handle(&con, &mut data, &Event::Sabme(Sabme { poll: true }, peer));
+        assert!(c.is_none());
+        assert!(matches!(
+            events.as_slice(),
+            [ReturnEvent::Packet(Packet {
+                packet_type: PacketType::Dm(Dm { poll: true }),
+                ..
+            })]
+        ));
+        assert!(!data.ext());
+        Ok(())
+    }
+
+    #[test]
+    fn disconnected_not_listening() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        let con = Disconnected::new();
+        for ev in [
+            Event::Sabm(Sabm { poll: true }, Addr::new("M0THC-2")?),
+            Event::Sabme(Sabme { poll: true }, Addr::new("M0THC-2")?),
+        ] {
+            let (c2, events) = handle(&con, &mut data, &ev);
+            assert!(c2.is_none());
+            assert_all(
+                &[ReturnEvent::Packet(Packet {
+                    src: Addr::new("M0THC-1")?,
+                    dst: Addr::new("M0THC-2")?,
+                    command_response: false,
+                    command_response_la: true,
+                    digipeater: vec![],
+                    rr_dist1: false,
+                    rr_extseq: false,
+                    packet_type: PacketType::Dm(Dm { poll: true }),
+                })],
+                &events,
+                "not listening",
+            );
+            assert!(data.peer.is_none());
+        }
+
+        data.listen(true);
+        let (c2, _) = handle(
+            &con,
+            &mut data,
+            &Event::Sabm(Sabm { poll: true }, Addr::new("M0THC-2")?),
+        );
+        assert_eq!(c2.unwrap().name(), "Connected");
+        Ok(())
+    }
+
+    #[test]
+    fn disconnected_incoming_filtered() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.listen(true);
+        data.accept_filter(Box::new(|peer| peer.call() == "M0THC-3"));
+        let con = Disconnected::new();
+
+        let (c2, events) = handle(
+            &con,
+            &mut data,
+            &Event::Sabm(Sabm { poll: true }, Addr::new("M0THC-2")?),
+        );
+        assert!(c2.is_none());
+        assert_all(
+            &[ReturnEvent::Packet(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Dm(Dm { poll: true }),
+            })],
+            &events,
+            "refused",
+        );
+        assert!(data.peer.is_none());
+
+        let (c2, events) = handle(
+            &con,
+            &mut data,
+            &Event::Sabm(Sabm { poll: true }, Addr::new("M0THC-3")?),
+        );
+        assert_eq!(c2.unwrap().name(), "Connected");
+        assert_eq!(data.peer, Some(Addr::new("M0THC-3")?));
+        assert_all(
+            &[ReturnEvent::Packet(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-3")?,
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Ua(Ua { poll: true }),
+            })],
+            &events,
+            "allowed",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn connected() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        let con = Connected::new(ConnectedState::Connected);
+
+        eprintln!("Receive data packet");
+        let (c2, events) = handle(
+            &con,
+            &mut data,
+            &Event::Iframe(
+                Iframe {
+                    nr: 0,
+                    ns: 0,
+                    poll: true, // TODO: poll or no?
+                    pid: 0xF0,
+                    payload: vec![1, 2, 3],
+                },
+                true,
             ),
         );
         assert!(matches![c2, None]);
@@ -2207,6 +4161,2326 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn bytes_acked() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        let con = Connected::new(ConnectedState::Connected);
+
+        let (_, _) = handle(&con, &mut data, &Event::Data(vec![1, 2, 3]));
+        let (_, _) = handle(&con, &mut data, &Event::Data(vec![4, 5]));
+        assert_eq!(data.bytes_written(), 5);
+        assert_eq!(data.bytes_acked(), 0);
+
+        let (_, _) = handle(
+            &con,
+            &mut data,
+            &Event::Rr(Rr { poll: false, nr: 1 }, false),
+        );
+        assert_eq!(data.bytes_acked(), 3);
+        let (_, _) = handle(
+            &con,
+            &mut data,
+            &Event::Rr(Rr { poll: false, nr: 2 }, false),
+        );
+        assert_eq!(data.bytes_acked(), 5);
+        Ok(())
+    }
+
+    /// Trigger every DlError that can be produced.
+    #[test]
+    fn dl_errors() -> Result<()> {
+        let peer = Addr::new("M0THC-2")?;
+        let new_data = || -> Result<Data> {
+            let mut data = Data::new(Addr::new("M0THC-1")?);
+            data.peer = Some(peer.clone());
+            Ok(data)
+        };
+        let errors = |state: &dyn State, data: &mut Data, event: &Event| -> Vec<DlError> {
+            let (_, events) = handle(state, data, event);
+            events
+                .into_iter()
+                .filter_map(|e| match e {
+                    ReturnEvent::DlError(e) => Some(e),
+                    _ => None,
+                })
+                .collect()
+        };
+        let con = Connected::new(ConnectedState::Connected);
+        let rec = Connected::new(ConnectedState::TimerRecovery);
+
+        // A: Response with F=1, when no poll outstanding.
+        let mut data = new_data()?;
+        assert_eq!(
+            errors(&con, &mut data, &Event::Rr(Rr { poll: true, nr: 0 }, false)),
+            vec![DlError::A]
+        );
+
+        // C: UA while connected.
+        let mut data = new_data()?;
+        assert_eq!(
+            errors(&con, &mut data, &Event::Ua(Ua { poll: true })),
+            vec![DlError::C]
+        );
+
+        // D: UA without F=1 while connecting or disconnecting.
+        for state in [
+            &AwaitingConnection::new() as &dyn State,
+            &AwaitingRelease::new(),
+        ] {
+            let mut data = new_data()?;
+            assert_eq!(
+                errors(state, &mut data, &Event::Ua(Ua { poll: false })),
+                vec![DlError::D],
+                "{}",
+                state.name()
+            );
+        }
+
+        // E: DM while connected.
+        let mut data = new_data()?;
+        assert_eq!(
+            errors(&con, &mut data, &Event::Dm(Dm { poll: true })),
+            vec![DlError::E]
+        );
+
+        // F: Peer resets the link.
+        let mut data = new_data()?;
+        assert_eq!(
+            errors(
+                &con,
+                &mut data,
+                &Event::Sabm(Sabm { poll: true }, peer.clone())
+            ),
+            vec![DlError::F]
+        );
+
+        // G: Connection timed out.
+        let mut data = new_data()?;
+        data.rc = data.n2;
+        assert_eq!(
+            errors(&AwaitingConnection::new(), &mut data, &Event::T1),
+            vec![DlError::G]
+        );
+
+        // H: Disconnection timed out.
+        let mut data = new_data()?;
+        data.rc = data.n2;
+        assert_eq!(
+            errors(&AwaitingRelease::new(), &mut data, &Event::T1),
+            vec![DlError::H]
+        );
+
+        // I: Retries exhausted with unacked data.
+        let mut data = new_data()?;
+        let _ = handle(&con, &mut data, &Event::Data(vec![1]));
+        data.rc = data.n2 - 1;
+        assert_eq!(errors(&rec, &mut data, &Event::T1), vec![DlError::I]);
+
+        // J: N(R) out of range.
+        let mut data = new_data()?;
+        assert_eq!(
+            errors(&con, &mut data, &Event::Rr(Rr { poll: false, nr: 3 }, true)),
+            vec![DlError::J]
+        );
+
+        // K: FRMR received.
+        let mut data = new_data()?;
+        assert_eq!(
+            errors(&con, &mut data, &Event::Frmr(Frmr::default())),
+            vec![DlError::K]
+        );
+
+        // L, M, N: Invalid frames.
+        for (reason, want) in [
+            (InvalidReason::Control, DlError::L),
+            (InvalidReason::InfoNotPermitted, DlError::M),
+            (InvalidReason::Length, DlError::N),
+        ] {
+            for state in [&con as &dyn State, &rec] {
+                let mut data = new_data()?;
+                let me = data.me.clone();
+                assert_eq!(
+                    errors(
+                        state,
+                        &mut data,
+                        &Event::Invalid(reason, peer.clone(), me.clone())
+                    ),
+                    vec![want]
+                );
+
+                // Not from the peer, or not to us.
+                let other = Addr::new("M0THC-3")?;
+                for (src, dst) in [(&other, &me), (&peer, &other)] {
+                    let (c, events) = handle(
+                        state,
+                        &mut data,
+                        &Event::Invalid(reason, src.clone(), dst.clone()),
+                    );
+                    assert!(c.is_none());
+                    assert!(events.is_empty(), "{events:?}");
+                }
+            }
+        }
+
+        // O: Too big iframe.
+        let mut data = new_data()?;
+        data.n1 = 10;
+        let big = Iframe {
+            nr: 0,
+            ns: 0,
+            poll: false,
+            pid: 0xF0,
+            payload: vec![0; 11],
+        };
+        assert_eq!(
+            errors(&con, &mut data, &Event::Iframe(big.clone(), true)),
+            vec![DlError::O]
+        );
+
+        // Q: UI response.
+        let ui = |len| Ui {
+            push: false,
+            pid: 0xF0,
+            payload: vec![0; len],
+        };
+        let ui_event = |ui, cr| Event::Ui(ui, cr, peer.clone(), Addr::new("M0THC-1").unwrap());
+        for state in [&Disconnected::new() as &dyn State, &con] {
+            let mut data = new_data()?;
+            assert_eq!(
+                errors(state, &mut data, &ui_event(ui(1), false)),
+                vec![DlError::Q],
+                "{}",
+                state.name()
+            );
+        }
+
+        // R: Too big UI.
+        for state in [&Disconnected::new() as &dyn State, &con] {
+            let mut data = new_data()?;
+            data.n1 = 10;
+            assert_eq!(
+                errors(state, &mut data, &ui_event(ui(11), true)),
+                vec![DlError::R],
+                "{}",
+                state.name()
+            );
+        }
+
+        // S: Iframe response.
+        let mut data = new_data()?;
+        assert_eq!(
+            errors(
+                &con,
+                &mut data,
+                &Event::Iframe(
+                    Iframe {
+                        payload: vec![1],
+                        ..big
+                    },
+                    false
+                )
+            ),
+            vec![DlError::S]
+        );
+
+        // T: No response to enquiry.
+        let mut data = new_data()?;
+        data.rc = data.n2 - 1;
+        assert_eq!(errors(&rec, &mut data, &Event::T1), vec![DlError::T]);
+
+        // U: Peer busy for too long.
+        //
+        // RNR is not yet handled in connected states, so set busy directly.
+        let mut data = new_data()?;
+        data.peer_receiver_busy = true;
+        data.rc = data.n2 - 1;
+        assert_eq!(errors(&rec, &mut data, &Event::T1), vec![DlError::U]);
+        Ok(())
+    }
+
+    #[test]
+    fn connect_refused() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        let (con, _) = handle(
+            &Disconnected::new(),
+            &mut data,
+            &Event::Connect {
+                addr: Addr::new("M0THC-2")?,
+                ext: true,
+            },
+        );
+        let con = con.unwrap();
+
+        // Not a reply to our SABME.
+        let (c, _) = handle(&*con, &mut data, &Event::Dm(Dm { poll: false }));
+        assert!(c.is_none());
+        assert!(!data.connect_refused());
+
+        let (c, events) = handle(&*con, &mut data, &Event::Dm(Dm { poll: true }));
+        assert_eq!(c.unwrap().kind(), StateKind::Disconnected);
+        assert!(events.is_empty(), "{events:?}");
+        assert!(data.connect_refused());
+        assert!(!data.t1.running);
+        Ok(())
+    }
+
+    #[test]
+    fn sabme_collision() -> Result<()> {
+        let a = Addr::new("M0THC-1")?;
+        let b = Addr::new("M0THC-2")?;
+        let mut ends = Vec::new();
+        for (me, peer) in [(&a, &b), (&b, &a)] {
+            let mut data = Data::new(me.clone());
+            let (con, events) = handle(
+                &Disconnected::new(),
+                &mut data,
+                &Event::Connect {
+                    addr: peer.clone(),
+                    ext: true,
+                },
+            );
+            // A SABM packet, sent as SABME since the modulus is 128.
+            assert!(matches!(
+                events[..],
+                [ReturnEvent::Packet(Packet {
+                    packet_type: PacketType::Sabm(_),
+                    ..
+                })]
+            ));
+            assert!(data.ext());
+
+            // A SABME from someone else is refused, and not acked to the peer.
+            let other = Addr::new("M0THC-3")?;
+            let con = con.unwrap();
+            let (c, events) = handle(
+                &*con,
+                &mut data,
+                &Event::Sabme(Sabme { poll: true }, other.clone()),
+            );
+            assert!(c.is_none());
+            assert!(
+                matches!(
+                    &events[..],
+                    [ReturnEvent::Packet(Packet {
+                        dst,
+                        packet_type: PacketType::Dm(Dm { poll: true }),
+                        ..
+                    })] if *dst == other
+                ),
+                "{events:?}"
+            );
+            ends.push((con, data, peer.clone()));
+        }
+        for (con, data, peer) in &mut ends {
+            // The SABMEs cross.
+            let (c, events) = handle(
+                &**con,
+                data,
+                &Event::Sabme(Sabme { poll: true }, peer.clone()),
+            );
+            assert!(c.is_none());
+            assert!(matches!(
+                events[..],
+                [ReturnEvent::Packet(Packet {
+                    packet_type: PacketType::Ua(Ua { poll: true }),
+                    ..
+                })]
+            ));
+
+            // And then the UAs.
+            let (c, _) = handle(&**con, data, &Event::Ua(Ua { poll: true }));
+            assert_eq!(c.unwrap().kind(), StateKind::Connected);
+            assert_eq!(data.modulus, 128);
+        }
+
+        // Refused if we're connecting in mod 8.
+        let mut data = Data::new(a.clone());
+        let (con, _) = handle(
+            &Disconnected::new(),
+            &mut data,
+            &Event::Connect {
+                addr: b.clone(),
+                ext: false,
+            },
+        );
+        let (c, events) = handle(
+            &*con.unwrap(),
+            &mut data,
+            &Event::Sabme(Sabme { poll: true }, b.clone()),
+        );
+        assert!(c.is_none());
+        assert!(matches!(
+            events[..],
+            [ReturnEvent::Packet(Packet {
+                packet_type: PacketType::Dm(Dm { poll: true }),
+                ..
+            })]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn awaiting_release() -> Result<()> {
+        let peer = Addr::new("M0THC-2")?;
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(peer.clone());
+        let (rel, events) = handle(
+            &Connected::new(ConnectedState::Connected),
+            &mut data,
+            &Event::Disconnect,
+        );
+        let rel = rel.unwrap();
+        assert_eq!(rel.kind(), StateKind::AwaitingRelease);
+        assert!(matches!(
+            events[..],
+            [ReturnEvent::Packet(Packet {
+                packet_type: PacketType::Disc(_),
+                ..
+            })]
+        ));
+        let sent = |events: &[ReturnEvent]| -> Vec<PacketType> {
+            events
+                .iter()
+                .filter_map(|e| match e {
+                    ReturnEvent::Packet(p) => Some(p.packet_type.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let dm = vec![PacketType::Dm(Dm { poll: true })];
+        for (event, want) in [
+            (Event::Sabm(Sabm { poll: true }, peer.clone()), dm.clone()),
+            (Event::Sabme(Sabme { poll: true }, peer.clone()), dm.clone()),
+            (Event::Rr(Rr { poll: true, nr: 0 }, true), dm.clone()),
+            (Event::Rr(Rr { poll: true, nr: 0 }, false), vec![]),
+            (Event::Rnr(Rnr { poll: false, nr: 0 }, true), vec![]),
+            (Event::Rej(Rej { poll: true, nr: 0 }), dm.clone()),
+            (Event::Srej(Srej { poll: false, nr: 0 }), vec![]),
+        ] {
+            let (c, events) = handle(&*rel, &mut data, &event);
+            assert!(c.is_none(), "{event:?}");
+            assert_eq!(sent(&events), want, "{event:?}");
+        }
+
+        // Others trying to connect get their own DM.
+        let other = Addr::new("M0THC-3")?;
+        let (c, events) = handle(
+            &*rel,
+            &mut data,
+            &Event::Sabme(Sabme { poll: true }, other.clone()),
+        );
+        assert!(c.is_none());
+        assert!(
+            matches!(
+                &events[..],
+                [ReturnEvent::Packet(Packet {
+                    dst,
+                    packet_type: PacketType::Dm(Dm { poll: true }),
+                    ..
+                })] if *dst == other
+            ),
+            "{events:?}"
+        );
+
+        // The peer disconnects at the same time.
+        let (c, events) = handle(&*rel, &mut data, &Event::Disc(Disc { poll: true }));
+        assert_eq!(c.unwrap().kind(), StateKind::Disconnected);
+        assert_eq!(sent(&events), vec![PacketType::Ua(Ua { poll: true })]);
+        Ok(())
+    }
+
+    #[test]
+    fn t1_backoff() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.srt_default(std::time::Duration::from_millis(1));
+        data.n2 = 10;
+        let con = Disconnected::new();
+        let (con, _) = handle(
+            &con,
+            &mut data,
+            &Event::Connect {
+                addr: Addr::new("M0THC-2")?,
+                ext: false,
+            },
+        );
+        let con = con.unwrap();
+        assert_eq!(data.t1v, std::time::Duration::from_millis(2));
+
+        let mut prev = data.t1v;
+        for _ in 0..3 {
+            // Expire T1 without waiting for it.
+            data.t1.expiry = std::time::Instant::now() - std::time::Duration::from_millis(1);
+            assert!(data.t1_expired());
+            let (c, _) = handle(&*con, &mut data, &Event::T1);
+            assert!(c.is_none());
+            assert!(data.t1v > prev, "T1V {:?} <= {prev:?}", data.t1v);
+            assert!(data.t1.remaining().unwrap() <= data.t1v);
+            prev = data.t1v;
+        }
+        // SRT doesn't back off.
+        assert_eq!(data.srt, std::time::Duration::from_millis(1));
+
+        // But T1V only up to a limit.
+        data.t1v = MAX_T1V - std::time::Duration::from_secs(1);
+        data.t1.expiry = std::time::Instant::now() - std::time::Duration::from_millis(1);
+        let (c, _) = handle(&*con, &mut data, &Event::T1);
+        assert!(c.is_none());
+        assert_eq!(data.t1v, MAX_T1V);
+        Ok(())
+    }
+
+    #[test]
+    fn connect_frmr() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        let (con, _) = handle(
+            &Disconnected::new(),
+            &mut data,
+            &Event::Connect {
+                addr: Addr::new("M0THC-2")?,
+                ext: true,
+            },
+        );
+        let (c, events) = handle(&*con.unwrap(), &mut data, &Event::Frmr(Frmr::default()));
+        assert_eq!(c.unwrap().kind(), StateKind::Disconnected);
+        assert!(events.is_empty(), "{events:?}");
+        assert!(data.connect_refused());
+        Ok(())
+    }
+
+    #[test]
+    fn write_while_connecting() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        let con = Disconnected::new();
+        let (con, _) = handle(
+            &con,
+            &mut data,
+            &Event::Connect {
+                addr: Addr::new("M0THC-2")?,
+                ext: false,
+            },
+        );
+        let con = con.unwrap();
+        assert_eq!(con.kind(), StateKind::AwaitingConnection);
+
+        // Nothing sent yet.
+        let (c, events) = handle(&*con, &mut data, &Event::Data(vec![1, 2, 3]));
+        assert!(c.is_none());
+        assert!(events.is_empty(), "{events:?}");
+        assert_eq!(data.bytes_written(), 3);
+
+        // Sent as soon as the connection is up.
+        let (c, events) = handle(&*con, &mut data, &Event::Ua(Ua { poll: true }));
+        assert_eq!(c.unwrap().kind(), StateKind::Connected);
+        assert_all(
+            &[ReturnEvent::Packet(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                command_response: true,
+                command_response_la: false,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Iframe(Iframe {
+                    nr: 0,
+                    ns: 0,
+                    poll: false,
+                    pid: 0xF0,
+                    payload: vec![1, 2, 3],
+                }),
+            })],
+            &events,
+            "write while connecting",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn write_while_connecting_refused() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        let (con, _) = handle(
+            &Disconnected::new(),
+            &mut data,
+            &Event::Connect {
+                addr: Addr::new("M0THC-2")?,
+                ext: false,
+            },
+        );
+        let con = con.unwrap();
+        let (c, _) = handle(&*con, &mut data, &Event::Data(vec![1, 2, 3]));
+        assert!(c.is_none());
+        assert_eq!(data.obuf_len(), 3);
+
+        // Refused. The written data is dropped.
+        let (c, _) = handle(&*con, &mut data, &Event::Dm(Dm { poll: true }));
+        assert_eq!(c.unwrap().kind(), StateKind::Disconnected);
+        assert_eq!(data.obuf_len(), 0);
+        assert_eq!(data.bytes_written(), 0);
+
+        // And not sent on the next connection.
+        let (con, _) = handle(
+            &Disconnected::new(),
+            &mut data,
+            &Event::Connect {
+                addr: Addr::new("M0THC-3")?,
+                ext: false,
+            },
+        );
+        let (c, events) = handle(&*con.unwrap(), &mut data, &Event::Ua(Ua { poll: true }));
+        assert_eq!(c.unwrap().kind(), StateKind::Connected);
+        assert!(events.is_empty(), "{events:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn state_kind() {
+        assert_eq!(Disconnected::new().kind(), StateKind::Disconnected);
+        assert_eq!(
+            AwaitingConnection::new().kind(),
+            StateKind::AwaitingConnection
+        );
+        assert_eq!(AwaitingRelease::new().kind(), StateKind::AwaitingRelease);
+        assert_eq!(
+            Connected::new(ConnectedState::Connected).kind(),
+            StateKind::Connected
+        );
+        assert_eq!(
+            Connected::new(ConnectedState::TimerRecovery).kind(),
+            StateKind::TimerRecovery
+        );
+    }
+
+    #[test]
+    fn requeue_on_reset() -> Result<()> {
+        for requeue in [false, true] {
+            let mut data = Data::new(Addr::new("M0THC-1")?);
+            data.peer = Some(Addr::new("M0THC-2")?);
+            data.requeue_on_reset(requeue);
+            let con = Connected::new(ConnectedState::Connected);
+
+            let (_, _) = handle(&con, &mut data, &Event::Data(vec![1, 2, 3]));
+            let (_, _) = handle(&con, &mut data, &Event::Data(vec![4, 5]));
+            let (_, _) = handle(
+                &con,
+                &mut data,
+                &Event::Rr(Rr { poll: false, nr: 1 }, false),
+            );
+
+            // Peer resets the link with [4, 5] still unacked.
+            let (c, events) = handle(
+                &con,
+                &mut data,
+                &Event::Sabm(Sabm { poll: true }, Addr::new("M0THC-2")?),
+            );
+            assert_eq!(c.unwrap().name(), "Connected");
+            assert_eq!((data.vs, data.va, data.vr), (u8::from(requeue), 0, 0));
+            let iframes: Vec<_> = events
+                .iter()
+                .filter_map(|e| match e {
+                    ReturnEvent::Packet(Packet {
+                        packet_type: PacketType::Iframe(i),
+                        ..
+                    }) => Some(i.clone()),
+                    _ => None,
+                })
+                .collect();
+            if requeue {
+                assert_eq!(iframes.len(), 1);
+                assert_eq!(iframes[0].ns, 0);
+                assert_eq!(iframes[0].payload, vec![4, 5]);
+                assert_eq!(data.iframe_resend_queue.len(), 1);
+            } else {
+                assert!(iframes.is_empty(), "{iframes:?}");
+                assert!(data.iframe_resend_queue.is_empty());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn xid_negotiation() -> Result<()> {
+        let me = Addr::new("M0THC-1")?;
+        let peer = Addr::new("M0THC-2")?;
+        let mut data = Data::new(me.clone());
+        data.listen(true);
+        data.srej(true);
+        let xid = Xid::new(true, false, false, 64, 2);
+
+        // Not for us.
+        let (c, events) = handle(
+            &Disconnected::new(),
+            &mut data,
+            &Event::Xid(xid.clone(), true, peer.clone(), Addr::new("M0THC-3")?),
+        );
+        assert!(c.is_none());
+        assert!(events.is_empty(), "{events:?}");
+        assert!(data.peer_xid.is_none());
+
+        let (c, events) = handle(
+            &Disconnected::new(),
+            &mut data,
+            &Event::Xid(xid.clone(), true, peer.clone(), me.clone()),
+        );
+        assert!(c.is_none());
+        assert_all(
+            &[ReturnEvent::Packet(Packet {
+                src: me.clone(),
+                dst: peer.clone(),
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Xid(Xid::new(true, false, false, DEFAULT_MTU_IN, 7)),
+            })],
+            &events,
+            "xid response",
+        );
+        // Configuration is untouched.
+        assert_eq!(
+            (data.max_modulus, data.srej_enabled, data.mtu_config),
+            (128, true, DEFAULT_MTU_OUT)
+        );
+        assert!(!data.accepts_extended(&peer));
+        assert!(data.accepts_extended(&Addr::new("M0THC-3")?));
+
+        // Survives the connection reset, which would otherwise set k to 4.
+        let (c, _) = handle(
+            &Disconnected::new(),
+            &mut data,
+            &Event::Sabm(Sabm { poll: true }, Addr::new("M0THC-2")?),
+        );
+        assert_eq!(c.unwrap().kind(), StateKind::Connected);
+        assert_eq!((data.modulus, data.k, data.mtu_out), (8, 2, 64));
+        assert!(!data.srej_active());
+        assert!(data.xid_negotiated());
+
+        // XID from others is ignored while connected.
+        let con = Connected::new(ConnectedState::Connected);
+        let (_, events) = handle(
+            &con,
+            &mut data,
+            &Event::Xid(
+                Xid::new(true, false, false, 10, 1),
+                true,
+                Addr::new("M0THC-3")?,
+                me.clone(),
+            ),
+        );
+        assert!(events.is_empty(), "{events:?}");
+        assert_eq!((data.k, data.mtu_out), (2, 64));
+
+        // Extended mode was negotiated away.
+        let (c, events) = handle(
+            &Disconnected::new(),
+            &mut data,
+            &Event::Sabme(Sabme { poll: true }, Addr::new("M0THC-2")?),
+        );
+        assert!(c.is_none());
+        assert_all(
+            &[ReturnEvent::Packet(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Dm(Dm { poll: true }),
+            })],
+            &events,
+            "sabme after xid",
+        );
+
+        // Forgotten when the link goes down.
+        let (c, _) = handle(&con, &mut data, &Event::Disc(Disc { poll: true }));
+        assert_eq!(c.unwrap().kind(), StateKind::Disconnected);
+        assert!(!data.xid_negotiated());
+        assert_eq!(data.mtu_out, DEFAULT_MTU_OUT);
+        assert!(data.accepts_extended(&peer));
+        Ok(())
+    }
+
+    #[test]
+    fn reuse_payloads() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        data.mtu(10);
+        let con = Connected::new(ConnectedState::Connected);
+        let (_, _) = handle(&con, &mut data, &Event::Data(vec![1; 30]));
+        assert_eq!(data.iframe_resend_queue.len(), 3);
+        assert!(data.spare_payloads.is_empty());
+
+        let (_, _) = handle(
+            &con,
+            &mut data,
+            &Event::Rr(Rr { poll: false, nr: 2 }, false),
+        );
+        assert_eq!(data.iframe_resend_queue.len(), 1);
+        assert_eq!(data.spare_payloads.len(), 2);
+        let ptrs: Vec<_> = data.spare_payloads.iter().map(|p| p.as_ptr()).collect();
+
+        // New frames are built in the recycled buffers.
+        let (_, events) = handle(&con, &mut data, &Event::Data(vec![2; 15]));
+        assert!(data.spare_payloads.is_empty());
+        assert_eq!(data.iframe_resend_queue.len(), 3);
+        for (i, want) in data.iframe_resend_queue.iter().skip(1).zip([10, 5]) {
+            assert_eq!(i.payload, vec![2; want]);
+            assert!(ptrs.contains(&i.payload.as_ptr()));
+        }
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(
+                    e,
+                    ReturnEvent::Packet(Packet {
+                        packet_type: PacketType::Iframe(_),
+                        ..
+                    })
+                ))
+                .count(),
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn modulus_change_in_flight() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        let con = Connected::new(ConnectedState::Connected);
+        let (_, _) = handle(&con, &mut data, &Event::Data(vec![1, 2, 3]));
+        assert_eq!(data.iframe_resend_queue.len(), 1);
+
+        // Refused directly, with the frame still numbered mod 8.
+        data.set_version_2_2();
+        assert_eq!((data.modulus, data.k), (8, 7));
+        assert_eq!(data.iframe_resend_queue.len(), 1);
+
+        // A reset to extended mode drops the frame first, then switches.
+        let (c, _) = handle(
+            &con,
+            &mut data,
+            &Event::Sabme(Sabme { poll: true }, Addr::new("M0THC-2")?),
+        );
+        assert_eq!(c.unwrap().name(), "Connected");
+        assert!(data.iframe_resend_queue.is_empty());
+        assert_eq!((data.modulus, data.k), (128, 32));
+
+        // Same thing with requeueing, back to mod 8.
+        data.requeue_on_reset(true);
+        let (_, _) = handle(&con, &mut data, &Event::Data(vec![4, 5]));
+        let (_, events) = handle(
+            &con,
+            &mut data,
+            &Event::Sabm(Sabm { poll: true }, Addr::new("M0THC-2")?),
+        );
+        assert_eq!((data.modulus, data.k), (8, 4));
+        assert_eq!(data.iframe_resend_queue.len(), 1);
+        assert_eq!(data.iframe_resend_queue[0].ns, 0);
+        assert!(events.iter().any(|e| matches!(
+            e,
+            ReturnEvent::Packet(Packet {
+                packet_type: PacketType::Iframe(_),
+                ..
+            })
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn idle_time() -> Result<()> {
+        let hour = std::time::Duration::from_secs(3600);
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        let con = Connected::new(ConnectedState::Connected);
+
+        // Sending data.
+        data.last_activity = std::time::Instant::now() - hour;
+        let (_, _) = handle(&con, &mut data, &Event::Data(vec![1, 2, 3]));
+        assert!(data.idle_time() < hour);
+
+        // Protocol traffic is not activity.
+        data.last_activity = std::time::Instant::now() - hour;
+        let (_, _) = handle(&con, &mut data, &Event::Rr(Rr { poll: true, nr: 1 }, true));
+        let (_, _) = handle(&con, &mut data, &Event::T3);
+        assert!(data.idle_time() >= hour);
+
+        // Receiving data.
+        let (_, _) = handle(
+            &con,
+            &mut data,
+            &Event::Iframe(
+                Iframe {
+                    nr: 1,
+                    ns: 0,
+                    poll: false,
+                    pid: 0xF0,
+                    payload: vec![4, 5],
+                },
+                true,
+            ),
+        );
+        assert!(data.idle_time() < hour);
+        Ok(())
+    }
+
+    #[test]
+    fn lenient_initial_seq() -> Result<()> {
+        let iframe = |ns| {
+            Event::Iframe(
+                Iframe {
+                    nr: 0,
+                    ns,
+                    poll: false,
+                    pid: 0xF0,
+                    payload: vec![ns],
+                },
+                true,
+            )
+        };
+        let delivered = |events: &[ReturnEvent]| -> Vec<Vec<u8>> {
+            events
+                .iter()
+                .filter_map(|e| match e {
+                    ReturnEvent::Data(Res::Some(d)) => Some(d.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        for lenient in [false, true] {
+            let mut data = Data::new(Addr::new("M0THC-1")?);
+            data.listen(true);
+            data.lenient_initial_seq(lenient);
+            let (con, _) = handle(
+                &Disconnected::new(),
+                &mut data,
+                &Event::Sabm(Sabm { poll: true }, Addr::new("M0THC-2")?),
+            );
+            let con = con.unwrap();
+
+            // Peer starts at 3.
+            let (_, events) = handle(&*con, &mut data, &iframe(3));
+            let (_, events2) = handle(&*con, &mut data, &iframe(4));
+            if lenient {
+                assert_eq!(delivered(&events), vec![vec![3]]);
+                assert_eq!(delivered(&events2), vec![vec![4]]);
+                assert_eq!(data.vr, 5);
+
+                // Only the first iframe is adopted.
+                let (_, events) = handle(&*con, &mut data, &iframe(7));
+                assert!(delivered(&events).is_empty());
+                assert_eq!(data.vr, 5);
+            } else {
+                assert!(delivered(&events).is_empty());
+                assert!(delivered(&events2).is_empty());
+                assert_eq!(data.vr, 0);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn retransmit_on_timeout() -> Result<()> {
+        struct Side {
+            state: Box<dyn State>,
+            data: Data,
+            delivered: Vec<u8>,
+            iframes_sent: usize,
+        }
+        impl Side {
+            fn new(me: &str, peer: &str) -> Result<Self> {
+                let mut data = Data::new(Addr::new(me)?);
+                data.peer = Some(Addr::new(peer)?);
+                data.listen(true);
+                Ok(Self {
+                    state: Box::new(Disconnected::new()),
+                    data,
+                    delivered: Vec::new(),
+                    iframes_sent: 0,
+                })
+            }
+            // Handle event, returning any packets to send.
+            fn handle(&mut self, event: &Event) -> Vec<Packet> {
+                let (c, events) = handle(&*self.state, &mut self.data, event);
+                if let Some(c) = c {
+                    self.state = c;
+                }
+                let mut ret = Vec::new();
+                for e in events {
+                    match e {
+                        ReturnEvent::Packet(p) => {
+                            if let PacketType::Iframe(_) = p.packet_type {
+                                self.iframes_sent += 1;
+                            }
+                            ret.push(p);
+                        }
+                        ReturnEvent::Data(Res::Some(d)) => self.delivered.extend(d),
+                        other => panic!("unexpected {other:?}"),
+                    }
+                }
+                ret
+            }
+        }
+        // Deliver packets back and forth until the link is quiet.
+        fn pump(a: &mut Side, b: &mut Side, mut to_b: Vec<Packet>) {
+            while !to_b.is_empty() {
+                let mut to_a = Vec::new();
+                for p in to_b.drain(..) {
+                    to_a.extend(b.handle(&event(&p)));
+                }
+                for p in to_a {
+                    to_b.extend(a.handle(&event(&p)));
+                }
+            }
+        }
+        fn event(p: &Packet) -> Event {
+            match &p.packet_type {
+                PacketType::Sabm(s) => Event::Sabm(s.clone(), p.src.clone()),
+                PacketType::Ua(u) => Event::Ua(u.clone()),
+                PacketType::Iframe(i) => Event::Iframe(i.clone(), p.command_response),
+                PacketType::Rr(r) => Event::Rr(r.clone(), p.command_response),
+                other => panic!("unexpected packet {other:?}"),
+            }
+        }
+
+        let mut a = Side::new("M0THC-1", "M0THC-2")?;
+        let mut b = Side::new("M0THC-2", "M0THC-1")?;
+        let sabm = a.handle(&Event::Connect {
+            addr: Addr::new("M0THC-2")?,
+            ext: false,
+        });
+        pump(&mut a, &mut b, sabm);
+        assert_eq!(a.state.kind(), StateKind::Connected);
+        assert_eq!(b.state.kind(), StateKind::Connected);
+
+        // The frame is lost.
+        let lost = a.handle(&Event::Data(b"hello".to_vec()));
+        assert_eq!(lost.len(), 1);
+        assert_eq!(a.iframes_sent, 1);
+
+        // T1 polls, and the poll response makes it retransmit.
+        let poll = a.handle(&Event::T1);
+        assert_eq!(a.state.kind(), StateKind::TimerRecovery);
+        pump(&mut a, &mut b, poll);
+        assert_eq!(b.delivered, b"hello");
+        assert_eq!(a.iframes_sent, 2, "expected exactly one retransmission");
+
+        // Next T1 gets the ACK, and everything is back to normal.
+        let poll = a.handle(&Event::T1);
+        pump(&mut a, &mut b, poll);
+        assert_eq!(a.state.kind(), StateKind::Connected);
+        assert_eq!(a.data.va, a.data.vs);
+        assert_eq!(a.data.bytes_acked(), 5);
+        assert_eq!(a.iframes_sent, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn srt_from_roundtrip() -> Result<()> {
+        let ms = std::time::Duration::from_millis;
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        let con = Connected::new(ConnectedState::Connected);
+        assert_eq!(data.srt, DEFAULT_SRT);
+
+        // Pretend every frame took `rtt` to be acked.
+        let exchange = |data: &mut Data, rtt| {
+            let (_, _) = handle(&con, data, &Event::Data(vec![1]));
+            let ns = data.va;
+            let sent = data.iframe_sent_at.get_mut(&ns).expect("send time");
+            *sent -= rtt;
+            let (_, _) = handle(
+                &con,
+                data,
+                &Event::Rr(
+                    Rr {
+                        poll: false,
+                        nr: data.vs,
+                    },
+                    false,
+                ),
+            );
+        };
+
+        // First measurement is taken as is.
+        exchange(&mut data, ms(400));
+        assert!(data.srt >= ms(400) && data.srt < ms(450), "{:?}", data.srt);
+        assert_eq!(data.t1v, data.srt * 2);
+
+        // Then smoothed towards the new roundtrip.
+        let mut prev = data.srt;
+        for _ in 0..3 {
+            exchange(&mut data, ms(200));
+            assert!(data.srt < prev, "{:?} >= {prev:?}", data.srt);
+            prev = data.srt;
+        }
+        assert!(data.srt > ms(250) && data.srt < ms(350), "{:?}", data.srt);
+
+        // Retransmitted frames are not measured.
+        let (_, _) = handle(&con, &mut data, &Event::Data(vec![1]));
+        let _ = data.invoke_retransmission(data.va);
+        assert!(data.iframe_sent_at.is_empty());
+        let srt = data.srt;
+        let nr = data.vs;
+        let (_, _) = handle(&con, &mut data, &Event::Rr(Rr { poll: false, nr }, false));
+        assert_eq!(data.srt, srt);
+
+        // Fast links don't go below the floor.
+        for _ in 0..50 {
+            exchange(&mut data, ms(0));
+        }
+        assert_eq!(data.srt, MIN_SRT);
+        Ok(())
+    }
+
+    #[test]
+    fn segmentation() -> Result<()> {
+        let mut tx = Data::new(Addr::new("M0THC-1")?);
+        tx.peer = Some(Addr::new("M0THC-2")?);
+        tx.mtu(10);
+        tx.segmentation(true);
+        let con = Connected::new(ConnectedState::Connected);
+
+        // Send through serialization, to check the PID is kept.
+        let mut frames = Vec::new();
+        let mut send = |events: Vec<ReturnEvent>| -> Result<()> {
+            for e in events {
+                let ReturnEvent::Packet(p) = e else {
+                    panic!("unexpected {e:?}");
+                };
+                let PacketType::Iframe(i) =
+                    Packet::parse(&p.serialize(false), Some(false))?.packet_type
+                else {
+                    panic!("not iframe: {p:?}");
+                };
+                frames.push(i);
+            }
+            Ok(())
+        };
+        let (_, events) = handle(&con, &mut tx, &Event::Data(vec![1, 2, 3]));
+        send(events)?;
+        let big: Vec<u8> = (0..30).collect();
+        let (_, events) = handle(&con, &mut tx, &Event::Data(big.clone()));
+        send(events)?;
+        assert_eq!(
+            frames
+                .iter()
+                .map(|i| (i.pid, i.payload.first().copied()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0xF0, Some(1)),
+                (PID_SEGMENT, Some(0x83)),
+                (PID_SEGMENT, Some(0x02)),
+                (PID_SEGMENT, Some(0x01)),
+                (PID_SEGMENT, Some(0x00)),
+            ]
+        );
+        assert!(frames.iter().all(|i| i.payload.len() <= 10));
+
+        // Reassembled on the other side.
+        let mut rx = Data::new(Addr::new("M0THC-2")?);
+        rx.peer = Some(Addr::new("M0THC-1")?);
+        let mut got = Vec::new();
+        for i in &frames {
+            let (_, events) = handle(&con, &mut rx, &Event::Iframe(i.clone(), true));
+            got.extend(events);
+        }
+        assert_eq!(
+            got,
+            vec![
+                ReturnEvent::Data(Res::Some(vec![1, 2, 3])),
+                ReturnEvent::Data(Res::Some(big)),
+            ]
+        );
+
+        // Segment headers are not counted as acked application data.
+        let (_, _) = handle(&con, &mut tx, &Event::Rr(Rr { poll: false, nr: 5 }, false));
+        assert_eq!(tx.bytes_acked(), 33);
+
+        // Missing the first segment, the rest is dropped.
+        let mut rx = Data::new(Addr::new("M0THC-2")?);
+        rx.peer = Some(Addr::new("M0THC-1")?);
+        rx.vr = 2;
+        for i in &frames[2..] {
+            let (_, events) = handle(&con, &mut rx, &Event::Iframe(i.clone(), true));
+            assert!(events.is_empty(), "{events:?}");
+        }
+        assert!(rx.reassembly.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn priority_data() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        data.mtu_out = 2;
+        data.k = 1;
+        let con = Connected::new(ConnectedState::Connected);
+        let sent = |events: &[ReturnEvent]| -> Vec<Vec<u8>> {
+            events
+                .iter()
+                .filter_map(|e| match e {
+                    ReturnEvent::Packet(Packet {
+                        packet_type: PacketType::Iframe(i),
+                        ..
+                    }) => Some(i.payload.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let (_, events) = handle(&con, &mut data, &Event::Data(vec![1, 2, 3, 4]));
+        assert_eq!(sent(&events), vec![vec![1, 2]]);
+
+        // Window full, so this is buffered, but ahead of the rest.
+        let (_, events) = handle(&con, &mut data, &Event::PriorityData(vec![9]));
+        assert!(sent(&events).is_empty(), "{events:?}");
+        assert_eq!(data.obuf_len(), 3);
+        assert_eq!(data.bytes_written(), 5);
+
+        let (_, events) = handle(
+            &con,
+            &mut data,
+            &Event::Rr(Rr { poll: false, nr: 1 }, false),
+        );
+        assert_eq!(sent(&events), vec![vec![9, 3]]);
+        let (_, events) = handle(
+            &con,
+            &mut data,
+            &Event::Rr(Rr { poll: false, nr: 2 }, false),
+        );
+        assert_eq!(sent(&events), vec![vec![4]]);
+        Ok(())
+    }
+
+    #[test]
+    fn priority_data_after_segments() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        data.segmentation = true;
+        data.mtu_out = 4;
+        data.k = 1;
+        let con = Connected::new(ConnectedState::Connected);
+        let sent = |events: &[ReturnEvent]| -> Vec<u8> {
+            events
+                .iter()
+                .filter_map(|e| match e {
+                    ReturnEvent::Packet(Packet {
+                        packet_type: PacketType::Iframe(i),
+                        ..
+                    }) => Some(i.pid),
+                    _ => None,
+                })
+                .collect()
+        };
+        let (_, events) = handle(&con, &mut data, &Event::Data(vec![1, 2, 3, 4, 5, 6]));
+        let mut pids = sent(&events);
+        let (_, events) = handle(&con, &mut data, &Event::PriorityData(vec![9]));
+        assert!(sent(&events).is_empty(), "{events:?}");
+
+        // The rest of the segmented write goes first.
+        for nr in 1..8 {
+            let (_, events) = handle(&con, &mut data, &Event::Rr(Rr { poll: false, nr }, false));
+            pids.extend(sent(&events));
+        }
+        assert_eq!(pids, vec![PID_SEGMENT, PID_SEGMENT, PID_SEGMENT, NO_L3]);
+        Ok(())
+    }
+
+    #[test]
+    fn window_and_n2() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        data.mtu(1);
+        data.window(2);
+        data.n2(20);
+        data.set_version_2_2();
+        assert_eq!((data.k, data.n2), (2, 20));
+        data.set_version_2();
+        assert_eq!((data.k, data.n2), (2, 20));
+
+        let con = Connected::new(ConnectedState::Connected);
+        let (_, events) = handle(&con, &mut data, &Event::Data(vec![0; 5]));
+        let iframes = events
+            .iter()
+            .filter(|e| {
+                matches!(
+                    e,
+                    ReturnEvent::Packet(Packet {
+                        packet_type: PacketType::Iframe(_),
+                        ..
+                    })
+                )
+            })
+            .count();
+        assert_eq!(iframes, 2);
+
+        // Limited by modulus.
+        data.window(100);
+        assert_eq!(data.k, 7);
+        data.clear_iframe_queue();
+        data.set_version_2_2();
+        assert_eq!(data.k, 100);
+        data.window(200);
+        assert_eq!(data.k, 127);
+        Ok(())
+    }
+
+    #[test]
+    fn connect_window() -> Result<()> {
+        for (ext, window, want) in [
+            (false, None, 4),
+            (false, Some(20), 7),
+            (true, None, 32),
+            (true, Some(20), 20),
+        ] {
+            let mut data = Data::new(Addr::new("M0THC-1")?);
+            if let Some(k) = window {
+                data.window(k);
+            }
+            let (c, _) = handle(
+                &Disconnected::new(),
+                &mut data,
+                &Event::Connect {
+                    addr: Addr::new("M0THC-2")?,
+                    ext,
+                },
+            );
+            assert_eq!(c.unwrap().kind(), StateKind::AwaitingConnection);
+            assert_eq!(
+                (data.modulus, data.k),
+                (if ext { 128 } else { 8 }, want),
+                "ext={ext} window={window:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn adaptive_window() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        data.mtu_out = 1;
+        data.adaptive_window(true);
+        data.clear_exception_conditions();
+        assert_eq!(data.k, 1);
+        let con = Connected::new(ConnectedState::Connected);
+        let rec = Connected::new(ConnectedState::TimerRecovery);
+        let iframes = |events: &[ReturnEvent]| {
+            events
+                .iter()
+                .filter(|e| {
+                    matches!(
+                        e,
+                        ReturnEvent::Packet(Packet {
+                            packet_type: PacketType::Iframe(_),
+                            ..
+                        })
+                    )
+                })
+                .count()
+        };
+
+        // Clean link. Each window fully acked grows it by one.
+        for k in 1..6 {
+            assert_eq!(data.k, k);
+            let (_, events) = handle(&con, &mut data, &Event::Data(vec![0; k as usize]));
+            assert_eq!(iframes(&events), k as usize);
+            let nr = data.vs;
+            let _ = handle(&con, &mut data, &Event::Rr(Rr { poll: false, nr }, false));
+        }
+        assert_eq!(data.k, 6);
+
+        // Lossy link. Nothing acked, so it's all retransmitted.
+        let (_, events) = handle(&con, &mut data, &Event::Data(vec![0; 6]));
+        assert_eq!(iframes(&events), 6);
+        let nr = data.va;
+        let _ = handle(&con, &mut data, &Event::T1);
+        let (_, events) = handle(&rec, &mut data, &Event::Rr(Rr { poll: true, nr }, false));
+        assert_eq!(iframes(&events), 6);
+        assert_eq!(data.k, 3);
+
+        // Clean again.
+        let nr = data.vs;
+        let _ = handle(&rec, &mut data, &Event::T1);
+        let (c, _) = handle(&rec, &mut data, &Event::Rr(Rr { poll: true, nr }, false));
+        assert_eq!(c.map(|c| c.kind()), Some(StateKind::Connected));
+        assert_eq!(data.k, 4);
+
+        // Only a smaller window is sent.
+        let (_, events) = handle(&con, &mut data, &Event::Data(vec![0; 6]));
+        assert_eq!(iframes(&events), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn adaptive_window_srej_batch() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        data.mtu_out = 1;
+        data.adaptive_window(true);
+        data.clear_exception_conditions();
+        data.k = 6;
+        let con = Connected::new(ConnectedState::Connected);
+        let _ = handle(&con, &mut data, &Event::Data(vec![0; 6]));
+        assert_eq!(data.vs, 6);
+
+        // Three frames lost in the same batch only halve k once.
+        for nr in [1, 3, 4] {
+            let _ = handle(&con, &mut data, &Event::Srej(Srej { poll: false, nr }));
+        }
+        assert_eq!(data.k, 3);
+
+        // Once that batch is acked, a new loss shrinks it again.
+        let _ = handle(
+            &con,
+            &mut data,
+            &Event::Rr(Rr { poll: false, nr: 6 }, false),
+        );
+        assert_eq!(data.k, 4);
+        let _ = handle(&con, &mut data, &Event::Data(vec![0; 2]));
+        let _ = handle(&con, &mut data, &Event::Srej(Srej { poll: false, nr: 6 }));
+        assert_eq!(data.k, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn adaptive_window_peer_xid() -> Result<()> {
+        let peer = Addr::new("M0THC-2")?;
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(peer.clone());
+        data.adaptive_window(true);
+        data.peer_xid = Some((peer, Xid::new(false, false, false, 256, 2)));
+        data.clear_exception_conditions();
+        assert_eq!(data.k, 1);
+        for _ in 0..20 {
+            data.window_acked();
+        }
+        assert_eq!(data.k, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn window_overrun() -> Result<()> {
+        let iframe = |ns| {
+            Event::Iframe(
+                Iframe {
+                    nr: 0,
+                    ns,
+                    poll: false,
+                    pid: 0xF0,
+                    payload: vec![ns],
+                },
+                true,
+            )
+        };
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.listen(true);
+        let (con, _) = handle(
+            &Disconnected::new(),
+            &mut data,
+            &Event::Sabm(Sabm { poll: true }, Addr::new("M0THC-2")?),
+        );
+        let con = con.unwrap();
+        data.strict_window(true);
+        assert_eq!(data.k, 4);
+
+        // A full window is fine.
+        for ns in 0..4 {
+            let (_, events) = handle(&*con, &mut data, &iframe(ns));
+            assert!(
+                events.contains(&ReturnEvent::Data(Res::Some(vec![ns]))),
+                "{events:?}"
+            );
+        }
+
+        // One more, without waiting for our ACK.
+        let (_, events) = handle(&*con, &mut data, &iframe(4));
+        assert_eq!(events, vec![ReturnEvent::DlError(DlError::P)]);
+        assert_eq!(data.vr, 4);
+
+        // Peer asks, and gets told about all four.
+        let (_, events) = handle(&*con, &mut data, &Event::Rr(Rr { poll: true, nr: 0 }, true));
+        assert_eq!(events.len(), 1, "{events:?}");
+        assert_eq!(data.nr_sent, 4);
+
+        // Retransmission because the ACK got lost is not an overrun.
+        let (_, events) = handle(&*con, &mut data, &iframe(3));
+        assert!(
+            !events.contains(&ReturnEvent::DlError(DlError::P)),
+            "{events:?}"
+        );
+
+        // Now there's room.
+        let (_, events) = handle(&*con, &mut data, &iframe(4));
+        assert!(
+            events.contains(&ReturnEvent::Data(Res::Some(vec![4]))),
+            "{events:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_while_disconnected() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        let con = Disconnected::new();
+        let test = |cr, dst: &str| -> Result<Event> {
+            Ok(Event::Test(
+                Test {
+                    poll: true,
+                    payload: vec![1, 2, 3],
+                },
+                cr,
+                Addr::new("M0THC-2")?,
+                Addr::new(dst)?,
+            ))
+        };
+        let (c, events) = handle(&con, &mut data, &test(true, "M0THC-1")?);
+        assert!(c.is_none());
+        assert_eq!(
+            events,
+            vec![ReturnEvent::Packet(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Test(Test {
+                    poll: true,
+                    payload: vec![1, 2, 3],
+                }),
+            })]
+        );
+        assert!(data.peer.is_none());
+
+        // Responses, and commands to others, are not answered.
+        let (_, events) = handle(&con, &mut data, &test(false, "M0THC-1")?);
+        assert!(events.is_empty(), "{events:?}");
+        let (_, events) = handle(&con, &mut data, &test(true, "M0THC-3")?);
+        assert!(events.is_empty(), "{events:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_payload_size() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.mtu(100);
+        let con = Connected::new(ConnectedState::Connected);
+        for (len, want) in [(100, 100), (101, 0)] {
+            let payload: Vec<u8> = (0..len).map(|n| n as u8).collect();
+            let (_, events) = handle(
+                &con,
+                &mut data,
+                &Event::Test(
+                    Test {
+                        poll: true,
+                        payload: payload.clone(),
+                    },
+                    true,
+                    Addr::new("M0THC-2")?,
+                    Addr::new("M0THC-1")?,
+                ),
+            );
+            let [ReturnEvent::Packet(Packet {
+                packet_type: PacketType::Test(t),
+                ..
+            })] = &events[..]
+            else {
+                panic!("not a TEST reply: {events:?}");
+            };
+            assert_eq!(t.payload, payload[..want], "payload length {len}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ui_command_response() -> Result<()> {
+        let me = Addr::new("M0THC-1")?;
+        let peer = Addr::new("M0THC-2")?;
+        let ui = |push, cr, dst: &Addr| {
+            Event::Ui(
+                Ui {
+                    push,
+                    pid: 0xF0,
+                    payload: vec![1, 2, 3],
+                },
+                cr,
+                peer.clone(),
+                dst.clone(),
+            )
+        };
+        let response = |pt| {
+            ReturnEvent::Packet(Packet {
+                src: me.clone(),
+                dst: peer.clone(),
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: pt,
+            })
+        };
+        let aprs = Addr::new("APRS")?;
+        let dis = Disconnected::new();
+        let con = Connected::new(ConnectedState::Connected);
+        for (state, reply) in [
+            (
+                &dis as &dyn State,
+                response(PacketType::Dm(Dm { poll: true })),
+            ),
+            (&con, response(PacketType::Rr(Rr { poll: true, nr: 0 }))),
+        ] {
+            let name = state.name();
+            let new_data = || {
+                let mut data = Data::new(me.clone());
+                if name == "Connected" {
+                    data.peer = Some(peer.clone());
+                }
+                data
+            };
+
+            // Plain command.
+            let (c, events) = handle(state, &mut new_data(), &ui(false, true, &me));
+            assert!(c.is_none());
+            assert!(events.is_empty(), "{name}: {events:?}");
+
+            // Command with poll.
+            let (c, events) = handle(state, &mut new_data(), &ui(true, true, &me));
+            assert!(c.is_none());
+            assert_eq!(events, vec![reply], "{name}");
+
+            // Command with poll, but not to us.
+            let (_, events) = handle(state, &mut new_data(), &ui(true, true, &aprs));
+            assert!(events.is_empty(), "{name}: {events:?}");
+
+            // Response, with and without the final bit.
+            for push in [false, true] {
+                let (c, events) = handle(state, &mut new_data(), &ui(push, false, &me));
+                assert!(c.is_none());
+                assert_eq!(events, vec![ReturnEvent::DlError(DlError::Q)], "{name}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pause_resume() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        data.t3.start(data.t3v);
+        let con = Connected::new(ConnectedState::Connected);
+        let packet = |pt| Packet {
+            src: Addr::new("M0THC-1").unwrap(),
+            dst: Addr::new("M0THC-2").unwrap(),
+            command_response: false,
+            command_response_la: true,
+            digipeater: vec![],
+            rr_dist1: false,
+            rr_extseq: false,
+            packet_type: pt,
+        };
+
+        let (c, events) = handle(&con, &mut data, &Event::Pause);
+        assert!(c.is_none());
+        assert_eq!(
+            events,
+            vec![ReturnEvent::Packet(packet(PacketType::Rnr(Rnr {
+                poll: false,
+                nr: 0
+            })))]
+        );
+        assert!(data.paused());
+        assert!(data.t3.remaining().is_none());
+
+        // Data is buffered, not sent.
+        let (_, events) = handle(&con, &mut data, &Event::Data(vec![1, 2, 3]));
+        assert!(events.is_empty(), "{events:?}");
+        assert_eq!(data.obuf_len(), 3);
+
+        // T3 doesn't probe.
+        let (c, events) = handle(&con, &mut data, &Event::T3);
+        assert!(c.is_none());
+        assert!(events.is_empty(), "{events:?}");
+
+        // Resume says we're ready, and sends the buffered data.
+        let (c, events) = handle(&con, &mut data, &Event::Resume);
+        assert!(c.is_none());
+        assert_eq!(
+            events,
+            vec![
+                ReturnEvent::Packet(packet(PacketType::Rr(Rr { poll: false, nr: 0 }))),
+                ReturnEvent::Packet(Packet {
+                    command_response: true,
+                    command_response_la: false,
+                    ..packet(PacketType::Iframe(Iframe {
+                        nr: 0,
+                        ns: 0,
+                        poll: false,
+                        pid: 0xF0,
+                        payload: vec![1, 2, 3],
+                    }))
+                }),
+            ]
+        );
+        assert!(!data.paused());
+        assert_eq!(data.obuf_len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn idle_timeout() -> Result<()> {
+        let con = Connected::new(ConnectedState::Connected);
+        let new_data = || -> Result<Data> {
+            let mut data = Data::new(Addr::new("M0THC-1")?);
+            data.peer = Some(Addr::new("M0THC-2")?);
+            data.idle_timeout(std::time::Duration::from_secs(60));
+            data.t3.start(data.t3v);
+            Ok(data)
+        };
+
+        // Not idle long enough, so probe.
+        let mut data = new_data()?;
+        let (c, _) = handle(&con, &mut data, &Event::T3);
+        assert_eq!(c.unwrap().name(), "TimerRecovery");
+
+        // Idle.
+        let mut data = new_data()?;
+        data.last_activity -= std::time::Duration::from_secs(61);
+        let (c, events) = handle(&con, &mut data, &Event::T3);
+        assert_eq!(c.unwrap().name(), "AwaitingRelease");
+        assert!(matches!(
+            &events[..],
+            [ReturnEvent::Packet(Packet {
+                packet_type: PacketType::Disc(_),
+                ..
+            })]
+        ));
+
+        // Writing is activity.
+        let mut data = new_data()?;
+        data.last_activity -= std::time::Duration::from_secs(61);
+        let _ = handle(&con, &mut data, &Event::Data(vec![1]));
+        data.t1.stop();
+        let (c, _) = handle(&con, &mut data, &Event::T3);
+        assert_eq!(c.unwrap().name(), "TimerRecovery");
+        Ok(())
+    }
+
+    #[test]
+    fn receiver_busy() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        data.t3.start(data.t3v);
+        let con = Connected::new(ConnectedState::Connected);
+        let packet = |pt| Packet {
+            src: Addr::new("M0THC-1").unwrap(),
+            dst: Addr::new("M0THC-2").unwrap(),
+            command_response: false,
+            command_response_la: true,
+            digipeater: vec![],
+            rr_dist1: false,
+            rr_extseq: false,
+            packet_type: pt,
+        };
+        let iframe = |ns, poll| {
+            Event::Iframe(
+                Iframe {
+                    nr: 0,
+                    ns,
+                    poll,
+                    pid: 0xF0,
+                    payload: vec![1, 2, 3],
+                },
+                true,
+            )
+        };
+
+        let (c, events) = handle(&con, &mut data, &Event::ReceiverBusy(true));
+        assert!(c.is_none());
+        assert_eq!(
+            events,
+            vec![ReturnEvent::Packet(packet(PacketType::Rnr(Rnr {
+                poll: false,
+                nr: 0
+            })))]
+        );
+        assert!(data.receiver_busy());
+
+        // Already busy.
+        let (_, events) = handle(&con, &mut data, &Event::ReceiverBusy(true));
+        assert!(events.is_empty(), "{events:?}");
+
+        // Incoming data is discarded, and polls answered with RNR.
+        let (_, events) = handle(&con, &mut data, &iframe(0, false));
+        assert!(events.is_empty(), "{events:?}");
+        let (_, events) = handle(&con, &mut data, &iframe(0, true));
+        assert_eq!(
+            events,
+            vec![ReturnEvent::Packet(packet(PacketType::Rnr(Rnr {
+                poll: true,
+                nr: 0
+            })))]
+        );
+        assert_eq!(data.vr, 0);
+
+        // Our own data is still sent.
+        let (_, events) = handle(&con, &mut data, &Event::Data(vec![4, 5]));
+        assert_eq!(events.len(), 1, "{events:?}");
+        assert_eq!(data.obuf_len(), 0);
+
+        // Pausing and resuming while busy leaves us busy.
+        let (_, events) = handle(&con, &mut data, &Event::Pause);
+        assert!(events.is_empty(), "{events:?}");
+        let (_, events) = handle(&con, &mut data, &Event::Resume);
+        assert!(events.is_empty(), "{events:?}");
+        assert!(data.own_receiver_busy);
+
+        let (c, events) = handle(&con, &mut data, &Event::ReceiverBusy(false));
+        assert!(c.is_none());
+        assert_eq!(
+            events,
+            vec![ReturnEvent::Packet(packet(PacketType::Rr(Rr {
+                poll: false,
+                nr: 0
+            })))]
+        );
+        assert!(!data.receiver_busy());
+
+        // Resent data is now accepted.
+        let (_, events) = handle(&con, &mut data, &iframe(0, false));
+        assert!(
+            events.contains(&ReturnEvent::Data(Res::Some(vec![1, 2, 3]))),
+            "{events:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rnr_timer_recovery() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        let con = Connected::new(ConnectedState::Connected);
+        let rec = Connected::new(ConnectedState::TimerRecovery);
+        let iframes = |events: &[ReturnEvent]| {
+            events
+                .iter()
+                .filter(|e| {
+                    matches!(
+                        e,
+                        ReturnEvent::Packet(Packet {
+                            packet_type: PacketType::Iframe(_),
+                            ..
+                        })
+                    )
+                })
+                .count()
+        };
+        let (_, events) = handle(&con, &mut data, &Event::Data(vec![1, 2, 3]));
+        assert_eq!(iframes(&events), 1);
+
+        // Peer busy, so the outstanding frame is not retransmitted.
+        let (c, events) = handle(
+            &rec,
+            &mut data,
+            &Event::Rnr(Rnr { poll: true, nr: 0 }, false),
+        );
+        assert!(c.is_none());
+        assert_eq!(iframes(&events), 0, "{events:?}");
+        assert!(data.peer_receiver_busy);
+        assert!(data.t1.remaining().is_some());
+
+        // T1 only polls.
+        let (c, events) = handle(&rec, &mut data, &Event::T1);
+        assert_eq!(c.map(|c| c.kind()), Some(StateKind::TimerRecovery));
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(
+                    e,
+                    ReturnEvent::Packet(Packet {
+                        packet_type: PacketType::Rr(Rr { poll: true, .. }),
+                        ..
+                    })
+                ))
+                .count(),
+            1,
+            "{events:?}"
+        );
+        assert_eq!(iframes(&events), 0, "{events:?}");
+
+        // Still busy, but everything acked.
+        let (c, events) = handle(
+            &rec,
+            &mut data,
+            &Event::Rnr(Rnr { poll: true, nr: 1 }, false),
+        );
+        assert_eq!(c.map(|c| c.kind()), Some(StateKind::Connected));
+        assert_eq!(iframes(&events), 0, "{events:?}");
+        assert_eq!(data.va, data.vs);
+        assert!(data.peer_receiver_busy);
+
+        // Busy peer with nothing outstanding times out as U.
+        data.rc = data.n2 - 1;
+        let (_, events) = handle(&rec, &mut data, &Event::T1);
+        assert!(
+            events.contains(&ReturnEvent::DlError(DlError::U)),
+            "{events:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_ua() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        let con = Disconnected::new();
+        let (con, _) = handle(
+            &con,
+            &mut data,
+            &Event::Connect {
+                addr: Addr::new("M0THC-2")?,
+                ext: false,
+            },
+        );
+        let (con, _) = handle(&*con.unwrap(), &mut data, &Event::Ua(Ua { poll: true }));
+        let con = con.unwrap();
+        assert_eq!(con.kind(), StateKind::Connected);
+
+        // The same UA again is ignored.
+        let (c, events) = handle(&*con, &mut data, &Event::Ua(Ua { poll: true }));
+        assert!(c.is_none());
+        assert!(events.is_empty(), "{events:?}");
+
+        // But a UA on a connection we accepted is unexpected.
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.listen(true);
+        let con = Disconnected::new();
+        let (con, _) = handle(
+            &con,
+            &mut data,
+            &Event::Sabm(Sabm { poll: true }, Addr::new("M0THC-2")?),
+        );
+        let con = con.unwrap();
+        assert_eq!(con.kind(), StateKind::Connected);
+        let (c, events) = handle(&*con, &mut data, &Event::Ua(Ua { poll: true }));
+        assert_eq!(c.unwrap().kind(), StateKind::AwaitingConnection);
+        assert!(
+            events.contains(&ReturnEvent::DlError(DlError::C)),
+            "{events:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reorder_without_srej() -> Result<()> {
+        let iframe = |ns: u8, poll| {
+            Event::Iframe(
+                Iframe {
+                    nr: 0,
+                    ns,
+                    poll,
+                    pid: 0xF0,
+                    payload: vec![ns],
+                },
+                true,
+            )
+        };
+        let con = Connected::new(ConnectedState::Connected);
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        data.k = 4;
+        data.vr = 6;
+        data.nr_sent = 6;
+
+        // Frame 6 is lost, and 7 and 0 stored across the wrap around.
+        let (_, events) = handle(&con, &mut data, &iframe(7, false));
+        assert_eq!(events.len(), 1, "expected just REJ, got {events:?}");
+        let (_, events) = handle(&con, &mut data, &iframe(0, false));
+        assert!(events.is_empty(), "{events:?}");
+        assert_eq!(data.reorder_buffer.len(), 2);
+
+        // Gap filled.
+        let (_, events) = handle(&con, &mut data, &iframe(6, false));
+        assert_eq!(
+            events,
+            vec![
+                ReturnEvent::Data(Res::Some(vec![6])),
+                ReturnEvent::Data(Res::Some(vec![7])),
+                ReturnEvent::Data(Res::Some(vec![0])),
+            ]
+        );
+        assert_eq!(data.vr, 1);
+        assert!(!data.reject_exception);
+
+        // Resent frames after the REJ are discarded, without another REJ.
+        let (_, events) = handle(&con, &mut data, &iframe(7, false));
+        assert!(events.is_empty(), "{events:?}");
+        let (_, events) = handle(&con, &mut data, &iframe(0, true));
+        assert_eq!(events.len(), 1);
+        let ReturnEvent::Packet(Packet {
+            packet_type: PacketType::Rr(rr),
+            ..
+        }) = &events[0]
+        else {
+            panic!("expected RR, got {events:?}");
+        };
+        assert_eq!(*rr, Rr { poll: true, nr: 1 });
+        let (_, events) = handle(&con, &mut data, &iframe(1, false));
+        assert_eq!(events, vec![ReturnEvent::Data(Res::Some(vec![1]))]);
+        assert!(data.reorder_delivered.is_empty());
+
+        // With k=7 a frame one ahead could also be one of seven behind.
+        data.k = 7;
+        let (_, _) = handle(&con, &mut data, &iframe(3, false));
+        assert!(data.reorder_buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn srej_resent_duplicate() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-2")?);
+        data.peer = Some(Addr::new("M0THC-1")?);
+        data.srej(true);
+        let con = Connected::new(ConnectedState::Connected);
+        // Polled, so that every frame is acked.
+        let iframe = |ns, payload| {
+            Event::Iframe(
+                Iframe {
+                    nr: 0,
+                    ns,
+                    poll: true,
+                    pid: 0xF0,
+                    payload,
+                },
+                true,
+            )
+        };
+        let delivered = |events: &[ReturnEvent]| -> Vec<Vec<u8>> {
+            events
+                .iter()
+                .filter_map(|e| match e {
+                    ReturnEvent::Data(Res::Some(d)) => Some(d.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        for ns in 0..7 {
+            let _ = handle(&con, &mut data, &iframe(ns, vec![ns]));
+        }
+        assert_eq!(data.vr, 7);
+
+        // Our ack got lost, and the peer resends frame 6. It's not stored, and
+        // nothing is SREJed.
+        let (_, events) = handle(&con, &mut data, &iframe(6, vec![0xEE]));
+        assert!(data.reorder_buffer.is_empty());
+        assert!(data.srej_requested.is_empty());
+        assert!(delivered(&events).is_empty(), "{events:?}");
+
+        // After wrapping, the real frame 6 is delivered, not the old one.
+        for ns in [7, 0, 1, 2, 3, 4, 5] {
+            let (_, events) = handle(&con, &mut data, &iframe(ns, vec![ns]));
+            assert_eq!(delivered(&events), vec![vec![ns]]);
+        }
+        let (_, events) = handle(&con, &mut data, &iframe(6, vec![6]));
+        assert_eq!(delivered(&events), vec![vec![6]]);
+        Ok(())
+    }
+
+    #[test]
+    fn srej_ambiguous_window_rejects() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-2")?);
+        data.peer = Some(Addr::new("M0THC-1")?);
+        data.srej(true);
+        data.window(7);
+        let con = Connected::new(ConnectedState::Connected);
+
+        // With mod-8 and k=7 frame 1 could be a resend, so it's not known
+        // what's missing. REJ instead of waiting for T1.
+        let (_, events) = handle(
+            &con,
+            &mut data,
+            &Event::Iframe(
+                Iframe {
+                    nr: 0,
+                    ns: 1,
+                    poll: false,
+                    pid: 0xF0,
+                    payload: vec![1],
+                },
+                true,
+            ),
+        );
+        let sent: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ReturnEvent::Packet(p) => Some(p.packet_type.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, vec![PacketType::Rej(Rej { poll: false, nr: 0 })]);
+        assert!(data.reorder_buffer.is_empty());
+        assert!(data.srej_requested.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn srej_multi_gap() -> Result<()> {
+        let sent = |events: &[ReturnEvent]| -> Vec<PacketType> {
+            events
+                .iter()
+                .filter_map(|e| match e {
+                    ReturnEvent::Packet(p) => Some(p.packet_type.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let delivered = |events: &[ReturnEvent]| -> Vec<Vec<u8>> {
+            events
+                .iter()
+                .filter_map(|e| match e {
+                    ReturnEvent::Data(Res::Some(d)) => Some(d.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Sender sends five frames.
+        let mut tx = Data::new(Addr::new("M0THC-1")?);
+        tx.peer = Some(Addr::new("M0THC-2")?);
+        let txcon = Connected::new(ConnectedState::Connected);
+        let mut frames = Vec::new();
+        for n in 0..5 {
+            let (_, events) = handle(&txcon, &mut tx, &Event::Data(vec![n]));
+            for t in sent(&events) {
+                let PacketType::Iframe(i) = t else {
+                    panic!("expected iframe, got {t:?}");
+                };
+                frames.push(i);
+            }
+        }
+        assert_eq!(frames.len(), 5);
+        frames[4].poll = true;
+
+        // Receiver gets all but 1 and 3.
+        // With k=4, frames up to 3 ahead of V(R) can't be resends.
+        let mut rx = Data::new(Addr::new("M0THC-2")?);
+        rx.peer = Some(Addr::new("M0THC-1")?);
+        rx.srej(true);
+        rx.window(4);
+        let rxcon = Connected::new(ConnectedState::Connected);
+        let mut srejs = Vec::new();
+        for n in [0, 2, 4] {
+            let (_, events) = handle(&rxcon, &mut rx, &Event::Iframe(frames[n].clone(), true));
+            srejs.extend(sent(&events));
+        }
+        assert_eq!(
+            srejs,
+            vec![
+                PacketType::Srej(Srej { poll: false, nr: 1 }),
+                PacketType::Srej(Srej { poll: true, nr: 3 }),
+            ]
+        );
+        assert_eq!(rx.vr, 1);
+
+        // Sender resends exactly those two.
+        let mut resent = Vec::new();
+        for s in &srejs {
+            let PacketType::Srej(s) = s else {
+                unreachable!()
+            };
+            let (c, events) = handle(&txcon, &mut tx, &Event::Srej(s.clone()));
+            assert!(c.is_none());
+            resent.extend(sent(&events));
+        }
+        let resent: Vec<_> = resent
+            .into_iter()
+            .map(|t| match t {
+                PacketType::Iframe(i) => i,
+                other => panic!("expected iframe, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(resent.iter().map(|i| i.ns).collect::<Vec<_>>(), vec![1, 3]);
+
+        // Receiver fills the gaps, and delivers in order.
+        let mut got = Vec::new();
+        for i in resent {
+            let (_, events) = handle(&rxcon, &mut rx, &Event::Iframe(i, true));
+            got.extend(delivered(&events));
+        }
+        assert_eq!(got, vec![vec![1], vec![2], vec![3], vec![4]]);
+        assert_eq!(rx.vr, 5);
+        assert!(rx.srej_requested.is_empty());
+        assert!(rx.reorder_buffer.is_empty());
+
+        // Several frames lost in one go are requested in one batch.
+        let mut rx = Data::new(Addr::new("M0THC-2")?);
+        rx.peer = Some(Addr::new("M0THC-1")?);
+        rx.srej(true);
+        rx.window(4);
+        let mut last = frames[3].clone();
+        last.poll = true;
+        let (_, events) = handle(&rxcon, &mut rx, &Event::Iframe(last, true));
+        assert_eq!(
+            sent(&events),
+            vec![
+                PacketType::Srej(Srej { poll: false, nr: 0 }),
+                PacketType::Srej(Srej { poll: false, nr: 1 }),
+                PacketType::Srej(Srej { poll: true, nr: 2 }),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn iframe_while_awaiting_release() -> Result<()> {
+        let mut data = Data::new(Addr::new("M0THC-1")?);
+        data.peer = Some(Addr::new("M0THC-2")?);
+        let con = Connected::new(ConnectedState::Connected);
+        let (con, _) = handle(&con, &mut data, &Event::Disconnect);
+        let con = con.unwrap();
+        assert_eq!(con.kind(), StateKind::AwaitingRelease);
+
+        let iframe = |poll| {
+            Event::Iframe(
+                Iframe {
+                    nr: 0,
+                    ns: 0,
+                    poll,
+                    pid: 0xF0,
+                    payload: vec![1, 2, 3],
+                },
+                true,
+            )
+        };
+
+        // Not polled: resend DISC.
+        let (c, events) = handle(&*con, &mut data, &iframe(false));
+        assert!(c.is_none());
+        assert_all(
+            &[ReturnEvent::Packet(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                command_response: true,
+                command_response_la: false,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Disc(Disc { poll: true }),
+            })],
+            &events,
+            "iframe without poll",
+        );
+
+        // Polled: DM.
+        let (c, events) = handle(&*con, &mut data, &iframe(true));
+        assert!(c.is_none());
+        assert_all(
+            &[ReturnEvent::Packet(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Dm(Dm { poll: true }),
+            })],
+            &events,
+            "iframe with poll",
+        );
+        assert_eq!(data.vr, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn simultaneous_disconnect() -> Result<()> {
+        let mut data1 = Data::new(Addr::new("M0THC-1")?);
+        data1.peer = Some(Addr::new("M0THC-2")?);
+        let mut data2 = Data::new(Addr::new("M0THC-2")?);
+        data2.peer = Some(Addr::new("M0THC-1")?);
+        let con = Connected::new(ConnectedState::Connected);
+
+        // Both sides initiate disconnect.
+        let (c1, events1) = handle(&con, &mut data1, &Event::Disconnect);
+        let c1 = c1.unwrap();
+        assert_eq!(c1.name(), "AwaitingRelease");
+        let (c2, events2) = handle(&con, &mut data2, &Event::Disconnect);
+        let c2 = c2.unwrap();
+        assert_eq!(c2.name(), "AwaitingRelease");
+
+        // The DISCs cross on the air.
+        let disc = |events: &[ReturnEvent]| -> Disc {
+            for e in events {
+                if let ReturnEvent::Packet(Packet {
+                    packet_type: PacketType::Disc(d),
+                    ..
+                }) = e
+                {
+                    return d.clone();
+                }
+            }
+            panic!("no DISC sent in {events:?}");
+        };
+        let (disc1, disc2) = (disc(&events1), disc(&events2));
+        let (c1, events1) = handle(&*c1, &mut data1, &Event::Disc(disc2));
+        let (c2, events2) = handle(&*c2, &mut data2, &Event::Disc(disc1));
+        assert_eq!(c1.unwrap().name(), "Disconnected");
+        assert_eq!(c2.unwrap().name(), "Disconnected");
+        assert_all(
+            &[ReturnEvent::Packet(Packet {
+                src: Addr::new("M0THC-1")?,
+                dst: Addr::new("M0THC-2")?,
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Ua(Ua { poll: true }),
+            })],
+            &events1,
+            "disc",
+        );
+        assert_all(
+            &[ReturnEvent::Packet(Packet {
+                src: Addr::new("M0THC-2")?,
+                dst: Addr::new("M0THC-1")?,
+                command_response: false,
+                command_response_la: true,
+                digipeater: vec![],
+                rr_dist1: false,
+                rr_extseq: false,
+                packet_type: PacketType::Ua(Ua { poll: true }),
+            })],
+            &events2,
+            "disc",
+        );
+        assert!(data1.t1.remaining().is_none());
+        assert!(data2.t1.remaining().is_none());
+
+        // The UAs to the DISCs arrive after both went disconnected. They're
+        // not errors.
+        let ua = Event::Ua(Ua { poll: true });
+        let (c1, events1) = handle(&Disconnected::new(), &mut data1, &ua);
+        let (c2, events2) = handle(&Disconnected::new(), &mut data2, &ua);
+        assert!(c1.is_none());
+        assert!(c2.is_none());
+        assert!(events1.is_empty(), "{events1:?}");
+        assert!(events2.is_empty(), "{events2:?}");
+
+        // But any further UA is.
+        let (_, events1) = handle(&Disconnected::new(), &mut data1, &ua);
+        assert_eq!(
+            events1,
+            vec![
+                ReturnEvent::DlError(DlError::C),
+                ReturnEvent::DlError(DlError::D)
+            ]
+        );
+        Ok(())
+    }
 }
 /* vim: textwidth=80
  */
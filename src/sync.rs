@@ -8,7 +8,8 @@
 //!
 //! ## Client
 //!
-//! ```no_run
+#![cfg_attr(feature = "kiss", doc = "```no_run")]
+#![cfg_attr(not(feature = "kiss"), doc = "```ignore")]
 //! use std::sync::atomic::AtomicBool;
 //! use std::sync::Arc;
 //!
@@ -26,7 +27,8 @@
 //!
 //! ## Server
 //!
-//! ```no_run
+#![cfg_attr(all(feature = "bus", feature = "kiss"), doc = "```no_run")]
+#![cfg_attr(not(all(feature = "bus", feature = "kiss")), doc = "```ignore")]
 //! use std::sync::{Arc, Mutex};
 //!
 //! use rax25::sync::Client;
@@ -48,7 +50,7 @@
 //! # Ok::<(), anyhow::Error>(())
 //! ```
 use anyhow::{Error, Result};
-use log::{debug, error};
+use log::{debug, error, warn};
 
 use crate::state;
 use crate::{Addr, Hub, Packet, PacketType};
@@ -66,6 +68,7 @@ pub struct Client {
     pub(crate) data: state::Data,
     state: Box<dyn state::State>,
     eof: bool,
+    on_dl_error: Option<state::DlErrorFn>,
 
     incoming: std::collections::VecDeque<u8>,
 }
@@ -87,10 +90,20 @@ impl Client {
             eof: false,
             data: state::Data::new(me),
             state: state::new(),
+            on_dl_error: None,
             incoming: std::collections::VecDeque::new(),
         }
     }
 
+    /// Call `f` for every data link error, such as `DlError::T` when the peer
+    /// stops responding.
+    ///
+    /// Without a callback, they're only logged. Clients returned by
+    /// `accept()` don't inherit this.
+    pub fn on_dl_error(&mut self, f: state::DlErrorFn) {
+        self.on_dl_error = Some(f);
+    }
+
     /// Connect to a remote node, optionally using extended (mod-128) mode.
     pub fn connect(&mut self, addr: &Addr, ext: bool) -> Result<()> {
         self.actions(state::Event::Connect {
@@ -127,6 +140,14 @@ impl Client {
         }
     }
 
+    /// Set whether to accept incoming connections on this client.
+    ///
+    /// When not listening, an incoming SABM(E) is refused with DM. `accept()`
+    /// doesn't need this, since it creates new listening clients.
+    pub fn listen(&mut self, v: bool) {
+        self.data.listen(v);
+    }
+
     /// Wait for an incoming connection.
     ///
     /// Return a new client for that connection.
@@ -152,7 +173,7 @@ impl Client {
                             let mut new_client =
                                 Client::new(self.data.me.clone(), self.kiss.clone());
                             new_client.data.peer = Some(packet.src.clone());
-                            new_client.data.able_to_establish = true;
+                            new_client.data.listen(true);
                             new_client.actions_packet(&packet)?;
                             return Ok(Some(new_client));
                         }
@@ -161,7 +182,7 @@ impl Client {
                                 Client::new(self.data.me.clone(), self.kiss.clone());
                             new_client.data.peer = Some(packet.src.clone());
                             new_client.data.set_version_2_2();
-                            new_client.data.able_to_establish = true;
+                            new_client.data.listen(true);
                             new_client.actions_packet(&packet)?;
                             return Ok(Some(new_client));
                         }
@@ -183,14 +204,43 @@ impl Client {
         Ok(())
     }
 
+    /// Tell the peer to stop (RNR) or resume (RR) sending to us.
+    ///
+    /// While busy, received iframes are discarded, and the peer will resend
+    /// them once no longer busy.
+    pub fn set_receiver_busy(&mut self, busy: bool) {
+        self.actions(state::Event::ReceiverBusy(busy));
+    }
+
     /// Write data on an established connection.
     ///
-    /// This may block.
+    /// This may block. Fails if the output buffer doesn't have room for the
+    /// data.
     pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.check_obuf_room(data.len())?;
         self.actions(state::Event::Data(data.to_vec()));
         Ok(())
     }
 
+    /// Write data for a layer 3 protocol, such as NET/ROM, in frames of its
+    /// own.
+    pub fn write_with_pid(&mut self, pid: crate::Pid, data: &[u8]) -> Result<()> {
+        self.data.check_data_with_pid(pid.into(), data)?;
+        self.check_obuf_room(data.len())?;
+        self.actions(state::Event::DataWithPid(pid.into(), data.to_vec()));
+        Ok(())
+    }
+
+    fn check_obuf_room(&self, len: usize) -> Result<()> {
+        if self.data.obuf_room() < len {
+            return Err(Error::msg(format!(
+                "output buffer full, {} bytes of room for a write of {len}",
+                self.data.obuf_room()
+            )));
+        }
+        Ok(())
+    }
+
     /// Try reading a raw packet.
     ///
     /// This should normally not be used. Instead use `.write()`.
@@ -215,11 +265,30 @@ impl Client {
 
     /// Returns true if remote end has disconnected.
     ///
-    /// TODO: really, this maybe should be `.is_connected()`.
+    /// See also `is_connected()`.
     pub fn eof(&self) -> bool {
         self.eof
     }
 
+    /// Return true if the connection is established, including while
+    /// recovering from a timeout.
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        self.state.is_state_connected()
+    }
+
+    /// Return the remote address of the current, or last, connection.
+    #[must_use]
+    pub fn peer(&self) -> Option<&Addr> {
+        self.data.peer()
+    }
+
+    /// Return the number of sent iframes not yet acked by the peer.
+    #[must_use]
+    pub fn outstanding(&self) -> usize {
+        self.data.outstanding()
+    }
+
     /// Read data, or time out after a while.
     ///
     /// Returns an error (possibly timeout error), Some data, or None
@@ -258,17 +327,35 @@ impl Client {
             }
             PacketType::Ua(ua) => self.actions(state::Event::Ua(ua.clone())),
             PacketType::Disc(p) => self.actions(state::Event::Disc(p.clone())),
-            PacketType::Rnr(p) => self.actions(state::Event::Rnr(p.clone())),
+            PacketType::Rnr(p) => {
+                self.actions(state::Event::Rnr(p.clone(), packet.command_response))
+            }
             PacketType::Rej(p) => self.actions(state::Event::Rej(p.clone())),
             PacketType::Srej(p) => self.actions(state::Event::Srej(p.clone())),
             PacketType::Frmr(p) => self.actions(state::Event::Frmr(p.clone())),
-            PacketType::Xid(p) => {
-                self.actions(state::Event::Xid(p.clone(), packet.command_response))
-            }
-            PacketType::Ui(p) => self.actions(state::Event::Ui(p.clone(), packet.command_response)),
-            PacketType::Test(p) => {
-                self.actions(state::Event::Test(p.clone(), packet.command_response))
-            }
+            PacketType::Invalid(p) => self.actions(state::Event::Invalid(
+                p.reason(),
+                packet.src.clone(),
+                packet.dst.clone(),
+            )),
+            PacketType::Xid(p) => self.actions(state::Event::Xid(
+                p.clone(),
+                packet.command_response,
+                packet.src.clone(),
+                packet.dst.clone(),
+            )),
+            PacketType::Ui(p) => self.actions(state::Event::Ui(
+                p.clone(),
+                packet.command_response,
+                packet.src.clone(),
+                packet.dst.clone(),
+            )),
+            PacketType::Test(p) => self.actions(state::Event::Test(
+                p.clone(),
+                packet.command_response,
+                packet.src.clone(),
+                packet.dst.clone(),
+            )),
             PacketType::Dm(p) => self.actions(state::Event::Dm(p.clone())),
             PacketType::Rr(rr) => {
                 self.actions(state::Event::Rr(rr.clone(), packet.command_response))
@@ -296,9 +383,10 @@ impl Client {
         }
         for act in actions {
             match &act {
-                state::ReturnEvent::DlError(e) => {
-                    eprintln!("DLError: {e:?}");
-                }
+                state::ReturnEvent::DlError(e) => match &self.on_dl_error {
+                    Some(f) => f(*e),
+                    None => warn!("DLError: {e}"),
+                },
                 state::ReturnEvent::Data(res) => match res {
                     state::Res::None => {}
                     state::Res::EOF => self.eof = true,
@@ -330,7 +418,10 @@ mod tests {
         let mut c = Client::new(Addr::new("M0THC-1")?, Box::new(k));
         c.data.srt_default = std::time::Duration::from_millis(1);
         c.connect(&Addr::new("M0THC-2")?, false)?;
+        assert!(c.is_connected());
+        assert_eq!(c.peer(), Some(&Addr::new("M0THC-2")?));
         c.write(&vec![1, 2, 3])?;
+        assert_eq!(c.outstanding(), 1);
         let reply = c.try_read()?.unwrap();
         assert_eq!(
             reply,